@@ -0,0 +1,111 @@
+use crate::surface::{SurfaceInfo, SurfaceSource};
+use wgpu_engine::*;
+
+/// Flag of the sampling code that repeats the texture outside of the normalized [0, 1] range.
+pub const SAMPLING_REPEAT: u32 = 1 << 4;
+
+/// Depth of the wallpapers, the farthest one, so that wallpapers are always drawn beneath every surface.
+pub(crate) const WALLPAPER_DEPTH: f32 = 1.0;
+
+/// How a wallpaper is laid out on the area of an output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WallpaperMode {
+    /// Scale preserving the aspect ratio to cover the whole output, cropping the exceeding part.
+    Fill,
+    /// Scale preserving the aspect ratio to be entirely visible, centered on the output.
+    Fit,
+    /// Scale to the size of the output, ignoring the aspect ratio.
+    Stretch,
+    /// Keep the original size, centered on the output.
+    Center,
+    /// Keep the original size, repeated from the top left corner of the output.
+    Tile,
+}
+impl WallpaperMode {
+    /**
+    Returns the geometry, as `[x, y, width, height]` in logical coordinates, and the source rectangle,
+    in buffer pixels, of a wallpaper of the provided size laid out on the provided output area.
+    */
+    pub fn layout(&self, area: [f32; 4], image_size: [u32; 2]) -> ([f32; 4], [f32; 4]) {
+        let width = image_size[0] as f32;
+        let height = image_size[1] as f32;
+        let whole_image = [0.0, 0.0, width, height];
+        match self {
+            Self::Fill => {
+                let scale = (area[2] / width).max(area[3] / height);
+                let visible = [area[2] / scale, area[3] / scale];
+                let source_rect = [
+                    (width - visible[0]) / 2.0,
+                    (height - visible[1]) / 2.0,
+                    visible[0],
+                    visible[1],
+                ];
+                (area, source_rect)
+            }
+            Self::Fit => {
+                let scale = (area[2] / width).min(area[3] / height);
+                let size = [width * scale, height * scale];
+                let geometry = [
+                    area[0] + (area[2] - size[0]) / 2.0,
+                    area[1] + (area[3] - size[1]) / 2.0,
+                    size[0],
+                    size[1],
+                ];
+                (geometry, whole_image)
+            }
+            Self::Stretch => (area, whole_image),
+            Self::Center => {
+                let geometry = [
+                    area[0] + ((area[2] - width) / 2.0).floor(),
+                    area[1] + ((area[3] - height) / 2.0).floor(),
+                    width,
+                    height,
+                ];
+                (geometry, whole_image)
+            }
+            Self::Tile => (area, [0.0, 0.0, area[2], area[3]]),
+        }
+    }
+}
+
+/// Content drawn on an output beneath every surface.
+#[derive(Debug, Clone)]
+pub enum OutputBackground {
+    /// Solid color, in linear RGBA, used to clear the output.
+    Color(wgpu::Color),
+    /// Image laid out on the output; the uncovered area is cleared to black.
+    Wallpaper {
+        source: SurfaceSource,
+        mode: WallpaperMode,
+    },
+}
+impl Default for OutputBackground {
+    fn default() -> Self {
+        Self::Color(wgpu::Color::BLACK)
+    }
+}
+impl OutputBackground {
+    /// Returns the color used to clear the output before drawing.
+    pub fn clear_color(&self) -> wgpu::Color {
+        match self {
+            Self::Color(color) => *color,
+            Self::Wallpaper { .. } => wgpu::Color::BLACK,
+        }
+    }
+}
+
+/// Wallpaper of an output, stored by the SurfaceManager alongside the surfaces.
+#[derive(Debug)]
+pub(crate) struct Wallpaper {
+    pub surface: SurfaceInfo,
+    pub mode: WallpaperMode,
+}
+impl Wallpaper {
+    /// Lay out the wallpaper on the provided output area, in logical coordinates.
+    pub fn set_area(&mut self, area: [f32; 4]) {
+        let (geometry, source_rect) = self.mode.layout(area, self.surface.info.size());
+        self.surface.position = [geometry[0], geometry[1]];
+        self.surface.size = [geometry[2], geometry[3]];
+        self.surface.source_rect = Some(source_rect);
+    }
+}
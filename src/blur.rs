@@ -0,0 +1,523 @@
+use crate::engine::Engine;
+use crate::error::{OrResourceError, ScreenTaskError};
+use crate::shaders::*;
+use bytemuck::{Pod, Zeroable};
+use std::num::NonZeroU32;
+use wgpu_engine::*;
+
+/// Number of downsample passes of the background blur, each one halving the size of the blurred content.
+pub const BLUR_PASSES: usize = 4;
+
+/// Blur mode that samples the source as is.
+pub const BLUR_MODE_COPY: u32 = 0;
+/// Blur mode that downsamples the source to a target of half its size.
+pub const BLUR_MODE_DOWNSAMPLE: u32 = 1;
+/// Blur mode that upsamples the source to a target of twice its size.
+pub const BLUR_MODE_UPSAMPLE: u32 = 2;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+/// Constant data passed to the fragment stage of the blur passes.
+pub struct BlurPushConstants {
+    /// Size of a texel of the sampled texture, in normalized coordinates.
+    pub texel_size: [f32; 2],
+    pub mode: u32,
+    _padding: u32,
+}
+impl BlurPushConstants {
+    pub fn new(source_size: [u32; 2], mode: u32) -> Self {
+        Self {
+            texel_size: [
+                1.0 / source_size[0].max(1) as f32,
+                1.0 / source_size[1].max(1) as f32,
+            ],
+            mode,
+            _padding: 0,
+        }
+    }
+}
+
+/// Returns the sizes of the targets of the downsample passes of an output of the provided size.
+pub fn blur_level_sizes(size: [u32; 2]) -> Vec<[u32; 2]> {
+    (1..=BLUR_PASSES)
+        .map(|level| [(size[0] >> level).max(1), (size[1] >> level).max(1)])
+        .collect()
+}
+
+/// Shaders, layouts and sampler shared by the background blur of every display of a device.
+pub struct BlurPipelines {
+    pub vertex_shader: ShaderModuleId,
+    pub blur_fragment_shader: ShaderModuleId,
+    pub backdrop_fragment_shader: ShaderModuleId,
+    pub sampler: SamplerId,
+    pub bind_group_layout: BindGroupLayoutId,
+    pub blur_pipeline_layout: PipelineLayoutId,
+    pub backdrop_pipeline_layout: PipelineLayoutId,
+}
+impl BlurPipelines {
+    pub fn new<E: Engine>(
+        update_context: &mut E,
+        device: DeviceId,
+    ) -> Result<Self, ScreenTaskError> {
+        let mut shader = |label: &str, code: &[u32]| {
+            let descriptor = ShaderModuleDescriptor {
+                device,
+                label: String::from(label),
+                source: ShaderSource::SpirV(code.to_vec()),
+                flags: wgpu::ShaderFlags::empty(),
+            };
+            update_context
+                .add_shader_module_descriptor(descriptor)
+                .or_resource_error(label)
+        };
+        let vertex_shader = shader("ScreenTask blur VS", BLUR_VERTEX_SHADER_CODE)?;
+        let blur_fragment_shader = shader("ScreenTask blur FS", BLUR_FRAGMENT_SHADER_CODE)?;
+        let backdrop_fragment_shader =
+            shader("ScreenTask backdrop FS", BACKDROP_FRAGMENT_SHADER_CODE)?;
+
+        let sampler_descriptor = SamplerDescriptor {
+            device,
+            label: String::from("ScreenTask blur sampler"),
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            min_filter: wgpu::FilterMode::Linear,
+            mag_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            lod_min_clamp: -100.0,
+            lod_max_clamp: 100.0,
+            compare: None,
+            anisotropy_clamp: None,
+            border_color: None,
+        };
+        let sampler = update_context
+            .add_sampler_descriptor(sampler_descriptor)
+            .or_resource_error("blur sampler")?;
+
+        let bind_group_layout_descriptor = BindGroupLayoutDescriptor {
+            device,
+            label: String::from("ScreenTask blur bind group layout"),
+            entries: vec![
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler {
+                        comparison: false,
+                        filtering: true,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+            ],
+        };
+        let bind_group_layout = update_context
+            .add_bind_group_layout_descriptor(bind_group_layout_descriptor)
+            .or_resource_error("blur bind group layout")?;
+
+        let blur_pipeline_layout_descriptor = PipelineLayoutDescriptor {
+            device,
+            label: String::from("ScreenTask blur pipeline layout"),
+            bind_group_layouts: vec![bind_group_layout],
+            push_constant_ranges: vec![wgpu::PushConstantRange {
+                stages: wgpu::ShaderStage::FRAGMENT,
+                range: 0..std::mem::size_of::<BlurPushConstants>() as u32,
+            }],
+        };
+        let blur_pipeline_layout = update_context
+            .add_pipeline_layout_descriptor(blur_pipeline_layout_descriptor)
+            .or_resource_error("blur pipeline layout")?;
+
+        // The backdrop is drawn with the vertex stage of the surfaces, so it shares their push constants.
+        let aligned_size = std::mem::size_of::<crate::PushConstants>().div_ceil(4) * 4;
+        let backdrop_pipeline_layout_descriptor = PipelineLayoutDescriptor {
+            device,
+            label: String::from("ScreenTask backdrop pipeline layout"),
+            bind_group_layouts: vec![bind_group_layout],
+            push_constant_ranges: vec![wgpu::PushConstantRange {
+                stages: wgpu::ShaderStage::VERTEX,
+                range: 0..aligned_size as u32,
+            }],
+        };
+        let backdrop_pipeline_layout = update_context
+            .add_pipeline_layout_descriptor(backdrop_pipeline_layout_descriptor)
+            .or_resource_error("backdrop pipeline layout")?;
+
+        Ok(Self {
+            vertex_shader,
+            blur_fragment_shader,
+            backdrop_fragment_shader,
+            sampler,
+            bind_group_layout,
+            blur_pipeline_layout,
+            backdrop_pipeline_layout,
+        })
+    }
+}
+
+/// Offscreen texture rendered by a pass and sampled by the following ones.
+pub struct RenderTarget {
+    pub texture: TextureId,
+    pub view: TextureViewId,
+    pub bind_group: BindGroupId,
+    pub size: [u32; 2],
+}
+impl RenderTarget {
+    fn descriptors(
+        device: DeviceId,
+        label: &str,
+        size: [u32; 2],
+        format: wgpu::TextureFormat,
+    ) -> (
+        TextureDescriptor,
+        impl Fn(TextureId) -> TextureViewDescriptor,
+    ) {
+        let texture_descriptor = TextureDescriptor {
+            device,
+            label: String::from(label),
+            source: TextureSource::Local,
+            size: wgpu_engine::Extent3d {
+                width: size[0],
+                height: size[1],
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu_engine::TextureDimension::D2,
+            format,
+            usage: wgpu_engine::TextureUsage::RENDER_ATTACHMENT
+                | wgpu_engine::TextureUsage::SAMPLED,
+        };
+        let label = String::from(label) + " view";
+        let view_descriptor = move |texture| TextureViewDescriptor {
+            device,
+            label: label.clone(),
+            texture,
+            dimension: wgpu_engine::TextureViewDimension::D2,
+            format,
+            aspect: wgpu_engine::TextureAspect::All,
+            base_mip_level: 0,
+            mip_level_count: Some(NonZeroU32::new(1).unwrap()),
+            base_array_layer: 0,
+            array_layer_count: Some(NonZeroU32::new(1).unwrap()),
+        };
+        (texture_descriptor, view_descriptor)
+    }
+
+    fn bind_group_descriptor(
+        device: DeviceId,
+        pipelines: &BlurPipelines,
+        view: TextureViewId,
+    ) -> BindGroupDescriptor {
+        BindGroupDescriptor {
+            device,
+            label: String::from("ScreenTask blur bind group"),
+            entries: vec![
+                BindGroupEntry {
+                    binding: 0,
+                    resource: BindingResource::Sampler(pipelines.sampler),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: BindingResource::TextureView(view),
+                },
+            ],
+            layout: pipelines.bind_group_layout,
+        }
+    }
+
+    pub fn new<E: Engine>(
+        update_context: &mut E,
+        pipelines: &BlurPipelines,
+        device: DeviceId,
+        label: &str,
+        size: [u32; 2],
+        format: wgpu::TextureFormat,
+    ) -> Result<Self, ScreenTaskError> {
+        let (texture_descriptor, view_descriptor) = Self::descriptors(device, label, size, format);
+        let texture = update_context
+            .add_texture_descriptor(texture_descriptor)
+            .or_resource_error("blur texture")?;
+        let view = update_context
+            .add_texture_view_descriptor(view_descriptor(texture))
+            .or_resource_error("blur texture view")?;
+        let bind_group = update_context
+            .add_bind_group_descriptor(Self::bind_group_descriptor(device, pipelines, view))
+            .or_resource_error("blur bind group")?;
+        Ok(Self {
+            texture,
+            view,
+            bind_group,
+            size,
+        })
+    }
+
+    pub fn resize<E: Engine>(
+        &mut self,
+        update_context: &mut E,
+        pipelines: &BlurPipelines,
+        device: DeviceId,
+        label: &str,
+        size: [u32; 2],
+        format: wgpu::TextureFormat,
+    ) -> Result<(), ScreenTaskError> {
+        let (texture_descriptor, view_descriptor) = Self::descriptors(device, label, size, format);
+        update_context
+            .update_texture_descriptor(&mut self.texture, texture_descriptor)
+            .or_resource_error("blur texture")?;
+        update_context
+            .update_texture_view_descriptor(&mut self.view, view_descriptor(self.texture))
+            .or_resource_error("blur texture view")?;
+        update_context
+            .update_bind_group_descriptor(
+                &mut self.bind_group,
+                Self::bind_group_descriptor(device, pipelines, self.view),
+            )
+            .or_resource_error("blur bind group")?;
+        self.size = size;
+        Ok(())
+    }
+
+    pub fn remove<E: Engine>(&self, update_context: &mut E) -> Result<(), ScreenTaskError> {
+        update_context
+            .remove_texture_view(&self.view)
+            .or_resource_error("blur texture view")?;
+        update_context
+            .remove_texture(&self.texture)
+            .or_resource_error("blur texture")
+    }
+}
+
+/**
+Offscreen targets of a display drawing surfaces with a blurred background.
+
+The surfaces are composited on `scene` instead of the swapchain. Before drawing a surface with a blurred background,
+the scene is downsampled through `levels` and upsampled back to `blurred`, which is drawn beneath the surface;
+the scene is finally copied to the swapchain.
+*/
+pub struct BlurTargets {
+    pub device: DeviceId,
+    pub format: wgpu::TextureFormat,
+    pub scene: RenderTarget,
+    pub levels: Vec<RenderTarget>,
+    pub blurred: RenderTarget,
+    /// Pipeline of the downsample, upsample and copy passes.
+    pub blur_pipeline: RenderPipelineId,
+    /// Pipeline drawing the blurred background beneath a surface.
+    pub backdrop_pipeline: RenderPipelineId,
+}
+impl BlurTargets {
+    pub fn new<E: Engine>(
+        update_context: &mut E,
+        pipelines: &BlurPipelines,
+        device: DeviceId,
+        size: [u32; 2],
+        format: wgpu::TextureFormat,
+        surface_vertex_shader: ShaderModuleId,
+        depth_stencil_view: TextureViewId,
+    ) -> Result<Self, ScreenTaskError> {
+        let scene = RenderTarget::new(
+            update_context,
+            pipelines,
+            device,
+            "ScreenTask scene",
+            size,
+            format,
+        )?;
+        let levels = blur_level_sizes(size)
+            .into_iter()
+            .map(|level_size| {
+                RenderTarget::new(
+                    update_context,
+                    pipelines,
+                    device,
+                    "ScreenTask blur level",
+                    level_size,
+                    format,
+                )
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let blurred = RenderTarget::new(
+            update_context,
+            pipelines,
+            device,
+            "ScreenTask blurred scene",
+            size,
+            format,
+        )?;
+
+        let blur_pipeline = update_context
+            .add_render_pipeline_descriptor(Self::blur_pipeline_descriptor(
+                device, pipelines, format,
+            ))
+            .or_resource_error("blur render pipeline")?;
+        let backdrop_pipeline = update_context
+            .add_render_pipeline_descriptor(Self::backdrop_pipeline_descriptor(
+                device,
+                pipelines,
+                format,
+                surface_vertex_shader,
+                depth_stencil_view,
+            ))
+            .or_resource_error("backdrop render pipeline")?;
+
+        Ok(Self {
+            device,
+            format,
+            scene,
+            levels,
+            blurred,
+            blur_pipeline,
+            backdrop_pipeline,
+        })
+    }
+
+    /// Follow the size of the display.
+    pub fn resize<E: Engine>(
+        &mut self,
+        update_context: &mut E,
+        pipelines: &BlurPipelines,
+        size: [u32; 2],
+    ) -> Result<(), ScreenTaskError> {
+        let (device, format) = (self.device, self.format);
+        self.scene.resize(
+            update_context,
+            pipelines,
+            device,
+            "ScreenTask scene",
+            size,
+            format,
+        )?;
+        for (level, level_size) in self.levels.iter_mut().zip(blur_level_sizes(size)) {
+            level.resize(
+                update_context,
+                pipelines,
+                device,
+                "ScreenTask blur level",
+                level_size,
+                format,
+            )?;
+        }
+        self.blurred.resize(
+            update_context,
+            pipelines,
+            device,
+            "ScreenTask blurred scene",
+            size,
+            format,
+        )
+    }
+
+    /// Remove the offscreen textures. Every texture is removed even on failure; the first error is returned.
+    pub fn remove<E: Engine>(&self, update_context: &mut E) -> Result<(), ScreenTaskError> {
+        let mut result = Ok(());
+        let targets = self
+            .levels
+            .iter()
+            .chain(std::iter::once(&self.scene))
+            .chain(std::iter::once(&self.blurred));
+        for target in targets {
+            let current = target.remove(update_context);
+            if result.is_ok() {
+                result = current;
+            }
+        }
+        result
+    }
+
+    /// Size of the display, in physical pixels.
+    pub fn size(&self) -> [u32; 2] {
+        self.scene.size
+    }
+
+    fn blur_pipeline_descriptor(
+        device: DeviceId,
+        pipelines: &BlurPipelines,
+        format: wgpu::TextureFormat,
+    ) -> RenderPipelineDescriptor {
+        RenderPipelineDescriptor {
+            device,
+            label: String::from("ScreenTask blur render pipeline"),
+            layout: Some(pipelines.blur_pipeline_layout),
+            vertex: VertexState {
+                module: pipelines.vertex_shader,
+                entry_point: String::from("main"),
+                buffers: Vec::new(),
+            },
+            primitive: wgpu::PrimitiveState {
+                topology: wgpu::PrimitiveTopology::TriangleList,
+                ..Default::default()
+            },
+            multisample: wgpu::MultisampleState::default(),
+            depth_stencil: None,
+            fragment: Some(FragmentState {
+                module: pipelines.blur_fragment_shader,
+                entry_point: String::from("main"),
+                targets: vec![wgpu::ColorTargetState {
+                    format,
+                    blend: None,
+                    write_mask: wgpu::ColorWrite::ALL,
+                }],
+            }),
+        }
+    }
+
+    fn backdrop_pipeline_descriptor(
+        device: DeviceId,
+        pipelines: &BlurPipelines,
+        format: wgpu::TextureFormat,
+        surface_vertex_shader: ShaderModuleId,
+        depth_stencil_view: TextureViewId,
+    ) -> RenderPipelineDescriptor {
+        RenderPipelineDescriptor {
+            device,
+            label: String::from("ScreenTask backdrop render pipeline"),
+            layout: Some(pipelines.backdrop_pipeline_layout),
+            vertex: VertexState {
+                module: surface_vertex_shader,
+                entry_point: String::from("main"),
+                buffers: vec![crate::screen_task::surface_vertex_buffer_layout()],
+            },
+            primitive: wgpu::PrimitiveState {
+                topology: wgpu::PrimitiveTopology::TriangleStrip,
+                ..Default::default()
+            },
+            multisample: wgpu::MultisampleState::default(),
+            depth_stencil: Some(DepthStencilState {
+                id: depth_stencil_view,
+                depth_write_enabled: false,
+                depth_compare: wgpu::CompareFunction::LessEqual,
+                stencil: wgpu::StencilState::default(),
+                bias: wgpu::DepthBiasState::default(),
+            }),
+            fragment: Some(FragmentState {
+                module: pipelines.backdrop_fragment_shader,
+                entry_point: String::from("main"),
+                targets: vec![wgpu::ColorTargetState {
+                    format,
+                    blend: Some(wgpu::BlendState {
+                        color: wgpu::BlendComponent {
+                            src_factor: wgpu::BlendFactor::One,
+                            dst_factor: wgpu::BlendFactor::OneMinusSrcAlpha,
+                            operation: wgpu::BlendOperation::Add,
+                        },
+                        alpha: wgpu::BlendComponent {
+                            src_factor: wgpu::BlendFactor::One,
+                            dst_factor: wgpu::BlendFactor::OneMinusSrcAlpha,
+                            operation: wgpu::BlendOperation::Add,
+                        },
+                    }),
+                    write_mask: wgpu::ColorWrite::ALL,
+                }],
+            }),
+        }
+    }
+}
@@ -0,0 +1,101 @@
+/// Maximum number of rectangles produced by merge_damage.
+pub const MAX_DAMAGE_RECTS: usize = 8;
+
+/// Returns the intersection of a `[x, y, width, height]` rectangle with a buffer of the provided size.
+pub fn clamp_rect(rect: [u32; 4], size: [u32; 2]) -> Option<[u32; 4]> {
+    let x = rect[0].min(size[0]);
+    let y = rect[1].min(size[1]);
+    let right = rect[0].saturating_add(rect[2]).min(size[0]);
+    let bottom = rect[1].saturating_add(rect[3]).min(size[1]);
+    if right > x && bottom > y {
+        Some([x, y, right - x, bottom - y])
+    } else {
+        None
+    }
+}
+
+/// Returns the smallest rectangle containing both the provided rectangles.
+pub fn union_rect(a: [u32; 4], b: [u32; 4]) -> [u32; 4] {
+    let x = a[0].min(b[0]);
+    let y = a[1].min(b[1]);
+    let right = (a[0] + a[2]).max(b[0] + b[2]);
+    let bottom = (a[1] + a[3]).max(b[1] + b[3]);
+    [x, y, right - x, bottom - y]
+}
+
+/// Returns true if the rectangles overlap or share an edge.
+pub fn rects_touch(a: [u32; 4], b: [u32; 4]) -> bool {
+    a[0] <= b[0] + b[2] && b[0] <= a[0] + a[2] && a[1] <= b[1] + b[3] && b[1] <= a[1] + a[3]
+}
+
+fn area(rect: [u32; 4]) -> u64 {
+    rect[2] as u64 * rect[3] as u64
+}
+
+/**
+Reduce a list of damaged `[x, y, width, height]` rectangles to a small set of non-overlapping rectangles
+inside a buffer of the provided size.
+Overlapping or adjacent rectangles are merged into their bounding box, then the pairs that waste the least
+area are merged until at most MAX_DAMAGE_RECTS rectangles remain.
+*/
+pub fn merge_damage(rects: &[[u32; 4]], size: [u32; 2]) -> Vec<[u32; 4]> {
+    let mut merged: Vec<[u32; 4]> = rects
+        .iter()
+        .filter_map(|rect| clamp_rect(*rect, size))
+        .collect();
+
+    loop {
+        merge_touching(&mut merged);
+        if merged.len() <= MAX_DAMAGE_RECTS {
+            return merged;
+        }
+
+        let mut best = (0, 1, u64::MAX);
+        for i in 0..merged.len() {
+            for j in i + 1..merged.len() {
+                let union = union_rect(merged[i], merged[j]);
+                let waste = area(union) - area(merged[i]) - area(merged[j]);
+                if waste < best.2 {
+                    best = (i, j, waste);
+                }
+            }
+        }
+        let other = merged.swap_remove(best.1);
+        merged[best.0] = union_rect(merged[best.0], other);
+    }
+}
+
+/// Merge overlapping or adjacent rectangles into their bounding box until none of them touch.
+fn merge_touching(rects: &mut Vec<[u32; 4]>) {
+    let mut changed = true;
+    while changed {
+        changed = false;
+        'search: for i in 0..rects.len() {
+            for j in i + 1..rects.len() {
+                if rects_touch(rects[i], rects[j]) {
+                    let other = rects.swap_remove(j);
+                    rects[i] = union_rect(rects[i], other);
+                    changed = true;
+                    break 'search;
+                }
+            }
+        }
+    }
+}
+
+/**
+Copy the pixels of a `[x, y, width, height]` rectangle out of a buffer with the provided stride.
+The returned data is tightly packed. Rows missing from the buffer are filled with zeros.
+*/
+pub fn extract_rect(data: &[u8], stride: u32, bytes_per_pixel: u32, rect: [u32; 4]) -> Vec<u8> {
+    let row_length = (rect[2] * bytes_per_pixel) as usize;
+    let mut extracted = Vec::with_capacity(row_length * rect[3] as usize);
+    for row in rect[1]..rect[1] + rect[3] {
+        let start = row as usize * stride as usize + (rect[0] * bytes_per_pixel) as usize;
+        match data.get(start..start + row_length) {
+            Some(row) => extracted.extend_from_slice(row),
+            None => extracted.resize(extracted.len() + row_length, 0),
+        }
+    }
+    extracted
+}
@@ -1,4 +1,7 @@
-use crate::surface_manager::SurfaceManager;
+use crate::blur::BlurTargets;
+use crate::engine::Engine;
+use crate::error::{OrResourceError, ScreenTaskError};
+use crate::transform::Transform;
 use std::num::NonZeroU32;
 use wgpu_engine::*;
 
@@ -13,16 +16,21 @@ pub struct Display {
 
     position: [i32; 2],
     size: [u32; 2],
+    scale: f32,
+    transform: Transform,
+    clear_color: wgpu::Color,
 }
 impl Display {
-    pub fn new(
-        update_context: &mut UpdateContext,
+    pub fn new<E: Engine>(
+        update_context: &mut E,
         external_id: usize,
         device: DeviceId,
         swapchain: SwapchainId,
         position: [i32; 2],
-    ) -> Self {
-        let swapchain_descriptor = update_context.swapchain_descriptor_ref(&swapchain).unwrap();
+    ) -> Result<Self, ScreenTaskError> {
+        let swapchain_descriptor = update_context
+            .swapchain_descriptor_ref(&swapchain)
+            .or_resource_error("swapchain descriptor")?;
         let size = [swapchain_descriptor.width, swapchain_descriptor.height];
         let texture_descriptor = TextureDescriptor {
             device,
@@ -41,7 +49,7 @@ impl Display {
         };
         let depth_stencil = update_context
             .add_texture_descriptor(texture_descriptor)
-            .unwrap();
+            .or_resource_error("depth stencil texture")?;
 
         let texture_view_descriptor = TextureViewDescriptor {
             device,
@@ -57,9 +65,9 @@ impl Display {
         };
         let depth_stencil_view = update_context
             .add_texture_view_descriptor(texture_view_descriptor)
-            .unwrap();
+            .or_resource_error("depth stencil texture view")?;
 
-        Self {
+        Ok(Self {
             external_id,
             device,
             swapchain,
@@ -67,13 +75,17 @@ impl Display {
             depth_stencil_view,
             position,
             size,
-        }
+            scale: 1.0,
+            transform: Transform::Normal,
+            clear_color: wgpu::Color::BLACK,
+        })
     }
 
-    pub fn update(&mut self, update_context: &mut UpdateContext) {
+    /// Resize the depth stencil to follow the size of the swapchain.
+    pub fn update<E: Engine>(&mut self, update_context: &mut E) -> Result<(), ScreenTaskError> {
         let swapchain_descriptor = update_context
             .swapchain_descriptor_ref(&self.swapchain)
-            .unwrap();
+            .or_resource_error("swapchain descriptor")?;
         self.size = [swapchain_descriptor.width, swapchain_descriptor.height];
         let texture_descriptor = TextureDescriptor {
             device: self.device,
@@ -90,9 +102,9 @@ impl Display {
             format: crate::DEPTH_STENCIL_FORMAT,
             usage: wgpu_engine::TextureUsage::RENDER_ATTACHMENT,
         };
-        assert!(
-            update_context.update_texture_descriptor(&mut self.depth_stencil, texture_descriptor)
-        );
+        update_context
+            .update_texture_descriptor(&mut self.depth_stencil, texture_descriptor)
+            .or_resource_error("depth stencil texture")?;
 
         let texture_view_descriptor = TextureViewDescriptor {
             device: self.device,
@@ -106,13 +118,23 @@ impl Display {
             base_array_layer: 0,
             array_layer_count: Some(NonZeroU32::new(1).unwrap()),
         };
-        assert!(update_context
-            .update_texture_view_descriptor(&mut self.depth_stencil_view, texture_view_descriptor));
+        update_context
+            .update_texture_view_descriptor(&mut self.depth_stencil_view, texture_view_descriptor)
+            .or_resource_error("depth stencil texture view")
     }
 
     pub fn move_output(&mut self, position: [i32; 2]) {
         self.position = position;
     }
+    pub fn set_scale(&mut self, scale: f32) {
+        self.scale = scale;
+    }
+    pub fn set_transform(&mut self, transform: Transform) {
+        self.transform = transform;
+    }
+    pub fn set_clear_color(&mut self, clear_color: wgpu::Color) {
+        self.clear_color = clear_color;
+    }
 
     pub fn external_id(&self) -> usize {
         self.external_id
@@ -130,45 +152,79 @@ impl Display {
     pub fn position(&self) -> [i32; 2] {
         self.position
     }
+    /// Size in physical pixels.
     pub fn size(&self) -> [u32; 2] {
         self.size
     }
+    pub fn scale(&self) -> f32 {
+        self.scale
+    }
+    pub fn transform(&self) -> Transform {
+        self.transform
+    }
+    /// Size in logical coordinates, the area of the surface space covered by the display once transformed.
+    pub fn logical_size(&self) -> [u32; 2] {
+        let size = if self.transform.swaps_dimensions() {
+            [self.size[1], self.size[0]]
+        } else {
+            self.size
+        };
+        [
+            (size[0] as f32 / self.scale).round() as u32,
+            (size[1] as f32 / self.scale).round() as u32,
+        ]
+    }
+    /// Area of the surface space covered by the display, as `[x, y, width, height]` in logical coordinates.
+    pub fn logical_area(&self) -> [f32; 4] {
+        let size = self.logical_size();
+        [
+            self.position[0] as f32,
+            self.position[1] as f32,
+            size[0] as f32,
+            size[1] as f32,
+        ]
+    }
+    pub fn clear_color(&self) -> wgpu::Color {
+        self.clear_color
+    }
+    pub fn push_constants(&self) -> crate::PushConstants {
+        crate::PushConstants::for_output(self.position, self.size, self.scale, self.transform)
+    }
 }
 
 /// Resources and informations indirectly related to a display.
 pub struct DisplayResources {
     pub display: Display,
-    pub render_pipeline_ready: bool,
     pub render_pipeline: RenderPipelineId,
+    /// Offscreen targets, created once a surface needs a blurred background and kept afterwards.
+    pub blur: Option<BlurTargets>,
 }
 impl DisplayResources {
-    pub fn new(
-        update_context: &mut UpdateContext,
+    pub fn new<E: Engine>(
+        update_context: &mut E,
         display: Display,
         pipeline_layout: PipelineLayoutId,
         vertex_shader: ShaderModuleId,
         fragment_shader: ShaderModuleId,
-        surface_manager: &SurfaceManager,
-    ) -> Self {
+    ) -> Result<Self, ScreenTaskError> {
         let device = display.device;
-        let (render_pipeline_descriptor, render_pipeline_ready) =
-            crate::screen_task::ScreenTask::prepare_render_pipeline(
+        let render_pipeline_descriptor =
+            crate::screen_task::ScreenTask::<E>::prepare_render_pipeline(
                 update_context,
                 device,
                 &display,
                 pipeline_layout,
                 vertex_shader,
                 fragment_shader,
-                surface_manager,
             );
         let render_pipeline = update_context
             .add_render_pipeline_descriptor(render_pipeline_descriptor)
-            .unwrap();
+            .or_resource_error("render pipeline")?;
 
-        DisplayResources {
+        Ok(DisplayResources {
             display,
             render_pipeline,
-            render_pipeline_ready,
-        }
+            blur: None,
+        })
     }
 }
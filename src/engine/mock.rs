@@ -0,0 +1,288 @@
+use crate::engine::Engine;
+use crate::surface::Surface;
+use std::collections::HashMap;
+use wgpu_engine::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+/// Kind of resource handled by the engine.
+pub enum ResourceKind {
+    Texture,
+    TextureView,
+    Sampler,
+    ShaderModule,
+    BindGroupLayout,
+    BindGroup,
+    PipelineLayout,
+    RenderPipeline,
+    CommandBuffer,
+    SurfaceBuffer,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Operation recorded by the MockEngine.
+pub enum EngineCall {
+    Add(ResourceKind),
+    Update(ResourceKind),
+    Remove(ResourceKind),
+    /// Write of the provided number of resources.
+    Write(usize),
+}
+
+#[derive(Debug)]
+/// Surface instances stored by the MockEngine.
+pub struct MockSurfaceBuffer {
+    id: BufferId,
+    surfaces: Vec<Surface>,
+}
+impl MockSurfaceBuffer {
+    /// Returns the surface instances currently stored.
+    pub fn surfaces(&self) -> &Vec<Surface> {
+        &self.surfaces
+    }
+}
+
+#[derive(Debug, Default)]
+/**
+Engine that does not allocate any resource, but records every operation performed on it.
+Swapchains are simulated with `create_swapchain` and `destroy_swapchain`, that generate the related events
+for the next frame.
+*/
+pub struct MockEngine {
+    id_counter: usize,
+    calls: Vec<EngineCall>,
+    events: Vec<ResourceEvent>,
+    textures: HashMap<TextureId, TextureDescriptor>,
+    texture_views: HashMap<TextureViewId, TextureId>,
+    texture_writes: HashMap<TextureId, Vec<TextureWrite>>,
+    command_buffers: HashMap<CommandBufferId, CommandBufferDescriptor>,
+    swapchains: HashMap<SwapchainId, (DeviceId, SwapchainDescriptor)>,
+}
+
+macro_rules! mock_resource_methods {
+    ($($add:ident, $update:ident, $descriptor:ty, $id:ident, $kind:ident);*) => {
+        $(
+            fn $add(&mut self, _descriptor: $descriptor) -> Option<$id> {
+                self.calls.push(EngineCall::Add(ResourceKind::$kind));
+                Some($id(self.next_id()))
+            }
+            fn $update(&mut self, _id: &mut $id, _descriptor: $descriptor) -> bool {
+                self.calls.push(EngineCall::Update(ResourceKind::$kind));
+                true
+            }
+        )*
+    };
+}
+
+impl MockEngine {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn next_id(&mut self) -> usize {
+        let id = self.id_counter;
+        self.id_counter += 1;
+        id
+    }
+
+    /// Returns the id of a new device.
+    pub fn create_device(&mut self) -> DeviceId {
+        DeviceId(self.next_id())
+    }
+
+    /// Simulate the creation of a swapchain of the provided size on the provided device.
+    pub fn create_swapchain(
+        &mut self,
+        external_id: usize,
+        device: DeviceId,
+        size: [u32; 2],
+    ) -> SwapchainId {
+        let swapchain = SwapchainId(self.next_id());
+        let descriptor = SwapchainDescriptor {
+            width: size[0],
+            height: size[1],
+            format: wgpu::TextureFormat::Bgra8UnormSrgb,
+        };
+        self.swapchains.insert(swapchain, (device, descriptor));
+        self.events.push(ResourceEvent::SwapchainCreated {
+            external_id,
+            swapchain,
+        });
+        swapchain
+    }
+
+    /// Simulate the destruction of a swapchain.
+    pub fn destroy_swapchain(&mut self, swapchain: SwapchainId) {
+        self.swapchains.remove(&swapchain);
+        self.events
+            .push(ResourceEvent::SwapchainDestroyed(swapchain));
+    }
+
+    /// Returns the current descriptor of a command buffer.
+    pub fn command_buffer(&self, id: &CommandBufferId) -> Option<&CommandBufferDescriptor> {
+        self.command_buffers.get(id)
+    }
+
+    /// Returns the texture seen through a texture view.
+    pub fn texture_of_view(&self, id: &TextureViewId) -> Option<TextureId> {
+        self.texture_views.get(id).copied()
+    }
+
+    /// Returns the writes that define the current content of a texture, oldest first.
+    pub fn texture_writes(&self, id: &TextureId) -> &[TextureWrite] {
+        self.texture_writes
+            .get(id)
+            .map(|writes| writes.as_slice())
+            .unwrap_or(&[])
+    }
+
+    /// End the current frame, returning the recorded operations and discarding the pending events.
+    pub fn finish_frame(&mut self) -> Vec<EngineCall> {
+        self.events.clear();
+        std::mem::take(&mut self.calls)
+    }
+}
+
+impl Engine for MockEngine {
+    type SurfaceBuffer = MockSurfaceBuffer;
+
+    mock_resource_methods!(
+        add_bind_group_layout_descriptor, update_bind_group_layout_descriptor, BindGroupLayoutDescriptor, BindGroupLayoutId, BindGroupLayout;
+        add_bind_group_descriptor, update_bind_group_descriptor, BindGroupDescriptor, BindGroupId, BindGroup;
+        add_pipeline_layout_descriptor, update_pipeline_layout_descriptor, PipelineLayoutDescriptor, PipelineLayoutId, PipelineLayout;
+        add_render_pipeline_descriptor, update_render_pipeline_descriptor, RenderPipelineDescriptor, RenderPipelineId, RenderPipeline
+    );
+
+    fn add_command_buffer_descriptor(
+        &mut self,
+        descriptor: CommandBufferDescriptor,
+    ) -> Option<CommandBufferId> {
+        self.calls
+            .push(EngineCall::Add(ResourceKind::CommandBuffer));
+        let id = CommandBufferId(self.next_id());
+        self.command_buffers.insert(id, descriptor);
+        Some(id)
+    }
+    fn update_command_buffer_descriptor(
+        &mut self,
+        id: &mut CommandBufferId,
+        descriptor: CommandBufferDescriptor,
+    ) -> bool {
+        self.calls
+            .push(EngineCall::Update(ResourceKind::CommandBuffer));
+        self.command_buffers.insert(*id, descriptor).is_some()
+    }
+
+    fn add_texture_descriptor(&mut self, descriptor: TextureDescriptor) -> Option<TextureId> {
+        self.calls.push(EngineCall::Add(ResourceKind::Texture));
+        let id = TextureId(self.next_id());
+        self.textures.insert(id, descriptor);
+        Some(id)
+    }
+    fn update_texture_descriptor(
+        &mut self,
+        id: &mut TextureId,
+        descriptor: TextureDescriptor,
+    ) -> bool {
+        self.calls.push(EngineCall::Update(ResourceKind::Texture));
+        self.texture_writes.remove(id);
+        self.textures.insert(*id, descriptor).is_some()
+    }
+    fn remove_texture(&mut self, id: &TextureId) -> bool {
+        self.calls.push(EngineCall::Remove(ResourceKind::Texture));
+        self.texture_writes.remove(id);
+        self.textures.remove(id).is_some()
+    }
+    fn add_texture_view_descriptor(
+        &mut self,
+        descriptor: TextureViewDescriptor,
+    ) -> Option<TextureViewId> {
+        self.calls.push(EngineCall::Add(ResourceKind::TextureView));
+        let id = TextureViewId(self.next_id());
+        self.texture_views.insert(id, descriptor.texture);
+        Some(id)
+    }
+    fn update_texture_view_descriptor(
+        &mut self,
+        id: &mut TextureViewId,
+        descriptor: TextureViewDescriptor,
+    ) -> bool {
+        self.calls
+            .push(EngineCall::Update(ResourceKind::TextureView));
+        self.texture_views.insert(*id, descriptor.texture).is_some()
+    }
+    fn remove_texture_view(&mut self, id: &TextureViewId) -> bool {
+        self.calls
+            .push(EngineCall::Remove(ResourceKind::TextureView));
+        self.texture_views.remove(id);
+        true
+    }
+    fn add_sampler_descriptor(&mut self, _descriptor: SamplerDescriptor) -> Option<SamplerId> {
+        self.calls.push(EngineCall::Add(ResourceKind::Sampler));
+        Some(SamplerId(self.next_id()))
+    }
+    fn add_shader_module_descriptor(
+        &mut self,
+        _descriptor: ShaderModuleDescriptor,
+    ) -> Option<ShaderModuleId> {
+        self.calls.push(EngineCall::Add(ResourceKind::ShaderModule));
+        Some(ShaderModuleId(self.next_id()))
+    }
+
+    fn texture_descriptor_ref(&self, id: &TextureId) -> Option<&TextureDescriptor> {
+        self.textures.get(id)
+    }
+    fn swapchain_descriptor_ref(&self, id: &SwapchainId) -> Option<&SwapchainDescriptor> {
+        self.swapchains.get(id).map(|(_, descriptor)| descriptor)
+    }
+    fn entity_device_id(&self, id: &SwapchainId) -> Option<DeviceId> {
+        self.swapchains.get(id).map(|(device, _)| *device)
+    }
+    fn events(&self) -> Vec<ResourceEvent> {
+        self.events.clone()
+    }
+    fn write_resource(&mut self, writes: &mut Vec<ResourceWrite>) {
+        self.calls.push(EngineCall::Write(writes.len()));
+        for write in writes.drain(..) {
+            let write = match write {
+                ResourceWrite::Texture(write) => write,
+                #[allow(unreachable_patterns)]
+                _ => continue,
+            };
+            let texture_writes = self.texture_writes.entry(write.texture).or_default();
+            // A write covering the whole texture replaces the previous content.
+            let covers_texture = match self.textures.get(&write.texture) {
+                Some(texture) => {
+                    write.origin.x == 0
+                        && write.origin.y == 0
+                        && write.size.width == texture.size.width
+                        && write.size.height == texture.size.height
+                }
+                None => false,
+            };
+            if covers_texture {
+                texture_writes.clear();
+            }
+            texture_writes.push(write);
+        }
+    }
+
+    fn create_surface_buffer(&mut self, _label: String, _device: DeviceId) -> MockSurfaceBuffer {
+        self.calls
+            .push(EngineCall::Add(ResourceKind::SurfaceBuffer));
+        MockSurfaceBuffer {
+            id: BufferId(self.next_id()),
+            surfaces: Vec::new(),
+        }
+    }
+    fn surface_buffer_id(buffer: &MockSurfaceBuffer) -> &BufferId {
+        &buffer.id
+    }
+    fn write_surface_buffer(&mut self, buffer: &mut MockSurfaceBuffer, surfaces: Vec<Surface>) {
+        self.calls
+            .push(EngineCall::Update(ResourceKind::SurfaceBuffer));
+        buffer.surfaces = surfaces;
+    }
+    fn update_surface_buffer(&mut self, _buffer: &mut MockSurfaceBuffer) -> Vec<Command> {
+        Vec::new()
+    }
+}
@@ -0,0 +1,211 @@
+use crate::surface::Surface;
+use wgpu_engine::*;
+
+mod mock;
+pub use mock::*;
+
+/**
+Resource operations performed by ScreenTask on the rendering engine.
+
+It covers the subset of UpdateContext used by the crate, so that the resource management
+can be driven by a different implementation, like the recording MockEngine used in tests.
+*/
+pub trait Engine {
+    /// Storage of the surface instances, used as vertex buffer by the render pipeline.
+    type SurfaceBuffer: std::fmt::Debug;
+
+    fn add_texture_descriptor(&mut self, descriptor: TextureDescriptor) -> Option<TextureId>;
+    fn update_texture_descriptor(
+        &mut self,
+        id: &mut TextureId,
+        descriptor: TextureDescriptor,
+    ) -> bool;
+    fn remove_texture(&mut self, id: &TextureId) -> bool;
+
+    fn add_texture_view_descriptor(
+        &mut self,
+        descriptor: TextureViewDescriptor,
+    ) -> Option<TextureViewId>;
+    fn update_texture_view_descriptor(
+        &mut self,
+        id: &mut TextureViewId,
+        descriptor: TextureViewDescriptor,
+    ) -> bool;
+    fn remove_texture_view(&mut self, id: &TextureViewId) -> bool;
+
+    fn add_sampler_descriptor(&mut self, descriptor: SamplerDescriptor) -> Option<SamplerId>;
+
+    fn add_shader_module_descriptor(
+        &mut self,
+        descriptor: ShaderModuleDescriptor,
+    ) -> Option<ShaderModuleId>;
+
+    fn add_bind_group_layout_descriptor(
+        &mut self,
+        descriptor: BindGroupLayoutDescriptor,
+    ) -> Option<BindGroupLayoutId>;
+    fn update_bind_group_layout_descriptor(
+        &mut self,
+        id: &mut BindGroupLayoutId,
+        descriptor: BindGroupLayoutDescriptor,
+    ) -> bool;
+
+    fn add_bind_group_descriptor(&mut self, descriptor: BindGroupDescriptor)
+        -> Option<BindGroupId>;
+    fn update_bind_group_descriptor(
+        &mut self,
+        id: &mut BindGroupId,
+        descriptor: BindGroupDescriptor,
+    ) -> bool;
+
+    fn add_pipeline_layout_descriptor(
+        &mut self,
+        descriptor: PipelineLayoutDescriptor,
+    ) -> Option<PipelineLayoutId>;
+    fn update_pipeline_layout_descriptor(
+        &mut self,
+        id: &mut PipelineLayoutId,
+        descriptor: PipelineLayoutDescriptor,
+    ) -> bool;
+
+    fn add_render_pipeline_descriptor(
+        &mut self,
+        descriptor: RenderPipelineDescriptor,
+    ) -> Option<RenderPipelineId>;
+    fn update_render_pipeline_descriptor(
+        &mut self,
+        id: &mut RenderPipelineId,
+        descriptor: RenderPipelineDescriptor,
+    ) -> bool;
+
+    fn add_command_buffer_descriptor(
+        &mut self,
+        descriptor: CommandBufferDescriptor,
+    ) -> Option<CommandBufferId>;
+    fn update_command_buffer_descriptor(
+        &mut self,
+        id: &mut CommandBufferId,
+        descriptor: CommandBufferDescriptor,
+    ) -> bool;
+
+    fn texture_descriptor_ref(&self, id: &TextureId) -> Option<&TextureDescriptor>;
+    fn swapchain_descriptor_ref(&self, id: &SwapchainId) -> Option<&SwapchainDescriptor>;
+    fn entity_device_id(&self, id: &SwapchainId) -> Option<DeviceId>;
+    fn events(&self) -> Vec<ResourceEvent>;
+    fn write_resource(&mut self, writes: &mut Vec<ResourceWrite>);
+
+    /// Create the buffer storing the surface instances.
+    fn create_surface_buffer(&mut self, label: String, device: DeviceId) -> Self::SurfaceBuffer;
+    /// Returns the id of the buffer storing the surface instances.
+    fn surface_buffer_id(buffer: &Self::SurfaceBuffer) -> &BufferId;
+    /// Replace the content of the buffer with the provided surface instances.
+    fn write_surface_buffer(&mut self, buffer: &mut Self::SurfaceBuffer, surfaces: Vec<Surface>);
+    /// Returns the commands needed to synchronize the buffer with the gpu.
+    fn update_surface_buffer(&mut self, buffer: &mut Self::SurfaceBuffer) -> Vec<Command>;
+}
+
+#[derive(Debug)]
+/**
+Surface instances stored through a BufferManager.
+Instances are keyed by their slot in the drawing order, so that slots are overwritten in place when possible.
+*/
+pub struct SurfaceBufferManager {
+    buffer: BufferManager<Surface, ()>,
+    len: usize,
+}
+impl SurfaceBufferManager {
+    /**
+    Overwrite the surface data stored in the provided buffer slot.
+    The slot is released and requested again with the whole instance,
+    so that no field of the previous surface in the slot survives.
+    */
+    fn write_slot(&mut self, slot: usize, surface_data: Surface) {
+        self.buffer.release_pending(&slot);
+        self.buffer.request(slot, (), surface_data);
+    }
+}
+
+macro_rules! forward_resource_methods {
+    ($($add:ident, $update:ident, $descriptor:ty, $id:ty);*) => {
+        $(
+            fn $add(&mut self, descriptor: $descriptor) -> Option<$id> {
+                UpdateContext::$add(self, descriptor).ok()
+            }
+            fn $update(&mut self, id: &mut $id, descriptor: $descriptor) -> bool {
+                UpdateContext::$update(self, id, descriptor)
+            }
+        )*
+    };
+}
+
+impl Engine for UpdateContext {
+    type SurfaceBuffer = SurfaceBufferManager;
+
+    forward_resource_methods!(
+        add_texture_descriptor, update_texture_descriptor, TextureDescriptor, TextureId;
+        add_texture_view_descriptor, update_texture_view_descriptor, TextureViewDescriptor, TextureViewId;
+        add_bind_group_layout_descriptor, update_bind_group_layout_descriptor, BindGroupLayoutDescriptor, BindGroupLayoutId;
+        add_bind_group_descriptor, update_bind_group_descriptor, BindGroupDescriptor, BindGroupId;
+        add_pipeline_layout_descriptor, update_pipeline_layout_descriptor, PipelineLayoutDescriptor, PipelineLayoutId;
+        add_render_pipeline_descriptor, update_render_pipeline_descriptor, RenderPipelineDescriptor, RenderPipelineId;
+        add_command_buffer_descriptor, update_command_buffer_descriptor, CommandBufferDescriptor, CommandBufferId
+    );
+
+    fn remove_texture(&mut self, id: &TextureId) -> bool {
+        UpdateContext::remove_texture(self, id).is_ok()
+    }
+    fn remove_texture_view(&mut self, id: &TextureViewId) -> bool {
+        UpdateContext::remove_texture_view(self, id).is_ok()
+    }
+    fn add_sampler_descriptor(&mut self, descriptor: SamplerDescriptor) -> Option<SamplerId> {
+        UpdateContext::add_sampler_descriptor(self, descriptor).ok()
+    }
+    fn add_shader_module_descriptor(
+        &mut self,
+        descriptor: ShaderModuleDescriptor,
+    ) -> Option<ShaderModuleId> {
+        UpdateContext::add_shader_module_descriptor(self, descriptor).ok()
+    }
+
+    fn texture_descriptor_ref(&self, id: &TextureId) -> Option<&TextureDescriptor> {
+        UpdateContext::texture_descriptor_ref(self, id)
+    }
+    fn swapchain_descriptor_ref(&self, id: &SwapchainId) -> Option<&SwapchainDescriptor> {
+        UpdateContext::swapchain_descriptor_ref(self, id)
+    }
+    fn entity_device_id(&self, id: &SwapchainId) -> Option<DeviceId> {
+        UpdateContext::entity_device_id(self, id)
+    }
+    fn events(&self) -> Vec<ResourceEvent> {
+        UpdateContext::events(self).clone()
+    }
+    fn write_resource(&mut self, writes: &mut Vec<ResourceWrite>) {
+        UpdateContext::write_resource(self, writes)
+    }
+
+    fn create_surface_buffer(&mut self, label: String, device: DeviceId) -> SurfaceBufferManager {
+        let buffer = BufferManager::new(self, label, device, 32, wgpu::BufferUsage::VERTEX);
+        SurfaceBufferManager { buffer, len: 0 }
+    }
+    fn surface_buffer_id(buffer: &SurfaceBufferManager) -> &BufferId {
+        buffer.buffer.id()
+    }
+    fn write_surface_buffer(&mut self, buffer: &mut SurfaceBufferManager, surfaces: Vec<Surface>) {
+        let surface_count = surfaces.len();
+        for (slot, surface_data) in surfaces.into_iter().enumerate() {
+            if slot < buffer.len {
+                buffer.write_slot(slot, surface_data);
+            } else {
+                buffer.buffer.request(slot, (), surface_data);
+            }
+        }
+        while buffer.len > surface_count {
+            buffer.len -= 1;
+            buffer.buffer.release_pending(&buffer.len);
+        }
+        buffer.len = surface_count;
+    }
+    fn update_surface_buffer(&mut self, buffer: &mut SurfaceBufferManager) -> Vec<Command> {
+        buffer.buffer.update(self)
+    }
+}
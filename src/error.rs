@@ -0,0 +1,151 @@
+use std::fmt;
+
+#[derive(Debug)]
+/// Errors reported by ScreenTask and the resources it manages.
+pub enum ScreenTaskError {
+    /// No surface is associated with the provided id.
+    UnknownSurface(usize),
+    /// A surface with the provided id already exists.
+    DuplicateSurface(usize),
+    /// No device is associated with the provided id.
+    UnknownDevice(wgpu_engine::DeviceId),
+    /// The surfaces can not be stacked next to each other, as they do not share the same parent.
+    InvalidSibling { id: usize, sibling: usize },
+    /// The stride, in bytes, is smaller than a row of the buffer.
+    InvalidStride { stride: u32, minimum: u32 },
+    /// The stride, in bytes, is not a multiple of the size of a pixel.
+    MisalignedStride { stride: u32, alignment: u32 },
+    /// The size has a zero dimension.
+    InvalidSize([u32; 2]),
+    /// The output scale factor is not a positive finite number.
+    InvalidScale(f32),
+    /// The corner radius or the border width is not a non-negative finite number.
+    InvalidDecoration { radius: f32, border_width: f32 },
+    /// The shadow offset is not finite or the blur radius is not a non-negative finite number.
+    InvalidShadow { offset: [f32; 2], blur_radius: f32 },
+    /// The color has a component that is not finite.
+    InvalidColor([f32; 4]),
+    /// The `[x, y, width, height]` rectangle is not finite, has a negative size or, as a source, is empty or outside its buffer.
+    InvalidRect([f32; 4]),
+    /// The data, in bytes, is shorter than the buffer it should describe.
+    InvalidDataLength { length: usize, expected: usize },
+    /// The buffer of `length` bytes starting at `offset` ends past the addressable memory.
+    BufferOutOfRange { offset: u64, length: u64 },
+    /// The pixel format is not supported for the requested source.
+    UnsupportedFormat(String),
+    /// The image could not be read or decoded.
+    Decode(image::ImageError),
+    /// The buffer could not be mapped in host memory.
+    Mapping(std::io::Error),
+    /// The file of a shared memory pool was shrunk while mapped, so the buffer can no longer be read.
+    TruncatedPool,
+    /// The acquire fence of a new source of the surface was not signaled in time, so the source was dropped.
+    AcquireTimeout(usize),
+    /// The engine failed to create or update a resource.
+    ResourceCreation(String),
+}
+
+impl fmt::Display for ScreenTaskError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownSurface(id) => write!(f, "surface {} does not exists", id),
+            Self::DuplicateSurface(id) => write!(f, "surface {} already exists", id),
+            Self::UnknownDevice(id) => write!(f, "device {:?} does not exists", id),
+            Self::InvalidSibling { id, sibling } => write!(
+                f,
+                "surface {} can not be stacked next to surface {}, they have different parents",
+                id, sibling
+            ),
+            Self::InvalidStride { stride, minimum } => write!(
+                f,
+                "stride of {} bytes is smaller than the minimum of {} bytes",
+                stride, minimum
+            ),
+            Self::MisalignedStride { stride, alignment } => write!(
+                f,
+                "stride of {} bytes is not a multiple of {} bytes",
+                stride, alignment
+            ),
+            Self::InvalidSize(size) => write!(f, "invalid size {:?}", size),
+            Self::InvalidScale(scale) => write!(f, "invalid output scale {}", scale),
+            Self::InvalidDecoration {
+                radius,
+                border_width,
+            } => write!(
+                f,
+                "invalid decoration with corner radius {} and border width {}",
+                radius, border_width
+            ),
+            Self::InvalidShadow {
+                offset,
+                blur_radius,
+            } => write!(
+                f,
+                "invalid shadow with offset {:?} and blur radius {}",
+                offset, blur_radius
+            ),
+            Self::InvalidColor(color) => write!(f, "invalid color {:?}", color),
+            Self::InvalidRect(rect) => write!(f, "invalid rectangle {:?}", rect),
+            Self::InvalidDataLength { length, expected } => write!(
+                f,
+                "data of {} bytes is shorter than the expected {} bytes",
+                length, expected
+            ),
+            Self::BufferOutOfRange { offset, length } => write!(
+                f,
+                "buffer of {} bytes at offset {} is out of the addressable range",
+                length, offset
+            ),
+            Self::UnsupportedFormat(format) => write!(f, "unsupported format: {}", format),
+            Self::Decode(error) => write!(f, "failed to decode image: {}", error),
+            Self::Mapping(error) => write!(f, "failed to map buffer: {}", error),
+            Self::TruncatedPool => write!(f, "shared memory pool truncated while mapped"),
+            Self::AcquireTimeout(id) => {
+                write!(f, "acquire fence of surface {} not signaled in time", id)
+            }
+            Self::ResourceCreation(resource) => write!(f, "failed to create {}", resource),
+        }
+    }
+}
+
+impl std::error::Error for ScreenTaskError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Decode(error) => Some(error),
+            Self::Mapping(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<image::ImageError> for ScreenTaskError {
+    fn from(error: image::ImageError) -> Self {
+        Self::Decode(error)
+    }
+}
+
+/// Conversion of the engine results into ScreenTaskError::ResourceCreation.
+pub(crate) trait OrResourceError<T> {
+    fn or_resource_error(self, resource: &str) -> Result<T, ScreenTaskError>;
+}
+impl<T, E: fmt::Debug> OrResourceError<T> for Result<T, E> {
+    fn or_resource_error(self, resource: &str) -> Result<T, ScreenTaskError> {
+        self.map_err(|error| {
+            ScreenTaskError::ResourceCreation(format!("{} ({:?})", resource, error))
+        })
+    }
+}
+impl<T> OrResourceError<T> for Option<T> {
+    fn or_resource_error(self, resource: &str) -> Result<T, ScreenTaskError> {
+        self.ok_or_else(|| ScreenTaskError::ResourceCreation(resource.to_string()))
+    }
+}
+impl OrResourceError<()> for bool {
+    fn or_resource_error(self, resource: &str) -> Result<(), ScreenTaskError> {
+        if self {
+            Ok(())
+        } else {
+            Err(ScreenTaskError::ResourceCreation(resource.to_string()))
+        }
+    }
+}
@@ -0,0 +1,149 @@
+use crate::error::ScreenTaskError;
+use crate::yuv::YuvFormat;
+use std::fmt;
+use wgpu_engine::*;
+
+/// DRM format modifier of buffers stored in linear memory, without tiling or compression.
+pub const DRM_FORMAT_MOD_LINEAR: DrmModifier = DrmModifier(0);
+
+/// Maximum number of planes of a Dma buffer, auxiliary planes of the modifier included.
+pub const MAX_DMABUF_PLANES: usize = 4;
+
+/// DRM fourcc code describing the pixel layout of a Dma buffer, as defined in `drm_fourcc.h`.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct DrmFourcc(pub u32);
+impl DrmFourcc {
+    /// 32 bit BGRA stored as little endian ARGB.
+    pub const ARGB8888: Self = Self::from_chars(*b"AR24");
+    /// 32 bit BGRX stored as little endian XRGB, the alpha channel is ignored.
+    pub const XRGB8888: Self = Self::from_chars(*b"XR24");
+    /// 32 bit RGBA stored as little endian ABGR.
+    pub const ABGR8888: Self = Self::from_chars(*b"AB24");
+    /// 32 bit RGBX stored as little endian XBGR, the alpha channel is ignored.
+    pub const XBGR8888: Self = Self::from_chars(*b"XB24");
+    /// 2 plane YCbCr 4:2:0, only importable as `SurfaceSource::YuvDmabuf`.
+    pub const NV12: Self = Self::from_chars(*b"NV12");
+    /// 3 plane YCbCr 4:2:0, only importable as `SurfaceSource::YuvDmabuf`.
+    pub const YUV420: Self = Self::from_chars(*b"YU12");
+    /// 2 plane YCbCr 4:2:0 with 16 bit samples, only supported in host allocations.
+    pub const P010: Self = Self::from_chars(*b"P010");
+
+    /// Build the code from its four characters.
+    pub const fn from_chars(chars: [u8; 4]) -> Self {
+        Self(u32::from_le_bytes(chars))
+    }
+
+    /**
+    Returns the texture format used to sample a single texture buffer of this fourcc.
+    Fails for the fourccs that can not be sampled from a single texture.
+    */
+    pub fn texture_format(&self) -> Result<wgpu::TextureFormat, ScreenTaskError> {
+        if let Some((_, format)) = RGB_FORMATS.iter().find(|(fourcc, _)| fourcc == self) {
+            return Ok(*format);
+        }
+        match self.yuv_format() {
+            Some(_) => Err(ScreenTaskError::UnsupportedFormat(format!(
+                "{} in single texture buffers, use a YUV source",
+                self
+            ))),
+            None => Err(ScreenTaskError::UnsupportedFormat(format!(
+                "{} buffers",
+                self
+            ))),
+        }
+    }
+
+    /// Returns the YUV format of the multi-plane fourccs.
+    pub fn yuv_format(&self) -> Option<YuvFormat> {
+        match *self {
+            Self::NV12 => Some(YuvFormat::Nv12),
+            Self::YUV420 => Some(YuvFormat::Yuv420),
+            Self::P010 => Some(YuvFormat::P010),
+            _ => None,
+        }
+    }
+
+    /// Returns true if the alpha channel of the buffer is padding, so the buffer is always opaque.
+    pub fn ignores_alpha(&self) -> bool {
+        matches!(*self, Self::XRGB8888 | Self::XBGR8888)
+    }
+}
+impl fmt::Display for DrmFourcc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let chars = self.0.to_le_bytes();
+        if chars
+            .iter()
+            .all(|char| char.is_ascii_graphic() || *char == b' ')
+        {
+            write!(f, "{}", String::from_utf8_lossy(&chars))
+        } else {
+            write!(f, "{:#010x}", self.0)
+        }
+    }
+}
+impl fmt::Debug for DrmFourcc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "DrmFourcc({})", self)
+    }
+}
+
+/// Fourccs sampled from a single texture, with the texture format of that texture.
+const RGB_FORMATS: [(DrmFourcc, wgpu::TextureFormat); 4] = [
+    (DrmFourcc::ARGB8888, wgpu::TextureFormat::Bgra8UnormSrgb),
+    (DrmFourcc::XRGB8888, wgpu::TextureFormat::Bgra8UnormSrgb),
+    (DrmFourcc::ABGR8888, wgpu::TextureFormat::Rgba8UnormSrgb),
+    (DrmFourcc::XBGR8888, wgpu::TextureFormat::Rgba8UnormSrgb),
+];
+
+/**
+Pixel formats that can be imported on a device, the basis to advertise the shm and the dmabuf formats to the clients.
+Modifiers are limited to the ones that do not depend on the device, so only linear buffers are listed:
+they can be imported with external memory or copied through a cpu mapping without it.
+*/
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct SupportedFormats {
+    /**
+    Fourccs accepted as host allocations, through their texture format or as YUV sources.
+    The shm protocol uses 0 and 1 instead of the fourcc codes of ARGB8888 and XRGB8888.
+    */
+    pub shm: Vec<DrmFourcc>,
+    /// Fourcc and modifier pairs accepted as Dma buffers, through a single texture or as YUV sources.
+    pub dmabuf: Vec<(DrmFourcc, DrmModifier)>,
+}
+impl SupportedFormats {
+    /**
+    Returns the formats supported by a device, depending on whether it imports the Dma buffers with external memory.
+    P010 planes are uploaded as half floats, so P010 Dma buffers are only supported when they are copied.
+    */
+    pub fn new(external_memory: bool) -> Self {
+        // Host allocations have no fourcc, so the padding of the X formats could not be ignored.
+        let shm = RGB_FORMATS
+            .iter()
+            .map(|(fourcc, _)| *fourcc)
+            .filter(|fourcc| !fourcc.ignores_alpha())
+            .chain(vec![DrmFourcc::NV12, DrmFourcc::YUV420, DrmFourcc::P010])
+            .collect();
+        let yuv_dmabuf = if external_memory {
+            vec![DrmFourcc::NV12, DrmFourcc::YUV420]
+        } else {
+            vec![DrmFourcc::NV12, DrmFourcc::YUV420, DrmFourcc::P010]
+        };
+        let dmabuf = RGB_FORMATS
+            .iter()
+            .map(|(fourcc, _)| *fourcc)
+            .chain(yuv_dmabuf)
+            .map(|fourcc| (fourcc, DRM_FORMAT_MOD_LINEAR))
+            .collect();
+        Self { shm, dmabuf }
+    }
+
+    /// Returns true if host allocations of the provided fourcc are supported.
+    pub fn supports_shm(&self, fourcc: DrmFourcc) -> bool {
+        self.shm.contains(&fourcc)
+    }
+
+    /// Returns true if Dma buffers of the provided fourcc and modifier are supported.
+    pub fn supports_dmabuf(&self, fourcc: DrmFourcc, modifier: DrmModifier) -> bool {
+        self.dmabuf.contains(&(fourcc, modifier))
+    }
+}
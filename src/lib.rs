@@ -1,3 +1,12 @@
+mod error;
+pub use error::ScreenTaskError;
+
+mod engine;
+pub use engine::*;
+
+mod background;
+pub use background::*;
+
 mod surface_manager;
 pub use surface_manager::SurfaceManager;
 
@@ -7,6 +16,35 @@ pub use display::Display;
 mod surface;
 pub use surface::*;
 
+mod transform;
+pub use transform::*;
+
+mod stacking;
+pub use stacking::*;
+
+mod shadow;
+pub use shadow::*;
+
+mod blur;
+pub use blur::*;
+
+mod damage;
+pub use damage::*;
+
+mod yuv;
+pub use yuv::*;
+
+mod fourcc;
+pub use fourcc::*;
+
+mod mapping;
+
+mod sync;
+pub use sync::*;
+
+mod software;
+pub use software::*;
+
 mod screen_task;
 pub use screen_task::*;
 
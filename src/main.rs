@@ -1,18 +1,4 @@
-use wgpu_engine::*;
-
-mod surface_manager;
-pub use surface_manager::SurfaceManager;
-
-mod display;
-pub use display::Display;
-
-mod surface;
-pub use surface::*;
-
-mod screen_task;
-use crate::screen_task::*;
-
-mod shaders;
+use screen_task::*;
 
 fn main() {
     env_logger::init();
@@ -28,8 +14,10 @@ fn main() {
         features |= wgpu_engine::Features::EXTERNAL_MEMORY;
     }
 
-    let mut limits = wgpu_engine::Limits::default();
-    limits.max_push_constant_size = std::mem::size_of::<PushConstants>() as u32;
+    let limits = wgpu_engine::Limits {
+        max_push_constant_size: std::mem::size_of::<PushConstants>() as u32,
+        ..Default::default()
+    };
 
     wgpu_engine::quick_run(
         1,
@@ -37,23 +25,29 @@ fn main() {
         limits,
         |_id, _tokio_runtime, update_context| {
             let mut screen_task = ScreenTask::new(update_context);
-            screen_task.create_surface(
-                0,
-                String::from("surface"),
-                SurfaceSource::from_file_path(std::path::PathBuf::from("./gfx_logo.png")),
-                [0, 0, 0],
-                [100, 100],
-            );
-            screen_task.create_surface(
-                1,
-                String::from("surface"),
-                SurfaceSource::from_file_path(std::path::PathBuf::from("./gfx_logo.png")),
-                [50, 50, 1],
-                [100, 100],
-            );
+            screen_task
+                .create_surface(
+                    0,
+                    String::from("surface"),
+                    SurfaceSource::from_file_path(std::path::PathBuf::from("./gfx_logo.png"))
+                        .unwrap(),
+                    [0, 0, 0],
+                    [100, 100],
+                )
+                .unwrap();
+            screen_task
+                .create_surface(
+                    1,
+                    String::from("surface"),
+                    SurfaceSource::from_file_path(std::path::PathBuf::from("./gfx_logo.png"))
+                        .unwrap(),
+                    [50, 50, 1],
+                    [100, 100],
+                )
+                .unwrap();
             screen_task
         },
-        |task| {},
+        |_task| {},
     );
 }
 // Triangle sopra
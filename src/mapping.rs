@@ -0,0 +1,279 @@
+use crate::error::ScreenTaskError;
+use crate::surface::buffer_end;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::os::unix::io::{AsRawFd, BorrowedFd, OwnedFd, RawFd};
+use std::sync::atomic::{compiler_fence, AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+
+/// `DMA_BUF_IOCTL_SYNC` request, `_IOW('b', 0, struct dma_buf_sync)`.
+const DMA_BUF_IOCTL_SYNC: libc::c_ulong = 0x4008_6200;
+const DMA_BUF_SYNC_READ: u64 = 1 << 0;
+const DMA_BUF_SYNC_START: u64 = 0 << 2;
+const DMA_BUF_SYNC_END: u64 = 1 << 2;
+
+/**
+Read-only shared mapping of a range of a file descriptor, unmapped when dropped.
+
+The range is checked against the size of the file when it is mapped, so that reading it does not fault
+unless the file shrinks afterwards; `read_range` survives that case.
+*/
+#[derive(Debug)]
+pub struct MemoryMapping {
+    pointer: *mut libc::c_void,
+    mapped_length: usize,
+    /// Distance of the requested offset from the page aligned start of the mapping.
+    start: usize,
+    length: usize,
+    /// Duplicate of the mapped file descriptor, which the client can close while the mapping is in use.
+    fd: OwnedFd,
+    /// Set once a read faulted and the mapping was replaced with zeroed memory.
+    truncated: AtomicBool,
+}
+// The mapping is read-only and owned by the struct, so it can be read from any thread.
+unsafe impl Send for MemoryMapping {}
+unsafe impl Sync for MemoryMapping {}
+impl MemoryMapping {
+    /// Map `length` bytes of the file descriptor starting at `offset`.
+    pub fn new(fd: RawFd, offset: u64, length: usize) -> Result<Self, ScreenTaskError> {
+        let size = file_size(fd)?;
+        let too_short = |expected| ScreenTaskError::InvalidDataLength {
+            length: size as usize,
+            expected,
+        };
+        let expected = usize::try_from(offset)
+            .ok()
+            .and_then(|offset| offset.checked_add(length))
+            .ok_or(ScreenTaskError::BufferOutOfRange {
+                offset,
+                length: length as u64,
+            })?;
+        if (size as usize) < expected {
+            return Err(too_short(expected));
+        }
+        // The descriptor was checked by fstat, so it is open.
+        let fd = unsafe { BorrowedFd::borrow_raw(fd) }
+            .try_clone_to_owned()
+            .map_err(ScreenTaskError::Mapping)?;
+
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64;
+        let aligned_offset = offset - offset % page_size;
+        let start = (offset - aligned_offset) as usize;
+        let mapped_length = (start + length).max(1);
+        let pointer = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                mapped_length,
+                libc::PROT_READ,
+                libc::MAP_SHARED,
+                fd.as_raw_fd(),
+                aligned_offset as libc::off_t,
+            )
+        };
+        if pointer == libc::MAP_FAILED {
+            return Err(ScreenTaskError::Mapping(std::io::Error::last_os_error()));
+        }
+        Ok(Self {
+            pointer,
+            mapped_length,
+            start,
+            length,
+            fd,
+            truncated: AtomicBool::new(false),
+        })
+    }
+
+    /// Returns the number of mapped bytes.
+    pub fn len(&self) -> usize {
+        self.length
+    }
+
+    /// Returns the mapped bytes.
+    pub fn data(&self) -> &[u8] {
+        unsafe {
+            std::slice::from_raw_parts((self.pointer as *const u8).add(self.start), self.length)
+        }
+    }
+
+    /**
+    Copy the mapped bytes into host memory.
+    Dma buffers are kept coherent with the devices writing them during the copy, other files ignore the sync.
+    */
+    pub fn read(&self) -> Vec<u8> {
+        dma_buf_sync(self.fd.as_raw_fd(), DMA_BUF_SYNC_START | DMA_BUF_SYNC_READ);
+        let data = self.data().to_vec();
+        dma_buf_sync(self.fd.as_raw_fd(), DMA_BUF_SYNC_END | DMA_BUF_SYNC_READ);
+        data
+    }
+
+    /**
+    Copy `length` bytes starting at `offset` into host memory, for files that other processes can shrink.
+    A read past the end of the shrunk file raises SIGBUS: the pages are replaced with zeroes and the copy fails,
+    like every later one, instead of crashing the process.
+    */
+    pub fn read_range(&self, offset: usize, length: usize) -> Result<Vec<u8>, ScreenTaskError> {
+        let end = offset
+            .checked_add(length)
+            .filter(|end| *end <= self.length)
+            .ok_or(ScreenTaskError::InvalidDataLength {
+                length: self.length.saturating_sub(offset),
+                expected: length,
+            })?;
+        if self.truncated.load(Ordering::Acquire) {
+            return Err(ScreenTaskError::TruncatedPool);
+        }
+        install_sigbus_handler();
+        GUARDED_RANGE.with(|range| range.set(Some((self.pointer as usize, self.mapped_length))));
+        compiler_fence(Ordering::SeqCst);
+        let data = self.data()[offset..end].to_vec();
+        compiler_fence(Ordering::SeqCst);
+        GUARDED_RANGE.with(|range| range.set(None));
+        if FAULTED.with(|faulted| faulted.replace(false)) {
+            self.truncated.store(true, Ordering::Release);
+            return Err(ScreenTaskError::TruncatedPool);
+        }
+        Ok(data)
+    }
+}
+impl Drop for MemoryMapping {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.pointer, self.mapped_length);
+        }
+    }
+}
+
+/// Mappings by device and inode of the pool file, released when no surface uses them.
+type PoolMappings = HashMap<(libc::dev_t, libc::ino_t), Weak<MemoryMapping>>;
+
+/**
+Mappings of the shared memory pools used by the surfaces, so that every pool is mapped once
+and shared by the buffers allocated from it, on every device.
+Clones share the same mappings.
+A pool is mapped again only when a buffer lies past the end of the mapping, after the pool grew.
+*/
+#[derive(Debug, Default, Clone)]
+pub struct ShmPools {
+    pools: Arc<Mutex<PoolMappings>>,
+}
+impl ShmPools {
+    /// Returns a mapping of the whole pool behind the file descriptor, holding at least `length` bytes.
+    pub fn map(&self, fd: RawFd, length: usize) -> Result<Arc<MemoryMapping>, ScreenTaskError> {
+        let mut pools = self.pools.lock().unwrap_or_else(|error| error.into_inner());
+        pools.retain(|_, mapping| mapping.strong_count() > 0);
+        let stat = file_stat(fd)?;
+        // The mapping keeps the file alive, so its inode can not be reused by another pool.
+        let identity = (stat.st_dev, stat.st_ino);
+        if let Some(mapping) = pools.get(&identity).and_then(Weak::upgrade) {
+            if mapping.len() >= length {
+                return Ok(mapping);
+            }
+        }
+        let mapping = Arc::new(MemoryMapping::new(
+            fd,
+            0,
+            (stat.st_size as usize).max(length),
+        )?);
+        pools.insert(identity, Arc::downgrade(&mapping));
+        Ok(mapping)
+    }
+}
+
+thread_local! {
+    /// Mapping being read by `read_range` on this thread, as address and length.
+    static GUARDED_RANGE: Cell<Option<(usize, usize)>> = const { Cell::new(None) };
+    /// Set by the SIGBUS handler when it replaced the guarded mapping.
+    static FAULTED: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Action that handled SIGBUS before `handle_sigbus` was installed.
+static PREVIOUS_SIGBUS_ACTION: OnceLock<libc::sigaction> = OnceLock::new();
+
+/// Install `handle_sigbus` once for the whole process.
+fn install_sigbus_handler() {
+    PREVIOUS_SIGBUS_ACTION.get_or_init(|| unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = handle_sigbus as extern "C" fn(_, _, _) as usize;
+        action.sa_flags = libc::SA_SIGINFO | libc::SA_NODEFER;
+        libc::sigemptyset(&mut action.sa_mask);
+        let mut previous: libc::sigaction = std::mem::zeroed();
+        libc::sigaction(libc::SIGBUS, &action, &mut previous);
+        previous
+    });
+}
+
+/**
+Recover from reads past the end of a shrunk file inside the guarded mapping of the thread,
+mapping zeroed memory over it so that the faulting read completes.
+Other faults restore the previous action, which handles the fault raised again when the handler returns.
+*/
+extern "C" fn handle_sigbus(
+    _signal: libc::c_int,
+    info: *mut libc::siginfo_t,
+    _context: *mut libc::c_void,
+) {
+    let address = unsafe { (*info).si_addr() } as usize;
+    let replaced = GUARDED_RANGE.with(|range| match range.get() {
+        Some((start, length)) if (start..start + length).contains(&address) => {
+            let pointer = unsafe {
+                libc::mmap(
+                    start as *mut libc::c_void,
+                    length,
+                    libc::PROT_READ,
+                    libc::MAP_PRIVATE | libc::MAP_FIXED | libc::MAP_ANONYMOUS,
+                    -1,
+                    0,
+                )
+            };
+            pointer != libc::MAP_FAILED
+        }
+        _ => false,
+    });
+    if replaced {
+        FAULTED.with(|faulted| faulted.set(true));
+        return;
+    }
+    unsafe {
+        match PREVIOUS_SIGBUS_ACTION.get() {
+            Some(previous) => libc::sigaction(libc::SIGBUS, previous, std::ptr::null_mut()),
+            None => {
+                libc::signal(libc::SIGBUS, libc::SIG_DFL);
+                0
+            }
+        };
+    }
+}
+
+/// Returns the status of the file behind the descriptor.
+fn file_stat(fd: RawFd) -> Result<libc::stat, ScreenTaskError> {
+    let mut stat = std::mem::MaybeUninit::<libc::stat>::uninit();
+    if unsafe { libc::fstat(fd, stat.as_mut_ptr()) } != 0 {
+        return Err(ScreenTaskError::Mapping(std::io::Error::last_os_error()));
+    }
+    Ok(unsafe { stat.assume_init() })
+}
+
+/// Returns the size in bytes of the file behind the descriptor; Dma buffers report the size of the buffer.
+fn file_size(fd: RawFd) -> Result<u64, ScreenTaskError> {
+    file_stat(fd).map(|stat| stat.st_size as u64)
+}
+
+/// Begin or end a cpu access to a Dma buffer. Fails silently on files that are not Dma buffers.
+fn dma_buf_sync(fd: RawFd, flags: u64) {
+    unsafe {
+        libc::ioctl(fd, DMA_BUF_IOCTL_SYNC as _, &flags as *const u64);
+    }
+}
+
+/// Map a plane of `rows` rows of `stride` bytes at `offset`, to copy it into host memory on every commit.
+pub fn map_plane(
+    fd: RawFd,
+    offset: u64,
+    stride: u32,
+    rows: u32,
+) -> Result<MemoryMapping, ScreenTaskError> {
+    // The end of the plane is addressable, so its length is too.
+    buffer_end(offset, stride, rows)?;
+    MemoryMapping::new(fd, offset, stride as usize * rows as usize)
+}
@@ -1,13 +1,14 @@
+use crate::blur::BlurPipelines;
 use crate::display::DisplayResources;
+use crate::engine::Engine;
 use crate::surface_manager::SurfaceManager;
 use wgpu_engine::*;
 
-
 /// Rendering resources related to a single device.
-pub struct DeviceResources {
+pub struct DeviceResources<E: Engine = UpdateContext> {
     pub displays: Vec<DisplayResources>,
 
-    pub surface_manager: SurfaceManager,
+    pub surface_manager: SurfaceManager<E>,
 
     pub fragment_shader: ShaderModuleId,
     pub vertex_shader: ShaderModuleId,
@@ -18,6 +19,11 @@ pub struct DeviceResources {
 
     pub pipeline_layout: PipelineLayoutId,
 
+    /// Resources of the background blur, created once a surface needs it.
+    pub blur: Option<BlurPipelines>,
+    /// Instances with a blurred background drawn by the current command buffer, for each display.
+    pub blurred_instances: Vec<Vec<u32>>,
+
     pub command_buffer: CommandBufferId,
     pub data_copy_command_buffer: CommandBufferId,
     pub data_copy_command_buffer_updated: bool,
@@ -1,7 +1,15 @@
+use crate::background::OutputBackground;
+use crate::engine::Engine;
+use std::collections::{HashMap, HashSet};
+use std::time::Instant;
 use wgpu_engine::*;
 
-use crate::screen_task::ScreenTask;
+use crate::error::ScreenTaskError;
+use crate::screen_task::{DeviceResources, Display, ScreenTask};
+use crate::shadow::SurfaceShadow;
 use crate::surface::*;
+use crate::sync::BufferSync;
+use crate::transform::{SurfaceTransform, Transform};
 
 /// Events of the ScreenTask task.
 pub enum ScreenTaskEvent {
@@ -12,14 +20,33 @@ pub enum ScreenTaskEvent {
         position: [i32; 3],
         size: [u32; 2],
     },
+    CreateSubsurface {
+        id: usize,
+        parent: usize,
+        label: String,
+        source: SurfaceSource,
+        position: [i32; 2],
+        size: [u32; 2],
+    },
     UpdateSource {
         id: usize,
         source: SurfaceSource,
+        sync: BufferSync,
+        /// Time at which the update was enqueued, to drop it if the acquire fence does not signal in time.
+        enqueued: Instant,
     },
     UpdateData {
         id: usize,
         data: Vec<u8>,
     },
+    UpdateDataRegion {
+        id: usize,
+        rects: Vec<[u32; 4]>,
+        data: Vec<u8>,
+    },
+    CommitSurface {
+        id: usize,
+    },
     ResizeSurface {
         id: usize,
         size: [u32; 2],
@@ -28,20 +55,172 @@ pub enum ScreenTaskEvent {
         id: usize,
         position: [i32; 3],
     },
+    SetSurfaceOpacity {
+        id: usize,
+        opacity: f32,
+    },
+    SetSurfaceSourceRect {
+        id: usize,
+        source_rect: [f32; 4],
+    },
+    SetSurfaceClipRect {
+        id: usize,
+        clip_rect: Option<[f32; 4]>,
+    },
+    SetSurfaceDecoration {
+        id: usize,
+        decoration: SurfaceDecoration,
+    },
+    SetSurfaceShadow {
+        id: usize,
+        shadow: Option<SurfaceShadow>,
+    },
+    SetSurfaceBackgroundBlur {
+        id: usize,
+        enabled: bool,
+    },
+    SetSurfaceTransform {
+        id: usize,
+        transform: SurfaceTransform,
+    },
     MoveOutput {
         id: usize,
         position: [i32; 2],
     },
+    SetOutputScale {
+        id: usize,
+        scale: f32,
+    },
+    SetOutputTransform {
+        id: usize,
+        transform: Transform,
+    },
+    SetOutputBackground {
+        id: usize,
+        background: OutputBackground,
+    },
+    RaiseSurface {
+        id: usize,
+    },
+    LowerSurface {
+        id: usize,
+    },
+    PlaceAbove {
+        id: usize,
+        sibling: usize,
+    },
+    PlaceBelow {
+        id: usize,
+        sibling: usize,
+    },
     RemoveSurface {
         id: usize,
     },
 }
 
-impl ScreenTask {
-    pub(crate) fn elaborate_events(&mut self, update_context: &mut UpdateContext) {
+impl ScreenTaskEvent {
+    /// Returns the surfaces named by the event, none for the output events.
+    fn surfaces(&self) -> Vec<usize> {
+        match self {
+            Self::MoveOutput { .. }
+            | Self::SetOutputScale { .. }
+            | Self::SetOutputTransform { .. }
+            | Self::SetOutputBackground { .. } => Vec::new(),
+            Self::CreateSubsurface { id, parent, .. } => vec![*id, *parent],
+            Self::PlaceAbove { id, sibling } | Self::PlaceBelow { id, sibling } => {
+                vec![*id, *sibling]
+            }
+            Self::CreateSurface { id, .. }
+            | Self::UpdateSource { id, .. }
+            | Self::UpdateData { id, .. }
+            | Self::UpdateDataRegion { id, .. }
+            | Self::CommitSurface { id }
+            | Self::ResizeSurface { id, .. }
+            | Self::MoveSurface { id, .. }
+            | Self::SetSurfaceOpacity { id, .. }
+            | Self::SetSurfaceSourceRect { id, .. }
+            | Self::SetSurfaceClipRect { id, .. }
+            | Self::SetSurfaceDecoration { id, .. }
+            | Self::SetSurfaceShadow { id, .. }
+            | Self::SetSurfaceBackgroundBlur { id, .. }
+            | Self::SetSurfaceTransform { id, .. }
+            | Self::RaiseSurface { id }
+            | Self::LowerSurface { id }
+            | Self::RemoveSurface { id } => vec![*id],
+        }
+    }
+
+    /// Returns true if the event creates the surface with the provided id.
+    fn creates(&self, surface: usize) -> bool {
+        match self {
+            Self::CreateSurface { id, .. } | Self::CreateSubsurface { id, .. } => *id == surface,
+            _ => false,
+        }
+    }
+
+    /// Returns true if the event sets the source of the surface with the provided id, creating it or not.
+    fn sets_source(&self, surface: usize) -> bool {
+        match self {
+            Self::UpdateSource { id, .. } => *id == surface,
+            _ => self.creates(surface),
+        }
+    }
+
+    /// Returns true if the event involves one of the provided surfaces, so it must be applied after their events.
+    fn involves(&self, surfaces: &HashSet<usize>) -> bool {
+        match self {
+            // Removing a surface removes its subsurfaces too.
+            Self::RemoveSurface { .. } => !surfaces.is_empty(),
+            _ => self.surfaces().iter().any(|id| surfaces.contains(id)),
+        }
+    }
+}
+
+impl<E: Engine> ScreenTask<E> {
+    /**
+    Apply the pending events.
+    Source updates waiting for their acquire fence stay pending, together with the later events of the same surfaces.
+    A source whose fence is not signaled within the acquire timeout is dropped, releasing its buffer.
+    */
+    pub(crate) fn elaborate_events(&mut self, update_context: &mut E) {
         let mut update_resource_needed = false;
-        for event in self.pending_events.drain(..) {
-            match event {
+        let mut waiting_surfaces = HashSet::new();
+        // The subsurfaces of a waiting removal are not known yet, so every later surface event waits for it.
+        let mut waiting_removal = false;
+        let mut events = std::mem::take(&mut self.pending_events).into_iter();
+        while let Some(event) = events.next() {
+            let waiting = (waiting_removal && !event.surfaces().is_empty())
+                || event.involves(&waiting_surfaces);
+            let event = match event {
+                ScreenTaskEvent::UpdateSource {
+                    id, sync, enqueued, ..
+                } if !waiting
+                    && !sync.is_acquired()
+                    && enqueued.elapsed() >= self.acquire_timeout =>
+                {
+                    let error = ScreenTaskError::AcquireTimeout(id);
+                    log::error!(target: "ScreenTask","{}",error);
+                    self.errors.push(error);
+                    self.releases.discard(id, sync.release);
+                    self.restore_source(id, events.as_slice());
+                    continue;
+                }
+                event => event,
+            };
+            let waiting = waiting
+                || match &event {
+                    ScreenTaskEvent::UpdateSource { sync, .. } => !sync.is_acquired(),
+                    _ => false,
+                };
+            if waiting {
+                waiting_surfaces.extend(event.surfaces());
+                if let ScreenTaskEvent::RemoveSurface { .. } = event {
+                    waiting_removal = true;
+                }
+                self.pending_events.push(event);
+                continue;
+            }
+            let result = match event {
                 ScreenTaskEvent::CreateSurface {
                     id,
                     label,
@@ -49,7 +228,8 @@ impl ScreenTask {
                     position,
                     size,
                 } => {
-                    self.devices.values_mut().for_each(|device_resources| {
+                    update_resource_needed = true;
+                    let result = Self::for_each_device(&mut self.devices, |device_resources| {
                         device_resources.surface_manager.create_surface(
                             update_context,
                             label.clone(),
@@ -57,66 +237,226 @@ impl ScreenTask {
                             source.clone(),
                             position,
                             size,
-                        );
+                        )
                     });
+                    if result.is_err() {
+                        self.discard_surface(update_context, id, events.as_slice());
+                    }
+                    result
+                }
+                ScreenTaskEvent::CreateSubsurface {
+                    id,
+                    parent,
+                    label,
+                    source,
+                    position,
+                    size,
+                } => {
                     update_resource_needed = true;
+                    let result = Self::for_each_device(&mut self.devices, |device_resources| {
+                        device_resources.surface_manager.create_subsurface(
+                            update_context,
+                            label.clone(),
+                            id,
+                            parent,
+                            source.clone(),
+                            SubsurfaceGeometry { position, size },
+                        )
+                    });
+                    if result.is_err() {
+                        self.discard_surface(update_context, id, events.as_slice());
+                    }
+                    result
                 }
-                ScreenTaskEvent::UpdateSource { id, source } => {
-                    self.devices.values_mut().for_each(|device_resources| {
-                        device_resources.surface_manager.update_source(
+                ScreenTaskEvent::UpdateSource {
+                    id, source, sync, ..
+                } => {
+                    let result = Self::for_each_device(&mut self.devices, |device_resources| {
+                        if device_resources.surface_manager.update_source(
                             update_context,
                             &id,
                             source.clone(),
-                        );
+                        )? {
+                            update_resource_needed = true;
+                        }
+                        Ok(())
                     });
+                    match result {
+                        Ok(()) => self.releases.attach(id, sync.release),
+                        Err(_) => {
+                            self.releases.discard(id, sync.release);
+                            self.restore_source(id, events.as_slice());
+                        }
+                    }
+                    result
                 }
                 ScreenTaskEvent::UpdateData { id, data } => {
-                    self.devices.values_mut().for_each(|device_resources| {
+                    Self::for_each_device(&mut self.devices, |device_resources| {
                         device_resources.surface_manager.update_data(
                             update_context,
                             &id,
                             data.clone(),
-                        );
-                    });
+                        )
+                    })
+                }
+                ScreenTaskEvent::UpdateDataRegion { id, rects, data } => {
+                    Self::for_each_device(&mut self.devices, |device_resources| {
+                        device_resources.surface_manager.update_data_region(
+                            update_context,
+                            &id,
+                            &rects,
+                            data.clone(),
+                        )
+                    })
+                }
+                ScreenTaskEvent::CommitSurface { id } => {
+                    Self::for_each_device(&mut self.devices, |device_resources| {
+                        device_resources
+                            .surface_manager
+                            .commit_surface(update_context, &id)
+                    })
                 }
                 ScreenTaskEvent::ResizeSurface { id, size } => {
-                    self.devices.values_mut().for_each(|device_resources| {
-                        device_resources.surface_manager.resize_surface(&id, size);
-                    });
+                    Self::for_each_device(&mut self.devices, |device_resources| {
+                        device_resources.surface_manager.resize_surface(&id, size)
+                    })
                 }
                 ScreenTaskEvent::MoveSurface { id, position } => {
-                    self.devices.values_mut().for_each(|device_resources| {
-                        device_resources.surface_manager.move_surface(&id, position);
-                    });
+                    Self::for_each_device(&mut self.devices, |device_resources| {
+                        device_resources.surface_manager.move_surface(&id, position)
+                    })
                 }
-                ScreenTaskEvent::RemoveSurface { id } => {
-                    self.devices.values_mut().for_each(|device_resources| {
+                ScreenTaskEvent::SetSurfaceOpacity { id, opacity } => {
+                    Self::for_each_device(&mut self.devices, |device_resources| {
                         device_resources
                             .surface_manager
-                            .remove_surface(update_context, &id);
-                    });
+                            .set_surface_opacity(&id, opacity)
+                    })
+                }
+                ScreenTaskEvent::SetSurfaceSourceRect { id, source_rect } => {
+                    Self::for_each_device(&mut self.devices, |device_resources| {
+                        device_resources
+                            .surface_manager
+                            .set_surface_source_rect(&id, source_rect)
+                    })
+                }
+                ScreenTaskEvent::SetSurfaceClipRect { id, clip_rect } => {
+                    Self::for_each_device(&mut self.devices, |device_resources| {
+                        device_resources
+                            .surface_manager
+                            .set_surface_clip_rect(&id, clip_rect)
+                    })
+                }
+                ScreenTaskEvent::SetSurfaceDecoration { id, decoration } => {
+                    Self::for_each_device(&mut self.devices, |device_resources| {
+                        device_resources
+                            .surface_manager
+                            .set_surface_decoration(&id, decoration)
+                    })
+                }
+                ScreenTaskEvent::SetSurfaceShadow { id, shadow } => {
+                    Self::for_each_device(&mut self.devices, |device_resources| {
+                        if device_resources
+                            .surface_manager
+                            .set_surface_shadow(&id, shadow)?
+                        {
+                            update_resource_needed = true;
+                        }
+                        Ok(())
+                    })
+                }
+                ScreenTaskEvent::SetSurfaceBackgroundBlur { id, enabled } => {
+                    Self::for_each_device(&mut self.devices, |device_resources| {
+                        device_resources
+                            .surface_manager
+                            .set_surface_background_blur(&id, enabled)
+                    })
+                }
+                ScreenTaskEvent::SetSurfaceTransform { id, transform } => {
+                    Self::for_each_device(&mut self.devices, |device_resources| {
+                        device_resources
+                            .surface_manager
+                            .set_surface_transform(&id, transform)
+                    })
+                }
+                ScreenTaskEvent::RaiseSurface { id } => {
+                    Self::for_each_device(&mut self.devices, |device_resources| {
+                        device_resources.surface_manager.raise_surface(&id)
+                    })
+                }
+                ScreenTaskEvent::LowerSurface { id } => {
+                    Self::for_each_device(&mut self.devices, |device_resources| {
+                        device_resources.surface_manager.lower_surface(&id)
+                    })
+                }
+                ScreenTaskEvent::PlaceAbove { id, sibling } => {
+                    Self::for_each_device(&mut self.devices, |device_resources| {
+                        device_resources.surface_manager.place_above(&id, &sibling)
+                    })
+                }
+                ScreenTaskEvent::PlaceBelow { id, sibling } => {
+                    Self::for_each_device(&mut self.devices, |device_resources| {
+                        device_resources.surface_manager.place_below(&id, &sibling)
+                    })
+                }
+                ScreenTaskEvent::RemoveSurface { id } => {
                     update_resource_needed = true;
+                    Self::for_each_device(&mut self.devices, |device_resources| {
+                        device_resources
+                            .surface_manager
+                            .remove_surface(update_context, &id)
+                    })
                 }
                 ScreenTaskEvent::MoveOutput { id, position } => {
-                    self.devices
-                        .iter_mut()
-                        .for_each(|(device, device_resources)| {
-                            device_resources
-                                .displays
-                                .iter_mut()
-                                .find(|display| display.display.external_id() == id)
-                                .map(|display| display.display.move_output(position));
-                            Self::update_command_buffer(update_context, *device, device_resources);
-                        });
+                    Self::update_output(&mut self.devices, update_context, id, |display| {
+                        display.move_output(position)
+                    });
+                    Ok(())
+                }
+                ScreenTaskEvent::SetOutputScale { id, scale } => {
+                    Self::update_output(&mut self.devices, update_context, id, |display| {
+                        display.set_scale(scale)
+                    });
+                    Ok(())
                 }
+                ScreenTaskEvent::SetOutputTransform { id, transform } => {
+                    Self::update_output(&mut self.devices, update_context, id, |display| {
+                        display.set_transform(transform)
+                    });
+                    Ok(())
+                }
+                ScreenTaskEvent::SetOutputBackground { id, background } => {
+                    update_resource_needed = true;
+                    let result = Self::for_each_device(&mut self.devices, |device_resources| {
+                        Self::apply_background(update_context, device_resources, id, &background)
+                    });
+                    self.backgrounds.insert(id, background);
+                    result
+                }
+            };
+            if let Err(error) = result {
+                log::error!(target: "ScreenTask","{}",error);
+                self.errors.push(error);
             }
         }
 
+        let devices = &self.devices;
+        self.releases.retain(|id| {
+            devices
+                .values()
+                .any(|device_resources| device_resources.surface_manager.contains(&id))
+        });
+
         self.devices
             .iter_mut()
             .for_each(|(device, device_resources)| {
                 if update_resource_needed {
                     Self::update_layout_and_bind_groups(update_context, *device, device_resources);
+                } else if device_resources.blurred_instances
+                    != Self::displayed_blurred_instances(device_resources)
+                {
+                    // Restacking and moving surfaces change the passes that blur their background.
+                    Self::update_command_buffer(update_context, *device, device_resources);
                 }
 
                 let commands = device_resources.surface_manager.update(update_context);
@@ -134,4 +474,123 @@ impl ScreenTask {
                 }
             })
     }
+
+    /**
+    Undo the creation of a surface that failed on some device, so that neither the devices nor the validator keep it.
+    The validator already mirrors every pending event, so it is left alone when a later event creates the surface again.
+    */
+    fn discard_surface(
+        &mut self,
+        update_context: &mut E,
+        id: usize,
+        later_events: &[ScreenTaskEvent],
+    ) {
+        let result = Self::for_each_device(&mut self.devices, |device_resources| {
+            if device_resources.surface_manager.contains(&id) {
+                device_resources
+                    .surface_manager
+                    .remove_surface(update_context, &id)
+            } else {
+                Ok(())
+            }
+        });
+        if let Err(error) = result {
+            log::error!(target: "ScreenTask","{}",error);
+            self.errors.push(error);
+        }
+        if !later_events.iter().any(|event| event.creates(id)) {
+            self.validator.discard_surface(id);
+        }
+    }
+
+    /**
+    Mirror in the validator the source kept by the devices, after a new source of a surface was dropped or failed.
+    The validator is left alone when a later event sets the source again, since it already mirrors that event.
+    */
+    fn restore_source(&mut self, id: usize, later_events: &[ScreenTaskEvent]) {
+        if later_events.iter().any(|event| event.sets_source(id)) {
+            return;
+        }
+        let info = self
+            .devices
+            .values()
+            .find_map(|device_resources| device_resources.surface_manager.source_info(&id));
+        if let Some(info) = info {
+            self.validator.restore_source(id, info.clone());
+        }
+    }
+
+    /**
+    Apply the operation to the resources of every device.
+    The operation is applied to all the devices even on failure, so that they stay in sync; the first error is returned.
+    */
+    fn for_each_device(
+        devices: &mut HashMap<DeviceId, DeviceResources<E>>,
+        mut operation: impl FnMut(&mut DeviceResources<E>) -> Result<(), ScreenTaskError>,
+    ) -> Result<(), ScreenTaskError> {
+        let mut result = Ok(());
+        for device_resources in devices.values_mut() {
+            let current = operation(device_resources);
+            if result.is_ok() {
+                result = current;
+            }
+        }
+        result
+    }
+
+    /**
+    Apply the operation to the display with the provided external_id, rebuilding the command buffers that draw it.
+    The wallpaper of the display follows the new area covered by the display.
+    */
+    fn update_output(
+        devices: &mut HashMap<DeviceId, DeviceResources<E>>,
+        update_context: &mut E,
+        id: usize,
+        mut operation: impl FnMut(&mut Display),
+    ) {
+        devices.iter_mut().for_each(|(device, device_resources)| {
+            if let Some(display) = device_resources
+                .displays
+                .iter_mut()
+                .find(|display| display.display.external_id() == id)
+            {
+                operation(&mut display.display);
+                device_resources
+                    .surface_manager
+                    .set_wallpaper_area(id, display.display.logical_area());
+            }
+            Self::update_command_buffer(update_context, *device, device_resources);
+        });
+    }
+
+    /**
+    Apply the background to the display with the provided external_id, if it belongs to the device.
+    The bind groups and the command buffer of the device need to be updated afterwards.
+    */
+    pub(crate) fn apply_background(
+        update_context: &mut E,
+        device_resources: &mut DeviceResources<E>,
+        id: usize,
+        background: &OutputBackground,
+    ) -> Result<(), ScreenTaskError> {
+        let display = match device_resources
+            .displays
+            .iter_mut()
+            .find(|display| display.display.external_id() == id)
+        {
+            Some(display) => &mut display.display,
+            None => return Ok(()),
+        };
+        display.set_clear_color(background.clear_color());
+        let area = display.logical_area();
+        match background {
+            OutputBackground::Wallpaper { source, mode } => device_resources
+                .surface_manager
+                .set_wallpaper(update_context, id, source.clone(), *mode, area),
+            OutputBackground::Color(_) => device_resources
+                .surface_manager
+                .remove_wallpaper(update_context, id)
+                .map(|_| ()),
+        }
+    }
 }
@@ -1,5 +1,8 @@
+use crate::background::OutputBackground;
+use crate::engine::Engine;
 use bytemuck::{Pod, Zeroable};
 use std::collections::HashMap;
+use std::time::{Duration, Instant};
 use ultraviolet::{Mat4, Vec4};
 use wgpu_engine::*;
 
@@ -8,66 +11,156 @@ mod events;
 mod prepare_descriptors;
 mod task_impl;
 mod update_descriptors;
+mod validation;
 
 pub use crate::display::{Display, DisplayResources};
+use crate::error::ScreenTaskError;
+use crate::fourcc::SupportedFormats;
+use crate::mapping::ShmPools;
 pub use crate::screen_task::device_resources::DeviceResources;
 pub use crate::screen_task::events::ScreenTaskEvent;
+use crate::shadow::SurfaceShadow;
 pub use crate::surface::*;
-pub use crate::surface_manager::SurfaceManager;
+use crate::sync::{BufferRelease, BufferSync, ReleaseTracker};
+use crate::transform::{SurfaceTransform, Transform};
+pub(crate) use prepare_descriptors::surface_vertex_buffer_layout;
+pub(crate) use validation::EventValidator;
 
 #[repr(C)]
 #[derive(Debug, Clone, Copy, Pod, Zeroable)]
 /**
 Constant data passed to the vertex shader stage.
+
+Surfaces are positioned in logical coordinates: the vertex shader scales them by `output_scale`,
+rounds them to the physical pixel grid and subtracts `output_origin`, before applying the projection.
+The rounding is shared by every output, so adjacent outputs and surfaces meet without seams.
 */
 pub struct PushConstants {
     pub projection_matrix: Mat4,
+    /// Position of the output in physical pixels.
+    pub output_origin: [f32; 2],
+    pub output_scale: f32,
+    _padding: f32,
 }
 impl PushConstants {
-    pub fn new(
+    pub fn new(target_surface_position: [i32; 2], target_surface_size: [u32; 2]) -> Self {
+        Self::for_output(
+            target_surface_position,
+            target_surface_size,
+            1.0,
+            Transform::Normal,
+        )
+    }
+
+    /**
+    Push constants of an output placed at a logical position, with a size in physical pixels,
+    the provided scale factor and transform.
+    The transform maps the normalized logical area of the output to the normalized framebuffer,
+    with the same semantics of the buffer transform of the surfaces.
+    */
+    pub fn for_output(
         target_surface_position: [i32; 2],
         target_surface_size: [u32; 2],
-        max_surface_count: u32,
+        scale: f32,
+        transform: Transform,
     ) -> Self {
+        // Size of the output in physical pixels, before applying the transform.
+        let (width, height) = if transform.swaps_dimensions() {
+            (target_surface_size[1] as f32, target_surface_size[0] as f32)
+        } else {
+            (target_surface_size[0] as f32, target_surface_size[1] as f32)
+        };
+        let [[a, b, c], [d, e, f]] = transform.uv_matrix();
         let projection_matrix = Mat4::new(
-            Vec4::new(2.0 / target_surface_size[0] as f32, 0.0, 0.0, 0.0),
-            Vec4::new(0.0, -2.0 / target_surface_size[1] as f32, 0.0, 0.0),
-            Vec4::new(0.0, 0.0, 1.0 / max_surface_count as f32, 0.0),
-            Vec4::new(
-                -1.0 - target_surface_position[0] as f32 / target_surface_size[0] as f32 * 2.0,
-                1.0 + target_surface_position[1] as f32 / target_surface_size[1] as f32 * 2.0,
-                0.0,
-                0.0,
-            ),
+            Vec4::new(2.0 * a / width, -2.0 * d / width, 0.0, 0.0),
+            Vec4::new(2.0 * b / height, -2.0 * e / height, 0.0, 0.0),
+            Vec4::new(0.0, 0.0, 1.0, 0.0),
+            Vec4::new(2.0 * c - 1.0, 1.0 - 2.0 * f, 0.0, 0.0),
         );
-        Self { projection_matrix }
+        let output_origin = [
+            snap(target_surface_position[0] as f32 * scale),
+            snap(target_surface_position[1] as f32 * scale),
+        ];
+        Self {
+            projection_matrix,
+            output_origin,
+            output_scale: scale,
+            _padding: 0.0,
+        }
     }
+
+    /// Returns the position in physical pixels, relative to the output, of a point in logical coordinates.
+    pub fn physical_position(&self, position: [f32; 2]) -> [f32; 2] {
+        [
+            snap(position[0] * self.output_scale) - self.output_origin[0],
+            snap(position[1] * self.output_scale) - self.output_origin[1],
+        ]
+    }
+}
+
+/// Round to the nearest physical pixel, with the same tie breaking of the vertex shader.
+fn snap(value: f32) -> f32 {
+    (value + 0.5).floor()
 }
 
 pub const DEPTH_STENCIL_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float;
 
 /**
 Task compatible with WGpuEngine to display surfaces on the screen.
+
+Surface events are validated when they are enqueued, so invalid events are rejected immediately;
+errors raised later while elaborating them can be retrieved with `drain_errors`.
 */
-pub struct ScreenTask {
+pub struct ScreenTask<E: Engine = UpdateContext> {
     pending_events: Vec<ScreenTaskEvent>,
-    devices: HashMap<DeviceId, DeviceResources>,
+    devices: HashMap<DeviceId, DeviceResources<E>>,
+    errors: Vec<ScreenTaskError>,
+    validator: EventValidator,
+    /// Background of every output, applied again when the output is recreated.
+    backgrounds: HashMap<usize, OutputBackground>,
+    releases: ReleaseTracker,
+    /// Time after which a source update whose acquire fence is not signaled is dropped.
+    acquire_timeout: Duration,
+    /// Mappings of the shared memory pools, shared by the surface managers of every device.
+    shm_pools: ShmPools,
 }
 
-impl ScreenTask {
+impl<E: Engine> ScreenTask<E> {
     pub const TASK_NAME: &'static str = "ScreenTask";
+    /// Default time waited for the acquire fence of a source update.
+    pub const ACQUIRE_TIMEOUT: Duration = Duration::from_secs(1);
 
-    pub fn new(_update_context: &mut UpdateContext) -> Self {
+    pub fn new(_update_context: &mut E) -> Self {
         let pending_events = Vec::new();
         let _task_name = Self::TASK_NAME.to_string();
         let devices = HashMap::new();
+        let errors = Vec::new();
+        let validator = EventValidator::new();
+        let backgrounds = HashMap::new();
+        let releases = ReleaseTracker::default();
+        let acquire_timeout = Self::ACQUIRE_TIMEOUT;
+        let shm_pools = ShmPools::default();
 
         Self {
             pending_events,
             devices,
+            errors,
+            validator,
+            backgrounds,
+            releases,
+            acquire_timeout,
+            shm_pools,
         }
     }
 
+    /**
+    Returns the errors raised while elaborating the deferred events and the resource events, in order of occurrence.
+    The errors are removed from the queue.
+    */
+    pub fn drain_errors(&mut self) -> Vec<ScreenTaskError> {
+        std::mem::take(&mut self.errors)
+    }
+
     /// Create a new surface and assign it the provided external_id.
     pub fn create_surface(
         &mut self,
@@ -76,7 +169,9 @@ impl ScreenTask {
         source: SurfaceSource,
         position: [i32; 3],
         size: [u32; 2],
-    ) {
+    ) -> Result<(), ScreenTaskError> {
+        self.validator
+            .create_surface(external_id, &source, position[2], size)?;
         let label = label.into();
         self.pending_events.push(ScreenTaskEvent::CreateSurface {
             id: external_id,
@@ -85,44 +180,374 @@ impl ScreenTask {
             position,
             size,
         });
+        Ok(())
+    }
+
+    /**
+    Create a new subsurface of the surface with the provided parent id and assign it the provided external_id.
+    The position is relative to the parent: the subsurface follows the parent when it moves,
+    it is stacked on top of the parent and its siblings, and it is removed together with the parent.
+    */
+    pub fn create_subsurface(
+        &mut self,
+        external_id: usize,
+        parent: usize,
+        label: impl Into<String>,
+        source: SurfaceSource,
+        position: [i32; 2],
+        size: [u32; 2],
+    ) -> Result<(), ScreenTaskError> {
+        self.validator
+            .create_subsurface(external_id, parent, &source, size)?;
+        let label = label.into();
+        self.pending_events.push(ScreenTaskEvent::CreateSubsurface {
+            id: external_id,
+            parent,
+            label,
+            source,
+            position,
+            size,
+        });
+        Ok(())
     }
 
     /// Update the source of the surface with the provided external_id.
-    pub fn update_source(&mut self, external_id: usize, source: SurfaceSource) {
+    pub fn update_source(
+        &mut self,
+        external_id: usize,
+        source: SurfaceSource,
+    ) -> Result<(), ScreenTaskError> {
+        self.update_source_synced(external_id, source, BufferSync::default())
+    }
+
+    /**
+    Update the source of the surface with the provided external_id once the acquire fence is signaled.
+    The later events of the surface wait for the new source, so that they are applied in order,
+    unless the fence is not signaled within the acquire timeout.
+    The release token is reported by `drain_released_buffers` once the buffer is no longer sampled.
+    */
+    pub fn update_source_synced(
+        &mut self,
+        external_id: usize,
+        source: SurfaceSource,
+        sync: BufferSync,
+    ) -> Result<(), ScreenTaskError> {
+        self.validator.update_source(external_id, &source)?;
         self.pending_events.push(ScreenTaskEvent::UpdateSource {
             id: external_id,
             source,
+            sync,
+            enqueued: Instant::now(),
         });
+        Ok(())
+    }
+
+    /**
+    Set the time waited for the acquire fence of a source update.
+    Once it elapsed the buffer is dropped and released, and `drain_errors` reports `AcquireTimeout`.
+    */
+    pub fn set_acquire_timeout(&mut self, timeout: Duration) {
+        self.acquire_timeout = timeout;
+    }
+
+    /// Returns the serial of the last frame prepared by `update_engine_resources`.
+    pub fn frame_serial(&self) -> u64 {
+        self.releases.frame()
+    }
+
+    /**
+    Notify that the gpu finished the frames up to the provided serial,
+    releasing the buffers that were sampled by them for the last time.
+    */
+    pub fn frame_completed(&mut self, serial: u64) {
+        self.releases.complete(serial);
+    }
+
+    /**
+    Returns the buffers that are no longer sampled, in order of release.
+    The buffers are removed from the queue.
+    */
+    pub fn drain_released_buffers(&mut self) -> Vec<BufferRelease> {
+        self.releases.drain()
     }
 
     /// Update the data of the surface with the provided external_id.
-    pub fn update_data(&mut self, external_id: usize, data: Vec<u8>) {
+    pub fn update_data(
+        &mut self,
+        external_id: usize,
+        data: Vec<u8>,
+    ) -> Result<(), ScreenTaskError> {
+        self.validator.update_data(external_id, data.len())?;
         self.pending_events.push(ScreenTaskEvent::UpdateData {
             id: external_id,
             data,
         });
+        Ok(())
+    }
+
+    /**
+    Update only the damaged `[x, y, width, height]` regions of the surface with the provided external_id.
+    The data contains the whole buffer; overlapping regions are merged before being uploaded.
+    */
+    pub fn update_data_region(
+        &mut self,
+        external_id: usize,
+        rects: Vec<[u32; 4]>,
+        data: Vec<u8>,
+    ) -> Result<(), ScreenTaskError> {
+        self.validator.update_data(external_id, data.len())?;
+        self.pending_events.push(ScreenTaskEvent::UpdateDataRegion {
+            id: external_id,
+            rects,
+            data,
+        });
+        Ok(())
+    }
+
+    /**
+    Notify that the client committed new content in the buffer of the surface with the provided external_id.
+    Dma buffers copied through host memory, on devices without external memory, are copied again.
+    */
+    pub fn commit_surface(&mut self, external_id: usize) -> Result<(), ScreenTaskError> {
+        self.validator.check_surface(external_id)?;
+        self.pending_events
+            .push(ScreenTaskEvent::CommitSurface { id: external_id });
+        Ok(())
     }
 
     /// Resize the surface with the provided external_id.
-    pub fn resize_surface(&mut self, external_id: usize, size: [u32; 2]) {
+    pub fn resize_surface(
+        &mut self,
+        external_id: usize,
+        size: [u32; 2],
+    ) -> Result<(), ScreenTaskError> {
+        self.validator.resize_surface(external_id, size)?;
         self.pending_events.push(ScreenTaskEvent::ResizeSurface {
             id: external_id,
             size,
         });
+        Ok(())
     }
 
-    /// Move the surface with the provided external_id.
-    pub fn move_surface(&mut self, external_id: usize, position: [i32; 3]) {
+    /**
+    Move the surface with the provided external_id, its subsurfaces follow it.
+    The position of a subsurface is relative to its parent and its z is ignored.
+    */
+    pub fn move_surface(
+        &mut self,
+        external_id: usize,
+        position: [i32; 3],
+    ) -> Result<(), ScreenTaskError> {
+        self.validator.move_surface(external_id, position[2])?;
         self.pending_events.push(ScreenTaskEvent::MoveSurface {
             id: external_id,
             position,
         });
+        Ok(())
     }
 
-    /// Remove the surface with the provided external_id.
-    pub fn remove_surface(&mut self, external_id: usize) {
+    /// Set the opacity of the surface with the provided external_id, between 0.0 (invisible) and 1.0 (opaque).
+    pub fn set_surface_opacity(
+        &mut self,
+        external_id: usize,
+        opacity: f32,
+    ) -> Result<(), ScreenTaskError> {
+        self.validator.check_surface(external_id)?;
+        self.pending_events
+            .push(ScreenTaskEvent::SetSurfaceOpacity {
+                id: external_id,
+                opacity,
+            });
+        Ok(())
+    }
+
+    /**
+    Display only the `[x, y, width, height]` region of the buffer of the surface with the provided external_id.
+    The region is expressed in buffer pixels and it is scaled to the size of the surface.
+    The region must have a positive size and lie inside the buffer;
+    solid colors have no buffer to crop, so they reject source rectangles.
+    */
+    pub fn set_surface_source_rect(
+        &mut self,
+        external_id: usize,
+        source_rect: [f32; 4],
+    ) -> Result<(), ScreenTaskError> {
+        self.validator
+            .set_surface_source_rect(external_id, source_rect)?;
+        self.pending_events
+            .push(ScreenTaskEvent::SetSurfaceSourceRect {
+                id: external_id,
+                source_rect,
+            });
+        Ok(())
+    }
+
+    /**
+    Clip the surface with the provided external_id to the `[x, y, width, height]` rectangle, in logical coordinates,
+    the same space of the surface positions. Subsurfaces inherit the clipping of their parent.
+    None removes the clipping. The rectangle must be finite, with no negative size.
+    */
+    pub fn set_surface_clip_rect(
+        &mut self,
+        external_id: usize,
+        clip_rect: Option<[f32; 4]>,
+    ) -> Result<(), ScreenTaskError> {
+        self.validator
+            .set_surface_clip_rect(external_id, clip_rect)?;
+        self.pending_events
+            .push(ScreenTaskEvent::SetSurfaceClipRect {
+                id: external_id,
+                clip_rect,
+            });
+        Ok(())
+    }
+
+    /**
+    Round the corners of the surface with the provided external_id and draw a solid border inside its edges.
+    The radius and the border width are in logical coordinates, the border color in linear RGBA;
+    a radius and a border width of zero remove the decoration.
+    */
+    pub fn set_surface_decoration(
+        &mut self,
+        external_id: usize,
+        radius: f32,
+        border_width: f32,
+        border_color: [f32; 4],
+    ) -> Result<(), ScreenTaskError> {
+        self.validator.check_surface(external_id)?;
+        let decoration = SurfaceDecoration {
+            radius,
+            border_width,
+            border_color,
+        };
+        decoration.validate()?;
+        self.pending_events
+            .push(ScreenTaskEvent::SetSurfaceDecoration {
+                id: external_id,
+                decoration,
+            });
+        Ok(())
+    }
+
+    /**
+    Draw a soft drop shadow beneath the surface with the provided external_id, None removes it.
+    The shadow is drawn in the same instanced draw of the surfaces, right beneath its surface.
+    */
+    pub fn set_surface_shadow(
+        &mut self,
+        external_id: usize,
+        shadow: Option<SurfaceShadow>,
+    ) -> Result<(), ScreenTaskError> {
+        self.validator.check_surface(external_id)?;
+        if let Some(shadow) = &shadow {
+            shadow.validate()?;
+        }
+        self.pending_events.push(ScreenTaskEvent::SetSurfaceShadow {
+            id: external_id,
+            shadow,
+        });
+        Ok(())
+    }
+
+    /**
+    Blur the content behind the surface with the provided external_id, before drawing the surface itself.
+    The blurred content is clipped like the surface and follows its rounded corners, so translucent surfaces
+    look like frosted glass.
+    */
+    pub fn set_surface_background_blur(
+        &mut self,
+        external_id: usize,
+        enabled: bool,
+    ) -> Result<(), ScreenTaskError> {
+        self.validator.check_surface(external_id)?;
+        self.pending_events
+            .push(ScreenTaskEvent::SetSurfaceBackgroundBlur {
+                id: external_id,
+                enabled,
+            });
+        Ok(())
+    }
+
+    /**
+    Set the transform of the surface with the provided external_id.
+    Accepts both a plain buffer transform and a full SurfaceTransform with an affine matrix.
+    */
+    pub fn set_surface_transform(
+        &mut self,
+        external_id: usize,
+        transform: impl Into<SurfaceTransform>,
+    ) -> Result<(), ScreenTaskError> {
+        self.validator.check_surface(external_id)?;
+        self.pending_events
+            .push(ScreenTaskEvent::SetSurfaceTransform {
+                id: external_id,
+                transform: transform.into(),
+            });
+        Ok(())
+    }
+
+    /// Place the surface with the provided external_id on top of every other surface, adopting the z of the nearest one.
+    pub fn raise_surface(&mut self, external_id: usize) -> Result<(), ScreenTaskError> {
+        self.validator.stacking_order_mut().raise(external_id)?;
+        self.pending_events
+            .push(ScreenTaskEvent::RaiseSurface { id: external_id });
+        Ok(())
+    }
+
+    /// Place the surface with the provided external_id below every other surface, adopting the z of the farthest one.
+    pub fn lower_surface(&mut self, external_id: usize) -> Result<(), ScreenTaskError> {
+        self.validator.stacking_order_mut().lower(external_id)?;
+        self.pending_events
+            .push(ScreenTaskEvent::LowerSurface { id: external_id });
+        Ok(())
+    }
+
+    /// Place the surface with the provided external_id directly above the sibling, adopting its z.
+    pub fn place_above(
+        &mut self,
+        external_id: usize,
+        sibling: usize,
+    ) -> Result<(), ScreenTaskError> {
+        self.validator
+            .stacking_order_mut()
+            .place_above(external_id, sibling)?;
+        self.pending_events.push(ScreenTaskEvent::PlaceAbove {
+            id: external_id,
+            sibling,
+        });
+        Ok(())
+    }
+
+    /// Place the surface with the provided external_id directly below the sibling, adopting its z.
+    pub fn place_below(
+        &mut self,
+        external_id: usize,
+        sibling: usize,
+    ) -> Result<(), ScreenTaskError> {
+        self.validator
+            .stacking_order_mut()
+            .place_below(external_id, sibling)?;
+        self.pending_events.push(ScreenTaskEvent::PlaceBelow {
+            id: external_id,
+            sibling,
+        });
+        Ok(())
+    }
+
+    /**
+    Returns the surface ids from the farthest to the nearest,
+    as they will be stacked once the pending events are elaborated.
+    */
+    pub fn stacking_order(&self) -> Vec<usize> {
+        self.validator.stacking_order().order()
+    }
+
+    /// Remove the surface with the provided external_id, together with its subsurfaces.
+    pub fn remove_surface(&mut self, external_id: usize) -> Result<(), ScreenTaskError> {
+        self.validator.remove_surface(external_id)?;
         self.pending_events
             .push(ScreenTaskEvent::RemoveSurface { id: external_id });
+        Ok(())
     }
 
     /// Move the output with the provided external_id.
@@ -133,6 +558,68 @@ impl ScreenTask {
         });
     }
 
+    /**
+    Set the background of the output with the provided external_id, drawn beneath every surface.
+    Outputs without a background are cleared to black.
+    */
+    pub fn set_output_background(
+        &mut self,
+        external_id: usize,
+        background: OutputBackground,
+    ) -> Result<(), ScreenTaskError> {
+        if let OutputBackground::Wallpaper { source, .. } = &background {
+            source.validate()?;
+        }
+        self.pending_events
+            .push(ScreenTaskEvent::SetOutputBackground {
+                id: external_id,
+                background,
+            });
+        Ok(())
+    }
+
+    /// Set the transform of the output with the provided external_id, to drive rotated or flipped monitors.
+    pub fn set_output_transform(&mut self, external_id: usize, transform: Transform) {
+        self.pending_events
+            .push(ScreenTaskEvent::SetOutputTransform {
+                id: external_id,
+                transform,
+            });
+    }
+
+    /**
+    Set the scale factor of the output with the provided external_id.
+    Surfaces are placed in logical coordinates, that the output maps to its physical pixels multiplying them by the scale.
+    */
+    pub fn set_output_scale(
+        &mut self,
+        external_id: usize,
+        scale: f32,
+    ) -> Result<(), ScreenTaskError> {
+        if !(scale.is_finite() && scale > 0.0) {
+            return Err(ScreenTaskError::InvalidScale(scale));
+        }
+        self.pending_events.push(ScreenTaskEvent::SetOutputScale {
+            id: external_id,
+            scale,
+        });
+        Ok(())
+    }
+
+    /**
+    Returns the pixel formats and the modifiers that the surfaces can use on the provided device.
+    They depend on whether the device imports the Dma buffers with external memory or copies them through host memory.
+    */
+    pub fn supported_formats(&self, device: DeviceId) -> Result<SupportedFormats, ScreenTaskError> {
+        let device_resources = self
+            .devices
+            .get(&device)
+            .ok_or(ScreenTaskError::UnknownDevice(device))?;
+        Ok(SupportedFormats::new(
+            device_resources.surface_manager.external_memory(),
+        ))
+    }
+
     pub fn features_and_limits() -> (wgpu::Features, wgpu::Limits) {
         let mut features = wgpu::Features::PUSH_CONSTANTS
             | wgpu::Features::UNSIZED_BINDING_ARRAY
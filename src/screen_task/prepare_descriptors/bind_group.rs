@@ -1,30 +1,29 @@
+use crate::engine::Engine;
 use crate::surface_manager::SurfaceManager;
 use crate::ScreenTask;
 use wgpu_engine::*;
 
-impl ScreenTask {
+impl<E: Engine> ScreenTask<E> {
     /// Generate the bind group descriptor.
     pub(crate) fn prepare_bind_group(
-        _update_context: &mut UpdateContext,
+        _update_context: &mut E,
         device: DeviceId,
-        surface_manager: &SurfaceManager,
+        surface_manager: &SurfaceManager<E>,
         layout: BindGroupLayoutId,
         sampler: SamplerId,
     ) -> BindGroupDescriptor {
         let views = surface_manager.rectangle_views();
-        log::info!(target: "ScreenTask","Preparing bind group descriptor with {} images",surface_manager.len());
+        log::info!(target: "ScreenTask","Preparing bind group descriptor with {} images",views.len());
         let mut entries = Vec::new();
         entries.push(BindGroupEntry {
             binding: 0,
             resource: BindingResource::Sampler(sampler),
         });
 
-        if views.len() > 0 {
-            entries.push(BindGroupEntry {
-                binding: 1,
-                resource: BindingResource::TextureViewArray(views),
-            });
-        }
+        entries.push(BindGroupEntry {
+            binding: 1,
+            resource: BindingResource::TextureViewArray(views),
+        });
 
         BindGroupDescriptor {
             device,
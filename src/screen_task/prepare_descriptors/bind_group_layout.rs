@@ -1,16 +1,18 @@
+use crate::engine::Engine;
 use crate::surface_manager::SurfaceManager;
 use crate::ScreenTask;
 use std::num::NonZeroU32;
 use wgpu_engine::*;
 
-impl ScreenTask {
+impl<E: Engine> ScreenTask<E> {
     /// Generate the bind group layout descriptor.
     pub(crate) fn prepare_bind_group_layout(
-        _update_context: &mut UpdateContext,
+        _update_context: &mut E,
         device: DeviceId,
-        surface_manager: &SurfaceManager,
+        surface_manager: &SurfaceManager<E>,
     ) -> BindGroupLayoutDescriptor {
-        log::info!(target: "ScreenTask","Preparing bind group layout descriptor for {} images",surface_manager.len());
+        let view_count = surface_manager.rectangle_views().len();
+        log::info!(target: "ScreenTask","Preparing bind group layout descriptor for {} images",view_count);
 
         let mut entries = Vec::new();
         entries.push(wgpu_engine::BindGroupLayoutEntry {
@@ -23,18 +25,16 @@ impl ScreenTask {
             count: None,
         });
 
-        if surface_manager.len() > 0 {
-            entries.push(wgpu::BindGroupLayoutEntry {
-                binding: 1,
-                visibility: wgpu::ShaderStage::FRAGMENT,
-                ty: wgpu::BindingType::Texture {
-                    sample_type: wgpu::TextureSampleType::Float { filterable: true },
-                    view_dimension: wgpu::TextureViewDimension::D2,
-                    multisampled: false,
-                },
-                count: NonZeroU32::new(surface_manager.len() as u32),
-            });
-        }
+        entries.push(wgpu::BindGroupLayoutEntry {
+            binding: 1,
+            visibility: wgpu::ShaderStage::FRAGMENT,
+            ty: wgpu::BindingType::Texture {
+                sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                view_dimension: wgpu::TextureViewDimension::D2,
+                multisampled: false,
+            },
+            count: NonZeroU32::new(view_count as u32),
+        });
 
         BindGroupLayoutDescriptor {
             device,
@@ -1,70 +1,53 @@
+use crate::blur::{
+    BlurPushConstants, BlurTargets, RenderTarget, BLUR_MODE_COPY, BLUR_MODE_DOWNSAMPLE,
+    BLUR_MODE_UPSAMPLE,
+};
+use crate::engine::Engine;
 use crate::surface_manager::SurfaceManager;
 use crate::DisplayResources;
-use crate::PushConstants;
 use crate::ScreenTask;
 use wgpu_engine::*;
 
-impl ScreenTask {
-    /// Generate the command buffer descriptor.
+impl<E: Engine> ScreenTask<E> {
+    /**
+    Generate the command buffer descriptor.
+
+    Every display is drawn with a single render pass, unless some surfaces blur their background:
+    then the display is composited on an offscreen target, split in ordered passes around the blur passes
+    of each of those surfaces, and finally copied to the swapchain.
+    */
     pub(crate) fn prepare_command_buffer(
-        _update_context: &mut UpdateContext,
+        _update_context: &mut E,
         device: DeviceId,
         display_resources: &Vec<DisplayResources>,
         bind_group: BindGroupId,
-        surface_manager: &SurfaceManager,
+        surface_manager: &SurfaceManager<E>,
     ) -> CommandBufferDescriptor {
         log::info!(target: "ScreenTask","Preparing command buffer descriptor");
         let render_passes: Vec<_> = display_resources
             .iter()
             .map(|display_resources| {
-                let commands = if surface_manager.len() > 0 {
-                    vec![
-                        RenderCommand::SetPipeline {
-                            pipeline: display_resources.render_pipeline,
-                        },
-                        RenderCommand::SetPushConstants {
-                            stages: wgpu::ShaderStage::VERTEX,
-                            offset: 0,
-                            data: bytemuck::bytes_of(&PushConstants::new(
-                                display_resources.display.position(),
-                                display_resources.display.size(),
-                                1024,
-                            ))
-                            .to_vec(),
-                        },
-                        RenderCommand::SetBindGroup {
-                            index: 0,
-                            bind_group: bind_group,
-                            offsets: Vec::new(),
-                        },
-                        RenderCommand::SetVertexBuffer {
-                            slot: 0,
-                            buffer: *surface_manager.buffer_id(),
-                            slice: Slice::from(..),
-                        },
-                        RenderCommand::Draw {
-                            vertices: 0..4,
-                            instances: 0..surface_manager.len() as u32,
-                        },
-                    ]
-                } else {
-                    Vec::new()
-                };
-
-                Command::RenderPass {
-                    label: Self::TASK_NAME.to_string(),
-                    depth_stencil: Some(*display_resources.display.depth_stencil_view()),
-                    color_attachments: vec![RenderPassColorAttachment {
-                        view: ColorView::Swapchain(*display_resources.display.swapchain()),
-                        resolve_target: None,
-                        ops: wgpu::Operations {
-                            load: wgpu::LoadOp::Load,
-                            store: true,
-                        },
-                    }],
-                    commands,
+                // Only the blurred surfaces shown by the display need the blur passes.
+                let blurred_instances =
+                    surface_manager.blurred_instances_in(display_resources.display.logical_area());
+                match &display_resources.blur {
+                    Some(blur) if !blurred_instances.is_empty() => {
+                        Self::prepare_blur_render_passes(
+                            display_resources,
+                            blur,
+                            bind_group,
+                            surface_manager,
+                            &blurred_instances,
+                        )
+                    }
+                    _ => vec![Self::prepare_render_pass(
+                        display_resources,
+                        bind_group,
+                        surface_manager,
+                    )],
                 }
             })
+            .flatten()
             .collect();
 
         let descriptor = CommandBufferDescriptor {
@@ -75,4 +58,242 @@ impl ScreenTask {
 
         descriptor
     }
+
+    /// Render pass drawing the display directly on its swapchain.
+    fn prepare_render_pass(
+        display_resources: &DisplayResources,
+        bind_group: BindGroupId,
+        surface_manager: &SurfaceManager<E>,
+    ) -> Command {
+        let display = &display_resources.display;
+        let mut commands = Vec::new();
+        // Each display draws only its own wallpaper, before the surfaces.
+        let wallpaper_slot = surface_manager.wallpaper_slot(display.external_id());
+        if wallpaper_slot.is_some() || !surface_manager.is_empty() {
+            commands.extend(Self::surface_setup_commands(
+                display_resources,
+                bind_group,
+                surface_manager,
+            ));
+            if let Some(slot) = wallpaper_slot {
+                commands.push(RenderCommand::Draw {
+                    vertices: 0..4,
+                    instances: slot..slot + 1,
+                });
+            }
+            // Surfaces and their shadows are drawn with a single instanced draw.
+            if !surface_manager.is_empty() {
+                let first = surface_manager.wallpaper_count() as u32;
+                commands.push(RenderCommand::Draw {
+                    vertices: 0..4,
+                    instances: first..first + surface_manager.instance_count() as u32,
+                });
+            }
+        }
+
+        Self::render_pass(
+            ColorView::Swapchain(*display.swapchain()),
+            Some(*display.depth_stencil_view()),
+            wgpu::LoadOp::Clear(display.clear_color()),
+            commands,
+        )
+    }
+
+    /// Render passes drawing the display on its offscreen scene, blurring the background of the blurred instances.
+    fn prepare_blur_render_passes(
+        display_resources: &DisplayResources,
+        blur: &BlurTargets,
+        bind_group: BindGroupId,
+        surface_manager: &SurfaceManager<E>,
+        blurred_instances: &[u32],
+    ) -> Vec<Command> {
+        let display = &display_resources.display;
+        let scene = || ColorView::TextureView(blur.scene.view);
+        let depth_stencil = Some(*display.depth_stencil_view());
+        let first = surface_manager.wallpaper_count() as u32;
+        let end = first + surface_manager.instance_count() as u32;
+        let mut passes = Vec::new();
+
+        // Wallpaper and surfaces beneath the first blurred instance.
+        let mut commands =
+            Self::surface_setup_commands(display_resources, bind_group, surface_manager);
+        if let Some(slot) = surface_manager.wallpaper_slot(display.external_id()) {
+            commands.push(RenderCommand::Draw {
+                vertices: 0..4,
+                instances: slot..slot + 1,
+            });
+        }
+        if blurred_instances[0] > first {
+            commands.push(RenderCommand::Draw {
+                vertices: 0..4,
+                instances: first..blurred_instances[0],
+            });
+        }
+        passes.push(Self::render_pass(
+            scene(),
+            depth_stencil,
+            wgpu::LoadOp::Clear(display.clear_color()),
+            commands,
+        ));
+
+        for (index, instance) in blurred_instances.iter().enumerate() {
+            passes.extend(Self::prepare_blur_passes(blur));
+
+            // The blurred background is drawn with the geometry of the surface, then the drawing continues.
+            let mut commands = vec![
+                RenderCommand::SetPipeline {
+                    pipeline: blur.backdrop_pipeline,
+                },
+                RenderCommand::SetPushConstants {
+                    stages: wgpu::ShaderStage::VERTEX,
+                    offset: 0,
+                    data: bytemuck::bytes_of(&display.push_constants()).to_vec(),
+                },
+                RenderCommand::SetBindGroup {
+                    index: 0,
+                    bind_group: blur.blurred.bind_group,
+                    offsets: Vec::new(),
+                },
+                RenderCommand::SetVertexBuffer {
+                    slot: 0,
+                    buffer: *surface_manager.buffer_id(),
+                    slice: Slice::from(..),
+                },
+                RenderCommand::Draw {
+                    vertices: 0..4,
+                    instances: *instance..instance + 1,
+                },
+            ];
+            let next = blurred_instances.get(index + 1).copied().unwrap_or(end);
+            commands.extend(Self::surface_setup_commands(
+                display_resources,
+                bind_group,
+                surface_manager,
+            ));
+            commands.push(RenderCommand::Draw {
+                vertices: 0..4,
+                instances: *instance..next,
+            });
+            passes.push(Self::render_pass(
+                scene(),
+                depth_stencil,
+                wgpu::LoadOp::Load,
+                commands,
+            ));
+        }
+
+        passes.push(Self::blur_pass(
+            blur,
+            ColorView::Swapchain(*display.swapchain()),
+            &blur.scene,
+            BLUR_MODE_COPY,
+        ));
+        passes
+    }
+
+    /// Passes downsampling the scene through every blur level and upsampling it back to the blurred target.
+    fn prepare_blur_passes(blur: &BlurTargets) -> Vec<Command> {
+        let mut passes = Vec::new();
+        let mut source = &blur.scene;
+        for level in &blur.levels {
+            passes.push(Self::blur_pass(
+                blur,
+                ColorView::TextureView(level.view),
+                source,
+                BLUR_MODE_DOWNSAMPLE,
+            ));
+            source = level;
+        }
+        let targets = blur.levels.iter().rev().skip(1).chain(Some(&blur.blurred));
+        for target in targets {
+            passes.push(Self::blur_pass(
+                blur,
+                ColorView::TextureView(target.view),
+                source,
+                BLUR_MODE_UPSAMPLE,
+            ));
+            source = target;
+        }
+        passes
+    }
+
+    /// Render pass drawing the source on the whole target with the provided blur mode.
+    fn blur_pass(
+        blur: &BlurTargets,
+        target: ColorView,
+        source: &RenderTarget,
+        mode: u32,
+    ) -> Command {
+        let commands = vec![
+            RenderCommand::SetPipeline {
+                pipeline: blur.blur_pipeline,
+            },
+            RenderCommand::SetPushConstants {
+                stages: wgpu::ShaderStage::FRAGMENT,
+                offset: 0,
+                data: bytemuck::bytes_of(&BlurPushConstants::new(source.size, mode)).to_vec(),
+            },
+            RenderCommand::SetBindGroup {
+                index: 0,
+                bind_group: source.bind_group,
+                offsets: Vec::new(),
+            },
+            RenderCommand::Draw {
+                vertices: 0..3,
+                instances: 0..1,
+            },
+        ];
+        Self::render_pass(
+            target,
+            None,
+            wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+            commands,
+        )
+    }
+
+    /// Commands binding the pipeline and the resources used to draw the surfaces.
+    fn surface_setup_commands(
+        display_resources: &DisplayResources,
+        bind_group: BindGroupId,
+        surface_manager: &SurfaceManager<E>,
+    ) -> Vec<RenderCommand> {
+        vec![
+            RenderCommand::SetPipeline {
+                pipeline: display_resources.render_pipeline,
+            },
+            RenderCommand::SetPushConstants {
+                stages: wgpu::ShaderStage::VERTEX,
+                offset: 0,
+                data: bytemuck::bytes_of(&display_resources.display.push_constants()).to_vec(),
+            },
+            RenderCommand::SetBindGroup {
+                index: 0,
+                bind_group: bind_group,
+                offsets: Vec::new(),
+            },
+            RenderCommand::SetVertexBuffer {
+                slot: 0,
+                buffer: *surface_manager.buffer_id(),
+                slice: Slice::from(..),
+            },
+        ]
+    }
+
+    fn render_pass(
+        view: ColorView,
+        depth_stencil: Option<TextureViewId>,
+        load: wgpu::LoadOp<wgpu::Color>,
+        commands: Vec<RenderCommand>,
+    ) -> Command {
+        Command::RenderPass {
+            label: Self::TASK_NAME.to_string(),
+            depth_stencil,
+            color_attachments: vec![RenderPassColorAttachment {
+                view,
+                resolve_target: None,
+                ops: wgpu::Operations { load, store: true },
+            }],
+            commands,
+        }
+    }
 }
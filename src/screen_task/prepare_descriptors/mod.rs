@@ -1,3 +1,4 @@
+use crate::engine::Engine;
 use wgpu_engine::*;
 
 mod bind_group;
@@ -5,7 +6,9 @@ mod bind_group_layout;
 mod command_buffer;
 mod pipeline_layout;
 mod render_pipeline;
+pub(crate) use render_pipeline::surface_vertex_buffer_layout;
 
+use crate::error::{OrResourceError, ScreenTaskError};
 use crate::DeviceResources;
 use crate::Display;
 use crate::DisplayResources;
@@ -14,15 +17,15 @@ use crate::ScreenTask;
 use crate::shaders::*;
 use crate::surface_manager::SurfaceManager;
 
-impl ScreenTask {
+impl<E: Engine> ScreenTask<E> {
     pub(crate) fn init_device_resources(
-        update_context: &mut UpdateContext,
+        update_context: &mut E,
         external_display_id: usize,
         device: DeviceId,
         swapchain: SwapchainId,
         //display_position: [u32; 2],
-    ) -> DeviceResources {
-        let surface_manager = SurfaceManager::new(update_context, device);
+    ) -> Result<DeviceResources<E>, ScreenTaskError> {
+        let surface_manager = SurfaceManager::new(update_context, device)?;
 
         let vertex_shader_descriptor = ShaderModuleDescriptor {
             device,
@@ -32,7 +35,7 @@ impl ScreenTask {
         };
         let vertex_shader = update_context
             .add_shader_module_descriptor(vertex_shader_descriptor)
-            .unwrap();
+            .or_resource_error("vertex shader")?;
 
         let fragment_shader_descriptor = ShaderModuleDescriptor {
             device,
@@ -42,7 +45,7 @@ impl ScreenTask {
         };
         let fragment_shader = update_context
             .add_shader_module_descriptor(fragment_shader_descriptor)
-            .unwrap();
+            .or_resource_error("fragment shader")?;
 
         let sampler_descriptor = SamplerDescriptor {
             device,
@@ -62,13 +65,13 @@ impl ScreenTask {
 
         let sampler = update_context
             .add_sampler_descriptor(sampler_descriptor)
-            .unwrap();
+            .or_resource_error("sampler")?;
 
         let bind_group_layout_descriptor =
             Self::prepare_bind_group_layout(update_context, device, &surface_manager);
         let bind_group_layout = update_context
             .add_bind_group_layout_descriptor(bind_group_layout_descriptor)
-            .unwrap();
+            .or_resource_error("bind group layout")?;
 
         let bind_group_descriptor = Self::prepare_bind_group(
             update_context,
@@ -79,13 +82,13 @@ impl ScreenTask {
         );
         let bind_group = update_context
             .add_bind_group_descriptor(bind_group_descriptor)
-            .unwrap();
+            .or_resource_error("bind group")?;
 
         let pipeline_layout_descriptor =
             Self::prepare_pipeline_layout(update_context, device, bind_group_layout);
         let pipeline_layout = update_context
             .add_pipeline_layout_descriptor(pipeline_layout_descriptor)
-            .unwrap();
+            .or_resource_error("pipeline layout")?;
 
         let display = Display::new(
             update_context,
@@ -93,15 +96,14 @@ impl ScreenTask {
             device,
             swapchain,
             [0, 0],
-        );
+        )?;
         let display_resources = DisplayResources::new(
             update_context,
             display,
             pipeline_layout,
             vertex_shader,
             fragment_shader,
-            &surface_manager,
-        );
+        )?;
         let displays = vec![display_resources];
 
         let command_buffer_descriptor = Self::prepare_command_buffer(
@@ -113,7 +115,7 @@ impl ScreenTask {
         );
         let command_buffer = update_context
             .add_command_buffer_descriptor(command_buffer_descriptor)
-            .unwrap();
+            .or_resource_error("command buffer")?;
 
         let data_copy_command_buffer_descriptor = CommandBufferDescriptor {
             device,
@@ -122,11 +124,11 @@ impl ScreenTask {
         };
         let data_copy_command_buffer = update_context
             .add_command_buffer_descriptor(data_copy_command_buffer_descriptor)
-            .unwrap();
+            .or_resource_error("data copy command buffer")?;
 
         let data_copy_command_buffer_updated = false;
 
-        DeviceResources {
+        Ok(DeviceResources {
             displays,
 
             surface_manager,
@@ -140,10 +142,13 @@ impl ScreenTask {
 
             pipeline_layout,
 
+            blur: None,
+            blurred_instances: Vec::new(),
+
             command_buffer,
 
             data_copy_command_buffer,
             data_copy_command_buffer_updated,
-        }
+        })
     }
 }
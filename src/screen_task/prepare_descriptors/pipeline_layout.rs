@@ -1,11 +1,12 @@
+use crate::engine::Engine;
 use crate::PushConstants;
 use crate::ScreenTask;
 use wgpu_engine::*;
 
-impl ScreenTask {
+impl<E: Engine> ScreenTask<E> {
     /// Generate the pipeline layout descriptor.
     pub(crate) fn prepare_pipeline_layout(
-        _update_context: &mut UpdateContext,
+        _update_context: &mut E,
         device: DeviceId,
         bind_group_layout: BindGroupLayoutId,
     ) -> PipelineLayoutDescriptor {
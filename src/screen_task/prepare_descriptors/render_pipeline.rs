@@ -1,44 +1,33 @@
 use crate::display::Display;
+use crate::engine::Engine;
 use crate::surface::Surface;
 use crate::ScreenTask;
-use crate::SurfaceManager;
 use wgpu_engine::*;
 
-impl ScreenTask {
+impl<E: Engine> ScreenTask<E> {
     /// Generate the render pipeline descriptor.
     pub(crate) fn prepare_render_pipeline(
-        update_context: &mut UpdateContext,
+        update_context: &mut E,
         device: DeviceId,
         display: &Display,
         layout: PipelineLayoutId,
         vertex_shader: ShaderModuleId,
         fragment_shader: ShaderModuleId,
-        surface_manager: &SurfaceManager,
-    ) -> (RenderPipelineDescriptor, bool) {
+    ) -> RenderPipelineDescriptor {
         log::info!(target: "ScreenTask","Preparing render pipeline descriptor");
         let format = update_context
             .swapchain_descriptor_ref(display.swapchain())
             .unwrap()
             .format;
-        let ready = surface_manager.len() > 0;
 
-        let descriptor = RenderPipelineDescriptor {
+        RenderPipelineDescriptor {
             device,
             label: Self::TASK_NAME.to_string() + " render pipeline",
             layout: Some(layout),
             vertex: VertexState {
                 module: vertex_shader,
                 entry_point: String::from("main"),
-                buffers: vec![VertexBufferLayout {
-                    array_stride: std::mem::size_of::<Surface>() as wgpu::BufferAddress,
-                    step_mode: wgpu::InputStepMode::Instance,
-                    attributes: wgpu::vertex_attr_array![
-                        0 => Float32x3,
-                        1 => Float32x2,
-                        2 => Uint32,
-                    ]
-                    .to_vec(),
-                }],
+                buffers: vec![surface_vertex_buffer_layout()],
             },
             primitive: wgpu::PrimitiveState {
                 //front_face: wgpu::FrontFace::Ccw,
@@ -49,24 +38,55 @@ impl ScreenTask {
             depth_stencil: Some(DepthStencilState {
                 id: *display.depth_stencil_view(),
                 depth_write_enabled: true,
-                depth_compare: wgpu::CompareFunction::Less,
+                depth_compare: wgpu::CompareFunction::LessEqual,
                 stencil: wgpu::StencilState::default(),
                 bias: wgpu::DepthBiasState::default(),
             }),
-            fragment: if ready {
-                Some(FragmentState {
-                    module: fragment_shader,
-                    entry_point: String::from("main"),
-                    targets: vec![wgpu::ColorTargetState {
-                        format,
-                        blend: None,
-                        write_mask: wgpu::ColorWrite::ALL,
-                    }],
-                })
-            } else {
-                None
-            },
-        };
-        (descriptor, ready)
+            fragment: Some(FragmentState {
+                module: fragment_shader,
+                entry_point: String::from("main"),
+                targets: vec![wgpu::ColorTargetState {
+                    format,
+                    blend: Some(wgpu::BlendState {
+                        color: wgpu::BlendComponent {
+                            src_factor: wgpu::BlendFactor::One,
+                            dst_factor: wgpu::BlendFactor::OneMinusSrcAlpha,
+                            operation: wgpu::BlendOperation::Add,
+                        },
+                        alpha: wgpu::BlendComponent {
+                            src_factor: wgpu::BlendFactor::One,
+                            dst_factor: wgpu::BlendFactor::OneMinusSrcAlpha,
+                            operation: wgpu::BlendOperation::Add,
+                        },
+                    }),
+                    write_mask: wgpu::ColorWrite::ALL,
+                }],
+            }),
+        }
+    }
+}
+
+/// Layout of the surface instances in the vertex buffer.
+pub(crate) fn surface_vertex_buffer_layout() -> VertexBufferLayout {
+    VertexBufferLayout {
+        array_stride: std::mem::size_of::<Surface>() as wgpu::BufferAddress,
+        step_mode: wgpu::InputStepMode::Instance,
+        attributes: wgpu::vertex_attr_array![
+            0 => Float32x3,
+            1 => Float32x2,
+            2 => Uint32,
+            3 => Float32,
+            4 => Float32x4,
+            5 => Float32x3,
+            6 => Float32x3,
+            7 => Float32x3,
+            8 => Float32x3,
+            9 => Uint32,
+            10 => Uint32,
+            11 => Float32x4,
+            12 => Float32x2,
+            13 => Float32x4,
+        ]
+        .to_vec(),
     }
 }
@@ -2,98 +2,118 @@ use std::collections::hash_map::Entry;
 use wgpu_engine::*;
 
 use crate::display::{Display, DisplayResources};
+use crate::engine::Engine;
+use crate::error::{OrResourceError, ScreenTaskError};
 use crate::screen_task::ScreenTask;
 
-impl TaskTrait for ScreenTask {
+impl TaskTrait for ScreenTask<UpdateContext> {
     fn name(&self) -> String {
         Self::TASK_NAME.to_string()
     }
 
     fn update_resources(&mut self, update_context: &mut UpdateContext) {
+        self.update_engine_resources(update_context);
+    }
+
+    fn command_buffers(&self) -> Vec<CommandBufferId> {
+        self.engine_command_buffers()
+    }
+}
+
+impl<E: Engine> ScreenTask<E> {
+    /// Elaborate the resource events and the pending events, updating the resources through the provided engine.
+    pub fn update_engine_resources(&mut self, update_context: &mut E) {
+        self.releases.begin_frame();
         self.devices.values_mut().for_each(|device_resources| {
             device_resources.data_copy_command_buffer_updated = false;
         });
 
-        let events = update_context.events().clone();
-        events.iter().for_each(|event| match event {
-            ResourceEvent::SwapchainCreated {
-                external_id,
-                swapchain,
-            } => {
-                let device = update_context.entity_device_id(swapchain).unwrap();
-                match self.devices.entry(device) {
-                    Entry::Vacant(vacant) => {
-                        let resources = Self::init_device_resources(
-                            update_context,
-                            *external_id,
-                            device,
-                            *swapchain,
-                        );
-                        vacant.insert(resources);
-                    }
-                    Entry::Occupied(mut occupied) => {
-                        let device_resources = occupied.get_mut();
-                        let display =
-                            Display::new(update_context, *external_id, device, *swapchain, [0, 0]);
-                        let display_resources = DisplayResources::new(
-                            update_context,
-                            display,
-                            device_resources.pipeline_layout,
-                            device_resources.vertex_shader,
-                            device_resources.fragment_shader,
-                            &device_resources.surface_manager,
-                        );
-
-                        device_resources.displays.push(display_resources);
-
-                        Self::update_command_buffer(update_context, device, device_resources);
-                    }
-                }
-            }
-            ResourceEvent::SwapchainDestroyed(swapchain) => {
-                self.devices.retain(|device, device_resources| {
-                    if let Some(index) =
-                        device_resources
-                            .displays
-                            .iter()
-                            .position(|display_resources| {
-                                display_resources.display.swapchain() == swapchain
-                            })
-                    {
-                        device_resources.displays.remove(index);
-                        if !device_resources.displays.is_empty() {
-                            Self::update_command_buffer(update_context, *device, device_resources);
-                            true
+        let events = update_context.events();
+        for event in events.iter() {
+            let result = match event {
+                ResourceEvent::SwapchainCreated {
+                    external_id,
+                    swapchain,
+                } => self.create_display(update_context, *external_id, *swapchain),
+                ResourceEvent::SwapchainDestroyed(swapchain) => {
+                    let mut result = Ok(());
+                    self.devices.retain(|device, device_resources| {
+                        if let Some(index) =
+                            device_resources
+                                .displays
+                                .iter()
+                                .position(|display_resources| {
+                                    display_resources.display.swapchain() == swapchain
+                                })
+                        {
+                            let display_resources = device_resources.displays.remove(index);
+                            if let Some(blur) = &display_resources.blur {
+                                if let Err(error) = blur.remove(update_context) {
+                                    result = Err(error);
+                                }
+                            }
+                            if !device_resources.displays.is_empty() {
+                                let external_id = display_resources.display.external_id();
+                                match device_resources
+                                    .surface_manager
+                                    .remove_wallpaper(update_context, external_id)
+                                {
+                                    Ok(true) => Self::update_layout_and_bind_groups(
+                                        update_context,
+                                        *device,
+                                        device_resources,
+                                    ),
+                                    Ok(false) => Self::update_command_buffer(
+                                        update_context,
+                                        *device,
+                                        device_resources,
+                                    ),
+                                    Err(error) => result = Err(error),
+                                }
+                                true
+                            } else {
+                                false
+                            }
                         } else {
-                            false
+                            true
                         }
-                    } else {
-                        true
-                    }
-                });
-            }
-            ResourceEvent::SwapchainUpdated(swapchain) => {
-                self.devices
+                    });
+                    result
+                }
+                ResourceEvent::SwapchainUpdated(swapchain) => self
+                    .devices
                     .iter_mut()
                     .find_map(|(device, device_resources)| {
-                        Self::update_command_buffer(update_context, *device, device_resources);
+                        let surface_manager = &mut device_resources.surface_manager;
                         let result = device_resources.displays.iter_mut().find_map(|display| {
                             if display.display.swapchain() == swapchain {
-                                display.display.update(update_context);
-                                Some(())
+                                let display = &mut display.display;
+                                surface_manager.set_wallpaper_area(
+                                    display.external_id(),
+                                    display.logical_area(),
+                                );
+                                Some(display.update(update_context))
                             } else {
                                 None
                             }
                         });
-                        //
+                        // The offscreen targets follow the new size of the display.
+                        Self::update_command_buffer(update_context, *device, device_resources);
                         result
-                    });
+                    })
+                    .unwrap_or(Ok(())),
+            };
+            if let Err(error) = result {
+                log::error!(target: "ScreenTask","{}",error);
+                self.errors.push(error);
             }
-        });
+        }
 
         self.elaborate_events(update_context);
     }
-    fn command_buffers(&self) -> Vec<CommandBufferId> {
+
+    /// Returns the command buffers to submit for the current frame.
+    pub fn engine_command_buffers(&self) -> Vec<CommandBufferId> {
         self.devices
             .values()
             .map(|device_resources| {
@@ -108,4 +128,51 @@ impl TaskTrait for ScreenTask {
             .flatten()
             .collect()
     }
+
+    /// Create the display, and the device resources if needed, of a newly created swapchain.
+    fn create_display(
+        &mut self,
+        update_context: &mut E,
+        external_id: usize,
+        swapchain: SwapchainId,
+    ) -> Result<(), ScreenTaskError> {
+        let device = update_context
+            .entity_device_id(&swapchain)
+            .or_resource_error("swapchain device")?;
+        match self.devices.entry(device) {
+            Entry::Vacant(vacant) => {
+                let mut resources =
+                    Self::init_device_resources(update_context, external_id, device, swapchain)?;
+                resources
+                    .surface_manager
+                    .set_shm_pools(self.shm_pools.clone());
+                vacant.insert(resources);
+            }
+            Entry::Occupied(mut occupied) => {
+                let device_resources = occupied.get_mut();
+                let display = Display::new(update_context, external_id, device, swapchain, [0, 0])?;
+                let display_resources = DisplayResources::new(
+                    update_context,
+                    display,
+                    device_resources.pipeline_layout,
+                    device_resources.vertex_shader,
+                    device_resources.fragment_shader,
+                )?;
+
+                device_resources.displays.push(display_resources);
+
+                Self::update_command_buffer(update_context, device, device_resources);
+            }
+        }
+
+        if let Some(background) = self.backgrounds.get(&external_id) {
+            let device_resources = self
+                .devices
+                .get_mut(&device)
+                .or_resource_error("device resources")?;
+            Self::apply_background(update_context, device_resources, external_id, background)?;
+            Self::update_layout_and_bind_groups(update_context, device, device_resources);
+        }
+        Ok(())
+    }
 }
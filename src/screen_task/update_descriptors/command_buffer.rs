@@ -1,15 +1,23 @@
+use crate::blur::{BlurPipelines, BlurTargets};
+use crate::engine::Engine;
+use crate::error::{OrResourceError, ScreenTaskError};
 use wgpu_engine::*;
 
 use crate::screen_task::device_resources::DeviceResources;
 use crate::screen_task::ScreenTask;
 
-impl ScreenTask {
+impl<E: Engine> ScreenTask<E> {
     /// Update the command buffer descriptor for the passed DeviceResources.
     pub(crate) fn update_command_buffer(
-        update_context: &mut UpdateContext,
+        update_context: &mut E,
         device: DeviceId,
-        device_resources: &mut DeviceResources,
+        device_resources: &mut DeviceResources<E>,
     ) {
+        if let Err(error) = Self::update_blur_targets(update_context, device, device_resources) {
+            log::error!(target: "ScreenTask","{}, surfaces are drawn without background blur",error);
+        }
+        device_resources.blurred_instances = Self::displayed_blurred_instances(device_resources);
+
         let command_buffer_descriptor = Self::prepare_command_buffer(
             update_context,
             device,
@@ -22,4 +30,68 @@ impl ScreenTask {
             command_buffer_descriptor,
         );
     }
+
+    /// Returns the instances with a blurred background that are drawn on each display.
+    pub(crate) fn displayed_blurred_instances(
+        device_resources: &DeviceResources<E>,
+    ) -> Vec<Vec<u32>> {
+        device_resources
+            .displays
+            .iter()
+            .map(|display_resources| {
+                device_resources
+                    .surface_manager
+                    .blurred_instances_in(display_resources.display.logical_area())
+            })
+            .collect()
+    }
+
+    /// Create the offscreen targets of the displays once a surface blurs its background, and keep them sized as the displays.
+    fn update_blur_targets(
+        update_context: &mut E,
+        device: DeviceId,
+        device_resources: &mut DeviceResources<E>,
+    ) -> Result<(), ScreenTaskError> {
+        if device_resources
+            .surface_manager
+            .blurred_instances()
+            .is_empty()
+        {
+            return Ok(());
+        }
+        if device_resources.blur.is_none() {
+            device_resources.blur = Some(BlurPipelines::new(update_context, device)?);
+        }
+        let pipelines = device_resources
+            .blur
+            .as_ref()
+            .or_resource_error("blur pipelines")?;
+
+        for display_resources in &mut device_resources.displays {
+            let display = &display_resources.display;
+            match &mut display_resources.blur {
+                Some(blur) => {
+                    if blur.size() != display.size() {
+                        blur.resize(update_context, pipelines, display.size())?;
+                    }
+                }
+                None => {
+                    let format = update_context
+                        .swapchain_descriptor_ref(display.swapchain())
+                        .or_resource_error("swapchain descriptor")?
+                        .format;
+                    display_resources.blur = Some(BlurTargets::new(
+                        update_context,
+                        pipelines,
+                        device,
+                        display.size(),
+                        format,
+                        device_resources.vertex_shader,
+                        *display.depth_stencil_view(),
+                    )?);
+                }
+            }
+        }
+        Ok(())
+    }
 }
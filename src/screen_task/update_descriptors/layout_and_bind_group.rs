@@ -1,14 +1,15 @@
+use crate::engine::Engine;
 use wgpu_engine::*;
 
 use crate::screen_task::device_resources::DeviceResources;
 use crate::screen_task::ScreenTask;
 
-impl ScreenTask {
+impl<E: Engine> ScreenTask<E> {
     /// Update the pipeline layout, bind group layout and the bind group descriptors for the passed DeviceResources.
     pub(crate) fn update_layout_and_bind_groups(
-        update_context: &mut UpdateContext,
+        update_context: &mut E,
         device: DeviceId,
-        device_resources: &mut DeviceResources,
+        device_resources: &mut DeviceResources<E>,
     ) {
         let bind_group_layout_descriptor = Self::prepare_bind_group_layout(
             update_context,
@@ -30,7 +31,6 @@ impl ScreenTask {
             pipeline_layout_descriptor,
         );
 
-        device_resources.surface_manager.update_image_indexes();
         let bind_group_descriptor = Self::prepare_bind_group(
             update_context,
             device,
@@ -41,7 +41,6 @@ impl ScreenTask {
         update_context
             .update_bind_group_descriptor(&mut device_resources.bind_group, bind_group_descriptor);
 
-        Self::update_render_pipeline(update_context, device, device_resources);
         Self::update_command_buffer(update_context, device, device_resources);
     }
 }
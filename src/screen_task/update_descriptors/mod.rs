@@ -1,3 +1,2 @@
 mod command_buffer;
 mod layout_and_bind_group;
-mod render_pipeline;
@@ -0,0 +1,149 @@
+use std::collections::HashMap;
+
+use crate::error::ScreenTaskError;
+use crate::stacking::StackingOrder;
+use crate::surface::{check_clip_rect, check_size, SurfaceSource, SurfaceSourceInfo};
+
+#[derive(Debug, Default)]
+/**
+Mirror of the surfaces that will exist once the pending events are elaborated.
+It is used to reject invalid events when they are enqueued, so that the caller gets the error directly.
+*/
+pub(crate) struct EventValidator {
+    surfaces: HashMap<usize, SurfaceSourceInfo>,
+    stacking_order: StackingOrder,
+}
+impl EventValidator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the source informations of the surface with the provided id.
+    fn surface(&self, id: usize) -> Result<&SurfaceSourceInfo, ScreenTaskError> {
+        self.surfaces
+            .get(&id)
+            .ok_or(ScreenTaskError::UnknownSurface(id))
+    }
+
+    /// Check that the surface with the provided id exists.
+    pub fn check_surface(&self, id: usize) -> Result<(), ScreenTaskError> {
+        self.surface(id).map(|_| ())
+    }
+
+    /// Validate and register a new surface.
+    pub fn create_surface(
+        &mut self,
+        id: usize,
+        source: &SurfaceSource,
+        z: i32,
+        size: [u32; 2],
+    ) -> Result<(), ScreenTaskError> {
+        if self.surfaces.contains_key(&id) {
+            return Err(ScreenTaskError::DuplicateSurface(id));
+        }
+        check_size(size)?;
+        source.validate()?;
+        self.stacking_order.insert(id, z)?;
+        self.surfaces.insert(id, SurfaceSourceInfo::from(source));
+        Ok(())
+    }
+
+    /// Validate and register a new subsurface of the provided parent.
+    pub fn create_subsurface(
+        &mut self,
+        id: usize,
+        parent: usize,
+        source: &SurfaceSource,
+        size: [u32; 2],
+    ) -> Result<(), ScreenTaskError> {
+        if self.surfaces.contains_key(&id) {
+            return Err(ScreenTaskError::DuplicateSurface(id));
+        }
+        self.check_surface(parent)?;
+        check_size(size)?;
+        source.validate()?;
+        self.stacking_order.insert_child(id, parent)?;
+        self.surfaces.insert(id, SurfaceSourceInfo::from(source));
+        Ok(())
+    }
+
+    /// Validate and register the new source of a surface.
+    pub fn update_source(
+        &mut self,
+        id: usize,
+        source: &SurfaceSource,
+    ) -> Result<(), ScreenTaskError> {
+        self.check_surface(id)?;
+        source.validate()?;
+        self.surfaces.insert(id, SurfaceSourceInfo::from(source));
+        Ok(())
+    }
+
+    /// Mirror the source kept by the devices, after the new source of the surface was dropped or failed.
+    pub fn restore_source(&mut self, id: usize, info: SurfaceSourceInfo) {
+        if let Some(current) = self.surfaces.get_mut(&id) {
+            *current = info;
+        }
+    }
+
+    /// Validate the length of the data used to update a surface.
+    pub fn update_data(&self, id: usize, length: usize) -> Result<(), ScreenTaskError> {
+        self.surface(id)?.validate_data(length)
+    }
+
+    /// Validate the source rectangle of a surface.
+    pub fn set_surface_source_rect(
+        &self,
+        id: usize,
+        source_rect: [f32; 4],
+    ) -> Result<(), ScreenTaskError> {
+        self.surface(id)?.validate_source_rect(source_rect)
+    }
+
+    /// Validate the clip rectangle of a surface.
+    pub fn set_surface_clip_rect(
+        &self,
+        id: usize,
+        clip_rect: Option<[f32; 4]>,
+    ) -> Result<(), ScreenTaskError> {
+        self.check_surface(id)?;
+        clip_rect.map_or(Ok(()), check_clip_rect)
+    }
+
+    /// Validate the new size of a surface.
+    pub fn resize_surface(&self, id: usize, size: [u32; 2]) -> Result<(), ScreenTaskError> {
+        self.check_surface(id)?;
+        check_size(size)
+    }
+
+    /// Validate the move of a surface, following its new z.
+    pub fn move_surface(&mut self, id: usize, z: i32) -> Result<(), ScreenTaskError> {
+        self.stacking_order.set_z(id, z)
+    }
+
+    /// Returns the stacking order that the surfaces will have once the events are elaborated.
+    pub fn stacking_order(&self) -> &StackingOrder {
+        &self.stacking_order
+    }
+
+    /// Returns the stacking order, to validate and mirror the restacking of the surfaces.
+    pub fn stacking_order_mut(&mut self) -> &mut StackingOrder {
+        &mut self.stacking_order
+    }
+
+    /// Unregister a surface whose creation failed, together with its subsurfaces, if it is still registered.
+    pub fn discard_surface(&mut self, id: usize) {
+        if self.surfaces.contains_key(&id) {
+            let _ = self.remove_surface(id);
+        }
+    }
+
+    /// Validate and unregister a surface, together with its subsurfaces.
+    pub fn remove_surface(&mut self, id: usize) -> Result<(), ScreenTaskError> {
+        self.check_surface(id)?;
+        for removed in self.stacking_order.remove(id)? {
+            self.surfaces.remove(&removed);
+        }
+        Ok(())
+    }
+}
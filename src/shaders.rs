@@ -7,46 +7,70 @@ pub const VERTEX_SHADER_CODE: &[u32] = inline_spirv!(
 layout(location = 0) in vec3 position;
 layout(location = 1) in vec2 size;
 layout(location = 2) in uint index;
+layout(location = 3) in float opacity;
+layout(location = 4) in vec4 source_rect;
+layout(location = 5) in vec3 transform_x;
+layout(location = 6) in vec3 transform_y;
+layout(location = 7) in vec3 uv_transform_x;
+layout(location = 8) in vec3 uv_transform_y;
+layout(location = 9) in uint sampling;
+layout(location = 10) in uint color_space;
+layout(location = 11) in vec4 clip_rect;
+layout(location = 12) in vec2 decoration;
+layout(location = 13) in vec4 border_color;
 
 layout(push_constant) uniform PushConstants {
     mat4 projection_matrix;
+    vec2 output_origin;
+    float output_scale;
 };
 
 layout(location = 0) out vec3 fragment_pos;
 layout(location = 1) flat out uint out_index;
+layout(location = 2) flat out float out_opacity;
+layout(location = 3) flat out uint out_sampling;
+layout(location = 4) flat out uint out_color_space;
+layout(location = 5) out vec2 out_physical;
+layout(location = 6) flat out vec4 out_clip;
+layout(location = 7) out vec2 out_local;
+layout(location = 8) flat out vec2 out_size;
+layout(location = 9) flat out vec2 out_decoration;
+layout(location = 10) flat out vec4 out_border_color;
+layout(location = 11) flat out float out_scale;
+layout(location = 12) flat out vec4 out_source_rect;
 
 void main() {
+    out_index = index;
+    out_opacity = opacity;
+    out_sampling = sampling;
+    out_color_space = color_space;
+    out_size = size;
+    out_decoration = decoration;
+    out_border_color = border_color;
+    out_scale = output_scale;
+    out_source_rect = source_rect;
+    // The clip rectangle is snapped like the geometry, so adjacent clipped surfaces meet without seams.
+    out_clip = vec4(
+        floor(clip_rect.xy * output_scale + 0.5) - output_origin,
+        floor(clip_rect.zw * output_scale + 0.5) - output_origin
+    );
+
+    vec2 corner;
     switch (gl_VertexIndex) {
         case 0:{
-            vec3 vertex = position;
-
-            vec4 projected_vertex = projection_matrix * vec4(vertex,1.0);
-            gl_Position = vec4(projected_vertex.xyz,1.0);
-            fragment_pos = vec3(0.0,0.0,projected_vertex.z);
+            corner = vec2(0.0,0.0);
             break;
         }
         case 1:{
-            vec3 vertex = vec3(position.x,position.y+size.y,position.z);
-
-            vec4 projected_vertex = projection_matrix * vec4(vertex,1.0);
-            gl_Position = vec4(projected_vertex.xyz,1.0);
-            fragment_pos = vec3(0.0,1.0,projected_vertex.z);
+            corner = vec2(0.0,1.0);
             break;
         }
         case 2:{
-            vec3 vertex = vec3(position.x+size.x,position.y,position.z);
-
-            vec4 projected_vertex = projection_matrix * vec4(vertex,1.0);
-            gl_Position = vec4(projected_vertex.xyz,1.0);
-            fragment_pos = vec3(1.0,0.0,projected_vertex.z);
+            corner = vec2(1.0,0.0);
             break;
         }
         case 3:{
-            vec3 vertex = vec3(position.x+size.x,position.y+size.y,position.z);
-
-            vec4 projected_vertex = projection_matrix * vec4(vertex,1.0);
-            gl_Position = vec4(projected_vertex.xyz,1.0);
-            fragment_pos = vec3(1.0,1.0,projected_vertex.z);
+            corner = vec2(1.0,1.0);
             break;
         }
         default:{
@@ -54,10 +78,28 @@ void main() {
 
             gl_Position = vec4(vertex,1.0);
             fragment_pos = vec3(0.0,0.0,0.0);
+            out_physical = vec2(0.0,0.0);
+            out_local = vec2(0.0,0.0);
+            return;
         }
     }
 
-    out_index = index;
+    out_local = corner * size;
+
+    // The geometry transform is applied around the center of the surface.
+    vec3 centered = vec3((corner - 0.5) * size,1.0);
+    vec2 transformed = vec2(dot(transform_x,centered),dot(transform_y,centered));
+    vec3 vertex = vec3(position.xy + size * 0.5 + transformed,position.z);
+
+    // Logical coordinates are snapped to the physical pixel grid of the output.
+    vec2 physical = floor(vertex.xy * output_scale + 0.5) - output_origin;
+    out_physical = physical;
+    vec4 projected_vertex = projection_matrix * vec4(physical,vertex.z,1.0);
+    gl_Position = vec4(projected_vertex.xyz,1.0);
+
+    // The buffer transform is applied before cropping the source rectangle.
+    vec2 uv = vec2(dot(uv_transform_x,vec3(corner,1.0)),dot(uv_transform_y,vec3(corner,1.0)));
+    fragment_pos = vec3(source_rect.xy + uv * source_rect.zw,projected_vertex.z);
 }
 "#,
     vert
@@ -71,21 +113,237 @@ pub const FRAGMENT_SHADER_CODE: &[u32] = inline_spirv!(
 
 layout(location = 0) in vec3 fragment_position;
 layout(location = 1) nonuniformEXT flat in uint index;  // dynamically non-uniform
+layout(location = 2) flat in float opacity;
+layout(location = 3) flat in uint sampling;
+layout(location = 4) flat in uint color_space;
+layout(location = 5) in vec2 physical_position;
+layout(location = 6) flat in vec4 clip;
+layout(location = 7) in vec2 local_position;
+layout(location = 8) flat in vec2 size;
+layout(location = 9) flat in vec2 decoration;
+layout(location = 10) flat in vec4 border_color;
+layout(location = 11) flat in float output_scale;
+layout(location = 12) flat in vec4 source_rect;
 layout(location = 0) out vec4 fragment_color;
 
 layout(set = 0, binding = 0) uniform sampler samp;
 layout(set = 0, binding = 1) uniform texture2D textures[];
 
+const uint SAMPLING_RGBA = 0u;
+const uint SAMPLING_YUV_SEMI_PLANAR = 1u;
+const uint SAMPLING_YUV_PLANAR = 2u;
+const uint SAMPLING_SHADOW = 3u;
+const uint SAMPLING_SOLID_COLOR = 4u;
+const uint SAMPLING_REPEAT = 16u;
+const uint SAMPLING_OPAQUE = 32u;
+const uint SAMPLING_FLAGS = SAMPLING_REPEAT | SAMPLING_OPAQUE;
+
+const uint COLOR_SPACE_BT709 = 1u;
+const uint COLOR_SPACE_LIMITED_RANGE = 2u;
+
+// Keep in sync with yuv_to_rgb in yuv.rs.
+vec3 yuv_to_rgb(vec3 yuv) {
+    vec3 centered;
+    if((color_space & COLOR_SPACE_LIMITED_RANGE) != 0u) {
+        centered = vec3(
+            (yuv.x - 16.0 / 255.0) * 255.0 / 219.0,
+            (yuv.y - 128.0 / 255.0) * 255.0 / 224.0,
+            (yuv.z - 128.0 / 255.0) * 255.0 / 224.0
+        );
+    }
+    else {
+        centered = vec3(yuv.x, yuv.y - 128.0 / 255.0, yuv.z - 128.0 / 255.0);
+    }
+
+    vec4 coefficients;
+    if((color_space & COLOR_SPACE_BT709) != 0u) {coefficients = vec4(1.5748, 0.187324, 0.468124, 1.8556);}
+    else {coefficients = vec4(1.402, 0.344136, 0.714136, 1.772);}
+
+    vec3 rgb = vec3(
+        centered.x + coefficients.x * centered.z,
+        centered.x - coefficients.y * centered.y - coefficients.z * centered.z,
+        centered.x + coefficients.w * centered.y
+    );
+    return clamp(rgb, 0.0, 1.0);
+}
+
+vec4 sample_surface(vec2 uv) {
+    if((sampling & SAMPLING_REPEAT) != 0u) {uv = fract(uv);}
+    uint mode = sampling & ~SAMPLING_FLAGS;
+    if(mode == SAMPLING_YUV_SEMI_PLANAR) {
+        float y = texture(sampler2D(textures[index], samp), uv).x;
+        vec2 uv_chroma = texture(sampler2D(textures[nonuniformEXT(index + 1u)], samp), uv).xy;
+        return vec4(yuv_to_rgb(vec3(y, uv_chroma)), 1.0);
+    }
+    else if(mode == SAMPLING_YUV_PLANAR) {
+        float y = texture(sampler2D(textures[index], samp), uv).x;
+        float u = texture(sampler2D(textures[nonuniformEXT(index + 1u)], samp), uv).x;
+        float v = texture(sampler2D(textures[nonuniformEXT(index + 2u)], samp), uv).x;
+        return vec4(yuv_to_rgb(vec3(y, u, v)), 1.0);
+    }
+    // Solid colors store their color in the source rectangle and sample no texture.
+    else if(mode == SAMPLING_SOLID_COLOR) {
+        return source_rect;
+    }
+    else if((sampling & SAMPLING_OPAQUE) != 0u) {
+        return vec4(texture(sampler2D(textures[index], samp), uv).xyz, 1.0);
+    }
+    else {
+        return texture(sampler2D(textures[index], samp), uv);
+    }
+}
+
+// Keep in sync with decorate in software.rs and with the corners of the backdrop fragment shader.
+vec4 decorate(vec4 color) {
+    float radius = min(decoration.x, 0.5 * min(size.x, size.y));
+    vec2 corner_offset = abs(local_position - size * 0.5) - size * 0.5 + radius;
+    float distance = length(max(corner_offset, 0.0)) + min(max(corner_offset.x, corner_offset.y), 0.0) - radius;
+    if(decoration.y > 0.0) {
+        color = mix(color, border_color, clamp(0.5 + (distance + decoration.y) * output_scale, 0.0, 1.0));
+    }
+    // Straight edges are left to the rasterizer, only the corners are anti-aliased.
+    if(radius > 0.0 && corner_offset.x > 0.0 && corner_offset.y > 0.0) {
+        color.w *= clamp(0.5 - distance * output_scale, 0.0, 1.0);
+    }
+    return color;
+}
+
+// Abramowitz and Stegun 7.1.26, keep in sync with erf in shadow.rs.
+float erf(float x) {
+    float t = 1.0 / (1.0 + 0.3275911 * abs(x));
+    float polynomial = ((((1.061405429 * t - 1.453152027) * t + 1.421413741) * t - 0.284496736) * t + 0.254829592) * t;
+    return sign(x) * (1.0 - polynomial * exp(-x * x));
+}
+
+// Coverage of the Gaussian blurred rectangle along an axis.
+float shadow_axis(float position, float size, float blur_radius) {
+    float scale = 1.0 / (sqrt(2.0) * max(blur_radius * 0.5, 1e-3));
+    float margin = 1.5 * blur_radius;
+    return 0.5 * (erf((position - margin) * scale) - erf((position - size + margin) * scale));
+}
+
+// Keep in sync with shadow_coverage in shadow.rs.
+float shadow_coverage() {
+    return shadow_axis(local_position.x, size.x, decoration.x) * shadow_axis(local_position.y, size.y, decoration.x);
+}
 
 void main() {
     //if(fragment_position.z == 0.5){discard;}
+    if(any(lessThan(physical_position, clip.xy)) || any(greaterThanEqual(physical_position, clip.zw))) {discard;}
 
-    vec4 color = vec4(texture(sampler2D(textures[index], samp), fragment_position.xy));
-    if(color.w == 0.0) {discard;}
-    else{fragment_color = color;}
+    vec4 color;
+    if((sampling & ~SAMPLING_FLAGS) == SAMPLING_SHADOW) {color = vec4(border_color.xyz, border_color.w * shadow_coverage());}
+    else {color = decorate(sample_surface(fragment_position.xy));}
+    float alpha = color.w * opacity;
+    if(alpha == 0.0) {discard;}
+    else{fragment_color = vec4(color.xyz * alpha, alpha);}
 
     gl_FragDepth = fragment_position.z;
 }
 "#,
     frag
 );
+
+pub const BLUR_VERTEX_SHADER_CODE: &[u32] = inline_spirv!(
+    r#"
+#version 450
+
+layout(location = 0) out vec2 uv;
+
+// Single triangle covering the whole target.
+void main() {
+    uv = vec2((gl_VertexIndex << 1) & 2, gl_VertexIndex & 2);
+    gl_Position = vec4(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0, 0.0, 1.0);
+}
+"#,
+    vert
+);
+
+pub const BLUR_FRAGMENT_SHADER_CODE: &[u32] = inline_spirv!(
+    r#"
+#version 450
+
+layout(location = 0) in vec2 uv;
+layout(location = 0) out vec4 fragment_color;
+
+layout(set = 0, binding = 0) uniform sampler samp;
+layout(set = 0, binding = 1) uniform texture2D source;
+
+layout(push_constant) uniform BlurPushConstants {
+    vec2 texel_size;
+    uint mode;
+};
+
+const uint BLUR_MODE_DOWNSAMPLE = 1u;
+const uint BLUR_MODE_UPSAMPLE = 2u;
+
+vec4 sample_offset(vec2 offset) {
+    return texture(sampler2D(source, samp), uv + offset * texel_size);
+}
+
+// Dual filter blur: every downsample and upsample pass widens the blur, at the cost of a few samples.
+void main() {
+    if(mode == BLUR_MODE_DOWNSAMPLE) {
+        fragment_color = (
+            sample_offset(vec2(0.0, 0.0)) * 4.0 +
+            sample_offset(vec2(-1.0, -1.0)) +
+            sample_offset(vec2(1.0, -1.0)) +
+            sample_offset(vec2(-1.0, 1.0)) +
+            sample_offset(vec2(1.0, 1.0))
+        ) / 8.0;
+    }
+    else if(mode == BLUR_MODE_UPSAMPLE) {
+        fragment_color = (
+            sample_offset(vec2(-1.0, 0.0)) +
+            sample_offset(vec2(1.0, 0.0)) +
+            sample_offset(vec2(0.0, -1.0)) +
+            sample_offset(vec2(0.0, 1.0)) +
+            sample_offset(vec2(-0.5, -0.5)) * 2.0 +
+            sample_offset(vec2(0.5, -0.5)) * 2.0 +
+            sample_offset(vec2(-0.5, 0.5)) * 2.0 +
+            sample_offset(vec2(0.5, 0.5)) * 2.0
+        ) / 12.0;
+    }
+    else {
+        fragment_color = sample_offset(vec2(0.0, 0.0));
+    }
+}
+"#,
+    frag
+);
+
+pub const BACKDROP_FRAGMENT_SHADER_CODE: &[u32] = inline_spirv!(
+    r#"
+#version 450
+
+layout(location = 5) in vec2 physical_position;
+layout(location = 6) flat in vec4 clip;
+layout(location = 7) in vec2 local_position;
+layout(location = 8) flat in vec2 size;
+layout(location = 9) flat in vec2 decoration;
+layout(location = 11) flat in float output_scale;
+layout(location = 0) out vec4 fragment_color;
+
+layout(set = 0, binding = 0) uniform sampler samp;
+layout(set = 0, binding = 1) uniform texture2D blurred;
+
+// Blurred content behind the surface, with the same clipping and rounded corners of the surface.
+void main() {
+    if(any(lessThan(physical_position, clip.xy)) || any(greaterThanEqual(physical_position, clip.zw))) {discard;}
+
+    // Keep in sync with the corners of decorate in the fragment shader.
+    float coverage = 1.0;
+    float radius = min(decoration.x, 0.5 * min(size.x, size.y));
+    vec2 corner_offset = abs(local_position - size * 0.5) - size * 0.5 + radius;
+    if(radius > 0.0 && corner_offset.x > 0.0 && corner_offset.y > 0.0) {
+        float distance = length(corner_offset) - radius;
+        coverage = clamp(0.5 - distance * output_scale, 0.0, 1.0);
+    }
+    if(coverage == 0.0) {discard;}
+
+    vec2 uv = gl_FragCoord.xy / vec2(textureSize(sampler2D(blurred, samp), 0));
+    fragment_color = texture(sampler2D(blurred, samp), uv) * coverage;
+}
+"#,
+    frag
+);
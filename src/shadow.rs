@@ -0,0 +1,89 @@
+use crate::error::ScreenTaskError;
+use crate::surface::Surface;
+use crate::transform::Transform;
+
+/// Sampling code of the shadow instances, evaluated analytically without textures.
+pub const SAMPLING_SHADOW: u32 = 3;
+
+/// Drop shadow drawn beneath a surface.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SurfaceShadow {
+    /// Offset of the shadow from the surface, in logical coordinates.
+    pub offset: [f32; 2],
+    /// Blur radius in logical coordinates; the standard deviation of the Gaussian blur is half of it.
+    pub blur_radius: f32,
+    /// Color of the shadow in linear RGBA.
+    pub color: [f32; 4],
+}
+impl SurfaceShadow {
+    /// Check that the offset and the blur radius are finite, and the blur radius is not negative.
+    pub fn validate(&self) -> Result<(), ScreenTaskError> {
+        let finite = self.offset.iter().all(|value| value.is_finite());
+        if finite && self.blur_radius.is_finite() && self.blur_radius >= 0.0 {
+            Ok(())
+        } else {
+            Err(ScreenTaskError::InvalidShadow {
+                offset: self.offset,
+                blur_radius: self.blur_radius,
+            })
+        }
+    }
+
+    /// Distance, in logical coordinates, from the shadowed rectangle to the edges of the shadow quad.
+    pub fn margin(&self) -> f32 {
+        1.5 * self.blur_radius
+    }
+
+    /**
+    Returns the instance drawing the shadow of the provided surface instance.
+    The quad covers the offset surface expanded by the margin; the shadow color is stored in `border_color`
+    and the blur radius in the first component of `decoration`.
+    */
+    pub fn generate_data(&self, surface: &Surface) -> Surface {
+        let margin = self.margin();
+        Surface {
+            position: [
+                surface.position[0] + self.offset[0] - margin,
+                surface.position[1] + self.offset[1] - margin,
+                surface.position[2],
+            ],
+            size: [
+                surface.size[0] + 2.0 * margin,
+                surface.size[1] + 2.0 * margin,
+            ],
+            image_index: 0,
+            opacity: surface.opacity,
+            source_rect: [0.0, 0.0, 1.0, 1.0],
+            transform: surface.transform,
+            uv_transform: Transform::Normal.uv_matrix(),
+            sampling: SAMPLING_SHADOW,
+            color_space: 0,
+            clip_rect: surface.clip_rect,
+            decoration: [self.blur_radius, 0.0],
+            border_color: self.color,
+        }
+    }
+}
+
+/**
+Returns the coverage of the Gaussian blurred rectangle at a point of a shadow quad of the provided size,
+in local logical coordinates. Keep in sync with shadow_coverage in the fragment shader.
+*/
+pub fn shadow_coverage(local: [f32; 2], size: [f32; 2], blur_radius: f32) -> f32 {
+    let sigma = (blur_radius * 0.5).max(1e-3);
+    let margin = 1.5 * blur_radius;
+    let axis = |position: f32, size: f32| {
+        let scale = 1.0 / (std::f32::consts::SQRT_2 * sigma);
+        0.5 * (erf((position - margin) * scale) - erf((position - size + margin) * scale))
+    };
+    axis(local[0], size[0]) * axis(local[1], size[1])
+}
+
+/// Approximation of the error function (Abramowitz and Stegun 7.1.26), shared with the fragment shader.
+fn erf(x: f32) -> f32 {
+    let t = 1.0 / (1.0 + 0.327_591_1 * x.abs());
+    let polynomial = ((((1.061_405_4 * t - 1.453_152) * t + 1.421_413_8) * t - 0.284_496_74) * t
+        + 0.254_829_6)
+        * t;
+    (1.0 - polynomial * (-x * x).exp()).copysign(x)
+}
@@ -0,0 +1,513 @@
+use crate::background::SAMPLING_REPEAT;
+use crate::error::ScreenTaskError;
+use crate::mapping::MemoryMapping;
+use crate::screen_task::PushConstants;
+use crate::shadow::{shadow_coverage, SAMPLING_SHADOW};
+use crate::surface::{
+    check_plane, Surface, SurfaceSource, SAMPLING_FLAGS, SAMPLING_OPAQUE, SAMPLING_SOLID_COLOR,
+};
+use crate::transform::apply_affine;
+use crate::yuv::{yuv_to_rgb, YuvColorSpace, SAMPLING_YUV_PLANAR, SAMPLING_YUV_SEMI_PLANAR};
+use image::RgbaImage;
+use ultraviolet::Vec4;
+use wgpu_engine::*;
+
+#[derive(Debug, Clone)]
+/// Texture stored in host memory, sampled by the SoftwareCompositor.
+pub struct SoftwareTexture {
+    size: [u32; 2],
+    format: wgpu::TextureFormat,
+    stride: u32,
+    data: Vec<u8>,
+}
+impl SoftwareTexture {
+    pub fn new(
+        size: [u32; 2],
+        format: wgpu::TextureFormat,
+        stride: u32,
+        data: Vec<u8>,
+    ) -> Result<Self, ScreenTaskError> {
+        let bytes_per_pixel = bytes_per_pixel(format).ok_or_else(|| {
+            ScreenTaskError::UnsupportedFormat(format!("{:?} in the software compositor", format))
+        })?;
+        check_plane(size, stride, bytes_per_pixel, data.len())?;
+        Ok(Self {
+            size,
+            format,
+            stride,
+            data,
+        })
+    }
+
+    /**
+    Generate the textures of every plane of a source, in the same order used for the texture array.
+    Only host allocations and shared memory pools are supported, while solid colors have no planes.
+    */
+    pub fn from_source(source: &SurfaceSource) -> Result<Vec<Self>, ScreenTaskError> {
+        source.validate()?;
+        match source {
+            SurfaceSource::HostAllocation { info, data } => Ok(vec![Self::new(
+                info.size,
+                info.format,
+                info.stride,
+                data.clone(),
+            )?]),
+            SurfaceSource::YuvHostAllocation { info, planes } => planes
+                .iter()
+                .zip(info.strides.iter())
+                .enumerate()
+                .map(|(plane, (data, stride))| {
+                    let (data, stride) =
+                        info.format
+                            .convert_plane(plane, info.size, *stride, data.clone());
+                    Self::new(
+                        info.format.plane_size(plane, info.size),
+                        info.format.plane_texture_format(plane),
+                        stride,
+                        data,
+                    )
+                })
+                .collect(),
+            SurfaceSource::SolidColor(_) => Ok(Vec::new()),
+            SurfaceSource::ShmPool {
+                fd,
+                offset,
+                size,
+                stride,
+                format,
+            } => {
+                let length = *stride as usize * size[1] as usize;
+                let data = MemoryMapping::new(*fd, *offset, length)?.read_range(0, length)?;
+                Ok(vec![Self::new(*size, *format, *stride, data)?])
+            }
+            SurfaceSource::Dmabuf { .. } | SurfaceSource::YuvDmabuf { .. } => {
+                Err(ScreenTaskError::UnsupportedFormat(String::from(
+                    "Dma buffers in the software compositor",
+                )))
+            }
+        }
+    }
+
+    /**
+    Rebuild the content of a texture from the writes performed on it, oldest first.
+    Texels that were never written are transparent black.
+    */
+    pub fn from_writes(
+        descriptor: &TextureDescriptor,
+        writes: &[TextureWrite],
+    ) -> Result<Self, ScreenTaskError> {
+        let format = descriptor.format;
+        let bytes_per_pixel = bytes_per_pixel(format).ok_or_else(|| {
+            ScreenTaskError::UnsupportedFormat(format!("{:?} in the software compositor", format))
+        })? as usize;
+        let size = [descriptor.size.width, descriptor.size.height];
+        let stride = size[0] as usize * bytes_per_pixel;
+        let mut data = vec![0; stride * size[1] as usize];
+        for write in writes {
+            let region = [write.size.width, write.size.height];
+            if write.origin.x + region[0] > size[0] || write.origin.y + region[1] > size[1] {
+                return Err(ScreenTaskError::InvalidSize(region));
+            }
+            let row_length = region[0] as usize * bytes_per_pixel;
+            let bytes_per_row = write
+                .layout
+                .bytes_per_row
+                .map_or(row_length, |bytes_per_row| bytes_per_row.get() as usize);
+            let offset = write.layout.offset as usize;
+            let expected =
+                offset + bytes_per_row * region[1].saturating_sub(1) as usize + row_length;
+            if write.data.len() < expected {
+                return Err(ScreenTaskError::InvalidDataLength {
+                    length: write.data.len(),
+                    expected,
+                });
+            }
+            for row in 0..region[1] as usize {
+                let source = offset + row * bytes_per_row;
+                let destination = (write.origin.y as usize + row) * stride
+                    + write.origin.x as usize * bytes_per_pixel;
+                data[destination..destination + row_length]
+                    .copy_from_slice(&write.data[source..source + row_length]);
+            }
+        }
+        Self::new(size, format, stride as u32, data)
+    }
+
+    /// Returns the linear color of the texel, filling the missing channels like the gpu does.
+    fn fetch(&self, x: u32, y: u32) -> [f32; 4] {
+        let bytes_per_pixel = bytes_per_pixel(self.format).unwrap_or(4) as usize;
+        let offset = y as usize * self.stride as usize + x as usize * bytes_per_pixel;
+        let texel = &self.data[offset..offset + bytes_per_pixel];
+        let value = |index: usize| texel[index] as f32 / 255.0;
+        let half =
+            |index: usize| half_float_value(u16::from_le_bytes([texel[index], texel[index + 1]]));
+        match self.format {
+            wgpu::TextureFormat::R16Float => [half(0), 0.0, 0.0, 1.0],
+            wgpu::TextureFormat::Rg16Float => [half(0), half(2), 0.0, 1.0],
+            wgpu::TextureFormat::R8Unorm => [value(0), 0.0, 0.0, 1.0],
+            wgpu::TextureFormat::Rg8Unorm => [value(0), value(1), 0.0, 1.0],
+            wgpu::TextureFormat::Rgba8Unorm => [value(0), value(1), value(2), value(3)],
+            wgpu::TextureFormat::Bgra8Unorm => [value(2), value(1), value(0), value(3)],
+            wgpu::TextureFormat::Bgra8UnormSrgb => [
+                srgb_to_linear(value(2)),
+                srgb_to_linear(value(1)),
+                srgb_to_linear(value(0)),
+                value(3),
+            ],
+            _ => [
+                srgb_to_linear(value(0)),
+                srgb_to_linear(value(1)),
+                srgb_to_linear(value(2)),
+                value(3),
+            ],
+        }
+    }
+
+    /// Sample the texture with bilinear filtering, clamping the coordinates to the edges.
+    fn sample(&self, uv: [f32; 2]) -> [f32; 4] {
+        let x = uv[0] * self.size[0] as f32 - 0.5;
+        let y = uv[1] * self.size[1] as f32 - 0.5;
+        let x0 = x.floor();
+        let y0 = y.floor();
+        let fx = x - x0;
+        let fy = y - y0;
+        let clamp_x = |x: f32| x.max(0.0).min((self.size[0] - 1) as f32) as u32;
+        let clamp_y = |y: f32| y.max(0.0).min((self.size[1] - 1) as f32) as u32;
+
+        let top_left = self.fetch(clamp_x(x0), clamp_y(y0));
+        let top_right = self.fetch(clamp_x(x0 + 1.0), clamp_y(y0));
+        let bottom_left = self.fetch(clamp_x(x0), clamp_y(y0 + 1.0));
+        let bottom_right = self.fetch(clamp_x(x0 + 1.0), clamp_y(y0 + 1.0));
+
+        let mut color = [0.0; 4];
+        for channel in 0..4 {
+            let top = top_left[channel] + (top_right[channel] - top_left[channel]) * fx;
+            let bottom = bottom_left[channel] + (bottom_right[channel] - bottom_left[channel]) * fx;
+            color[channel] = top + (bottom - top) * fy;
+        }
+        color
+    }
+}
+
+#[derive(Debug, Default)]
+/**
+Reference compositor that rasterizes the surface instances on the cpu.
+
+It follows the same rules of the render pipeline: the instances are projected with the PushConstants,
+sampled from the texture array with a linear clamp-to-edge sampler, depth tested with LessEqual
+and blended with premultiplied alpha in the provided order.
+The output is treated as an sRGB render target, so blending happens in linear space.
+*/
+pub struct SoftwareCompositor {
+    textures: Vec<SoftwareTexture>,
+}
+impl SoftwareCompositor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /**
+    Append the planes of the source to the texture array.
+    Returns the image index of the first plane, to be used by the surface instance.
+    */
+    pub fn push_source(&mut self, source: &SurfaceSource) -> Result<u32, ScreenTaskError> {
+        let image_index = self.textures.len() as u32;
+        self.textures.extend(SoftwareTexture::from_source(source)?);
+        Ok(image_index)
+    }
+
+    /**
+    Append a texture to the texture array.
+    Returns its image index.
+    */
+    pub fn push_texture(&mut self, texture: SoftwareTexture) -> u32 {
+        self.textures.push(texture);
+        self.textures.len() as u32 - 1
+    }
+
+    /// Returns the textures in the texture array.
+    pub fn textures(&self) -> &Vec<SoftwareTexture> {
+        &self.textures
+    }
+
+    /**
+    Rasterize the surface instances, provided in drawing order, into an output of the provided size.
+    The output starts from transparent black and it stores the premultiplied colors, encoded as sRGB.
+    */
+    pub fn render(
+        &self,
+        surfaces: &[Surface],
+        push_constants: &PushConstants,
+        size: [u32; 2],
+    ) -> RgbaImage {
+        let pixel_count = size[0] as usize * size[1] as usize;
+        let mut color_buffer = vec![[0.0f32; 4]; pixel_count];
+        let mut depth_buffer = vec![1.0f32; pixel_count];
+
+        for surface in surfaces {
+            self.draw_surface(
+                surface,
+                push_constants,
+                size,
+                &mut color_buffer,
+                &mut depth_buffer,
+            );
+        }
+
+        let mut image = RgbaImage::new(size[0], size[1]);
+        for (pixel, color) in image.pixels_mut().zip(color_buffer) {
+            let encode = |value: f32| (value.max(0.0).min(1.0) * 255.0).round() as u8;
+            pixel.0 = [
+                encode(linear_to_srgb(color[0])),
+                encode(linear_to_srgb(color[1])),
+                encode(linear_to_srgb(color[2])),
+                encode(color[3]),
+            ];
+        }
+        image
+    }
+
+    /// Rasterize a single surface instance, mirroring the vertex and fragment shaders.
+    fn draw_surface(
+        &self,
+        surface: &Surface,
+        push_constants: &PushConstants,
+        size: [u32; 2],
+        color_buffer: &mut [[f32; 4]],
+        depth_buffer: &mut [f32],
+    ) {
+        // Vertex stage: corners of the quad in physical and framebuffer coordinates.
+        let physical_corner = |corner: [f32; 2]| {
+            let centered = [
+                (corner[0] - 0.5) * surface.size[0],
+                (corner[1] - 0.5) * surface.size[1],
+            ];
+            let transformed = apply_affine(&surface.transform, centered);
+            push_constants.physical_position([
+                surface.position[0] + surface.size[0] * 0.5 + transformed[0],
+                surface.position[1] + surface.size[1] * 0.5 + transformed[1],
+            ])
+        };
+        let project = |corner: [f32; 2]| {
+            let physical = physical_corner(corner);
+            let vertex = Vec4::new(physical[0], physical[1], surface.position[2], 1.0);
+            let projected = push_constants.projection_matrix * vertex;
+            [
+                (projected.x + 1.0) * 0.5 * size[0] as f32,
+                (1.0 - projected.y) * 0.5 * size[1] as f32,
+                projected.z,
+            ]
+        };
+        let origin = project([0.0, 0.0]);
+        let right = project([1.0, 0.0]);
+        let down = project([0.0, 1.0]);
+        let opposite = project([1.0, 1.0]);
+
+        let depth = origin[2];
+        if !(0.0..=1.0).contains(&depth) {
+            return;
+        }
+
+        let axis_x = [right[0] - origin[0], right[1] - origin[1]];
+        let axis_y = [down[0] - origin[0], down[1] - origin[1]];
+        let determinant = axis_x[0] * axis_y[1] - axis_x[1] * axis_y[0];
+        if determinant.abs() < f32::EPSILON {
+            return;
+        }
+
+        let physical_origin = physical_corner([0.0, 0.0]);
+        let physical_right = physical_corner([1.0, 0.0]);
+        let physical_down = physical_corner([0.0, 1.0]);
+        let clip_min =
+            push_constants.physical_position([surface.clip_rect[0], surface.clip_rect[1]]);
+        let clip_max =
+            push_constants.physical_position([surface.clip_rect[2], surface.clip_rect[3]]);
+
+        let corners = [origin, right, down, opposite];
+        let bound = |axis: usize, limit: u32, pick: fn(f32, f32) -> f32| {
+            let value = corners
+                .iter()
+                .map(|corner| corner[axis])
+                .fold(corners[0][axis], pick);
+            value.max(0.0).min(limit as f32) as u32
+        };
+        let min_x = bound(0, size[0], f32::min);
+        let max_x = bound(0, size[0], f32::max);
+        let min_y = bound(1, size[1], f32::min);
+        let max_y = bound(1, size[1], f32::max);
+
+        for y in min_y..(max_y + 1).min(size[1]) {
+            for x in min_x..(max_x + 1).min(size[0]) {
+                // Coverage is evaluated at the pixel center, inverting the quad parametrization.
+                let offset = [x as f32 + 0.5 - origin[0], y as f32 + 0.5 - origin[1]];
+                let corner = [
+                    (offset[0] * axis_y[1] - offset[1] * axis_y[0]) / determinant,
+                    (axis_x[0] * offset[1] - axis_x[1] * offset[0]) / determinant,
+                ];
+                if corner[0] < 0.0 || corner[0] >= 1.0 || corner[1] < 0.0 || corner[1] >= 1.0 {
+                    continue;
+                }
+
+                // Clipping, on the physical position interpolated like the vertex outputs.
+                let physical = [
+                    physical_origin[0]
+                        + corner[0] * (physical_right[0] - physical_origin[0])
+                        + corner[1] * (physical_down[0] - physical_origin[0]),
+                    physical_origin[1]
+                        + corner[0] * (physical_right[1] - physical_origin[1])
+                        + corner[1] * (physical_down[1] - physical_origin[1]),
+                ];
+                if physical[0] < clip_min[0]
+                    || physical[1] < clip_min[1]
+                    || physical[0] >= clip_max[0]
+                    || physical[1] >= clip_max[1]
+                {
+                    continue;
+                }
+
+                let index = y as usize * size[0] as usize + x as usize;
+                if depth > depth_buffer[index] {
+                    continue;
+                }
+
+                // Fragment stage.
+                let uv = apply_affine(&surface.uv_transform, corner);
+                let uv = [
+                    surface.source_rect[0] + uv[0] * surface.source_rect[2],
+                    surface.source_rect[1] + uv[1] * surface.source_rect[3],
+                ];
+                let local = [corner[0] * surface.size[0], corner[1] * surface.size[1]];
+                let color = if surface.sampling & !SAMPLING_FLAGS == SAMPLING_SHADOW {
+                    let coverage = shadow_coverage(local, surface.size, surface.decoration[0]);
+                    let [red, green, blue, alpha] = surface.border_color;
+                    [red, green, blue, alpha * coverage]
+                } else {
+                    decorate(
+                        surface,
+                        local,
+                        push_constants.output_scale,
+                        self.sample_surface(surface, uv),
+                    )
+                };
+                let alpha = color[3] * surface.opacity;
+                if alpha == 0.0 {
+                    continue;
+                }
+
+                depth_buffer[index] = depth;
+                let destination = color_buffer[index];
+                color_buffer[index] = [
+                    color[0] * alpha + destination[0] * (1.0 - alpha),
+                    color[1] * alpha + destination[1] * (1.0 - alpha),
+                    color[2] * alpha + destination[2] * (1.0 - alpha),
+                    alpha + destination[3] * (1.0 - alpha),
+                ];
+            }
+        }
+    }
+
+    /// Sample the planes of a surface, converting YUV planes into RGB like the fragment shader.
+    fn sample_surface(&self, surface: &Surface, uv: [f32; 2]) -> [f32; 4] {
+        let uv = if surface.sampling & SAMPLING_REPEAT != 0 {
+            [uv[0] - uv[0].floor(), uv[1] - uv[1].floor()]
+        } else {
+            uv
+        };
+        let plane = |offset: u32| {
+            self.textures
+                .get((surface.image_index + offset) as usize)
+                .map(|texture| texture.sample(uv))
+                .unwrap_or([0.0; 4])
+        };
+        let color_space = YuvColorSpace::from_shader_code(surface.color_space);
+        let color = match surface.sampling & !SAMPLING_FLAGS {
+            SAMPLING_YUV_SEMI_PLANAR => {
+                let chroma = plane(1);
+                let rgb = yuv_to_rgb([plane(0)[0], chroma[0], chroma[1]], color_space);
+                [rgb[0], rgb[1], rgb[2], 1.0]
+            }
+            SAMPLING_YUV_PLANAR => {
+                let rgb = yuv_to_rgb([plane(0)[0], plane(1)[0], plane(2)[0]], color_space);
+                [rgb[0], rgb[1], rgb[2], 1.0]
+            }
+            SAMPLING_SOLID_COLOR => surface.source_rect,
+            _ => plane(0),
+        };
+        if surface.sampling & SAMPLING_OPAQUE != 0 {
+            [color[0], color[1], color[2], 1.0]
+        } else {
+            color
+        }
+    }
+}
+
+/**
+Apply the rounded corners and the border of the surface to a color, like the fragment shader.
+Keep in sync with decorate in the fragment shader.
+*/
+fn decorate(surface: &Surface, local: [f32; 2], scale: f32, color: [f32; 4]) -> [f32; 4] {
+    let [width, height] = surface.size;
+    let [radius, border_width] = surface.decoration;
+    let radius = radius.min(0.5 * width.min(height));
+    let offset = [
+        (local[0] - width * 0.5).abs() - width * 0.5 + radius,
+        (local[1] - height * 0.5).abs() - height * 0.5 + radius,
+    ];
+    let distance = (offset[0].max(0.0).powi(2) + offset[1].max(0.0).powi(2)).sqrt()
+        + offset[0].max(offset[1]).min(0.0)
+        - radius;
+    let clamp = |value: f32| value.max(0.0).min(1.0);
+
+    let mut color = color;
+    if border_width > 0.0 {
+        let border = clamp(0.5 + (distance + border_width) * scale);
+        for (channel, border_channel) in color.iter_mut().zip(surface.border_color.iter()) {
+            *channel += (border_channel - *channel) * border;
+        }
+    }
+    if radius > 0.0 && offset[0] > 0.0 && offset[1] > 0.0 {
+        color[3] *= clamp(0.5 - distance * scale);
+    }
+    color
+}
+
+/// Returns the size in bytes of a texel of the formats supported by the software compositor.
+fn bytes_per_pixel(format: wgpu::TextureFormat) -> Option<u32> {
+    match format {
+        wgpu::TextureFormat::R8Unorm => Some(1),
+        wgpu::TextureFormat::Rg8Unorm | wgpu::TextureFormat::R16Float => Some(2),
+        wgpu::TextureFormat::Rg16Float => Some(4),
+        wgpu::TextureFormat::Rgba8Unorm
+        | wgpu::TextureFormat::Rgba8UnormSrgb
+        | wgpu::TextureFormat::Bgra8Unorm
+        | wgpu::TextureFormat::Bgra8UnormSrgb => Some(4),
+        _ => None,
+    }
+}
+
+/// Decode a half precision float.
+pub(crate) fn half_float_value(bits: u16) -> f32 {
+    let exponent = ((bits >> 10) & 0x1f) as i32;
+    let mantissa = (bits & 0x3ff) as f32;
+    let sign = if bits & 0x8000 != 0 { -1.0 } else { 1.0 };
+    match exponent {
+        0 => sign * mantissa * 2f32.powi(-24),
+        _ => sign * (1.0 + mantissa / 1024.0) * 2f32.powi(exponent - 15),
+    }
+}
+
+fn srgb_to_linear(value: f32) -> f32 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(value: f32) -> f32 {
+    if value <= 0.0031308 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
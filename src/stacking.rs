@@ -0,0 +1,261 @@
+use crate::error::ScreenTaskError;
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Default)]
+/**
+Canonical stacking order of the surfaces, from the farthest to the nearest.
+
+The top level surfaces are always sorted by z, where an higher z is farther; surfaces with the same z keep the order
+in which they reached that z, so new surfaces are placed on top of the ones with their same z.
+Restacking operations adopt the z of the surfaces they are placed next to, keeping the order sorted.
+
+Subsurfaces have no z: they are stacked relative to their parent and their siblings,
+and they are drawn right next to their parent.
+*/
+pub struct StackingOrder {
+    entries: Vec<(usize, i32)>,
+    /// Parent of every subsurface.
+    parents: HashMap<usize, usize>,
+    /**
+    Subsurfaces of every surface with children, from the farthest to the nearest.
+    The list contains also the surface itself, to separate the children below it from the ones above it.
+    */
+    children: HashMap<usize, Vec<usize>>,
+}
+impl StackingOrder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the surface ids from the farthest to the nearest.
+    pub fn order(&self) -> Vec<usize> {
+        let mut order = Vec::new();
+        for (id, _) in &self.entries {
+            self.push_subtree(*id, &mut order);
+        }
+        order
+    }
+
+    /// Append the surface and its subsurfaces, from the farthest to the nearest.
+    fn push_subtree(&self, id: usize, order: &mut Vec<usize>) {
+        match self.children.get(&id) {
+            Some(children) => {
+                for child in children {
+                    if *child == id {
+                        order.push(id);
+                    } else {
+                        self.push_subtree(*child, order);
+                    }
+                }
+            }
+            None => order.push(id),
+        }
+    }
+
+    /// Returns how many surfaces are stacked, subsurfaces included.
+    pub fn len(&self) -> usize {
+        self.entries.len() + self.parents.len()
+    }
+
+    /// Returns true if no surface is stacked.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty() && self.parents.is_empty()
+    }
+
+    /// Returns true if the surface with the provided id is stacked.
+    pub fn contains(&self, id: usize) -> bool {
+        self.parents.contains_key(&id) || self.entries.iter().any(|(current, _)| *current == id)
+    }
+
+    /// Returns the parent of the subsurface with the provided id, None for top level surfaces.
+    pub fn parent(&self, id: usize) -> Option<usize> {
+        self.parents.get(&id).copied()
+    }
+
+    /// Returns the z of the surface with the provided id; subsurfaces share the z of their top level surface.
+    pub fn z(&self, id: usize) -> Result<i32, ScreenTaskError> {
+        let mut root = id;
+        while let Some(parent) = self.parent(root) {
+            root = parent;
+        }
+        self.index(root).map(|index| self.entries[index].1)
+    }
+
+    /// Returns the index of a top level surface.
+    fn index(&self, id: usize) -> Result<usize, ScreenTaskError> {
+        self.entries
+            .iter()
+            .position(|(current_id, _)| *current_id == id)
+            .ok_or(ScreenTaskError::UnknownSurface(id))
+    }
+
+    /// Returns the siblings of a subsurface, its parent included, and its index among them.
+    fn siblings_mut(&mut self, id: usize) -> Option<(&mut Vec<usize>, usize)> {
+        let parent = self.parent(id)?;
+        let siblings = self.children.get_mut(&parent)?;
+        let index = siblings.iter().position(|sibling| *sibling == id)?;
+        Some((siblings, index))
+    }
+
+    /// Stack a new top level surface on top of the ones with the same z.
+    pub fn insert(&mut self, id: usize, z: i32) -> Result<(), ScreenTaskError> {
+        if self.contains(id) {
+            return Err(ScreenTaskError::DuplicateSurface(id));
+        }
+        let index = self
+            .entries
+            .iter()
+            .position(|(_, current_z)| *current_z < z)
+            .unwrap_or(self.entries.len());
+        self.entries.insert(index, (id, z));
+        Ok(())
+    }
+
+    /// Stack a new subsurface on top of its parent and its siblings.
+    pub fn insert_child(&mut self, id: usize, parent: usize) -> Result<(), ScreenTaskError> {
+        if self.contains(id) {
+            return Err(ScreenTaskError::DuplicateSurface(id));
+        }
+        if !self.contains(parent) {
+            return Err(ScreenTaskError::UnknownSurface(parent));
+        }
+        self.children
+            .entry(parent)
+            .or_insert_with(|| vec![parent])
+            .push(id);
+        self.parents.insert(id, parent);
+        Ok(())
+    }
+
+    /**
+    Change the z of a top level surface, placing it on top of the ones with the new z.
+    Nothing changes if z is the same or if the surface is a subsurface.
+    */
+    pub fn set_z(&mut self, id: usize, z: i32) -> Result<(), ScreenTaskError> {
+        if self.parent(id).is_some() {
+            return Ok(());
+        }
+        let index = self.index(id)?;
+        if self.entries[index].1 != z {
+            self.entries.remove(index);
+            self.insert(id, z)?;
+        }
+        Ok(())
+    }
+
+    /// Remove the surface and all its subsurfaces. Returns the removed ids, the provided one first.
+    pub fn remove(&mut self, id: usize) -> Result<Vec<usize>, ScreenTaskError> {
+        match self.siblings_mut(id) {
+            Some((siblings, index)) => {
+                siblings.remove(index);
+            }
+            None => {
+                let index = self.index(id)?;
+                self.entries.remove(index);
+            }
+        }
+
+        let mut removed = vec![id];
+        let mut next = 0;
+        while next < removed.len() {
+            let current = removed[next];
+            self.parents.remove(&current);
+            if let Some(children) = self.children.remove(&current) {
+                removed.extend(children.into_iter().filter(|child| *child != current));
+            }
+            next += 1;
+        }
+        Ok(removed)
+    }
+
+    /// Place the surface on top of every other surface, or of its siblings and parent for subsurfaces.
+    pub fn raise(&mut self, id: usize) -> Result<(), ScreenTaskError> {
+        if let Some((siblings, index)) = self.siblings_mut(id) {
+            let id = siblings.remove(index);
+            siblings.push(id);
+            return Ok(());
+        }
+        let index = self.index(id)?;
+        let (_, z) = self.entries.remove(index);
+        let z = self.entries.last().map_or(z, |(_, nearest_z)| *nearest_z);
+        self.entries.push((id, z));
+        Ok(())
+    }
+
+    /// Place the surface below every other surface, or below its siblings and parent for subsurfaces.
+    pub fn lower(&mut self, id: usize) -> Result<(), ScreenTaskError> {
+        if let Some((siblings, index)) = self.siblings_mut(id) {
+            let id = siblings.remove(index);
+            siblings.insert(0, id);
+            return Ok(());
+        }
+        let index = self.index(id)?;
+        let (_, z) = self.entries.remove(index);
+        let z = self
+            .entries
+            .first()
+            .map_or(z, |(_, farthest_z)| *farthest_z);
+        self.entries.insert(0, (id, z));
+        Ok(())
+    }
+
+    /**
+    Place the surface directly above the sibling.
+    Top level surfaces can be placed next to other top level surfaces,
+    while subsurfaces can be placed next to their siblings or their parent.
+    */
+    pub fn place_above(&mut self, id: usize, sibling: usize) -> Result<(), ScreenTaskError> {
+        self.place_next_to(id, sibling, 1)
+    }
+
+    /// Place the surface directly below the sibling, with the same rules of `place_above`.
+    pub fn place_below(&mut self, id: usize, sibling: usize) -> Result<(), ScreenTaskError> {
+        self.place_next_to(id, sibling, 0)
+    }
+
+    /// Place the surface at the provided offset from the position of the sibling.
+    fn place_next_to(
+        &mut self,
+        id: usize,
+        sibling: usize,
+        offset: usize,
+    ) -> Result<(), ScreenTaskError> {
+        for surface in [id, sibling].iter() {
+            if !self.contains(*surface) {
+                return Err(ScreenTaskError::UnknownSurface(*surface));
+            }
+        }
+        if id == sibling {
+            return Ok(());
+        }
+
+        match self.parent(id) {
+            Some(parent) => {
+                if self.parent(sibling) != Some(parent) && sibling != parent {
+                    return Err(ScreenTaskError::InvalidSibling { id, sibling });
+                }
+                let siblings = self
+                    .children
+                    .get_mut(&parent)
+                    .ok_or(ScreenTaskError::UnknownSurface(parent))?;
+                siblings.retain(|current| *current != id);
+                let sibling_index = siblings
+                    .iter()
+                    .position(|current| *current == sibling)
+                    .ok_or(ScreenTaskError::UnknownSurface(sibling))?;
+                siblings.insert(sibling_index + offset, id);
+            }
+            None => {
+                if self.parent(sibling).is_some() {
+                    return Err(ScreenTaskError::InvalidSibling { id, sibling });
+                }
+                let index = self.index(id)?;
+                let z = self.z(sibling)?;
+                self.entries.remove(index);
+                let sibling_index = self.index(sibling)?;
+                self.entries.insert(sibling_index + offset, (id, z));
+            }
+        }
+        Ok(())
+    }
+}
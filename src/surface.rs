@@ -1,13 +1,108 @@
+use crate::background::SAMPLING_REPEAT;
+use crate::error::ScreenTaskError;
+use crate::fourcc::{DrmFourcc, DRM_FORMAT_MOD_LINEAR, MAX_DMABUF_PLANES};
+use crate::mapping::MemoryMapping;
+use crate::shadow::SurfaceShadow;
+use crate::transform::SurfaceTransform;
+use crate::yuv::{YuvColorSpace, YuvFormat, SAMPLING_RGBA};
 use bytemuck::{Pod, Zeroable};
+use std::convert::TryFrom;
 use std::path::PathBuf;
+use std::sync::Arc;
 
 #[repr(C)]
 #[derive(Debug, Clone, Copy, Pod, Zeroable)]
-/// Plain surface data representation used to send data to the gpu.
+/**
+Plain surface data representation used to send data to the gpu.
+Shadow instances reuse the decoration fields, see `SurfaceShadow::generate_data`,
+while solid color instances store their color in `source_rect`.
+*/
 pub struct Surface {
+    /// Position in logical coordinates, followed by the depth in the [0, 1] range.
     pub position: [f32; 3],
     pub size: [f32; 2],
     pub image_index: u32,
+    pub opacity: f32,
+    /// Sampled region of the texture, as normalized [x, y, width, height].
+    pub source_rect: [f32; 4],
+    /// Affine matrix applied to the geometry around the surface center.
+    pub transform: [[f32; 3]; 2],
+    /// Affine matrix applied to the normalized texture coordinates before cropping.
+    pub uv_transform: [[f32; 3]; 2],
+    /// How the planes of the surface are sampled and combined.
+    pub sampling: u32,
+    /// Color space used to convert YUV surfaces into RGB.
+    pub color_space: u32,
+    /// Visible region in logical coordinates, as [min_x, min_y, max_x, max_y]; pixels outside of it are discarded.
+    pub clip_rect: [f32; 4],
+    /// Corner radius and border width in logical coordinates.
+    pub decoration: [f32; 2],
+    /// Color of the border in linear RGBA.
+    pub border_color: [f32; 4],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+/**
+Decoration drawn by the compositor: rounded corners and a solid border, inside the geometry of the surface.
+Sizes are in logical coordinates, the border color in linear RGBA.
+*/
+pub struct SurfaceDecoration {
+    pub radius: f32,
+    pub border_width: f32,
+    pub border_color: [f32; 4],
+}
+impl SurfaceDecoration {
+    /// Check that the corner radius and the border width are non-negative finite numbers.
+    pub fn validate(&self) -> Result<(), ScreenTaskError> {
+        let valid = |value: f32| value.is_finite() && value >= 0.0;
+        if valid(self.radius) && valid(self.border_width) {
+            Ok(())
+        } else {
+            Err(ScreenTaskError::InvalidDecoration {
+                radius: self.radius,
+                border_width: self.border_width,
+            })
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Geometry of a new subsurface: the position is relative to its parent, the size is in logical coordinates.
+pub struct SubsurfaceGeometry {
+    pub position: [i32; 2],
+    pub size: [u32; 2],
+}
+
+/// Sampling code of the solid color instances, which are drawn without textures.
+pub const SAMPLING_SOLID_COLOR: u32 = 4;
+
+/// Flag of the sampling code that ignores the alpha channel of the planes, drawing them opaque.
+pub const SAMPLING_OPAQUE: u32 = 1 << 5;
+
+/// Flags that can be combined with the sampling codes; the remaining bits select how the planes are sampled.
+pub const SAMPLING_FLAGS: u32 = SAMPLING_REPEAT | SAMPLING_OPAQUE;
+
+/// Clip rectangle of the surfaces without clipping.
+pub const UNCLIPPED: [f32; 4] = [f32::MIN, f32::MIN, f32::MAX, f32::MAX];
+
+/// Check that the `[x, y, width, height]` clip rectangle is finite and has no negative size.
+pub(crate) fn check_clip_rect(clip_rect: [f32; 4]) -> Result<(), ScreenTaskError> {
+    if clip_rect.iter().all(|value| value.is_finite()) && clip_rect[2] >= 0.0 && clip_rect[3] >= 0.0
+    {
+        Ok(())
+    } else {
+        Err(ScreenTaskError::InvalidRect(clip_rect))
+    }
+}
+
+/// Returns the intersection of two clip rectangles in [min_x, min_y, max_x, max_y] form.
+pub fn intersect_clip(a: [f32; 4], b: [f32; 4]) -> [f32; 4] {
+    [
+        a[0].max(b[0]),
+        a[1].max(b[1]),
+        a[2].min(b[2]),
+        a[3].min(b[3]),
+    ]
 }
 
 #[derive(Debug, Clone)]
@@ -21,6 +116,31 @@ pub enum SurfaceSource {
         info: HostAllocationInfo,
         data: Vec<u8>,
     },
+    YuvDmabuf {
+        info: YuvDmabufInfo,
+    },
+    YuvHostAllocation {
+        info: YuvHostAllocationInfo,
+        planes: Vec<Vec<u8>>,
+    },
+    /**
+    Uniform color in linear RGBA, stretched to the size of the surface.
+    It has no planes, so it takes no texture and no slot of the texture array.
+    Scenes made only of solid colors are still drawn, with a placeholder bound in place of the textures.
+    */
+    SolidColor([f32; 4]),
+    /**
+    Buffer of `stride * size[1]` bytes at `offset` of a shared memory pool, like a wl_shm buffer.
+    The pool is mapped once and shared by its buffers, and every commit uploads the buffer from the mapping.
+    The file descriptor is only used while the source is applied.
+    */
+    ShmPool {
+        fd: std::os::unix::io::RawFd,
+        offset: u64,
+        size: [u32; 2],
+        stride: u32,
+        format: wgpu_engine::TextureFormat,
+    },
     /*
     OpaqueFd {
         offset: u64,
@@ -32,12 +152,11 @@ pub enum SurfaceSource {
     */
 }
 impl SurfaceSource {
-    pub fn from_file_path(path: PathBuf) -> Self {
+    pub fn from_file_path(path: PathBuf) -> Result<Self, ScreenTaskError> {
         use image::io::Reader as ImageReader;
-        let img = ImageReader::open(path.clone())
-            .unwrap()
-            .decode()
-            .unwrap()
+        let img = ImageReader::open(path)
+            .map_err(image::ImageError::IoError)?
+            .decode()?
             .into_rgba8();
 
         let sample_layout = img.sample_layout();
@@ -52,7 +171,136 @@ impl SurfaceSource {
             stride,
         };
         let data = img.into_raw();
-        Self::HostAllocation { info, data }
+        Ok(Self::HostAllocation { info, data })
+    }
+
+    /// Check that the source describes a buffer that can be turned into textures.
+    pub fn validate(&self) -> Result<(), ScreenTaskError> {
+        match self {
+            Self::Dmabuf { info } => info.validate(),
+            Self::HostAllocation { info, data } => {
+                check_size(info.size)?;
+                let bytes_per_pixel = info.format.describe().block_size as u32;
+                check_plane(info.size, info.stride, bytes_per_pixel, data.len())
+            }
+            Self::YuvDmabuf { info } => {
+                check_size(info.size)?;
+                check_plane_count(info.format, info.planes.len())?;
+                for (plane, plane_info) in info.planes.iter().enumerate() {
+                    check_stride(
+                        info.format.plane_size(plane, info.size),
+                        plane_info.stride,
+                        info.format.plane_bytes_per_pixel(plane),
+                    )?;
+                }
+                Ok(())
+            }
+            Self::YuvHostAllocation { info, planes } => {
+                check_size(info.size)?;
+                check_plane_count(info.format, info.strides.len())?;
+                check_plane_count(info.format, planes.len())?;
+                for (plane, (stride, data)) in info.strides.iter().zip(planes).enumerate() {
+                    check_plane(
+                        info.format.plane_size(plane, info.size),
+                        *stride,
+                        info.format.plane_bytes_per_pixel(plane),
+                        data.len(),
+                    )?;
+                }
+                Ok(())
+            }
+            Self::SolidColor(color) => {
+                if color.iter().all(|channel| channel.is_finite()) {
+                    Ok(())
+                } else {
+                    Err(ScreenTaskError::InvalidColor(*color))
+                }
+            }
+            Self::ShmPool {
+                offset,
+                size,
+                stride,
+                format,
+                ..
+            } => {
+                check_size(*size)?;
+                check_stride(*size, *stride, format.describe().block_size as u32)?;
+                buffer_end(*offset, *stride, size[1]).map(|_| ())
+            }
+        }
+    }
+}
+
+/// Returns the end of a buffer of `rows` rows of `stride` bytes starting at `offset`, failing if it can not be addressed.
+pub(crate) fn buffer_end(offset: u64, stride: u32, rows: u32) -> Result<usize, ScreenTaskError> {
+    let length = stride as u64 * rows as u64;
+    offset
+        .checked_add(length)
+        .and_then(|end| usize::try_from(end).ok())
+        .ok_or(ScreenTaskError::BufferOutOfRange { offset, length })
+}
+
+/// Check that the size has no zero dimension.
+pub(crate) fn check_size(size: [u32; 2]) -> Result<(), ScreenTaskError> {
+    if size[0] == 0 || size[1] == 0 {
+        Err(ScreenTaskError::InvalidSize(size))
+    } else {
+        Ok(())
+    }
+}
+
+/// Check that the stride holds a row of the provided size and that it is aligned to whole pixels.
+pub(crate) fn check_stride(
+    size: [u32; 2],
+    stride: u32,
+    bytes_per_pixel: u32,
+) -> Result<(), ScreenTaskError> {
+    let minimum = size[0]
+        .checked_mul(bytes_per_pixel)
+        .ok_or(ScreenTaskError::InvalidSize(size))?;
+    if stride < minimum {
+        Err(ScreenTaskError::InvalidStride { stride, minimum })
+    } else if stride % bytes_per_pixel != 0 {
+        Err(ScreenTaskError::MisalignedStride {
+            stride,
+            alignment: bytes_per_pixel,
+        })
+    } else {
+        Ok(())
+    }
+}
+
+/**
+Check that the stride and the data length are enough to hold a plane of the provided size.
+The last row only needs to hold the pixels of the plane, not the padding of the stride.
+*/
+pub(crate) fn check_plane(
+    size: [u32; 2],
+    stride: u32,
+    bytes_per_pixel: u32,
+    length: usize,
+) -> Result<(), ScreenTaskError> {
+    check_size(size)?;
+    check_stride(size, stride, bytes_per_pixel)?;
+    let expected = (stride as usize)
+        .checked_mul(size[1] as usize - 1)
+        .and_then(|rows| rows.checked_add(size[0] as usize * bytes_per_pixel as usize))
+        .ok_or(ScreenTaskError::InvalidSize(size))?;
+    if length < expected {
+        return Err(ScreenTaskError::InvalidDataLength { length, expected });
+    }
+    Ok(())
+}
+
+/// Check that a multi-plane source provides exactly the planes of its format.
+fn check_plane_count(format: YuvFormat, count: usize) -> Result<(), ScreenTaskError> {
+    if count != format.plane_count() {
+        Err(ScreenTaskError::UnsupportedFormat(format!(
+            "{:?} with {} planes",
+            format, count
+        )))
+    } else {
+        Ok(())
     }
 }
 
@@ -61,6 +309,132 @@ impl SurfaceSource {
 pub enum SurfaceSourceInfo {
     Dmabuf(DmabufInfo),
     HostAllocation(HostAllocationInfo),
+    YuvDmabuf(YuvDmabufInfo),
+    YuvHostAllocation(YuvHostAllocationInfo),
+    SolidColor([f32; 4]),
+    ShmPool(ShmPoolInfo),
+}
+impl SurfaceSourceInfo {
+    /// Returns the size in pixels of the source buffer; solid colors behave as a single pixel.
+    pub fn size(&self) -> [u32; 2] {
+        match self {
+            Self::Dmabuf(info) => info.size,
+            Self::HostAllocation(info) => info.size,
+            Self::YuvDmabuf(info) => info.size,
+            Self::YuvHostAllocation(info) => info.size,
+            Self::SolidColor(_) => [1, 1],
+            Self::ShmPool(info) => info.size,
+        }
+    }
+
+    /// Returns the number of planes, and so of textures, of the source.
+    pub fn plane_count(&self) -> usize {
+        match self {
+            Self::Dmabuf(_) | Self::HostAllocation(_) | Self::ShmPool(_) => 1,
+            Self::YuvDmabuf(info) => info.format.plane_count(),
+            Self::YuvHostAllocation(info) => info.format.plane_count(),
+            Self::SolidColor(_) => 0,
+        }
+    }
+
+    /// Returns the code used by the fragment shader to sample the planes.
+    pub fn sampling_code(&self) -> u32 {
+        match self {
+            Self::Dmabuf(info) if info.fourcc.ignores_alpha() => SAMPLING_RGBA | SAMPLING_OPAQUE,
+            Self::Dmabuf(_) | Self::HostAllocation(_) | Self::ShmPool(_) => SAMPLING_RGBA,
+            Self::YuvDmabuf(info) => info.format.sampling_code(),
+            Self::YuvHostAllocation(info) => info.format.sampling_code(),
+            Self::SolidColor(_) => SAMPLING_SOLID_COLOR,
+        }
+    }
+
+    /// Returns the code used by the fragment shader to select the YUV conversion.
+    pub fn color_space_code(&self) -> u32 {
+        match self {
+            Self::Dmabuf(_) | Self::HostAllocation(_) | Self::SolidColor(_) | Self::ShmPool(_) => 0,
+            Self::YuvDmabuf(info) => info.color_space.shader_code(),
+            Self::YuvHostAllocation(info) => info.color_space.shader_code(),
+        }
+    }
+
+    /**
+    Check that data of the provided length can update the whole source buffer.
+    Single-plane data follow the stride of host allocations and are tightly packed for Dma buffers,
+    while multi-plane data contain all the planes one after the other.
+    */
+    pub fn validate_data(&self, length: usize) -> Result<(), ScreenTaskError> {
+        match self {
+            Self::Dmabuf(info) => check_plane(
+                info.size,
+                info.size[0]
+                    .checked_mul(4)
+                    .ok_or(ScreenTaskError::InvalidSize(info.size))?,
+                4,
+                length,
+            ),
+            Self::HostAllocation(info) => check_plane(
+                info.size,
+                info.stride,
+                info.format.describe().block_size as u32,
+                length,
+            ),
+            Self::ShmPool(info) => check_plane(
+                info.size,
+                info.stride,
+                info.format.describe().block_size as u32,
+                length,
+            ),
+            Self::YuvDmabuf(_) => Err(ScreenTaskError::UnsupportedFormat(String::from(
+                "data updates of multi-plane Dma buffers",
+            ))),
+            Self::SolidColor(_) => Err(ScreenTaskError::UnsupportedFormat(String::from(
+                "data updates of solid colors",
+            ))),
+            Self::YuvHostAllocation(info) => {
+                let expected = info
+                    .strides
+                    .iter()
+                    .enumerate()
+                    .try_fold(0usize, |expected, (plane, stride)| {
+                        (*stride as usize)
+                            .checked_mul(info.format.plane_size(plane, info.size)[1] as usize)
+                            .and_then(|plane_length| expected.checked_add(plane_length))
+                    })
+                    .ok_or(ScreenTaskError::InvalidSize(info.size))?;
+                if length < expected {
+                    Err(ScreenTaskError::InvalidDataLength { length, expected })
+                } else {
+                    Ok(())
+                }
+            }
+        }
+    }
+
+    /**
+    Check that the `[x, y, width, height]` source rectangle, in buffer pixels, has a positive size
+    and lies inside the buffer. Solid colors have no buffer that a source rectangle can crop.
+    */
+    pub fn validate_source_rect(&self, source_rect: [f32; 4]) -> Result<(), ScreenTaskError> {
+        if let Self::SolidColor(_) = self {
+            return Err(ScreenTaskError::UnsupportedFormat(String::from(
+                "source rectangles of solid colors",
+            )));
+        }
+        let [x, y, width, height] = source_rect;
+        let size = self.size();
+        if source_rect.iter().all(|value| value.is_finite())
+            && x >= 0.0
+            && y >= 0.0
+            && width > 0.0
+            && height > 0.0
+            && x + width <= size[0] as f32
+            && y + height <= size[1] as f32
+        {
+            Ok(())
+        } else {
+            Err(ScreenTaskError::InvalidRect(source_rect))
+        }
+    }
 }
 impl From<DmabufInfo> for SurfaceSourceInfo {
     fn from(info: DmabufInfo) -> Self {
@@ -82,18 +456,105 @@ impl From<&SurfaceSource> for SurfaceSourceInfo {
         match source {
             SurfaceSource::Dmabuf { info } => Self::Dmabuf(info.clone()),
             SurfaceSource::HostAllocation { info, .. } => Self::HostAllocation(info.clone()),
+            SurfaceSource::YuvDmabuf { info } => Self::YuvDmabuf(info.clone()),
+            SurfaceSource::YuvHostAllocation { info, .. } => Self::YuvHostAllocation(info.clone()),
+            SurfaceSource::SolidColor(color) => Self::SolidColor(*color),
+            SurfaceSource::ShmPool {
+                fd,
+                offset,
+                size,
+                stride,
+                format,
+            } => Self::ShmPool(ShmPoolInfo {
+                fd: *fd,
+                offset: *offset,
+                size: *size,
+                stride: *stride,
+                format: *format,
+            }),
         }
     }
 }
 
 #[derive(Debug, Clone)]
-/// Informations about a Dma buffer.
+/**
+Informations about a Dma buffer sampled from a single texture.
+Modifiers with auxiliary planes, like compression metadata, use more than one plane of the same buffer.
+*/
 pub struct DmabufInfo {
     pub size: [u32; 2],
+    pub fourcc: DrmFourcc,
     pub modifier: wgpu_engine::DrmModifier,
-    pub fd: std::os::unix::io::RawFd,
-    pub plane_offset: u64,
-    pub plane_stride: u32,
+    /// Planes of the buffer, at most `MAX_DMABUF_PLANES`.
+    pub planes: Vec<DmabufPlane>,
+}
+impl DmabufInfo {
+    /// Check that the fourcc is supported and the planes are consistent with it and with the modifier.
+    pub fn validate(&self) -> Result<(), ScreenTaskError> {
+        check_size(self.size)?;
+        self.fourcc.texture_format()?;
+        let count = self.planes.len();
+        if count == 0
+            || count > MAX_DMABUF_PLANES
+            || (self.modifier == DRM_FORMAT_MOD_LINEAR && count != 1)
+        {
+            return Err(ScreenTaskError::UnsupportedFormat(format!(
+                "{} Dma buffers with modifier {:#x} and {} planes",
+                self.fourcc, self.modifier.0, count
+            )));
+        }
+        check_stride(self.size, self.planes[0].stride, 4)
+    }
+
+    /**
+    Returns the layout of every plane inside the buffer.
+    The size of the auxiliary planes depends on the modifier, so every plane is assumed to span
+    `stride * height` bytes from its offset.
+    */
+    pub fn plane_layouts(&self) -> Vec<wgpu_engine::PlaneLayout> {
+        self.planes
+            .iter()
+            .map(|plane| wgpu_engine::PlaneLayout {
+                slice: plane.offset..plane.offset + plane.stride as u64 * self.size[1] as u64,
+                row_pitch: plane.stride as u64,
+                array_pitch: 1,
+                depth_pitch: 1,
+            })
+            .collect()
+    }
+
+    /**
+    Returns the file descriptor used to import the buffer.
+    Planes can be sent with different file descriptors, but they must all refer to the same buffer.
+    */
+    pub fn import_fd(&self) -> Result<std::os::unix::io::RawFd, ScreenTaskError> {
+        let fd = self.planes.first().map(|plane| plane.fd).ok_or_else(|| {
+            ScreenTaskError::UnsupportedFormat(String::from("Dma buffers without planes"))
+        })?;
+        let identity = file_identity(fd);
+        for plane in &self.planes[1..] {
+            if plane.fd != fd && (identity.is_none() || file_identity(plane.fd) != identity) {
+                return Err(ScreenTaskError::UnsupportedFormat(String::from(
+                    "Dma buffers with planes in different buffers",
+                )));
+            }
+        }
+        Ok(fd)
+    }
+}
+
+/// Returns the device and the inode of the file behind the descriptor, which identify the buffer.
+fn file_identity(fd: std::os::unix::io::RawFd) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    use std::os::unix::io::FromRawFd;
+    if fd < 0 {
+        return None;
+    }
+    // The descriptor is borrowed, so the file must not close it.
+    let file = std::mem::ManuallyDrop::new(unsafe { std::fs::File::from_raw_fd(fd) });
+    file.metadata()
+        .ok()
+        .map(|metadata| (metadata.dev(), metadata.ino()))
 }
 
 #[derive(Debug, Clone)]
@@ -104,40 +565,161 @@ pub struct HostAllocationInfo {
     pub stride: u32,
 }
 
+#[derive(Debug, Clone)]
+/// Information related to a buffer of a shared memory pool.
+pub struct ShmPoolInfo {
+    pub fd: std::os::unix::io::RawFd,
+    pub offset: u64,
+    pub size: [u32; 2],
+    pub stride: u32,
+    pub format: wgpu_engine::TextureFormat,
+}
+impl ShmPoolInfo {
+    /// Returns the number of bytes of the buffer inside the pool.
+    pub fn length(&self) -> usize {
+        self.stride as usize * self.size[1] as usize
+    }
+}
+
+#[derive(Debug, Clone)]
+/// Informations about a single plane of a Dma buffer.
+pub struct DmabufPlane {
+    pub fd: std::os::unix::io::RawFd,
+    pub offset: u64,
+    pub stride: u32,
+}
+
+#[derive(Debug, Clone)]
+/// Informations about a multi-plane YUV Dma buffer.
+pub struct YuvDmabufInfo {
+    pub size: [u32; 2],
+    pub format: YuvFormat,
+    pub color_space: YuvColorSpace,
+    pub modifier: wgpu_engine::DrmModifier,
+    pub planes: Vec<DmabufPlane>,
+}
+
+#[derive(Debug, Clone)]
+/// Information related to a multi-plane YUV Host allocation.
+pub struct YuvHostAllocationInfo {
+    pub size: [u32; 2],
+    pub format: YuvFormat,
+    pub color_space: YuvColorSpace,
+    /// Stride in bytes of each plane.
+    pub strides: Vec<u32>,
+}
+
+#[derive(Debug, Clone, Copy)]
+/// Texture and texture view of a single plane of a surface.
+pub struct SurfacePlane {
+    pub texture_id: wgpu_engine::TextureId,
+    pub texture_view_id: wgpu_engine::TextureViewId,
+}
+
 #[derive(Debug)]
 /// Informations related to a surface.
 pub struct SurfaceInfo {
-    pub texture_id: wgpu_engine::TextureId,
-    pub texture_view_id: wgpu_engine::TextureViewId,
+    /// Planes of the surface, sampled from consecutive slots of the texture array.
+    pub planes: Vec<SurfacePlane>,
 
     pub info: SurfaceSourceInfo,
-    pub position: [f32; 3],
+    pub position: [f32; 2],
     pub size: [f32; 2],
+    pub opacity: f32,
+    /// Region of the source buffer to display, in buffer pixels. None means the whole buffer.
+    pub source_rect: Option<[f32; 4]>,
+    pub transform: SurfaceTransform,
+    /**
+    Region outside of which the surface is not drawn, as `[x, y, width, height]` in logical coordinates,
+    the same space of the surface positions. None means no clipping.
+    */
+    pub clip_rect: Option<[f32; 4]>,
+    pub decoration: SurfaceDecoration,
+    pub shadow: Option<SurfaceShadow>,
+    /// Blur the content behind the surface before drawing it.
+    pub background_blur: bool,
+    /// Mapping of the shared memory pool of the source, uploaded on every commit.
+    pub(crate) pool: Option<Arc<MemoryMapping>>,
+    /// Mappings of the planes of a Dma buffer copied through host memory, uploaded on every commit.
+    pub(crate) plane_mappings: Vec<MemoryMapping>,
 }
 impl SurfaceInfo {
     pub fn new(
-        texture_id: wgpu_engine::TextureId,
-        texture_view_id: wgpu_engine::TextureViewId,
+        planes: Vec<SurfacePlane>,
         info: SurfaceSourceInfo,
-        position: [i32; 3],
+        position: [i32; 2],
         size: [u32; 2],
     ) -> Self {
-        let position = [position[0] as f32, position[1] as f32, position[2] as f32];
+        let position = [position[0] as f32, position[1] as f32];
         let size = [size[0] as f32, size[1] as f32];
+        let opacity = 1.0;
+        let source_rect = None;
+        let transform = SurfaceTransform::default();
+        let clip_rect = None;
+        let decoration = SurfaceDecoration::default();
+        let shadow = None;
+        let background_blur = false;
         Self {
             info,
             position,
             size,
-            texture_id,
-            texture_view_id,
+            opacity,
+            source_rect,
+            transform,
+            clip_rect,
+            decoration,
+            shadow,
+            background_blur,
+            planes,
+            pool: None,
+            plane_mappings: Vec::new(),
         }
     }
 
-    pub fn generate_data(&self, image_index: u32) -> Surface {
+    /// Returns the data sent to the gpu, drawing the surface at the provided depth.
+    pub fn generate_data(&self, image_index: u32, depth: f32) -> Surface {
         Surface {
-            position: self.position,
+            position: [self.position[0], self.position[1], depth],
             size: self.size,
             image_index,
+            opacity: self.opacity,
+            source_rect: match self.info {
+                SurfaceSourceInfo::SolidColor(color) => color,
+                _ => self.normalized_source_rect(),
+            },
+            transform: self.transform.matrix,
+            uv_transform: self.transform.buffer_transform.uv_matrix(),
+            sampling: self.info.sampling_code(),
+            color_space: self.info.color_space_code(),
+            clip_rect: self.clip_bounds(),
+            decoration: [self.decoration.radius, self.decoration.border_width],
+            border_color: self.decoration.border_color,
+        }
+    }
+
+    /// Returns the clip rectangle in [min_x, min_y, max_x, max_y] form.
+    pub fn clip_bounds(&self) -> [f32; 4] {
+        match self.clip_rect {
+            Some([x, y, width, height]) => [x, y, x + width, y + height],
+            None => UNCLIPPED,
+        }
+    }
+
+    /// Returns the source rectangle normalized against the size of the source buffer.
+    pub fn normalized_source_rect(&self) -> [f32; 4] {
+        match self.source_rect {
+            Some(source_rect) => {
+                let buffer_size = self.info.size();
+                let width = buffer_size[0].max(1) as f32;
+                let height = buffer_size[1].max(1) as f32;
+                [
+                    source_rect[0] / width,
+                    source_rect[1] / height,
+                    source_rect[2] / width,
+                    source_rect[3] / height,
+                ]
+            }
+            None => [0.0, 0.0, 1.0, 1.0],
         }
     }
 }
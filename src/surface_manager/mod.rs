@@ -1,4 +1,18 @@
-use crate::surface::{Surface, SurfaceInfo, SurfaceSource, SurfaceSourceInfo};
+use crate::background::{Wallpaper, WallpaperMode, SAMPLING_REPEAT, WALLPAPER_DEPTH};
+use crate::damage::{extract_rect, merge_damage};
+use crate::engine::Engine;
+use crate::error::{OrResourceError, ScreenTaskError};
+use crate::mapping::{map_plane, MemoryMapping, ShmPools};
+use crate::shadow::SurfaceShadow;
+use crate::stacking::StackingOrder;
+use crate::surface::{buffer_end, check_clip_rect, check_size, intersect_clip};
+use crate::surface::{
+    HostAllocationInfo, SubsurfaceGeometry, Surface, SurfaceDecoration, SurfaceInfo, SurfacePlane,
+    SurfaceSource, SurfaceSourceInfo,
+};
+use crate::transform::{apply_affine, SurfaceTransform};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
 use wgpu_engine::*;
 
 mod prepare_texture;
@@ -9,35 +23,86 @@ mod prepare_texture_write;
 /**
 Manager responsible to correctly manage rendering resources (like buffers) where surface related data are stored,
 so it is also responsible to correctly synchronize the data with the gpu.
+
+Surface data are stored in the buffer in drawing order (back to front), so that translucent surfaces
+get correctly blended over the ones behind them.
+The wallpapers of the outputs are stored before the surfaces, one slot per output ordered by output id.
+The texture array instead holds the planes of the surfaces ordered by surface id, followed by the ones of the wallpapers,
+so that restacking surfaces does not change the bind group.
+Without any plane, a transparent 1x1 placeholder is bound, so that scenes of solid colors are drawn too.
 */
-pub struct SurfaceManager {
+pub struct SurfaceManager<E: Engine = UpdateContext> {
     device: DeviceId,
     id_counter: usize,
-    stack: Vec<usize>,
-    data_buffer: BufferManager<Surface, SurfaceInfo>,
+    surfaces: BTreeMap<usize, SurfaceInfo>,
+    wallpapers: BTreeMap<usize, Wallpaper>,
+    stacking_order: StackingOrder,
+    data_buffer: E::SurfaceBuffer,
+    data_buffer_dirty: bool,
+    /// Import Dma buffers directly; without external memory they are copied through host memory.
+    external_memory: bool,
+    shm_pools: ShmPools,
+    placeholder: Vec<SurfacePlane>,
 }
-impl SurfaceManager {
-    pub fn new(update_context: &mut UpdateContext, device: DeviceId) -> Self {
+impl<E: Engine> SurfaceManager<E> {
+    pub fn new(update_context: &mut E, device: DeviceId) -> Result<Self, ScreenTaskError> {
         let id_counter = 0;
-        let stack = Vec::new();
-        let data_buffer = BufferManager::new(
-            update_context,
-            String::from("SurfaceManager buffer"),
-            device,
-            32,
-            wgpu::BufferUsage::VERTEX,
-        );
-        Self {
+        let surfaces = BTreeMap::new();
+        let data_buffer =
+            update_context.create_surface_buffer(String::from("SurfaceManager buffer"), device);
+        let mut surface_manager = Self {
             device,
             id_counter,
-            stack,
+            surfaces,
+            wallpapers: BTreeMap::new(),
+            stacking_order: StackingOrder::new(),
             data_buffer,
-        }
+            data_buffer_dirty: false,
+            external_memory: crate::ScreenTask::<E>::features_and_limits()
+                .0
+                .contains(wgpu::Features::EXTERNAL_MEMORY),
+            shm_pools: ShmPools::default(),
+            placeholder: Vec::new(),
+        };
+        let placeholder = SurfaceSource::HostAllocation {
+            info: HostAllocationInfo {
+                size: [1, 1],
+                format: wgpu::TextureFormat::Rgba8Unorm,
+                stride: 4,
+            },
+            data: vec![0; 4],
+        };
+        surface_manager.placeholder = surface_manager.create_planes(
+            update_context,
+            String::from("SurfaceManager placeholder"),
+            placeholder,
+        )?;
+        Ok(surface_manager)
+    }
+
+    /// Choose whether Dma buffers are imported directly or copied through host memory, for the new sources.
+    pub fn set_external_memory(&mut self, external_memory: bool) {
+        self.external_memory = external_memory;
+    }
+
+    /// Share the mappings of the shared memory pools with other surface managers, for the new sources.
+    pub fn set_shm_pools(&mut self, shm_pools: ShmPools) {
+        self.shm_pools = shm_pools;
+    }
+
+    /// Returns true if Dma buffers are imported directly, false if they are copied through host memory.
+    pub fn external_memory(&self) -> bool {
+        self.external_memory
     }
 
     /// Returns the underlying BufferId.
     pub fn buffer_id(&self) -> &BufferId {
-        self.data_buffer.id()
+        E::surface_buffer_id(&self.data_buffer)
+    }
+
+    /// Returns the buffer storing the surface instances, as written by the last update.
+    pub fn surface_buffer(&self) -> &E::SurfaceBuffer {
+        &self.data_buffer
     }
 
     /// Book a identifier for a surface id. The returned id will not be assigned to other surfaces.
@@ -49,176 +114,982 @@ impl SurfaceManager {
 
     /// Returns how many surfaces are stored.
     pub fn len(&self) -> usize {
-        self.stack.len()
+        self.surfaces.len()
+    }
+
+    /// Returns true if no surface is stored.
+    pub fn is_empty(&self) -> bool {
+        self.surfaces.is_empty()
+    }
+
+    /// Returns true if a surface with the provided id is stored.
+    pub fn contains(&self, id: &usize) -> bool {
+        self.surfaces.contains_key(id)
+    }
+
+    /// Returns the informations about the current source of the surface with the provided id.
+    pub fn source_info(&self, id: &usize) -> Option<&SurfaceSourceInfo> {
+        self.surfaces.get(id).map(|surface| &surface.info)
     }
 
     /// Create a new surface and assign it the provided id.
     pub fn create_surface(
         &mut self,
-        update_context: &mut UpdateContext,
+        update_context: &mut E,
         label: String,
         id: usize,
         source: SurfaceSource,
         position: [i32; 3],
         size: [u32; 2],
-    ) {
+    ) -> Result<(), ScreenTaskError> {
         log::info!(target: "ScreenTask","Creating surface {}",id);
+        let surface = self.prepare_surface(update_context, label, id, source, position, size)?;
+        self.stacking_order.insert(id, position[2])?;
+        self.insert_surface(id, surface);
+        Ok(())
+    }
+
+    /**
+    Create a new subsurface of the provided parent and assign it the provided id.
+    The position is relative to the parent, and the subsurface is stacked on top of the parent and its siblings.
+    */
+    pub fn create_subsurface(
+        &mut self,
+        update_context: &mut E,
+        label: String,
+        id: usize,
+        parent: usize,
+        source: SurfaceSource,
+        geometry: SubsurfaceGeometry,
+    ) -> Result<(), ScreenTaskError> {
+        log::info!(target: "ScreenTask","Creating subsurface {} of {}",id,parent);
+        if !self.surfaces.contains_key(&parent) {
+            return Err(ScreenTaskError::UnknownSurface(parent));
+        }
+        let SubsurfaceGeometry { position, size } = geometry;
+        let position = [position[0], position[1], 0];
+        let surface = self.prepare_surface(update_context, label, id, source, position, size)?;
+        self.stacking_order.insert_child(id, parent)?;
+        self.insert_surface(id, surface);
+        Ok(())
+    }
+
+    /// Validate a new surface and create its planes.
+    fn prepare_surface(
+        &mut self,
+        update_context: &mut E,
+        label: String,
+        id: usize,
+        source: SurfaceSource,
+        position: [i32; 3],
+        size: [u32; 2],
+    ) -> Result<SurfaceInfo, ScreenTaskError> {
+        if self.surfaces.contains_key(&id) {
+            return Err(ScreenTaskError::DuplicateSurface(id));
+        }
+        check_size(size)?;
         let info = SurfaceSourceInfo::from(&source);
-        let (texture_descriptor, texture_data, layout) =
-            Self::prepare_texture(self.device, label.clone(), source);
-
-        let texture_format = texture_descriptor.format;
-        let texture_size = texture_descriptor.size;
-        let texture = update_context
-            .add_texture_descriptor(texture_descriptor)
-            .unwrap();
-
-        let texture_view_descriptor =
-            self.prepare_texture_view(label.clone(), texture, texture_format);
-        let texture_view = update_context
-            .add_texture_view_descriptor(texture_view_descriptor)
-            .unwrap();
-
-        if let Some(data) = texture_data {
-            let texture_write = Self::prepare_texture_write(texture, data, texture_size, layout);
-            update_context.write_resource(&mut vec![texture_write]);
+        let pool = self.map_pool(&source)?;
+        let plane_mappings = self.map_dmabuf(&source)?;
+        let planes = self.create_planes(update_context, label, source)?;
+        let mut surface = SurfaceInfo::new(planes, info, [position[0], position[1]], size);
+        surface.pool = pool;
+        surface.plane_mappings = plane_mappings;
+        Self::write_dmabuf(update_context, &surface);
+        if let Err(error) = Self::write_pool(update_context, &surface) {
+            Self::remove_planes(update_context, &surface.planes)?;
+            return Err(error);
+        }
+        Ok(surface)
+    }
+
+    /// Store a new surface, already inserted in the stacking order.
+    fn insert_surface(&mut self, id: usize, surface: SurfaceInfo) {
+        self.surfaces.insert(id, surface);
+        self.data_buffer_dirty = true;
+    }
+
+    /// Create the textures and texture views of every plane of the source, writing their eventual data.
+    fn create_planes(
+        &self,
+        update_context: &mut E,
+        label: String,
+        source: SurfaceSource,
+    ) -> Result<Vec<SurfacePlane>, ScreenTaskError> {
+        let mut planes = Vec::new();
+        for (texture_descriptor, texture_data, layout) in
+            Self::prepare_texture(self.device, label.clone(), source, self.external_memory)?
+        {
+            let texture_format = texture_descriptor.format;
+            let texture_size = texture_descriptor.size;
+            let texture = match update_context
+                .add_texture_descriptor(texture_descriptor)
+                .or_resource_error("texture")
+            {
+                Ok(texture) => texture,
+                Err(error) => {
+                    Self::remove_planes(update_context, &planes)?;
+                    return Err(error);
+                }
+            };
+
+            let texture_view_descriptor =
+                self.prepare_texture_view(label.clone(), texture, texture_format);
+            let texture_view = match update_context
+                .add_texture_view_descriptor(texture_view_descriptor)
+                .or_resource_error("texture view")
+            {
+                Ok(texture_view) => texture_view,
+                Err(error) => {
+                    update_context
+                        .remove_texture(&texture)
+                        .or_resource_error("texture")?;
+                    Self::remove_planes(update_context, &planes)?;
+                    return Err(error);
+                }
+            };
+
+            if let Some(data) = texture_data {
+                let texture_write =
+                    Self::prepare_texture_write(texture, data, texture_size, layout);
+                update_context.write_resource(&mut vec![texture_write]);
+            }
+
+            planes.push(SurfacePlane {
+                texture_id: texture,
+                texture_view_id: texture_view,
+            });
+        }
+        Ok(planes)
+    }
+
+    /// Remove the textures and texture views of the provided planes.
+    fn remove_planes(
+        update_context: &mut E,
+        planes: &[SurfacePlane],
+    ) -> Result<(), ScreenTaskError> {
+        for plane in planes {
+            update_context
+                .remove_texture_view(&plane.texture_view_id)
+                .or_resource_error("texture view removal")?;
+            update_context
+                .remove_texture(&plane.texture_id)
+                .or_resource_error("texture removal")?;
         }
+        Ok(())
+    }
 
-        let surface = SurfaceInfo::new(texture, texture_view, info, position, size);
-        let next_slot = self.data_buffer.next_slot() as u32;
-        let surface_data = surface.generate_data(next_slot);
-        self.data_buffer.request(id, surface, surface_data);
-        self.stack.push(id);
+    /// Returns the informations of the surface with the provided id.
+    fn surface_mut(&mut self, id: &usize) -> Result<&mut SurfaceInfo, ScreenTaskError> {
+        self.surfaces
+            .get_mut(id)
+            .ok_or(ScreenTaskError::UnknownSurface(*id))
     }
 
-    /// Update the source of the surface with the provided id.
+    /**
+    Update the source of the surface with the provided id.
+    Returns true if the number of planes changed, so the bind groups need to be updated.
+    */
     pub fn update_source(
         &mut self,
-        update_context: &mut UpdateContext,
+        update_context: &mut E,
         id: &usize,
         source: SurfaceSource,
-    ) {
+    ) -> Result<bool, ScreenTaskError> {
         log::info!(target: "ScreenTask","Updating source of surface {}",id);
         let device = self.device;
-        if let Some(surface_info) = self.data_buffer.associated_data_mut(id) {
-            if let Some(texture_descriptor) =
-                update_context.texture_descriptor_ref(&surface_info.texture_id)
+        let external_memory = self.external_memory;
+        let surface_info = self
+            .surfaces
+            .get(id)
+            .ok_or(ScreenTaskError::UnknownSurface(*id))?;
+        // Solid colors have no texture to take the label from.
+        let label = match surface_info.planes.first() {
+            Some(plane) => update_context
+                .texture_descriptor_ref(&plane.texture_id)
+                .or_resource_error("texture descriptor")?
+                .label
+                .clone(),
+            None => format!("Surface {}", id),
+        };
+        let info = SurfaceSourceInfo::from(&source);
+
+        let planes_changed = info.plane_count() != surface_info.planes.len();
+        let pool = self.map_pool(&source)?;
+        let plane_mappings = self.map_dmabuf(&source)?;
+        if planes_changed {
+            let planes = self.create_planes(update_context, label, source)?;
+            let surface_info = self.surface_mut(id)?;
+            let old_planes = std::mem::replace(&mut surface_info.planes, planes);
+            surface_info.info = info;
+            Self::remove_planes(update_context, &old_planes)?;
+        } else {
+            let textures = Self::prepare_texture(device, label, source, external_memory)?;
+            let surface_info = self.surface_mut(id)?;
+            for (plane, (texture_descriptor, texture_data, layout)) in
+                surface_info.planes.iter_mut().zip(textures)
             {
-                let (texture_descriptor, texture_data, layout) =
-                    Self::prepare_texture(device, texture_descriptor.label.clone(), source);
                 let texture_size = texture_descriptor.size.clone();
                 update_context
-                    .update_texture_descriptor(&mut surface_info.texture_id, texture_descriptor);
+                    .update_texture_descriptor(&mut plane.texture_id, texture_descriptor)
+                    .or_resource_error("texture update")?;
 
                 if let Some(data) = texture_data {
-                    let texture_write = Self::prepare_texture_write(
-                        surface_info.texture_id,
-                        data,
-                        texture_size,
-                        layout,
-                    );
+                    let texture_write =
+                        Self::prepare_texture_write(plane.texture_id, data, texture_size, layout);
                     update_context.write_resource(&mut vec![texture_write]);
                 }
-            };
-        } else {
-            println!("Failed");
+            }
+            surface_info.info = info;
         }
+        self.data_buffer_dirty = true;
+        let surface_info = self.surface_mut(id)?;
+        surface_info.pool = pool;
+        surface_info.plane_mappings = plane_mappings;
+        Self::write_dmabuf(update_context, surface_info);
+        Self::write_pool(update_context, surface_info)?;
+        Ok(planes_changed)
     }
 
-    /// Update the data of the surface with the provided id.
-    pub fn update_data(&mut self, update_context: &mut UpdateContext, id: &usize, data: Vec<u8>) {
+    /**
+    Update the data of the surface with the provided id.
+    Data of multi-plane surfaces are expected to contain all the planes one after the other.
+    */
+    pub fn update_data(
+        &mut self,
+        update_context: &mut E,
+        id: &usize,
+        data: Vec<u8>,
+    ) -> Result<(), ScreenTaskError> {
         log::info!(target: "ScreenTask","Updating data of surface {}",id);
-        if let Some(surface_info) = self.data_buffer.associated_data_mut(id) {
-            if let Some(texture_descriptor) =
-                update_context.texture_descriptor_ref(&surface_info.texture_id)
-            {
+        let surface_info = self
+            .surfaces
+            .get(id)
+            .ok_or(ScreenTaskError::UnknownSurface(*id))?;
+        surface_info.info.validate_data(data.len())?;
+        if let SurfaceSourceInfo::YuvHostAllocation(info) = &surface_info.info {
+            let mut offset = 0;
+            let mut texture_writes = Vec::new();
+            for (plane, surface_plane) in surface_info.planes.iter().enumerate() {
+                let plane_size = info.format.plane_size(plane, info.size);
+                let stride = info.strides[plane];
+                let plane_end = offset + stride as usize * plane_size[1] as usize;
+                let (plane_data, stride) = info.format.convert_plane(
+                    plane,
+                    info.size,
+                    stride,
+                    data[offset..plane_end].to_vec(),
+                );
+                offset = plane_end;
+
                 let layout = wgpu::ImageDataLayout {
                     offset: 0,
-                    bytes_per_row: std::num::NonZeroU32::new(
-                        texture_descriptor.format.describe().block_size as u32
-                            * texture_descriptor.size.width,
-                    ),
-                    rows_per_image: std::num::NonZeroU32::new(texture_descriptor.size.height),
+                    bytes_per_row: std::num::NonZeroU32::new(stride),
+                    rows_per_image: std::num::NonZeroU32::new(plane_size[1]),
+                };
+                let texture_size = wgpu::Extent3d {
+                    width: plane_size[0],
+                    height: plane_size[1],
+                    depth_or_array_layers: 1,
                 };
-                let texture_write = Self::prepare_texture_write(
-                    surface_info.texture_id,
-                    data,
-                    texture_descriptor.size,
+                texture_writes.push(Self::prepare_texture_write(
+                    surface_plane.texture_id,
+                    plane_data,
+                    texture_size,
                     layout,
-                );
-                update_context.write_resource(&mut vec![texture_write]);
+                ));
             }
+            update_context.write_resource(&mut texture_writes);
+        } else {
+            let texture_descriptor = update_context
+                .texture_descriptor_ref(&surface_info.planes[0].texture_id)
+                .or_resource_error("texture descriptor")?;
+            let bytes_per_row = match &surface_info.info {
+                SurfaceSourceInfo::HostAllocation(info) => info.stride,
+                SurfaceSourceInfo::ShmPool(info) => info.stride,
+                _ => {
+                    texture_descriptor.format.describe().block_size as u32
+                        * texture_descriptor.size.width
+                }
+            };
+            let layout = wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: std::num::NonZeroU32::new(bytes_per_row),
+                rows_per_image: std::num::NonZeroU32::new(texture_descriptor.size.height),
+            };
+            let texture_write = Self::prepare_texture_write(
+                surface_info.planes[0].texture_id,
+                data,
+                texture_descriptor.size,
+                layout,
+            );
+            update_context.write_resource(&mut vec![texture_write]);
+        }
+        Ok(())
+    }
+
+    /**
+    Update only the damaged `[x, y, width, height]` regions of the surface with the provided id.
+    The data contains the whole buffer, but only the damaged regions are uploaded.
+    Multi-plane surfaces are always fully updated.
+    */
+    pub fn update_data_region(
+        &mut self,
+        update_context: &mut E,
+        id: &usize,
+        rects: &[[u32; 4]],
+        data: Vec<u8>,
+    ) -> Result<(), ScreenTaskError> {
+        log::info!(target: "ScreenTask","Updating {} regions of surface {}",rects.len(),id);
+        let surface_info = self
+            .surfaces
+            .get(id)
+            .ok_or(ScreenTaskError::UnknownSurface(*id))?;
+        if surface_info.planes.len() != 1 {
+            return self.update_data(update_context, id, data);
+        }
+        let texture_id = surface_info.planes[0].texture_id;
+        let texture_descriptor = update_context
+            .texture_descriptor_ref(&texture_id)
+            .or_resource_error("texture descriptor")?;
+        let bytes_per_pixel = texture_descriptor.format.describe().block_size as u32;
+        let size = [
+            texture_descriptor.size.width,
+            texture_descriptor.size.height,
+        ];
+        let stride = match &surface_info.info {
+            SurfaceSourceInfo::HostAllocation(info) => info.stride,
+            SurfaceSourceInfo::ShmPool(info) => info.stride,
+            _ => bytes_per_pixel * size[0],
         };
+        surface_info.info.validate_data(data.len())?;
+
+        let mut texture_writes: Vec<_> = merge_damage(rects, size)
+            .into_iter()
+            .map(|rect| {
+                let layout = wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: std::num::NonZeroU32::new(rect[2] * bytes_per_pixel),
+                    rows_per_image: std::num::NonZeroU32::new(rect[3]),
+                };
+                let region_size = wgpu::Extent3d {
+                    width: rect[2],
+                    height: rect[3],
+                    depth_or_array_layers: 1,
+                };
+                Self::prepare_texture_region_write(
+                    texture_id,
+                    extract_rect(&data, stride, bytes_per_pixel, rect),
+                    [rect[0], rect[1]],
+                    region_size,
+                    layout,
+                )
+            })
+            .collect();
+        if !texture_writes.is_empty() {
+            update_context.write_resource(&mut texture_writes);
+        }
+        Ok(())
+    }
+
+    /**
+    Upload again the buffer of the surface with the provided id, after the client committed new content.
+    Buffers of shared memory pools and Dma buffers copied through host memory are uploaded from their mappings,
+    the imported Dma buffers are sampled directly.
+    */
+    pub fn commit_surface(
+        &mut self,
+        update_context: &mut E,
+        id: &usize,
+    ) -> Result<(), ScreenTaskError> {
+        let surface_info = self
+            .surfaces
+            .get(id)
+            .ok_or(ScreenTaskError::UnknownSurface(*id))?;
+        if let SurfaceSourceInfo::ShmPool(_) = &surface_info.info {
+            return Self::write_pool(update_context, surface_info);
+        }
+        if !surface_info.plane_mappings.is_empty() {
+            log::info!(target: "ScreenTask","Copying committed Dma buffer of surface {}",id);
+            Self::write_dmabuf(update_context, surface_info);
+        }
+        Ok(())
+    }
+
+    /// Map the planes of a Dma buffer copied through host memory, kept until the source changes.
+    fn map_dmabuf(&self, source: &SurfaceSource) -> Result<Vec<MemoryMapping>, ScreenTaskError> {
+        if self.external_memory {
+            return Ok(Vec::new());
+        }
+        let planes: Vec<_> = match source {
+            SurfaceSource::Dmabuf { info } => info
+                .planes
+                .iter()
+                .take(1)
+                .map(|plane| (plane, info.size[1]))
+                .collect(),
+            SurfaceSource::YuvDmabuf { info } => info
+                .planes
+                .iter()
+                .enumerate()
+                .take(info.format.plane_count())
+                .map(|(index, plane)| (plane, info.format.plane_size(index, info.size)[1]))
+                .collect(),
+            _ => return Ok(Vec::new()),
+        };
+        source.validate()?;
+        planes
+            .into_iter()
+            .map(|(plane, rows)| map_plane(plane.fd, plane.offset, plane.stride, rows))
+            .collect()
+    }
+
+    /// Upload the planes of a Dma buffer copied through host memory from their mappings to the textures.
+    fn write_dmabuf(update_context: &mut E, surface: &SurfaceInfo) {
+        let mut texture_writes = Vec::new();
+        for (index, (mapping, plane)) in surface
+            .plane_mappings
+            .iter()
+            .zip(&surface.planes)
+            .enumerate()
+        {
+            let (data, stride, size) = match &surface.info {
+                SurfaceSourceInfo::Dmabuf(info) => {
+                    (mapping.read(), info.planes[0].stride, info.size)
+                }
+                SurfaceSourceInfo::YuvDmabuf(info) => {
+                    let (data, stride) = info.format.convert_plane(
+                        index,
+                        info.size,
+                        info.planes[index].stride,
+                        mapping.read(),
+                    );
+                    (data, stride, info.format.plane_size(index, info.size))
+                }
+                _ => continue,
+            };
+            let layout = wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: std::num::NonZeroU32::new(stride),
+                rows_per_image: std::num::NonZeroU32::new(size[1]),
+            };
+            let size = wgpu::Extent3d {
+                width: size[0],
+                height: size[1],
+                depth_or_array_layers: 1,
+            };
+            texture_writes.push(Self::prepare_texture_write(
+                plane.texture_id,
+                data,
+                size,
+                layout,
+            ));
+        }
+        if !texture_writes.is_empty() {
+            update_context.write_resource(&mut texture_writes);
+        }
+    }
+
+    /// Map the shared memory pool of the source, reusing the mapping of the pool when possible.
+    fn map_pool(
+        &self,
+        source: &SurfaceSource,
+    ) -> Result<Option<Arc<MemoryMapping>>, ScreenTaskError> {
+        match source {
+            SurfaceSource::ShmPool {
+                fd,
+                offset,
+                size,
+                stride,
+                ..
+            } => self
+                .shm_pools
+                .map(*fd, buffer_end(*offset, *stride, size[1])?)
+                .map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    /**
+    Upload the buffer of a shared memory pool source from the mapping of the pool to the texture.
+    The engine takes the ownership of the written data, so the buffer is copied out of the mapping for every device.
+    */
+    fn write_pool(update_context: &mut E, surface: &SurfaceInfo) -> Result<(), ScreenTaskError> {
+        if let (SurfaceSourceInfo::ShmPool(info), Some(pool), Some(plane)) =
+            (&surface.info, &surface.pool, surface.planes.first())
+        {
+            let data = pool.read_range(info.offset as usize, info.length())?;
+            let layout = wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: std::num::NonZeroU32::new(info.stride),
+                rows_per_image: std::num::NonZeroU32::new(info.size[1]),
+            };
+            let size = wgpu::Extent3d {
+                width: info.size[0],
+                height: info.size[1],
+                depth_or_array_layers: 1,
+            };
+            let texture_write = Self::prepare_texture_write(plane.texture_id, data, size, layout);
+            update_context.write_resource(&mut vec![texture_write]);
+        }
+        Ok(())
     }
 
     /// Resize the surface with the provided id.
-    pub fn resize_surface(&mut self, id: &usize, size: [u32; 2]) -> bool {
+    pub fn resize_surface(&mut self, id: &usize, size: [u32; 2]) -> Result<(), ScreenTaskError> {
         log::info!(target: "ScreenTask","Resizing surface {} to {:?}",id,size);
-        let size = [size[0] as f32, size[1] as f32];
-        let offset = field_offset::offset_of!(Surface => size);
-        self.data_buffer.pending_write_field(id, offset, size)
+        check_size(size)?;
+        self.surface_mut(id)?.size = [size[0] as f32, size[1] as f32];
+        self.data_buffer_dirty = true;
+        Ok(())
     }
 
-    /// Move the surface with the provided id.
-    pub fn move_surface(&mut self, id: &usize, position: [i32; 3]) -> bool {
+    /**
+    Move the surface with the provided id, its subsurfaces follow it.
+    Positions of subsurfaces are relative to their parent, and their z is ignored.
+    */
+    pub fn move_surface(&mut self, id: &usize, position: [i32; 3]) -> Result<(), ScreenTaskError> {
         log::info!(target: "ScreenTask","Moving surface {} to {:?}",id,position);
-        let position = [position[0] as f32, position[1] as f32, position[2] as f32];
-        let offset = field_offset::offset_of!(Surface => position);
-        self.data_buffer.pending_write_field(id, offset, position)
+        self.surface_mut(id)?.position = [position[0] as f32, position[1] as f32];
+        self.stacking_order.set_z(*id, position[2])?;
+        self.data_buffer_dirty = true;
+        Ok(())
+    }
+
+    /// Set the opacity of the surface with the provided id. The value is clamped between 0.0 and 1.0.
+    pub fn set_surface_opacity(&mut self, id: &usize, opacity: f32) -> Result<(), ScreenTaskError> {
+        log::info!(target: "ScreenTask","Setting opacity of surface {} to {}",id,opacity);
+        self.surface_mut(id)?.opacity = opacity.clamp(0.0, 1.0);
+        self.data_buffer_dirty = true;
+        Ok(())
+    }
+
+    /// Set the region of the source buffer, in buffer pixels, displayed by the surface with the provided id.
+    pub fn set_surface_source_rect(
+        &mut self,
+        id: &usize,
+        source_rect: [f32; 4],
+    ) -> Result<(), ScreenTaskError> {
+        log::info!(target: "ScreenTask","Setting source rectangle of surface {} to {:?}",id,source_rect);
+        let surface_info = self.surface_mut(id)?;
+        surface_info.info.validate_source_rect(source_rect)?;
+        surface_info.source_rect = Some(source_rect);
+        self.data_buffer_dirty = true;
+        Ok(())
+    }
+
+    /**
+    Set the clip rectangle, as `[x, y, width, height]` in logical coordinates, of the surface with the provided id.
+    Subsurfaces are clipped also by the clip rectangles of their parents. None removes the clipping.
+    */
+    pub fn set_surface_clip_rect(
+        &mut self,
+        id: &usize,
+        clip_rect: Option<[f32; 4]>,
+    ) -> Result<(), ScreenTaskError> {
+        log::info!(target: "ScreenTask","Setting clip rectangle of surface {} to {:?}",id,clip_rect);
+        if let Some(clip_rect) = clip_rect {
+            check_clip_rect(clip_rect)?;
+        }
+        self.surface_mut(id)?.clip_rect = clip_rect;
+        self.data_buffer_dirty = true;
+        Ok(())
+    }
+
+    /// Set the rounded corners and the border of the surface with the provided id.
+    pub fn set_surface_decoration(
+        &mut self,
+        id: &usize,
+        decoration: SurfaceDecoration,
+    ) -> Result<(), ScreenTaskError> {
+        log::info!(target: "ScreenTask","Setting decoration of surface {} to {:?}",id,decoration);
+        self.surface_mut(id)?.decoration = decoration;
+        self.data_buffer_dirty = true;
+        Ok(())
+    }
+
+    /**
+    Set the drop shadow of the surface with the provided id, None removes it.
+    Returns true if the number of instances changed, so the command buffers need to be updated.
+    */
+    pub fn set_surface_shadow(
+        &mut self,
+        id: &usize,
+        shadow: Option<SurfaceShadow>,
+    ) -> Result<bool, ScreenTaskError> {
+        log::info!(target: "ScreenTask","Setting shadow of surface {} to {:?}",id,shadow);
+        let surface_info = self.surface_mut(id)?;
+        let instances_changed = surface_info.shadow.is_some() != shadow.is_some();
+        surface_info.shadow = shadow;
+        self.data_buffer_dirty = true;
+        Ok(instances_changed)
+    }
+
+    /// Enable or disable the blur of the content behind the surface with the provided id.
+    pub fn set_surface_background_blur(
+        &mut self,
+        id: &usize,
+        enabled: bool,
+    ) -> Result<(), ScreenTaskError> {
+        log::info!(target: "ScreenTask","Setting background blur of surface {} to {}",id,enabled);
+        self.surface_mut(id)?.background_blur = enabled;
+        Ok(())
     }
 
-    /// Remove the surface with the provided id.
-    pub fn remove_surface(&mut self, update_context: &mut UpdateContext, id: &usize) -> bool {
+    /// Set the transform of the surface with the provided id.
+    pub fn set_surface_transform(
+        &mut self,
+        id: &usize,
+        transform: SurfaceTransform,
+    ) -> Result<(), ScreenTaskError> {
+        log::info!(target: "ScreenTask","Setting transform of surface {} to {:?}",id,transform);
+        self.surface_mut(id)?.transform = transform;
+        self.data_buffer_dirty = true;
+        Ok(())
+    }
+
+    /// Remove the surface with the provided id, together with its subsurfaces.
+    pub fn remove_surface(
+        &mut self,
+        update_context: &mut E,
+        id: &usize,
+    ) -> Result<(), ScreenTaskError> {
         log::info!(target: "ScreenTask","Removing surface {}",id);
-        if let Some(associated_data) = self.data_buffer.release_pending(id) {
-            let index = self
-                .stack
-                .iter()
-                .position(|current_id| current_id == id)
-                .unwrap();
-            self.stack.remove(index);
-            update_context
-                .remove_texture_view(&associated_data.texture_view_id)
-                .unwrap();
-            update_context
-                .remove_texture(&associated_data.texture_id)
-                .unwrap();
+        if !self.surfaces.contains_key(id) {
+            return Err(ScreenTaskError::UnknownSurface(*id));
+        }
+        let removed = self.stacking_order.remove(*id)?;
+        self.data_buffer_dirty = true;
+        let mut result = Ok(());
+        for removed_id in removed {
+            if let Some(associated_data) = self.surfaces.remove(&removed_id) {
+                result = result.and(Self::remove_planes(update_context, &associated_data.planes));
+            }
+        }
+        result
+    }
 
-            true
-        } else {
-            log::error!(target: "ScreenTask","Failed to remove surface {} because it does not exists",id);
-            false
+    /**
+    Set the wallpaper of the output with the provided id, laid out on the provided output area.
+    The previous wallpaper of the output, if any, is replaced.
+    Like the surfaces, Dma buffers that can not be imported are copied through host memory.
+    */
+    pub fn set_wallpaper(
+        &mut self,
+        update_context: &mut E,
+        output: usize,
+        source: SurfaceSource,
+        mode: WallpaperMode,
+        area: [f32; 4],
+    ) -> Result<(), ScreenTaskError> {
+        log::info!(target: "ScreenTask","Setting wallpaper of output {}",output);
+        let info = SurfaceSourceInfo::from(&source);
+        let size = info.size();
+        let pool = self.map_pool(&source)?;
+        let plane_mappings = self.map_dmabuf(&source)?;
+        let planes = self.create_planes(update_context, format!("Wallpaper {}", output), source)?;
+        let mut wallpaper = Wallpaper {
+            surface: SurfaceInfo::new(planes, info, [0, 0], size),
+            mode,
+        };
+        wallpaper.surface.pool = pool;
+        wallpaper.surface.plane_mappings = plane_mappings;
+        Self::write_dmabuf(update_context, &wallpaper.surface);
+        if let Err(error) = Self::write_pool(update_context, &wallpaper.surface) {
+            Self::remove_planes(update_context, &wallpaper.surface.planes)?;
+            return Err(error);
+        }
+        wallpaper.set_area(area);
+        self.data_buffer_dirty = true;
+        match self.wallpapers.insert(output, wallpaper) {
+            Some(old_wallpaper) => {
+                Self::remove_planes(update_context, &old_wallpaper.surface.planes)
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Lay out again the wallpaper of the output with the provided id, after the output area changed.
+    pub fn set_wallpaper_area(&mut self, output: usize, area: [f32; 4]) {
+        if let Some(wallpaper) = self.wallpapers.get_mut(&output) {
+            wallpaper.set_area(area);
+            self.data_buffer_dirty = true;
         }
     }
 
-    /// Returns the TextureViewIds of all the stored
+    /// Remove the wallpaper of the output with the provided id. Returns true if the output had a wallpaper.
+    pub fn remove_wallpaper(
+        &mut self,
+        update_context: &mut E,
+        output: usize,
+    ) -> Result<bool, ScreenTaskError> {
+        match self.wallpapers.remove(&output) {
+            Some(wallpaper) => {
+                log::info!(target: "ScreenTask","Removing wallpaper of output {}",output);
+                self.data_buffer_dirty = true;
+                Self::remove_planes(update_context, &wallpaper.surface.planes)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Returns how many wallpapers are stored, they occupy the first slots of the buffer.
+    pub fn wallpaper_count(&self) -> usize {
+        self.wallpapers.len()
+    }
+
+    /// Returns the buffer slot of the wallpaper of the output with the provided id.
+    pub fn wallpaper_slot(&self, output: usize) -> Option<u32> {
+        self.wallpapers
+            .keys()
+            .position(|id| *id == output)
+            .map(|slot| slot as u32)
+    }
+
+    /**
+    Returns the TextureViewIds of all the stored surfaces ordered by surface id, with the planes of each surface
+    in consecutive slots, followed by the ones of the wallpapers.
+    The view of the placeholder is returned when there are no planes, so the texture array is never empty.
+    */
     pub fn rectangle_views(&self) -> Vec<TextureViewId> {
-        self.stack
+        let mut views: Vec<_> = self
+            .surfaces
+            .values()
+            .chain(self.wallpapers.values().map(|wallpaper| &wallpaper.surface))
+            .flat_map(|surface_info| &surface_info.planes)
+            .map(|plane| plane.texture_view_id)
+            .collect();
+        if views.is_empty() {
+            views.extend(self.placeholder.iter().map(|plane| plane.texture_view_id));
+        }
+        views
+    }
+
+    /// Returns how many texture views are stored, counting every plane of every surface and wallpaper.
+    pub fn view_count(&self) -> usize {
+        self.surfaces
+            .values()
+            .chain(self.wallpapers.values().map(|wallpaper| &wallpaper.surface))
+            .map(|surface_info| surface_info.planes.len())
+            .sum()
+    }
+
+    /// Returns the index in the texture array of the first plane of every surface.
+    fn image_indexes(&self) -> HashMap<usize, u32> {
+        let mut image_index = 0;
+        self.surfaces
             .iter()
-            .map(|id| {
-                self.data_buffer
-                    .associated_data(id)
-                    .map(|associated_data| associated_data.texture_view_id)
-                    .unwrap()
+            .map(|(id, surface_info)| {
+                let current = image_index;
+                image_index += surface_info.planes.len() as u32;
+                (*id, current)
             })
             .collect()
     }
 
-    /// Update the texture indexs associated with the surfaces.
-    pub fn update_image_indexes(&mut self) {
-        self.stack
-            .clone()
-            .into_iter()
-            .enumerate()
-            .for_each(|(image_index, id)| {
-                let offset = field_offset::offset_of!(Surface => image_index);
-                self.data_buffer
-                    .pending_write_field(&id, offset, image_index as u32);
-            });
+    /**
+    Returns the surface ids in drawing order, from the farthest to the nearest.
+    Surfaces with an higher z are drawn first; surfaces with the same z follow the stacking order.
+    */
+    pub fn draw_order(&self) -> Vec<usize> {
+        self.stacking_order.order()
+    }
+
+    /// Place the surface with the provided id on top of every other surface.
+    pub fn raise_surface(&mut self, id: &usize) -> Result<(), ScreenTaskError> {
+        log::info!(target: "ScreenTask","Raising surface {}",id);
+        self.stacking_order.raise(*id)?;
+        self.data_buffer_dirty = true;
+        Ok(())
+    }
+
+    /// Place the surface with the provided id below every other surface.
+    pub fn lower_surface(&mut self, id: &usize) -> Result<(), ScreenTaskError> {
+        log::info!(target: "ScreenTask","Lowering surface {}",id);
+        self.stacking_order.lower(*id)?;
+        self.data_buffer_dirty = true;
+        Ok(())
+    }
+
+    /// Place the surface with the provided id directly above the sibling.
+    pub fn place_above(&mut self, id: &usize, sibling: &usize) -> Result<(), ScreenTaskError> {
+        log::info!(target: "ScreenTask","Placing surface {} above {}",id,sibling);
+        self.stacking_order.place_above(*id, *sibling)?;
+        self.data_buffer_dirty = true;
+        Ok(())
+    }
+
+    /// Place the surface with the provided id directly below the sibling.
+    pub fn place_below(&mut self, id: &usize, sibling: &usize) -> Result<(), ScreenTaskError> {
+        log::info!(target: "ScreenTask","Placing surface {} below {}",id,sibling);
+        self.stacking_order.place_below(*id, *sibling)?;
+        self.data_buffer_dirty = true;
+        Ok(())
+    }
+
+    /// Returns the data of every wallpaper and surface in drawing order, as it is stored in the buffer.
+    pub fn surface_data(&self) -> Vec<Surface> {
+        let mut surface_data = Vec::new();
+
+        // Wallpaper planes follow the ones of the surfaces in the texture array.
+        let mut image_index = self
+            .surfaces
+            .values()
+            .map(|surface_info| surface_info.planes.len() as u32)
+            .sum();
+        for wallpaper in self.wallpapers.values() {
+            let mut data = wallpaper
+                .surface
+                .generate_data(image_index, WALLPAPER_DEPTH);
+            if wallpaper.mode == WallpaperMode::Tile {
+                data.sampling |= SAMPLING_REPEAT;
+            }
+            surface_data.push(data);
+            image_index += wallpaper.surface.planes.len() as u32;
+        }
+
+        // Shadows are drawn right beneath their surface, so they take a rank of their own.
+        let image_indexes = self.image_indexes();
+        let count = self.instance_count();
+        let mut rank = 0;
+        for id in self.draw_order() {
+            let surface_info = &self.surfaces[&id];
+            let mut data = surface_info.generate_data(image_indexes[&id], 0.0);
+            let position = self.absolute_position(id);
+            data.position = [position[0], position[1], 0.0];
+            data.clip_rect = self.clip_bounds(id);
+            if let Some(shadow) = &surface_info.shadow {
+                let mut shadow_data = shadow.generate_data(&data);
+                shadow_data.position[2] = rank_depth(rank, count);
+                surface_data.push(shadow_data);
+                rank += 1;
+            }
+            data.position[2] = rank_depth(rank, count);
+            surface_data.push(data);
+            rank += 1;
+        }
+        surface_data
+    }
+
+    /**
+    Returns the slots in the instance buffer of the surfaces with a blurred background, from the farthest to the nearest.
+    The slots follow the layout of `surface_data`.
+    */
+    pub fn blurred_instances(&self) -> Vec<u32> {
+        self.blurred_slots(|_| true)
+    }
+
+    /**
+    Returns the slots of the surfaces with a blurred background that intersect the provided area,
+    given as `[x, y, width, height]` in logical coordinates, from the farthest to the nearest.
+    */
+    pub fn blurred_instances_in(&self, area: [f32; 4]) -> Vec<u32> {
+        self.blurred_slots(|id| {
+            let bounds = self.bounds(id);
+            bounds[0] < area[0] + area[2]
+                && bounds[1] < area[1] + area[3]
+                && bounds[2] > area[0]
+                && bounds[3] > area[1]
+        })
+    }
+
+    /// Returns the slots of the surfaces with a blurred background accepted by the filter.
+    fn blurred_slots(&self, filter: impl Fn(usize) -> bool) -> Vec<u32> {
+        let mut slot = self.wallpaper_count() as u32;
+        let mut blurred_instances = Vec::new();
+        for id in self.draw_order() {
+            let surface_info = &self.surfaces[&id];
+            if surface_info.shadow.is_some() {
+                slot += 1;
+            }
+            if surface_info.background_blur && filter(id) {
+                blurred_instances.push(slot);
+            }
+            slot += 1;
+        }
+        blurred_instances
+    }
+
+    /// Returns the area covered by the surface once transformed and clipped, in [min_x, min_y, max_x, max_y] form.
+    pub fn bounds(&self, id: usize) -> [f32; 4] {
+        let surface_info = &self.surfaces[&id];
+        let position = self.absolute_position(id);
+        let size = surface_info.size;
+        let mut bounds = [
+            f32::INFINITY,
+            f32::INFINITY,
+            f32::NEG_INFINITY,
+            f32::NEG_INFINITY,
+        ];
+        // Corners are transformed around the center of the surface, like the vertex shader does.
+        for corner in [[-0.5, -0.5], [0.5, -0.5], [-0.5, 0.5], [0.5, 0.5]].iter() {
+            let transformed = apply_affine(
+                &surface_info.transform.matrix,
+                [corner[0] * size[0], corner[1] * size[1]],
+            );
+            let x = position[0] + size[0] * 0.5 + transformed[0];
+            let y = position[1] + size[1] * 0.5 + transformed[1];
+            bounds = [
+                bounds[0].min(x),
+                bounds[1].min(y),
+                bounds[2].max(x),
+                bounds[3].max(y),
+            ];
+        }
+        intersect_clip(bounds, self.clip_bounds(id))
+    }
+
+    /// Returns how many instances are drawn for the surfaces: one for each surface and one for each shadow.
+    pub fn instance_count(&self) -> usize {
+        let shadow_count = self
+            .surfaces
+            .values()
+            .filter(|surface_info| surface_info.shadow.is_some())
+            .count();
+        self.surfaces.len() + shadow_count
+    }
+
+    /// Returns the clip rectangle of the surface, in [min_x, min_y, max_x, max_y] form, intersected with the ones of its parents.
+    pub fn clip_bounds(&self, id: usize) -> [f32; 4] {
+        let clip_bounds = self.surfaces[&id].clip_bounds();
+        match self.stacking_order.parent(id) {
+            Some(parent) => intersect_clip(clip_bounds, self.clip_bounds(parent)),
+            None => clip_bounds,
+        }
+    }
+
+    /// Returns the position of the surface in logical coordinates, adding the positions of its parents.
+    pub fn absolute_position(&self, id: usize) -> [f32; 2] {
+        let position = self.surfaces[&id].position;
+        match self.stacking_order.parent(id) {
+            Some(parent) => {
+                let parent_position = self.absolute_position(parent);
+                [
+                    parent_position[0] + position[0],
+                    parent_position[1] + position[1],
+                ]
+            }
+            None => position,
+        }
     }
 
     /// Update buffer data and returns eventual commands that need to be scheduled with a command buffer.
-    pub fn update(&mut self, update_context: &mut UpdateContext) -> Vec<Command> {
-        self.data_buffer.update(update_context)
+    pub fn update(&mut self, update_context: &mut E) -> Vec<Command> {
+        if self.data_buffer_dirty {
+            let surface_data = self.surface_data();
+            update_context.write_surface_buffer(&mut self.data_buffer, surface_data);
+            self.data_buffer_dirty = false;
+        }
+        update_context.update_surface_buffer(&mut self.data_buffer)
     }
 }
+
+/**
+Returns the depth of the surface at the provided rank of the drawing order, where rank 0 is the farthest.
+Every rank gets a distinct depth in the (0, 1) range, so any z value and any number of surfaces can be drawn
+without clipping or depth fighting; the depth 1.0 is left to the wallpapers.
+*/
+pub fn rank_depth(rank: usize, count: usize) -> f32 {
+    (count - rank) as f32 / (count + 1) as f32
+}
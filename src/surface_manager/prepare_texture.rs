@@ -1,14 +1,29 @@
+use crate::engine::Engine;
+use crate::error::ScreenTaskError;
+use crate::fourcc::DRM_FORMAT_MOD_LINEAR;
 use crate::surface::SurfaceSource;
 use crate::surface_manager::SurfaceManager;
+use crate::yuv::YuvFormat;
 use wgpu_engine::*;
 
-impl SurfaceManager {
-    /// Generate the texture descriptor, image data layout and the eventual data from a SurfaceSource.
+/// Descriptor, eventual initial data and data layout of the texture of a plane.
+pub type PlaneTexture = (TextureDescriptor, Option<Vec<u8>>, wgpu::ImageDataLayout);
+
+impl<E: Engine> SurfaceManager<E> {
+    /**
+    Generate the texture descriptors, image data layouts and the eventual data from a SurfaceSource.
+    Multi-plane sources generate one texture for each plane, solid colors none.
+    Without external memory, Dma buffers are copied into local textures.
+    Buffers of shared memory pools and copied Dma buffers have no data here, they are uploaded from their mappings.
+    Fails if the source does not describe a valid buffer.
+    */
     pub fn prepare_texture(
         device: DeviceId,
         label: String,
         source: SurfaceSource,
-    ) -> (TextureDescriptor, Option<Vec<u8>>, wgpu::ImageDataLayout) {
+        external_memory: bool,
+    ) -> Result<Vec<PlaneTexture>, ScreenTaskError> {
+        source.validate()?;
         let width;
         let height;
         let depth_or_array_layers;
@@ -58,53 +73,160 @@ impl SurfaceManager {
                 texture_source = TextureSource::Local;
                 texture_format = info.format
             }
+            SurfaceSource::ShmPool {
+                size,
+                stride,
+                format,
+                ..
+            } => {
+                width = size[0];
+                height = size[1];
+                depth_or_array_layers = 1;
+                image_layout = wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: std::num::NonZeroU32::new(stride),
+                    rows_per_image: std::num::NonZeroU32::new(height),
+                };
+
+                texture_data = None;
+                texture_source = TextureSource::Local;
+                texture_format = format
+            }
+            SurfaceSource::Dmabuf { info } if !external_memory => {
+                check_host_copy(info.modifier)?;
+                let plane = &info.planes[0];
+                width = info.size[0];
+                height = info.size[1];
+                depth_or_array_layers = 1;
+                image_layout = wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: std::num::NonZeroU32::new(plane.stride),
+                    rows_per_image: std::num::NonZeroU32::new(height),
+                };
+                texture_data = None;
+                texture_source = TextureSource::Local;
+                texture_format = info.fourcc.texture_format()?;
+            }
             SurfaceSource::Dmabuf { info } => {
                 width = info.size[0];
                 height = info.size[1];
                 depth_or_array_layers = 1;
                 image_layout = wgpu::ImageDataLayout {
                     offset: 0,
-                    bytes_per_row: std::num::NonZeroU32::new(info.plane_stride),
+                    bytes_per_row: std::num::NonZeroU32::new(info.planes[0].stride),
                     rows_per_image: std::num::NonZeroU32::new(height),
                 };
                 texture_data = None;
-                use std::os::unix::io::AsRawFd;
-
-                let plane_layouts: Vec<wgpu_engine::PlaneLayout> = vec![PlaneLayout {
-                    slice: info.plane_offset
-                        ..(info.plane_stride * info.size[0] * info.size[1]) as u64,
-                    row_pitch: info.plane_stride as u64,
-                    array_pitch: 1,
-                    depth_pitch: 1,
-                }];
 
                 let drm_properties = wgpu_engine::DrmFormatImageProperties {
                     drm_modifier: info.modifier,
-                    plane_layouts,
+                    plane_layouts: info.plane_layouts(),
                 };
                 texture_source = TextureSource::DmaBuf {
-                    fd: info.fd.as_raw_fd(),
+                    fd: info.import_fd()?,
                     drm_properties: Some(drm_properties),
                     offset: 0,
                 };
-                texture_format = wgpu::TextureFormat::Rgba8UnormSrgb
-            } /*
-              SurfaceSource::OpaqueFd { offset, fd , size, stride, format}=>{
-                  width = size[0];
-                  height = size[1];
-                  depth_or_array_layers = 1;
-                  use std::os::unix::io::AsRawFd;
-                  texture_source = TextureSource::OpaqueFd{fd: fd.as_raw_fd(), offset};
-                  image_layout = wgpu::ImageDataLayout {
-                      offset: 0,
-                      bytes_per_row: std::num::NonZeroU32::new(stride),
-                      rows_per_image: std::num::NonZeroU32::new(height),
-                  };
+                texture_format = info.fourcc.texture_format()?;
+            }
+            SurfaceSource::YuvHostAllocation { info, planes } => {
+                return Ok(planes
+                    .into_iter()
+                    .zip(info.strides.iter())
+                    .enumerate()
+                    .take(info.format.plane_count())
+                    .map(|(plane, (data, stride))| {
+                        let plane_size = info.format.plane_size(plane, info.size);
+                        let (data, stride) =
+                            info.format.convert_plane(plane, info.size, *stride, data);
+                        let image_layout = wgpu::ImageDataLayout {
+                            offset: 0,
+                            bytes_per_row: std::num::NonZeroU32::new(stride),
+                            rows_per_image: std::num::NonZeroU32::new(plane_size[1]),
+                        };
+                        let descriptor = Self::prepare_plane_texture(
+                            device,
+                            format!("{} plane {}", label, plane),
+                            TextureSource::Local,
+                            plane_size,
+                            info.format.plane_texture_format(plane),
+                        );
+                        (descriptor, Some(data), image_layout)
+                    })
+                    .collect());
+            }
+            SurfaceSource::YuvDmabuf { info } => {
+                use std::os::unix::io::AsRawFd;
+                if !external_memory {
+                    check_host_copy(info.modifier)?;
+                } else if info.format == YuvFormat::P010 {
+                    // The planes are sampled as half floats, so they need the conversion of the copy.
+                    return Err(ScreenTaskError::UnsupportedFormat(String::from(
+                        "P010 Dma buffers with external memory",
+                    )));
+                }
+                return info
+                    .planes
+                    .iter()
+                    .enumerate()
+                    .take(info.format.plane_count())
+                    .map(|(plane, plane_info)| {
+                        let plane_size = info.format.plane_size(plane, info.size);
+                        let image_layout = wgpu::ImageDataLayout {
+                            offset: 0,
+                            bytes_per_row: std::num::NonZeroU32::new(plane_info.stride),
+                            rows_per_image: std::num::NonZeroU32::new(plane_size[1]),
+                        };
+                        let plane_layouts = vec![PlaneLayout {
+                            slice: plane_info.offset
+                                ..plane_info.offset
+                                    + plane_info.stride as u64 * plane_size[1] as u64,
+                            row_pitch: plane_info.stride as u64,
+                            array_pitch: 1,
+                            depth_pitch: 1,
+                        }];
+                        let drm_properties = wgpu_engine::DrmFormatImageProperties {
+                            drm_modifier: info.modifier,
+                            plane_layouts,
+                        };
+                        let texture_source = if external_memory {
+                            TextureSource::DmaBuf {
+                                fd: plane_info.fd.as_raw_fd(),
+                                drm_properties: Some(drm_properties),
+                                offset: 0,
+                            }
+                        } else {
+                            TextureSource::Local
+                        };
+                        let descriptor = Self::prepare_plane_texture(
+                            device,
+                            format!("{} plane {}", label, plane),
+                            texture_source,
+                            plane_size,
+                            info.format.plane_texture_format(plane),
+                        );
+                        Ok((descriptor, None, image_layout))
+                    })
+                    .collect();
+            }
+            SurfaceSource::SolidColor(_) => return Ok(Vec::new()),
+            /*
+            SurfaceSource::OpaqueFd { offset, fd , size, stride, format}=>{
+                width = size[0];
+                height = size[1];
+                depth_or_array_layers = 1;
+                use std::os::unix::io::AsRawFd;
+                texture_source = TextureSource::OpaqueFd{fd: fd.as_raw_fd(), offset};
+                image_layout = wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: std::num::NonZeroU32::new(stride),
+                    rows_per_image: std::num::NonZeroU32::new(height),
+                };
 
-                  texture_data = None;
-                  texture_format = format;
-              },
-              */
+                texture_data = None;
+                texture_format = format;
+            },
+            */
         }
 
         let descriptor = TextureDescriptor {
@@ -123,6 +245,43 @@ impl SurfaceManager {
             usage: wgpu::TextureUsage::SAMPLED | wgpu::TextureUsage::COPY_DST,
         };
 
-        (descriptor, texture_data, image_layout)
+        Ok(vec![(descriptor, texture_data, image_layout)])
+    }
+
+    /// Generate the texture descriptor of a single plane of a multi-plane source.
+    fn prepare_plane_texture(
+        device: DeviceId,
+        label: String,
+        source: TextureSource,
+        size: [u32; 2],
+        format: wgpu::TextureFormat,
+    ) -> TextureDescriptor {
+        TextureDescriptor {
+            device,
+            label: label + " texture",
+            source,
+            size: wgpu::Extent3d {
+                width: size[0],
+                height: size[1],
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format,
+            usage: wgpu::TextureUsage::SAMPLED | wgpu::TextureUsage::COPY_DST,
+        }
+    }
+}
+
+/// Check that a Dma buffer with the provided modifier can be copied through a cpu mapping.
+fn check_host_copy(modifier: DrmModifier) -> Result<(), ScreenTaskError> {
+    if modifier == DRM_FORMAT_MOD_LINEAR {
+        Ok(())
+    } else {
+        Err(ScreenTaskError::UnsupportedFormat(format!(
+            "Dma buffers with modifier {:#x} without external memory",
+            modifier.0
+        )))
     }
 }
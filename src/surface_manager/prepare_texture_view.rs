@@ -1,7 +1,8 @@
+use crate::engine::Engine;
 use crate::surface_manager::SurfaceManager;
 use wgpu_engine::*;
 
-impl SurfaceManager {
+impl<E: Engine> SurfaceManager<E> {
     /// Generate the texture view descriptor from a texture.
     pub fn prepare_texture_view(
         &self,
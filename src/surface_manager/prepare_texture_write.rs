@@ -1,7 +1,8 @@
+use crate::engine::Engine;
 use crate::surface_manager::SurfaceManager;
 use wgpu_engine::*;
 
-impl SurfaceManager {
+impl<E: Engine> SurfaceManager<E> {
     /// Generate the resource write for the provided texture.
     pub fn prepare_texture_write(
         texture: TextureId,
@@ -18,4 +19,26 @@ impl SurfaceManager {
             size,
         })
     }
+
+    /// Generate the resource write for a region of the provided texture, starting at the provided origin.
+    pub fn prepare_texture_region_write(
+        texture: TextureId,
+        data: Vec<u8>,
+        origin: [u32; 2],
+        size: wgpu::Extent3d,
+        layout: wgpu::ImageDataLayout,
+    ) -> ResourceWrite {
+        ResourceWrite::Texture(TextureWrite {
+            texture,
+            mip_level: 0,
+            origin: wgpu::Origin3d {
+                x: origin[0],
+                y: origin[1],
+                z: 0,
+            },
+            data,
+            layout,
+            size,
+        })
+    }
 }
@@ -0,0 +1,151 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+
+/**
+Fence signaled once the client finished rendering into a buffer, like the acquire point of linux-drm-syncobj.
+Timeline points need the DRM device that owns the syncobj, so they are implemented by the caller.
+*/
+pub trait AcquireFence: fmt::Debug + Send {
+    /// Returns true if the buffer can be sampled. It must not block.
+    fn is_signaled(&self) -> bool;
+}
+
+/// Acquire fence backed by a sync_file, signaled when its file descriptor becomes readable.
+#[derive(Debug)]
+pub struct SyncFileFence {
+    file: std::fs::File,
+}
+impl FromRawFd for SyncFileFence {
+    /// Take the ownership of the sync_file, which is closed when the fence is dropped.
+    unsafe fn from_raw_fd(fd: RawFd) -> Self {
+        Self {
+            file: std::fs::File::from_raw_fd(fd),
+        }
+    }
+}
+impl AcquireFence for SyncFileFence {
+    fn is_signaled(&self) -> bool {
+        let mut poll_fd = libc::pollfd {
+            fd: self.file.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let ready = unsafe { libc::poll(&mut poll_fd, 1, 0) };
+        ready > 0 && poll_fd.revents & libc::POLLIN != 0
+    }
+}
+
+/// Explicit synchronization of a buffer committed by a client.
+#[derive(Debug, Default)]
+pub struct BufferSync {
+    /// The commit is applied only once the fence is signaled; None applies it immediately.
+    pub acquire: Option<Box<dyn AcquireFence>>,
+    /// Token reported by `ScreenTask::drain_released_buffers` once the gpu stopped sampling the buffer.
+    pub release: Option<u64>,
+}
+impl BufferSync {
+    /// Returns true if the acquire fence, if any, is signaled.
+    pub fn is_acquired(&self) -> bool {
+        self.acquire
+            .as_ref()
+            .is_none_or(|fence| fence.is_signaled())
+    }
+}
+
+/// Notification that a buffer of a surface is no longer sampled, so the client can reuse it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BufferRelease {
+    pub surface: usize,
+    /// Release token provided with the buffer.
+    pub token: u64,
+}
+
+/**
+Tracks the buffers sampled by every surface and releases them once the frames sampling them have completed.
+A buffer replaced by the frame that applied it was never sampled, so it is released immediately.
+*/
+#[derive(Debug, Default)]
+pub(crate) struct ReleaseTracker {
+    /// Serial of the frame being prepared, starting from 1.
+    frame: u64,
+    /// Serial of the last frame completed by the gpu.
+    completed_frame: u64,
+    /// Release token of the current buffer of every surface, with the frame that applied it.
+    buffers: HashMap<usize, (u64, u64)>,
+    /// Releases waiting for the completion of the frame that sampled their buffer for the last time.
+    pending: Vec<(u64, BufferRelease)>,
+    released: Vec<BufferRelease>,
+}
+impl ReleaseTracker {
+    /// Start preparing a new frame.
+    pub fn begin_frame(&mut self) {
+        self.frame += 1;
+    }
+
+    /// Returns the serial of the frame being prepared, or of the last prepared one.
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    /// Replace the buffer of the surface, releasing the previous one once it is no longer sampled.
+    pub fn attach(&mut self, surface: usize, release: Option<u64>) {
+        self.retire(surface);
+        if let Some(token) = release {
+            self.buffers.insert(surface, (self.frame, token));
+        }
+    }
+
+    /// Release a buffer that was never sampled, because its surface failed to apply it.
+    pub fn discard(&mut self, surface: usize, release: Option<u64>) {
+        if let Some(token) = release {
+            self.released.push(BufferRelease { surface, token });
+        }
+    }
+
+    /// Release the buffers of the surfaces for which the predicate returns false, as they were removed.
+    pub fn retain(&mut self, mut predicate: impl FnMut(usize) -> bool) {
+        let removed: Vec<usize> = self
+            .buffers
+            .keys()
+            .copied()
+            .filter(|surface| !predicate(*surface))
+            .collect();
+        for surface in removed {
+            self.retire(surface);
+        }
+    }
+
+    /// Register the completion of every frame up to the provided serial.
+    pub fn complete(&mut self, frame: u64) {
+        self.completed_frame = self.completed_frame.max(frame);
+        let completed_frame = self.completed_frame;
+        let released = &mut self.released;
+        self.pending.retain(|(last_frame, release)| {
+            if *last_frame <= completed_frame {
+                released.push(*release);
+                false
+            } else {
+                true
+            }
+        });
+    }
+
+    /// Returns the buffers released since the last call.
+    pub fn drain(&mut self) -> Vec<BufferRelease> {
+        std::mem::take(&mut self.released)
+    }
+
+    /// Stop sampling the current buffer of the surface.
+    fn retire(&mut self, surface: usize) {
+        if let Some((applied_frame, token)) = self.buffers.remove(&surface) {
+            let release = BufferRelease { surface, token };
+            let last_frame = self.frame.saturating_sub(1);
+            if applied_frame == self.frame || last_frame <= self.completed_frame {
+                self.released.push(release);
+            } else {
+                self.pending.push((last_frame, release));
+            }
+        }
+    }
+}
@@ -27,20 +27,26 @@ fn swap_test() {
             let mut screen_task = ScreenTask::new(update_context);
             //screen_task.create_surface(0, String::from("surface"), SurfaceSource::File{path: std::path::PathBuf::from("./gfx_logo.png")}, [0,0,0], [100,100]);
             //screen_task.create_surface(1, String::from("surface"), SurfaceSource::File{path: std::path::PathBuf::from("./gfx_logo.png")}, [50,50,1], [100,100]);
-            screen_task.create_surface(
-                0,
-                String::from("surface"),
-                SurfaceSource::from_file_path(std::path::PathBuf::from("./gfx_logo.png")),
-                [0, 0, 0],
-                [100, 100],
-            );
-            screen_task.create_surface(
-                1,
-                String::from("surface"),
-                SurfaceSource::from_file_path(std::path::PathBuf::from("./gfx_logo.png")),
-                [0, 0, 1],
-                [200, 200],
-            );
+            screen_task
+                .create_surface(
+                    0,
+                    String::from("surface"),
+                    SurfaceSource::from_file_path(std::path::PathBuf::from("./gfx_logo.png"))
+                        .unwrap(),
+                    [0, 0, 0],
+                    [100, 100],
+                )
+                .unwrap();
+            screen_task
+                .create_surface(
+                    1,
+                    String::from("surface"),
+                    SurfaceSource::from_file_path(std::path::PathBuf::from("./gfx_logo.png"))
+                        .unwrap(),
+                    [0, 0, 1],
+                    [200, 200],
+                )
+                .unwrap();
             screen_task
         },
         |screen_task| {
@@ -49,11 +55,11 @@ fn swap_test() {
                 upper = !upper;
                 println!("Swapping surfaces!");
                 if upper {
-                    screen_task.move_surface(0, [0, 0, 0]);
-                    screen_task.move_surface(1, [0, 0, 1]);
+                    screen_task.move_surface(0, [0, 0, 0]).unwrap();
+                    screen_task.move_surface(1, [0, 0, 1]).unwrap();
                 } else {
-                    screen_task.move_surface(1, [0, 0, 0]);
-                    screen_task.move_surface(0, [0, 0, 1]);
+                    screen_task.move_surface(1, [0, 0, 0]).unwrap();
+                    screen_task.move_surface(0, [0, 0, 1]).unwrap();
                 }
             }
             std::thread::sleep(std::time::Duration::from_millis(1000 / 60));
@@ -83,18 +89,21 @@ fn destroy_surface_test() {
         limits,
         |_id, _tokio_runtime, update_context| {
             let mut screen_task = ScreenTask::new(update_context);
-            screen_task.create_surface(
-                0,
-                String::from("surface"),
-                SurfaceSource::from_file_path(std::path::PathBuf::from("./gfx_logo.png")),
-                [0, 0, 0],
-                [100, 100],
-            );
+            screen_task
+                .create_surface(
+                    0,
+                    String::from("surface"),
+                    SurfaceSource::from_file_path(std::path::PathBuf::from("./gfx_logo.png"))
+                        .unwrap(),
+                    [0, 0, 0],
+                    [100, 100],
+                )
+                .unwrap();
             screen_task
         },
         |screen_task| {
             if time.elapsed().as_millis() > 3000 && !removed {
-                screen_task.remove_surface(0);
+                screen_task.remove_surface(0).unwrap();
                 removed = true;
             }
             std::thread::sleep(std::time::Duration::from_millis(1000 / 60));
@@ -126,20 +135,26 @@ fn multioutput_test() {
             let mut screen_task = ScreenTask::new(update_context);
             //screen_task.create_surface(0, String::from("surface"), SurfaceSource::File{path: std::path::PathBuf::from("./gfx_logo.png")}, [0,0,0], [100,100]);
             //screen_task.create_surface(1, String::from("surface"), SurfaceSource::File{path: std::path::PathBuf::from("./gfx_logo.png")}, [50,50,1], [100,100]);
-            screen_task.create_surface(
-                0,
-                String::from("surface"),
-                SurfaceSource::from_file_path(std::path::PathBuf::from("./gfx_logo.png")),
-                [0, 0, 0],
-                [100, 100],
-            );
-            screen_task.create_surface(
-                1,
-                String::from("surface"),
-                SurfaceSource::from_file_path(std::path::PathBuf::from("./gfx_logo.png")),
-                [0, 0, 1],
-                [200, 200],
-            );
+            screen_task
+                .create_surface(
+                    0,
+                    String::from("surface"),
+                    SurfaceSource::from_file_path(std::path::PathBuf::from("./gfx_logo.png"))
+                        .unwrap(),
+                    [0, 0, 0],
+                    [100, 100],
+                )
+                .unwrap();
+            screen_task
+                .create_surface(
+                    1,
+                    String::from("surface"),
+                    SurfaceSource::from_file_path(std::path::PathBuf::from("./gfx_logo.png"))
+                        .unwrap(),
+                    [0, 0, 1],
+                    [200, 200],
+                )
+                .unwrap();
             screen_task
         },
         |screen_task| {
@@ -148,11 +163,11 @@ fn multioutput_test() {
                 upper = !upper;
                 println!("Swapping surfaces!");
                 if upper {
-                    screen_task.move_surface(0, [0, 0, 0]);
-                    screen_task.move_surface(1, [0, 0, 1]);
+                    screen_task.move_surface(0, [0, 0, 0]).unwrap();
+                    screen_task.move_surface(1, [0, 0, 1]).unwrap();
                 } else {
-                    screen_task.move_surface(1, [0, 0, 0]);
-                    screen_task.move_surface(0, [0, 0, 1]);
+                    screen_task.move_surface(1, [0, 0, 0]).unwrap();
+                    screen_task.move_surface(0, [0, 0, 1]).unwrap();
                 }
             }
             std::thread::sleep(std::time::Duration::from_millis(1000 / 60));
@@ -162,16 +177,2690 @@ fn multioutput_test() {
 
 #[test]
 fn projection_matrix_test() {
-    use ultraviolet::{Mat4, Vec4};
+    use ultraviolet::Vec4;
     let surface_position = Vec4::new(100.0, 100.0, 0.0, 1.0);
-    let push_constants = PushConstants::new([0, 0], [800, 800], 1024);
+    let push_constants = PushConstants::new([0, 0], [800, 800]);
     println!("{:#?}", push_constants.projection_matrix * surface_position);
 }
 
 #[test]
 fn projection_matrix_test2() {
-    use ultraviolet::{Mat4, Vec4};
+    use ultraviolet::Vec4;
     let surface_position = Vec4::new(0.0, 50.0, 0.0, 1.0);
-    let push_constants = crate::PushConstants::new([0, 50], [100, 100], 1024);
+    let push_constants = crate::PushConstants::new([0, 50], [100, 100]);
     println!("{:#?}", push_constants.projection_matrix * surface_position);
 }
+
+#[test]
+fn transform_uv_corners_test() {
+    use crate::Transform;
+    let corners = [[0.0, 0.0], [0.0, 1.0], [1.0, 0.0], [1.0, 1.0]];
+    for transform in Transform::ALL.iter() {
+        let mut mapped: Vec<[f32; 2]> = corners
+            .iter()
+            .map(|corner| transform.apply_to_uv(*corner))
+            .collect();
+        mapped.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(mapped, corners.to_vec(), "{:?}", transform);
+    }
+
+    assert_eq!(Transform::Rotated90.apply_to_uv([0.0, 0.0]), [1.0, 0.0]);
+    assert_eq!(Transform::Rotated180.apply_to_uv([0.0, 0.0]), [1.0, 1.0]);
+    assert_eq!(Transform::Rotated270.apply_to_uv([0.0, 0.0]), [0.0, 1.0]);
+    assert_eq!(Transform::Flipped.apply_to_uv([0.0, 0.0]), [1.0, 0.0]);
+    assert_eq!(Transform::Flipped270.apply_to_uv([1.0, 0.0]), [0.0, 1.0]);
+}
+
+#[test]
+fn transform_rotation_composition_test() {
+    use crate::Transform;
+    let point = [0.25, 0.75];
+    let mut rotated = point;
+    for _ in 0..4 {
+        rotated = Transform::Rotated90.apply_to_uv(rotated);
+    }
+    assert_eq!(rotated, point);
+
+    let rotated = Transform::Rotated90.apply_to_uv(Transform::Rotated90.apply_to_uv(point));
+    assert_eq!(rotated, Transform::Rotated180.apply_to_uv(point));
+
+    for transform in [
+        Transform::Flipped,
+        Transform::Flipped90,
+        Transform::Flipped180,
+        Transform::Flipped270,
+    ]
+    .iter()
+    {
+        assert_eq!(transform.apply_to_uv(transform.apply_to_uv(point)), point);
+    }
+}
+
+#[test]
+fn surface_transform_rotation_test() {
+    use crate::{apply_affine, SurfaceTransform};
+    let transform = SurfaceTransform::rotation(std::f32::consts::FRAC_PI_2);
+    let rotated = apply_affine(&transform.matrix, [10.0, 0.0]);
+    assert!((rotated[0] - 0.0).abs() < 1e-5);
+    assert!((rotated[1] + 10.0).abs() < 1e-5);
+}
+
+/// Reference YUV to RGB conversion, derived directly from the luma coefficients of the matrix.
+fn reference_yuv_to_rgb(yuv: [u8; 3], color_space: crate::YuvColorSpace) -> [f64; 3] {
+    use crate::{YuvMatrix, YuvRange};
+    let (kr, kb) = match color_space.matrix {
+        YuvMatrix::Bt601 => (0.299, 0.114),
+        YuvMatrix::Bt709 => (0.2126, 0.0722),
+    };
+    let kg = 1.0 - kr - kb;
+    let (y, cb, cr) = match color_space.range {
+        YuvRange::Full => (
+            yuv[0] as f64 / 255.0,
+            (yuv[1] as f64 - 128.0) / 255.0,
+            (yuv[2] as f64 - 128.0) / 255.0,
+        ),
+        YuvRange::Limited => (
+            (yuv[0] as f64 - 16.0) / 219.0,
+            (yuv[1] as f64 - 128.0) / 224.0,
+            (yuv[2] as f64 - 128.0) / 224.0,
+        ),
+    };
+    let r = y + 2.0 * (1.0 - kr) * cr;
+    let b = y + 2.0 * (1.0 - kb) * cb;
+    let g = (y - kr * r - kb * b) / kg;
+    [r, g, b].map(|value| value.clamp(0.0, 1.0))
+}
+
+#[test]
+fn yuv_to_rgb_reference_test() {
+    use crate::{yuv_to_rgb, YuvColorSpace, YuvMatrix, YuvRange};
+    for matrix in [YuvMatrix::Bt601, YuvMatrix::Bt709].iter() {
+        for range in [YuvRange::Full, YuvRange::Limited].iter() {
+            let color_space = YuvColorSpace {
+                matrix: *matrix,
+                range: *range,
+            };
+            for y in (0..=255u32).step_by(15) {
+                for u in (0..=255u32).step_by(15) {
+                    for v in (0..=255u32).step_by(15) {
+                        let yuv = [y as u8, u as u8, v as u8];
+                        let normalized = [y as f32 / 255.0, u as f32 / 255.0, v as f32 / 255.0];
+                        let rgb = yuv_to_rgb(normalized, color_space);
+                        let reference = reference_yuv_to_rgb(yuv, color_space);
+                        for channel in 0..3 {
+                            assert!(
+                                (rgb[channel] as f64 - reference[channel]).abs() < 1.0 / 512.0,
+                                "{:?} {:?}: {:?} != {:?}",
+                                color_space,
+                                yuv,
+                                rgb,
+                                reference
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn yuv_to_rgb_known_colors_test() {
+    use crate::{yuv_to_rgb, YuvColorSpace, YuvMatrix, YuvRange};
+    let normalize = |yuv: [u8; 3]| yuv.map(|value| value as f32 / 255.0);
+    // Encoded values are rounded, so allow an error of one unit.
+    let assert_rgb = |rgb: [f32; 3], expected: [u8; 3]| {
+        for channel in 0..3 {
+            let value = rgb[channel] * 255.0;
+            assert!(
+                (value - expected[channel] as f32).abs() <= 1.0,
+                "{:?} != {:?}",
+                rgb,
+                expected
+            );
+        }
+    };
+
+    let limited_601 = YuvColorSpace {
+        matrix: YuvMatrix::Bt601,
+        range: YuvRange::Limited,
+    };
+    assert_rgb(
+        yuv_to_rgb(normalize([16, 128, 128]), limited_601),
+        [0, 0, 0],
+    );
+    assert_rgb(
+        yuv_to_rgb(normalize([235, 128, 128]), limited_601),
+        [255, 255, 255],
+    );
+    assert_rgb(
+        yuv_to_rgb(normalize([81, 90, 240]), limited_601),
+        [255, 0, 0],
+    );
+
+    let full_709 = YuvColorSpace {
+        matrix: YuvMatrix::Bt709,
+        range: YuvRange::Full,
+    };
+    assert_rgb(yuv_to_rgb(normalize([0, 128, 128]), full_709), [0, 0, 0]);
+    assert_rgb(
+        yuv_to_rgb(normalize([255, 128, 128]), full_709),
+        [255, 255, 255],
+    );
+    assert_rgb(yuv_to_rgb(normalize([182, 30, 12]), full_709), [0, 255, 0]);
+}
+
+#[test]
+fn yuv_plane_layout_test() {
+    use crate::YuvFormat;
+    assert_eq!(YuvFormat::Nv12.plane_count(), 2);
+    assert_eq!(YuvFormat::Yuv420.plane_count(), 3);
+    assert_eq!(YuvFormat::Nv12.plane_size(0, [5, 3]), [5, 3]);
+    assert_eq!(YuvFormat::Nv12.plane_size(1, [5, 3]), [3, 2]);
+    assert_eq!(
+        YuvFormat::Nv12.plane_texture_format(1),
+        wgpu::TextureFormat::Rg8Unorm
+    );
+    assert_eq!(
+        YuvFormat::Yuv420.plane_texture_format(2),
+        wgpu::TextureFormat::R8Unorm
+    );
+
+    assert_eq!(
+        YuvFormat::P010.plane_texture_format(1),
+        wgpu::TextureFormat::Rg16Float
+    );
+
+    // 2x2 P010 luma plane with a stride of 6 bytes, uploaded as half floats without losing the 10 bits.
+    let samples: [u16; 4] = [0xFFC0, 0x0040, 0x8000, 0x1040];
+    let mut luma = Vec::new();
+    for row in samples.chunks(2) {
+        luma.extend(row.iter().flat_map(|sample| sample.to_le_bytes()));
+        luma.extend([0xAA, 0xAA]);
+    }
+    let (converted, stride) = YuvFormat::P010.convert_plane(0, [2, 2], 6, luma);
+    assert_eq!(stride, 4);
+    let decoded: Vec<u16> = converted
+        .chunks_exact(2)
+        .map(|bits| {
+            let value = crate::software::half_float_value(u16::from_le_bytes([bits[0], bits[1]]));
+            (value * 65535.0 / 64.0).round() as u16
+        })
+        .collect();
+    let expected: Vec<u16> = samples.iter().map(|sample| sample >> 6).collect();
+    assert_eq!(decoded, expected);
+
+    // 1x1 interleaved P010 chroma plane.
+    let chroma = vec![0x00, 0x40, 0x00, 0xC0];
+    let (converted, stride) = YuvFormat::P010.convert_plane(1, [2, 2], 4, chroma);
+    assert_eq!(stride, 4);
+    assert_eq!(converted.len(), 4);
+}
+
+#[test]
+fn merge_damage_overlapping_test() {
+    use crate::merge_damage;
+    let merged = merge_damage(&[[0, 0, 10, 10], [5, 5, 10, 10]], [100, 100]);
+    assert_eq!(merged, vec![[0, 0, 15, 15]]);
+
+    // Adjacent rectangles are merged too.
+    let merged = merge_damage(&[[0, 0, 10, 10], [10, 0, 10, 10]], [100, 100]);
+    assert_eq!(merged, vec![[0, 0, 20, 10]]);
+
+    // A merge that creates a new overlap keeps merging.
+    let merged = merge_damage(
+        &[[0, 0, 10, 10], [50, 0, 10, 10], [5, 0, 50, 5]],
+        [100, 100],
+    );
+    assert_eq!(merged, vec![[0, 0, 60, 10]]);
+}
+
+#[test]
+fn merge_damage_disjoint_test() {
+    use crate::merge_damage;
+    let rects = vec![[0, 0, 10, 10], [50, 50, 10, 10]];
+    assert_eq!(merge_damage(&rects, [100, 100]), rects);
+}
+
+#[test]
+fn merge_damage_clamp_test() {
+    use crate::merge_damage;
+    let merged = merge_damage(
+        &[[90, 90, 50, 50], [200, 200, 10, 10], [0, 0, 0, 10]],
+        [100, 100],
+    );
+    assert_eq!(merged, vec![[90, 90, 10, 10]]);
+}
+
+#[test]
+fn merge_damage_limit_test() {
+    use crate::{merge_damage, MAX_DAMAGE_RECTS};
+    let rects: Vec<[u32; 4]> = (0..20).map(|index| [index * 20, 0, 10, 10]).collect();
+    let merged = merge_damage(&rects, [1000, 1000]);
+    assert!(merged.len() <= MAX_DAMAGE_RECTS);
+    for rect in rects.iter() {
+        assert!(merged.iter().any(|merged| merged[0] <= rect[0]
+            && merged[1] <= rect[1]
+            && merged[0] + merged[2] >= rect[0] + rect[2]
+            && merged[1] + merged[3] >= rect[1] + rect[3]));
+    }
+}
+
+#[test]
+fn extract_rect_test() {
+    use crate::extract_rect;
+    // 4x3 buffer with 2 bytes per pixel and a stride of 10 bytes.
+    let data: Vec<u8> = (0..30).collect();
+    assert_eq!(
+        extract_rect(&data, 10, 2, [1, 1, 2, 2]),
+        vec![12, 13, 14, 15, 22, 23, 24, 25]
+    );
+    // Rows outside of a truncated buffer are filled with zeros.
+    assert_eq!(
+        extract_rect(&data[..20], 10, 2, [0, 1, 1, 2]),
+        vec![10, 11, 0, 0]
+    );
+}
+
+#[test]
+fn from_file_path_error_test() {
+    use crate::ScreenTaskError;
+    let result = SurfaceSource::from_file_path(std::path::PathBuf::from("./missing.png"));
+    assert!(matches!(result, Err(ScreenTaskError::Decode(_))));
+}
+
+#[test]
+fn source_validation_error_test() {
+    use crate::ScreenTaskError;
+    let source = host_source;
+    assert!(source([4, 4], 16, 64).validate().is_ok());
+    assert!(matches!(
+        source([0, 4], 16, 64).validate(),
+        Err(ScreenTaskError::InvalidSize([0, 4]))
+    ));
+    assert!(matches!(
+        source([4, 4], 12, 64).validate(),
+        Err(ScreenTaskError::InvalidStride {
+            stride: 12,
+            minimum: 16
+        })
+    ));
+    assert!(matches!(
+        source([4, 4], 16, 60).validate(),
+        Err(ScreenTaskError::InvalidDataLength {
+            length: 60,
+            expected: 64
+        })
+    ));
+}
+
+fn host_source(size: [u32; 2], stride: u32, length: usize) -> SurfaceSource {
+    SurfaceSource::HostAllocation {
+        info: crate::HostAllocationInfo {
+            size,
+            format: wgpu::TextureFormat::Rgba8UnormSrgb,
+            stride,
+        },
+        data: vec![0; length],
+    }
+}
+
+#[test]
+fn validation_duplicate_id_test() {
+    use crate::screen_task::EventValidator;
+    use crate::ScreenTaskError;
+    let mut validator = EventValidator::new();
+    validator
+        .create_surface(0, &host_source([4, 4], 16, 64), 0, [4, 4])
+        .unwrap();
+    assert!(matches!(
+        validator.create_surface(0, &host_source([4, 4], 16, 64), 0, [4, 4]),
+        Err(ScreenTaskError::DuplicateSurface(0))
+    ));
+    // Ids are released by the removal of the surface.
+    validator.remove_surface(0).unwrap();
+    validator
+        .create_surface(0, &host_source([4, 4], 16, 64), 0, [4, 4])
+        .unwrap();
+}
+
+#[test]
+fn validation_unknown_id_test() {
+    use crate::screen_task::EventValidator;
+    use crate::ScreenTaskError;
+    let mut validator = EventValidator::new();
+    assert!(matches!(
+        validator.check_surface(3),
+        Err(ScreenTaskError::UnknownSurface(3))
+    ));
+    assert!(matches!(
+        validator.update_source(3, &host_source([4, 4], 16, 64)),
+        Err(ScreenTaskError::UnknownSurface(3))
+    ));
+    assert!(matches!(
+        validator.update_data(3, 64),
+        Err(ScreenTaskError::UnknownSurface(3))
+    ));
+    assert!(matches!(
+        validator.resize_surface(3, [4, 4]),
+        Err(ScreenTaskError::UnknownSurface(3))
+    ));
+    assert!(matches!(
+        validator.remove_surface(3),
+        Err(ScreenTaskError::UnknownSurface(3))
+    ));
+}
+
+#[test]
+fn validation_zero_size_test() {
+    use crate::screen_task::EventValidator;
+    use crate::ScreenTaskError;
+    let mut validator = EventValidator::new();
+    assert!(matches!(
+        validator.create_surface(0, &host_source([4, 4], 16, 64), 0, [0, 10]),
+        Err(ScreenTaskError::InvalidSize([0, 10]))
+    ));
+    assert!(matches!(
+        validator.create_surface(0, &host_source([4, 0], 16, 0), 0, [4, 4]),
+        Err(ScreenTaskError::InvalidSize([4, 0]))
+    ));
+    validator
+        .create_surface(0, &host_source([4, 4], 16, 64), 0, [4, 4])
+        .unwrap();
+    assert!(matches!(
+        validator.resize_surface(0, [10, 0]),
+        Err(ScreenTaskError::InvalidSize([10, 0]))
+    ));
+}
+
+#[test]
+fn validation_stride_test() {
+    use crate::screen_task::EventValidator;
+    use crate::ScreenTaskError;
+    let mut validator = EventValidator::new();
+    assert!(matches!(
+        validator.create_surface(0, &host_source([4, 4], 12, 64), 0, [4, 4]),
+        Err(ScreenTaskError::InvalidStride {
+            stride: 12,
+            minimum: 16
+        })
+    ));
+    assert!(matches!(
+        validator.create_surface(0, &host_source([4, 4], 18, 72), 0, [4, 4]),
+        Err(ScreenTaskError::MisalignedStride {
+            stride: 18,
+            alignment: 4
+        })
+    ));
+    // Sizes whose rows can not be addressed are rejected instead of overflowing.
+    assert!(matches!(
+        validator.create_surface(0, &host_source([u32::MAX, 4], 16, 64), 0, [4, 4]),
+        Err(ScreenTaskError::InvalidSize([u32::MAX, 4]))
+    ));
+    // Padded rows are accepted as long as they are made of whole pixels.
+    validator
+        .create_surface(0, &host_source([4, 4], 20, 76), 0, [4, 4])
+        .unwrap();
+}
+
+#[test]
+fn validation_data_length_test() {
+    use crate::screen_task::EventValidator;
+    use crate::{ScreenTaskError, YuvColorSpace, YuvFormat, YuvHostAllocationInfo};
+    let mut validator = EventValidator::new();
+    assert!(matches!(
+        validator.create_surface(0, &host_source([4, 4], 20, 75), 0, [4, 4]),
+        Err(ScreenTaskError::InvalidDataLength {
+            length: 75,
+            expected: 76
+        })
+    ));
+    validator
+        .create_surface(0, &host_source([4, 4], 20, 76), 0, [4, 4])
+        .unwrap();
+    validator.update_data(0, 76).unwrap();
+    assert!(matches!(
+        validator.update_data(0, 64),
+        Err(ScreenTaskError::InvalidDataLength {
+            length: 64,
+            expected: 76
+        })
+    ));
+
+    // Multi-plane data contain every plane, each one with its own stride.
+    let yuv_source = SurfaceSource::YuvHostAllocation {
+        info: YuvHostAllocationInfo {
+            size: [4, 4],
+            format: YuvFormat::Nv12,
+            color_space: YuvColorSpace::default(),
+            strides: vec![4, 4],
+        },
+        planes: vec![vec![0; 16], vec![0; 8]],
+    };
+    validator.create_surface(1, &yuv_source, 0, [4, 4]).unwrap();
+    validator.update_data(1, 24).unwrap();
+    assert!(matches!(
+        validator.update_data(1, 20),
+        Err(ScreenTaskError::InvalidDataLength {
+            length: 20,
+            expected: 24
+        })
+    ));
+}
+
+#[test]
+fn validation_source_rect_test() {
+    use crate::screen_task::EventValidator;
+    use crate::ScreenTaskError;
+    let mut validator = EventValidator::new();
+    validator
+        .create_surface(0, &host_source([4, 4], 16, 64), 0, [4, 4])
+        .unwrap();
+    for source_rect in [
+        [f32::NAN, 0.0, 1.0, 1.0],
+        [0.0, 0.0, f32::INFINITY, 1.0],
+        [0.0, 0.0, 0.0, 2.0],
+        [0.0, 0.0, -1.0, 2.0],
+        [-1.0, 0.0, 2.0, 2.0],
+        [2.0, 2.0, 4.0, 4.0],
+    ]
+    .iter()
+    {
+        assert!(matches!(
+            validator.set_surface_source_rect(0, *source_rect),
+            Err(ScreenTaskError::InvalidRect(_))
+        ));
+    }
+    validator
+        .set_surface_source_rect(0, [0.0, 0.0, 4.0, 4.0])
+        .unwrap();
+    validator
+        .set_surface_source_rect(0, [2.0, 1.0, 2.0, 3.0])
+        .unwrap();
+}
+
+/// Returns a host allocation filled with a single sRGB color.
+fn solid_source(size: [u32; 2], color: [u8; 4]) -> SurfaceSource {
+    let data = color
+        .iter()
+        .copied()
+        .cycle()
+        .take((size[0] * size[1] * 4) as usize)
+        .collect();
+    SurfaceSource::HostAllocation {
+        info: crate::HostAllocationInfo {
+            size,
+            format: wgpu::TextureFormat::Rgba8UnormSrgb,
+            stride: size[0] * 4,
+        },
+        data,
+    }
+}
+
+/**
+Surfaces managed by a SurfaceManager on the MockEngine.
+They are rendered by the SoftwareCompositor from the instances stored in the surface buffer
+and from the textures rebuilt with the writes recorded by the engine.
+*/
+struct SoftwareScene {
+    engine: crate::MockEngine,
+    surfaces: crate::SurfaceManager<crate::MockEngine>,
+}
+impl SoftwareScene {
+    fn new() -> Self {
+        let mut engine = crate::MockEngine::new();
+        let device = engine.create_device();
+        let surfaces = crate::SurfaceManager::new(&mut engine, device).unwrap();
+        Self { engine, surfaces }
+    }
+
+    fn add(&mut self, id: usize, source: SurfaceSource, position: [i32; 3], size: [u32; 2]) {
+        self.surfaces
+            .create_surface(&mut self.engine, String::new(), id, source, position, size)
+            .unwrap();
+    }
+
+    /// Update the surface buffer and returns the instances stored in it.
+    fn instances(&mut self) -> Vec<crate::Surface> {
+        self.surfaces.update(&mut self.engine);
+        self.surfaces.surface_buffer().surfaces().clone()
+    }
+
+    /// Returns a compositor holding the texture array bound by the render pipeline.
+    fn compositor(&self) -> crate::SoftwareCompositor {
+        use crate::Engine;
+        let mut compositor = crate::SoftwareCompositor::new();
+        for texture_view in self.surfaces.rectangle_views() {
+            let texture = self.engine.texture_of_view(&texture_view).unwrap();
+            let descriptor = self.engine.texture_descriptor_ref(&texture).unwrap();
+            let writes = self.engine.texture_writes(&texture);
+            compositor
+                .push_texture(crate::SoftwareTexture::from_writes(descriptor, writes).unwrap());
+        }
+        compositor
+    }
+
+    fn render(&mut self, push_constants: &PushConstants, size: [u32; 2]) -> image::RgbaImage {
+        let instances = self.instances();
+        self.compositor().render(&instances, push_constants, size)
+    }
+}
+
+/**
+Compare the image with the golden image of the provided name, stored in `tests/golden`.
+Run the tests with `UPDATE_GOLDEN` set to regenerate the golden images.
+*/
+fn assert_golden(image: &image::RgbaImage, name: &str) {
+    let path = std::path::PathBuf::from("./tests/golden").join(format!("{}.png", name));
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        image.save(&path).unwrap();
+    }
+    let golden = image::open(&path).unwrap().into_rgba8();
+    assert_eq!(golden.dimensions(), image.dimensions());
+    for (golden, pixel) in golden.pixels().zip(image.pixels()) {
+        for (golden, channel) in golden.0.iter().zip(pixel.0.iter()) {
+            assert!(
+                (*golden as i32 - *channel as i32).abs() <= 1,
+                "{} differs from the golden image",
+                name
+            );
+        }
+    }
+}
+
+#[test]
+fn software_z_order_test() {
+    let mut scene = SoftwareScene::new();
+    scene.add(0, solid_source([8, 8], [255, 0, 0, 255]), [0, 0, 1], [8, 8]);
+    scene.add(1, solid_source([8, 8], [0, 0, 255, 255]), [4, 4, 0], [8, 8]);
+    let push_constants = PushConstants::new([0, 0], [16, 16]);
+
+    let image = scene.render(&push_constants, [16, 16]);
+    assert_eq!(image.get_pixel(2, 2).0, [255, 0, 0, 255]);
+    assert_eq!(image.get_pixel(6, 6).0, [0, 0, 255, 255]);
+    assert_eq!(image.get_pixel(10, 10).0, [0, 0, 255, 255]);
+    assert_eq!(image.get_pixel(14, 1).0, [0, 0, 0, 0]);
+    assert_golden(&image, "software_z_order");
+
+    // The depth test keeps the nearest surface visible even if it is drawn first.
+    let mut instances = scene.instances();
+    instances.reverse();
+    let reversed = scene
+        .compositor()
+        .render(&instances, &push_constants, [16, 16]);
+    assert_eq!(reversed.get_pixel(6, 6).0, [0, 0, 255, 255]);
+}
+
+#[test]
+fn software_source_rect_test() {
+    // 8x8 buffer split in four colored quadrants.
+    let quadrants: [[u8; 4]; 4] = [
+        [255, 0, 0, 255],
+        [0, 255, 0, 255],
+        [0, 0, 255, 255],
+        [255, 255, 255, 255],
+    ];
+    let data = (0..64)
+        .flat_map(|pixel| {
+            let quadrant = (pixel % 8) / 4 + (pixel / 8) / 4 * 2;
+            quadrants[quadrant].to_vec()
+        })
+        .collect();
+    let source = SurfaceSource::HostAllocation {
+        info: crate::HostAllocationInfo {
+            size: [8, 8],
+            format: wgpu::TextureFormat::Rgba8UnormSrgb,
+            stride: 32,
+        },
+        data,
+    };
+
+    let mut scene = SoftwareScene::new();
+    scene.add(0, source, [0, 0, 0], [8, 8]);
+    scene
+        .surfaces
+        .set_surface_source_rect(&0, [4.0, 0.0, 4.0, 4.0])
+        .unwrap();
+    let image = scene.render(&PushConstants::new([0, 0], [8, 8]), [8, 8]);
+    // Pixels far from the crop edges only show the top right quadrant.
+    for (x, y) in [(1, 1), (4, 4), (6, 6)].iter() {
+        assert_eq!(image.get_pixel(*x, *y).0, quadrants[1]);
+    }
+    assert_golden(&image, "software_source_rect");
+}
+
+#[test]
+fn software_blending_test() {
+    let mut scene = SoftwareScene::new();
+    scene.add(
+        0,
+        solid_source([4, 8], [255, 255, 255, 255]),
+        [0, 0, 1],
+        [4, 8],
+    );
+    scene.add(1, solid_source([8, 8], [255, 0, 0, 255]), [0, 0, 0], [8, 8]);
+    scene.surfaces.set_surface_opacity(&1, 0.5).unwrap();
+
+    let image = scene.render(&PushConstants::new([0, 0], [8, 8]), [8, 8]);
+    // Half transparent red over white, blended in linear space.
+    assert_eq!(image.get_pixel(1, 1).0, [255, 188, 188, 255]);
+    // Half transparent red over nothing, stored premultiplied.
+    assert_eq!(image.get_pixel(6, 1).0, [188, 0, 0, 128]);
+    assert_golden(&image, "software_blending");
+}
+
+#[test]
+fn software_multi_output_test() {
+    let mut scene = SoftwareScene::new();
+    scene.add(0, solid_source([4, 4], [0, 255, 0, 255]), [6, 2, 0], [4, 4]);
+    let surfaces = scene.instances();
+    let compositor = scene.compositor();
+
+    // Two 8x8 outputs side by side, the surface lies across both of them.
+    let left = compositor.render(&surfaces, &PushConstants::new([0, 0], [8, 8]), [8, 8]);
+    let right = compositor.render(&surfaces, &PushConstants::new([8, 0], [8, 8]), [8, 8]);
+    assert_eq!(left.get_pixel(6, 2).0, [0, 255, 0, 255]);
+    assert_eq!(left.get_pixel(5, 2).0, [0, 0, 0, 0]);
+    assert_eq!(right.get_pixel(1, 5).0, [0, 255, 0, 255]);
+    assert_eq!(right.get_pixel(2, 5).0, [0, 0, 0, 0]);
+    assert_golden(&left, "software_multi_output_left");
+    assert_golden(&right, "software_multi_output_right");
+}
+
+#[test]
+fn software_output_scale_test() {
+    let mut scene = SoftwareScene::new();
+    scene.add(0, solid_source([4, 4], [0, 255, 0, 255]), [1, 0, 0], [3, 2]);
+    let surfaces = scene.instances();
+    let compositor = scene.compositor();
+
+    // Two 3x3 outputs at scale 1.5, covering 2 logical units each.
+    let left = compositor.render(
+        &surfaces,
+        &PushConstants::for_output([0, 0], [3, 3], 1.5, crate::Transform::Normal),
+        [3, 3],
+    );
+    let right = compositor.render(
+        &surfaces,
+        &PushConstants::for_output([2, 0], [3, 3], 1.5, crate::Transform::Normal),
+        [3, 3],
+    );
+    // The surface spans the physical columns 2..6 without gaps between the outputs.
+    assert_eq!(left.get_pixel(1, 0).0, [0, 0, 0, 0]);
+    assert_eq!(left.get_pixel(2, 0).0, [0, 255, 0, 255]);
+    for x in 0..3 {
+        assert_eq!(right.get_pixel(x, 0).0, [0, 255, 0, 255]);
+        assert_eq!(right.get_pixel(x, 2).0, [0, 255, 0, 255]);
+    }
+    assert_eq!(left.get_pixel(2, 2).0, [0, 255, 0, 255]);
+}
+
+#[test]
+fn output_scale_test() {
+    use crate::{EngineCall, ResourceKind};
+    let (mut engine, mut screen_task) = mock_screen_task();
+    assert!(screen_task.set_output_scale(0, 0.0).is_err());
+    assert!(screen_task.set_output_scale(0, f32::NAN).is_err());
+
+    screen_task.set_output_scale(0, 2.0).unwrap();
+    let calls = mock_frame(&mut engine, &mut screen_task);
+    assert_eq!(calls, vec![EngineCall::Update(ResourceKind::CommandBuffer)]);
+
+    let push_constants =
+        PushConstants::for_output([10, 0], [64, 64], 2.0, crate::Transform::Normal);
+    assert_eq!(push_constants.output_origin, [20.0, 0.0]);
+    assert_eq!(push_constants.physical_position([10.25, 0.75]), [1.0, 2.0]);
+}
+
+/// Project a point in logical coordinates to the framebuffer pixel it lands on.
+fn project_to_framebuffer(
+    push_constants: &PushConstants,
+    point: [f32; 2],
+    size: [u32; 2],
+) -> [f32; 2] {
+    let physical = push_constants.physical_position(point);
+    let projected = push_constants.projection_matrix
+        * ultraviolet::Vec4::new(physical[0], physical[1], 0.0, 1.0);
+    [
+        (projected.x + 1.0) * 0.5 * size[0] as f32,
+        (1.0 - projected.y) * 0.5 * size[1] as f32,
+    ]
+}
+
+#[test]
+fn output_transform_projection_test() {
+    use crate::Transform;
+    // Framebuffer position of the top left and top right corners of the logical area of a 4x2 output.
+    let expected = [
+        (Transform::Normal, [0.0, 0.0], [4.0, 0.0]),
+        (Transform::Rotated90, [4.0, 0.0], [4.0, 2.0]),
+        (Transform::Rotated180, [4.0, 2.0], [0.0, 2.0]),
+        (Transform::Rotated270, [0.0, 2.0], [0.0, 0.0]),
+        (Transform::Flipped, [4.0, 0.0], [0.0, 0.0]),
+        (Transform::Flipped90, [4.0, 2.0], [4.0, 0.0]),
+        (Transform::Flipped180, [0.0, 2.0], [4.0, 2.0]),
+        (Transform::Flipped270, [0.0, 0.0], [0.0, 2.0]),
+    ];
+    for (transform, top_left, top_right) in expected.iter() {
+        let push_constants = PushConstants::for_output([10, 20], [4, 2], 1.0, *transform);
+        let width = if transform.swaps_dimensions() {
+            2.0
+        } else {
+            4.0
+        };
+        assert_eq!(
+            project_to_framebuffer(&push_constants, [10.0, 20.0], [4, 2]),
+            *top_left,
+            "{:?}",
+            transform
+        );
+        assert_eq!(
+            project_to_framebuffer(&push_constants, [10.0 + width, 20.0], [4, 2]),
+            *top_right,
+            "{:?}",
+            transform
+        );
+    }
+}
+
+#[test]
+fn output_transform_test() {
+    use crate::{EngineCall, ResourceKind, Transform};
+    let (mut engine, mut screen_task) = mock_screen_task();
+    screen_task.set_output_transform(0, Transform::Rotated90);
+    let calls = mock_frame(&mut engine, &mut screen_task);
+    assert_eq!(calls, vec![EngineCall::Update(ResourceKind::CommandBuffer)]);
+
+    // A portrait output keeps surfaces upright on the rotated monitor.
+    let mut scene = SoftwareScene::new();
+    scene.add(0, solid_source([2, 2], [255, 0, 0, 255]), [0, 0, 0], [2, 1]);
+    let push_constants = PushConstants::for_output([0, 0], [4, 2], 1.0, Transform::Rotated90);
+    let image = scene.render(&push_constants, [4, 2]);
+    assert_eq!(image.get_pixel(3, 0).0, [255, 0, 0, 255]);
+    assert_eq!(image.get_pixel(3, 1).0, [255, 0, 0, 255]);
+    assert_eq!(image.get_pixel(2, 0).0, [0, 0, 0, 0]);
+}
+
+/// Returns a ScreenTask driven by a MockEngine with a single 64x64 output.
+fn mock_screen_task() -> (crate::MockEngine, ScreenTask<crate::MockEngine>) {
+    let mut engine = crate::MockEngine::new();
+    let mut screen_task = ScreenTask::new(&mut engine);
+    let device = engine.create_device();
+    engine.create_swapchain(0, device, [64, 64]);
+    screen_task.update_engine_resources(&mut engine);
+    engine.finish_frame();
+    (engine, screen_task)
+}
+
+/// Run a frame and returns the recorded operations.
+fn mock_frame(
+    engine: &mut crate::MockEngine,
+    screen_task: &mut ScreenTask<crate::MockEngine>,
+) -> Vec<crate::EngineCall> {
+    screen_task.update_engine_resources(engine);
+    assert!(screen_task.drain_errors().is_empty());
+    engine.finish_frame()
+}
+
+/// Returns the bind groups, pipelines and command buffers rebuilt by the recorded operations.
+fn rebuilt_resources(calls: &[crate::EngineCall]) -> Vec<crate::ResourceKind> {
+    use crate::{EngineCall, ResourceKind};
+    calls
+        .iter()
+        .filter_map(|call| match call {
+            EngineCall::Update(ResourceKind::Texture)
+            | EngineCall::Update(ResourceKind::TextureView)
+            | EngineCall::Update(ResourceKind::SurfaceBuffer) => None,
+            EngineCall::Update(kind) => Some(*kind),
+            _ => None,
+        })
+        .collect()
+}
+
+#[test]
+fn opacity_order_test() {
+    let mut engine = crate::MockEngine::new();
+    let device = engine.create_device();
+    let mut surface_manager = crate::SurfaceManager::new(&mut engine, device).unwrap();
+    for (id, z, opacity) in [(0, 0, 0.5), (1, 2, 0.25), (2, -1, 1.0)] {
+        surface_manager
+            .create_surface(
+                &mut engine,
+                String::new(),
+                id,
+                solid_source([4, 4], [255; 4]),
+                [id as i32, 0, z],
+                [4, 4],
+            )
+            .unwrap();
+        surface_manager.set_surface_opacity(&id, opacity).unwrap();
+    }
+
+    // Instances are stored back to front, from the highest z, each one nearer than the previous.
+    let surface_data = surface_manager.surface_data();
+    let order: Vec<_> = surface_data
+        .iter()
+        .map(|data| (data.position[0], data.opacity))
+        .collect();
+    assert_eq!(order, vec![(1.0, 0.25), (0.0, 0.5), (2.0, 1.0)]);
+    assert!(surface_data
+        .windows(2)
+        .all(|pair| pair[0].position[2] > pair[1].position[2]));
+}
+
+#[test]
+fn mock_create_surface_test() {
+    use crate::{EngineCall, ResourceKind};
+    let (mut engine, mut screen_task) = mock_screen_task();
+
+    screen_task
+        .create_surface(
+            0,
+            "surface",
+            solid_source([4, 4], [0; 4]),
+            [0, 0, 0],
+            [4, 4],
+        )
+        .unwrap();
+    let calls = mock_frame(&mut engine, &mut screen_task);
+    assert_eq!(
+        &calls[..3],
+        &[
+            EngineCall::Add(ResourceKind::Texture),
+            EngineCall::Add(ResourceKind::TextureView),
+            EngineCall::Write(1)
+        ]
+    );
+    assert!(calls.contains(&EngineCall::Update(ResourceKind::SurfaceBuffer)));
+    assert_eq!(
+        rebuilt_resources(&calls),
+        vec![
+            ResourceKind::BindGroupLayout,
+            ResourceKind::PipelineLayout,
+            ResourceKind::BindGroup,
+            ResourceKind::CommandBuffer
+        ]
+    );
+
+    // The render pipeline is always able to draw, so it is never rebuilt.
+    screen_task
+        .create_surface(
+            1,
+            "surface",
+            solid_source([4, 4], [0; 4]),
+            [0, 0, 0],
+            [4, 4],
+        )
+        .unwrap();
+    let calls = mock_frame(&mut engine, &mut screen_task);
+    assert_eq!(
+        rebuilt_resources(&calls),
+        vec![
+            ResourceKind::BindGroupLayout,
+            ResourceKind::PipelineLayout,
+            ResourceKind::BindGroup,
+            ResourceKind::CommandBuffer
+        ]
+    );
+}
+
+#[test]
+fn mock_surface_update_test() {
+    use crate::{EngineCall, ResourceKind, YuvColorSpace, YuvFormat, YuvHostAllocationInfo};
+    let (mut engine, mut screen_task) = mock_screen_task();
+    screen_task
+        .create_surface(
+            0,
+            "surface",
+            solid_source([4, 4], [0; 4]),
+            [0, 0, 0],
+            [4, 4],
+        )
+        .unwrap();
+    mock_frame(&mut engine, &mut screen_task);
+
+    // Data updates only write the texture.
+    screen_task.update_data(0, vec![0; 64]).unwrap();
+    assert_eq!(
+        mock_frame(&mut engine, &mut screen_task),
+        vec![EngineCall::Write(1)]
+    );
+
+    // Geometry changes only rewrite the instances.
+    screen_task.move_surface(0, [10, 10, 0]).unwrap();
+    screen_task.set_surface_opacity(0, 0.5).unwrap();
+    screen_task
+        .set_surface_source_rect(0, [0.0, 0.0, 2.0, 2.0])
+        .unwrap();
+    assert_eq!(
+        mock_frame(&mut engine, &mut screen_task),
+        vec![EngineCall::Update(ResourceKind::SurfaceBuffer)]
+    );
+
+    // A source with the same planes updates the texture in place.
+    screen_task
+        .update_source(0, solid_source([8, 8], [0; 4]))
+        .unwrap();
+    let calls = mock_frame(&mut engine, &mut screen_task);
+    assert_eq!(calls[0], EngineCall::Update(ResourceKind::Texture));
+    assert!(rebuilt_resources(&calls).is_empty());
+
+    // A source with more planes needs new textures and so new bind groups.
+    let yuv_source = SurfaceSource::YuvHostAllocation {
+        info: YuvHostAllocationInfo {
+            size: [4, 4],
+            format: YuvFormat::Nv12,
+            color_space: YuvColorSpace::default(),
+            strides: vec![4, 4],
+        },
+        planes: vec![vec![0; 16], vec![0; 8]],
+    };
+    screen_task.update_source(0, yuv_source).unwrap();
+    let calls = mock_frame(&mut engine, &mut screen_task);
+    assert_eq!(
+        rebuilt_resources(&calls),
+        vec![
+            ResourceKind::BindGroupLayout,
+            ResourceKind::PipelineLayout,
+            ResourceKind::BindGroup,
+            ResourceKind::CommandBuffer
+        ]
+    );
+}
+
+#[test]
+fn mock_remove_surface_test() {
+    use crate::{EngineCall, ResourceKind};
+    let (mut engine, mut screen_task) = mock_screen_task();
+    screen_task
+        .create_surface(
+            0,
+            "surface",
+            solid_source([4, 4], [0; 4]),
+            [0, 0, 0],
+            [4, 4],
+        )
+        .unwrap();
+    mock_frame(&mut engine, &mut screen_task);
+
+    screen_task.remove_surface(0).unwrap();
+    let calls = mock_frame(&mut engine, &mut screen_task);
+    assert_eq!(
+        &calls[..2],
+        &[
+            EngineCall::Remove(ResourceKind::TextureView),
+            EngineCall::Remove(ResourceKind::Texture)
+        ]
+    );
+    // Without surfaces the placeholder is bound in place of their textures.
+    assert_eq!(
+        rebuilt_resources(&calls),
+        vec![
+            ResourceKind::BindGroupLayout,
+            ResourceKind::PipelineLayout,
+            ResourceKind::BindGroup,
+            ResourceKind::CommandBuffer
+        ]
+    );
+}
+
+#[test]
+fn mock_move_output_test() {
+    use crate::ResourceKind;
+    let (mut engine, mut screen_task) = mock_screen_task();
+    screen_task.move_output(0, [100, 0]);
+    let calls = mock_frame(&mut engine, &mut screen_task);
+    assert_eq!(rebuilt_resources(&calls), vec![ResourceKind::CommandBuffer]);
+}
+
+#[test]
+fn wallpaper_layout_test() {
+    use crate::WallpaperMode;
+    let area = [10.0, 20.0, 100.0, 50.0];
+    let expected = [
+        (WallpaperMode::Fill, area, [0.0, 12.5, 50.0, 25.0]),
+        (
+            WallpaperMode::Fit,
+            [35.0, 20.0, 50.0, 50.0],
+            [0.0, 0.0, 50.0, 50.0],
+        ),
+        (WallpaperMode::Stretch, area, [0.0, 0.0, 50.0, 50.0]),
+        (
+            WallpaperMode::Center,
+            [35.0, 20.0, 50.0, 50.0],
+            [0.0, 0.0, 50.0, 50.0],
+        ),
+        (WallpaperMode::Tile, area, [0.0, 0.0, 100.0, 50.0]),
+    ];
+    for (mode, geometry, source_rect) in expected.iter() {
+        assert_eq!(
+            mode.layout(area, [50, 50]),
+            (*geometry, *source_rect),
+            "{:?}",
+            mode
+        );
+    }
+}
+
+#[test]
+fn wallpaper_test() {
+    use crate::{OutputBackground, WallpaperMode};
+    let mut scene = SoftwareScene::new();
+    scene.add(0, solid_source([2, 2], [0, 0, 255, 255]), [2, 2, 0], [4, 4]);
+    // 2x2 checkerboard repeated over the whole output.
+    let checkerboard = SurfaceSource::HostAllocation {
+        info: crate::HostAllocationInfo {
+            size: [2, 2],
+            format: wgpu::TextureFormat::Rgba8UnormSrgb,
+            stride: 8,
+        },
+        data: vec![
+            255, 0, 0, 255, 0, 255, 0, 255, //
+            0, 255, 0, 255, 255, 0, 0, 255,
+        ],
+    };
+    scene
+        .surfaces
+        .set_wallpaper(
+            &mut scene.engine,
+            7,
+            checkerboard.clone(),
+            WallpaperMode::Tile,
+            [0.0, 0.0, 8.0, 8.0],
+        )
+        .unwrap();
+    assert_eq!(scene.surfaces.wallpaper_slot(7), Some(0));
+    assert_eq!(scene.surfaces.view_count(), 2);
+
+    // The texture array holds the surfaces first and then the wallpapers.
+    let image = scene.render(&PushConstants::new([0, 0], [8, 8]), [8, 8]);
+    assert_eq!(image.get_pixel(0, 0).0, [255, 0, 0, 255]);
+    assert_eq!(image.get_pixel(1, 0).0, [0, 255, 0, 255]);
+    assert_eq!(image.get_pixel(6, 7).0, [0, 255, 0, 255]);
+    assert_eq!(image.get_pixel(7, 7).0, [255, 0, 0, 255]);
+    assert_eq!(image.get_pixel(3, 3).0, [0, 0, 255, 255]);
+
+    assert!(scene
+        .surfaces
+        .remove_wallpaper(&mut scene.engine, 7)
+        .unwrap());
+    assert_eq!(scene.surfaces.wallpaper_slot(7), None);
+    assert_eq!(scene.instances().len(), 1);
+
+    // Backgrounds are applied to the output through the ScreenTask.
+    use crate::{EngineCall, ResourceKind};
+    let (mut engine, mut screen_task) = mock_screen_task();
+    let invalid = OutputBackground::Wallpaper {
+        source: solid_source([0, 2], [0; 4]),
+        mode: WallpaperMode::Fill,
+    };
+    assert!(screen_task.set_output_background(0, invalid).is_err());
+    let wallpaper = OutputBackground::Wallpaper {
+        source: checkerboard,
+        mode: WallpaperMode::Fill,
+    };
+    screen_task.set_output_background(0, wallpaper).unwrap();
+    let calls = mock_frame(&mut engine, &mut screen_task);
+    assert_eq!(
+        &calls[..3],
+        &[
+            EngineCall::Add(ResourceKind::Texture),
+            EngineCall::Add(ResourceKind::TextureView),
+            EngineCall::Write(1)
+        ]
+    );
+    assert!(rebuilt_resources(&calls).contains(&ResourceKind::BindGroup));
+
+    let color = OutputBackground::Color(wgpu::Color::TRANSPARENT);
+    screen_task.set_output_background(0, color).unwrap();
+    let calls = mock_frame(&mut engine, &mut screen_task);
+    assert_eq!(
+        &calls[..2],
+        &[
+            EngineCall::Remove(ResourceKind::TextureView),
+            EngineCall::Remove(ResourceKind::Texture)
+        ]
+    );
+}
+
+#[test]
+fn arbitrary_z_test() {
+    let mut scene = SoftwareScene::new();
+    // Overlapping surfaces with z outside of the old [0, 1024) range and equal z values.
+    let surfaces = [
+        (0, [255, 0, 0, 255], i32::MAX),
+        (1, [0, 255, 0, 255], -5000),
+        (2, [0, 0, 255, 255], -5000),
+        (3, [255, 255, 255, 255], i32::MIN),
+    ];
+    for (id, color, z) in surfaces.iter() {
+        scene.add(
+            *id,
+            solid_source([2, 2], *color),
+            [*id as i32 * 2, 0, *z],
+            [4, 2],
+        );
+    }
+    assert_eq!(scene.surfaces.draw_order(), vec![0, 1, 2, 3]);
+
+    let surface_data = scene.instances();
+    for pair in surface_data.windows(2) {
+        assert!(pair[0].position[2] > pair[1].position[2]);
+        assert!(pair[1].position[2] > 0.0 && pair[0].position[2] < 1.0);
+    }
+
+    let image = scene.render(&PushConstants::new([0, 0], [10, 2]), [10, 2]);
+    assert_eq!(image.get_pixel(1, 0).0, [255, 0, 0, 255]);
+    // Surfaces with the same z are stacked in creation order, the newest on top.
+    assert_eq!(image.get_pixel(3, 0).0, [0, 255, 0, 255]);
+    assert_eq!(image.get_pixel(5, 0).0, [0, 0, 255, 255]);
+    assert_eq!(image.get_pixel(7, 0).0, [255, 255, 255, 255]);
+    assert_eq!(image.get_pixel(9, 0).0, [255, 255, 255, 255]);
+
+    // Changing z restacks a surface on top of the ones with its new z.
+    scene.surfaces.move_surface(&3, [6, 0, 0]).unwrap();
+    scene.surfaces.move_surface(&0, [0, 0, -5000]).unwrap();
+    assert_eq!(scene.surfaces.draw_order(), vec![3, 1, 2, 0]);
+    // Moving without changing z keeps the order.
+    scene.surfaces.move_surface(&1, [0, 0, -5000]).unwrap();
+    assert_eq!(scene.surfaces.draw_order(), vec![3, 1, 2, 0]);
+}
+
+#[test]
+fn stacking_order_test() {
+    let mut stacking_order = crate::StackingOrder::new();
+    stacking_order.insert(0, 10).unwrap();
+    stacking_order.insert(1, 0).unwrap();
+    stacking_order.insert(2, 0).unwrap();
+    stacking_order.insert(3, -10).unwrap();
+    assert!(stacking_order.insert(3, 0).is_err());
+    assert_eq!(stacking_order.order(), vec![0, 1, 2, 3]);
+
+    stacking_order.raise(0).unwrap();
+    assert_eq!(stacking_order.order(), vec![1, 2, 3, 0]);
+    assert_eq!(stacking_order.z(0).unwrap(), -10);
+
+    stacking_order.lower(3).unwrap();
+    assert_eq!(stacking_order.order(), vec![3, 1, 2, 0]);
+    assert_eq!(stacking_order.z(3).unwrap(), 0);
+
+    stacking_order.place_above(3, 2).unwrap();
+    assert_eq!(stacking_order.order(), vec![1, 2, 3, 0]);
+    stacking_order.place_below(0, 1).unwrap();
+    assert_eq!(stacking_order.order(), vec![0, 1, 2, 3]);
+    assert_eq!(stacking_order.z(0).unwrap(), 0);
+    stacking_order.place_above(2, 2).unwrap();
+    assert_eq!(stacking_order.order(), vec![0, 1, 2, 3]);
+    assert!(stacking_order.place_above(2, 7).is_err());
+    assert_eq!(stacking_order.order(), vec![0, 1, 2, 3]);
+
+    // New surfaces go on top of the ones with the same z.
+    stacking_order.insert(4, 0).unwrap();
+    stacking_order.insert(5, 1).unwrap();
+    assert_eq!(stacking_order.order(), vec![5, 0, 1, 2, 3, 4]);
+}
+
+#[test]
+fn stacking_api_test() {
+    let (mut engine, mut screen_task) = mock_screen_task();
+    for id in 0..3 {
+        screen_task
+            .create_surface(
+                id,
+                "surface",
+                solid_source([2, 2], [0; 4]),
+                [0, 0, 0],
+                [2, 2],
+            )
+            .unwrap();
+    }
+    screen_task.raise_surface(0).unwrap();
+    screen_task.place_below(2, 1).unwrap();
+    assert!(screen_task.lower_surface(5).is_err());
+    assert!(screen_task.place_above(0, 5).is_err());
+    // The order is queryable before the events are elaborated.
+    assert_eq!(screen_task.stacking_order(), vec![2, 1, 0]);
+
+    let calls = mock_frame(&mut engine, &mut screen_task);
+    assert!(calls.contains(&crate::EngineCall::Update(
+        crate::ResourceKind::SurfaceBuffer
+    )));
+    screen_task.lower_surface(0).unwrap();
+    mock_frame(&mut engine, &mut screen_task);
+    assert_eq!(screen_task.stacking_order(), vec![0, 2, 1]);
+}
+
+#[test]
+fn subsurface_stacking_test() {
+    let mut stacking_order = crate::StackingOrder::new();
+    stacking_order.insert(0, 0).unwrap();
+    stacking_order.insert(1, 0).unwrap();
+    stacking_order.insert_child(2, 0).unwrap();
+    stacking_order.insert_child(3, 0).unwrap();
+    stacking_order.insert_child(4, 2).unwrap();
+    assert!(stacking_order.insert_child(5, 7).is_err());
+    // Subsurfaces are drawn right above their parent, below the next top level surface.
+    assert_eq!(stacking_order.order(), vec![0, 2, 4, 3, 1]);
+    assert_eq!(stacking_order.len(), 5);
+
+    stacking_order.place_below(3, 0).unwrap();
+    assert_eq!(stacking_order.order(), vec![3, 0, 2, 4, 1]);
+    stacking_order.raise(2).unwrap();
+    assert_eq!(stacking_order.order(), vec![3, 0, 2, 4, 1]);
+    stacking_order.lower(2).unwrap();
+    assert_eq!(stacking_order.order(), vec![2, 4, 3, 0, 1]);
+    // Subsurfaces can be stacked only next to their siblings and their parent.
+    assert!(matches!(
+        stacking_order.place_above(4, 0),
+        Err(crate::ScreenTaskError::InvalidSibling { id: 4, sibling: 0 })
+    ));
+    assert!(stacking_order.place_above(1, 3).is_err());
+    // Top level surfaces move with their subsurfaces.
+    stacking_order.raise(0).unwrap();
+    assert_eq!(stacking_order.order(), vec![1, 2, 4, 3, 0]);
+
+    assert_eq!(stacking_order.remove(0).unwrap(), vec![0, 2, 3, 4]);
+    assert_eq!(stacking_order.order(), vec![1]);
+    assert!(!stacking_order.contains(4));
+    assert!(!stacking_order.is_empty());
+    stacking_order.remove(1).unwrap();
+    assert!(stacking_order.is_empty());
+}
+
+#[test]
+fn texture_order_test() {
+    let mut scene = SoftwareScene::new();
+    // Created in reverse id order, so that the texture array differs from the creation order.
+    scene.add(1, solid_source([2, 2], [0, 255, 0, 255]), [2, 0, 0], [2, 2]);
+    scene.add(0, solid_source([2, 2], [255, 0, 0, 255]), [0, 0, 0], [2, 2]);
+    let views = scene.surfaces.rectangle_views();
+    let push_constants = PushConstants::new([0, 0], [4, 2]);
+    let image = scene.render(&push_constants, [4, 2]);
+    assert_eq!(image.get_pixel(0, 0).0, [255, 0, 0, 255]);
+    assert_eq!(image.get_pixel(2, 0).0, [0, 255, 0, 255]);
+
+    // Restacking changes the drawing order but not the texture array.
+    scene.surfaces.raise_surface(&1).unwrap();
+    assert_eq!(scene.surfaces.draw_order(), vec![0, 1]);
+    assert_eq!(scene.surfaces.rectangle_views(), views);
+    let image = scene.render(&push_constants, [4, 2]);
+    assert_eq!(image.get_pixel(0, 0).0, [255, 0, 0, 255]);
+    assert_eq!(image.get_pixel(2, 0).0, [0, 255, 0, 255]);
+}
+
+#[test]
+fn subsurface_test() {
+    use crate::{EngineCall, ResourceKind};
+    let mut scene = SoftwareScene::new();
+    let red = solid_source([2, 2], [255, 0, 0, 255]);
+    let green = solid_source([2, 2], [0, 255, 0, 255]);
+    scene
+        .surfaces
+        .create_surface(&mut scene.engine, String::new(), 0, red, [1, 0, 0], [4, 2])
+        .unwrap();
+    scene
+        .surfaces
+        .create_subsurface(
+            &mut scene.engine,
+            String::new(),
+            1,
+            0,
+            green,
+            crate::SubsurfaceGeometry {
+                position: [2, 0],
+                size: [2, 2],
+            },
+        )
+        .unwrap();
+    assert!(scene
+        .surfaces
+        .create_subsurface(
+            &mut scene.engine,
+            String::new(),
+            2,
+            5,
+            solid_source([2, 2], [0; 4]),
+            crate::SubsurfaceGeometry {
+                position: [0, 0],
+                size: [2, 2],
+            },
+        )
+        .is_err());
+
+    let push_constants = PushConstants::new([0, 0], [8, 2]);
+    let image = scene.render(&push_constants, [8, 2]);
+    assert_eq!(image.get_pixel(1, 0).0, [255, 0, 0, 255]);
+    assert_eq!(image.get_pixel(3, 0).0, [0, 255, 0, 255]);
+    assert_eq!(image.get_pixel(5, 0).0, [0, 0, 0, 0]);
+
+    // The subsurface follows its parent.
+    scene.surfaces.move_surface(&0, [4, 0, 0]).unwrap();
+    let image = scene.render(&push_constants, [8, 2]);
+    assert_eq!(image.get_pixel(4, 0).0, [255, 0, 0, 255]);
+    assert_eq!(image.get_pixel(7, 0).0, [0, 255, 0, 255]);
+
+    // Placed below its parent, the subsurface is hidden.
+    scene.surfaces.place_below(&1, &0).unwrap();
+    assert_eq!(scene.surfaces.draw_order(), vec![1, 0]);
+    let image = scene.render(&push_constants, [8, 2]);
+    assert_eq!(image.get_pixel(7, 0).0, [255, 0, 0, 255]);
+
+    // Removing the parent removes the subsurface.
+    scene.engine.finish_frame();
+    scene
+        .surfaces
+        .remove_surface(&mut scene.engine, &0)
+        .unwrap();
+    let removed_textures = scene
+        .engine
+        .finish_frame()
+        .iter()
+        .filter(|call| **call == EngineCall::Remove(ResourceKind::Texture))
+        .count();
+    assert_eq!(removed_textures, 2);
+    assert!(scene.surfaces.draw_order().is_empty());
+    assert!(scene.surfaces.move_surface(&1, [0, 0, 0]).is_err());
+}
+
+#[test]
+fn subsurface_api_test() {
+    let (mut engine, mut screen_task) = mock_screen_task();
+    screen_task
+        .create_surface(0, "parent", solid_source([2, 2], [0; 4]), [0, 0, 0], [2, 2])
+        .unwrap();
+    screen_task
+        .create_subsurface(1, 0, "child", solid_source([2, 2], [0; 4]), [1, 1], [2, 2])
+        .unwrap();
+    assert!(screen_task
+        .create_subsurface(2, 9, "orphan", solid_source([2, 2], [0; 4]), [0, 0], [2, 2])
+        .is_err());
+    assert!(screen_task.place_above(1, 2).is_err());
+    mock_frame(&mut engine, &mut screen_task);
+    assert_eq!(screen_task.stacking_order(), vec![0, 1]);
+
+    screen_task.remove_surface(0).unwrap();
+    assert!(screen_task.remove_surface(1).is_err());
+    mock_frame(&mut engine, &mut screen_task);
+    assert!(screen_task.stacking_order().is_empty());
+}
+
+#[test]
+fn clip_rect_test() {
+    let mut scene = SoftwareScene::new();
+    let red = solid_source([2, 2], [255, 0, 0, 255]);
+    let green = solid_source([2, 2], [0, 255, 0, 255]);
+    scene
+        .surfaces
+        .create_surface(&mut scene.engine, String::new(), 0, red, [0, 0, 0], [8, 4])
+        .unwrap();
+    scene
+        .surfaces
+        .create_subsurface(
+            &mut scene.engine,
+            String::new(),
+            1,
+            0,
+            green,
+            crate::SubsurfaceGeometry {
+                position: [4, 0],
+                size: [8, 4],
+            },
+        )
+        .unwrap();
+    scene
+        .surfaces
+        .set_surface_clip_rect(&0, Some([1.0, 1.0, 5.0, 2.0]))
+        .unwrap();
+
+    let push_constants = PushConstants::new([0, 0], [12, 4]);
+    let image = scene.render(&push_constants, [12, 4]);
+    assert_eq!(image.get_pixel(0, 1).0, [0, 0, 0, 0]);
+    assert_eq!(image.get_pixel(1, 0).0, [0, 0, 0, 0]);
+    assert_eq!(image.get_pixel(1, 1).0, [255, 0, 0, 255]);
+    assert_eq!(image.get_pixel(3, 2).0, [255, 0, 0, 255]);
+    assert_eq!(image.get_pixel(3, 3).0, [0, 0, 0, 0]);
+    // The subsurface inherits the clipping of its parent, in output coordinates.
+    assert_eq!(image.get_pixel(5, 1).0, [0, 255, 0, 255]);
+    assert_eq!(image.get_pixel(6, 1).0, [0, 0, 0, 0]);
+    assert_eq!(image.get_pixel(10, 1).0, [0, 0, 0, 0]);
+
+    // The clip rectangle of the subsurface is intersected with the one of the parent.
+    scene
+        .surfaces
+        .set_surface_clip_rect(&1, Some([5.0, 0.0, 10.0, 2.0]))
+        .unwrap();
+    let image = scene.render(&push_constants, [12, 4]);
+    assert_eq!(image.get_pixel(4, 1).0, [255, 0, 0, 255]);
+    assert_eq!(image.get_pixel(5, 1).0, [0, 255, 0, 255]);
+    assert_eq!(image.get_pixel(5, 2).0, [255, 0, 0, 255]);
+
+    // Clipping follows the scale of the output.
+    scene.surfaces.set_surface_clip_rect(&1, None).unwrap();
+    scene
+        .surfaces
+        .set_surface_clip_rect(&0, Some([0.0, 0.0, 2.0, 1.0]))
+        .unwrap();
+    let push_constants = PushConstants::for_output([0, 0], [24, 8], 2.0, crate::Transform::Normal);
+    let image = scene.render(&push_constants, [24, 8]);
+    assert_eq!(image.get_pixel(3, 1).0, [255, 0, 0, 255]);
+    assert_eq!(image.get_pixel(4, 1).0, [0, 0, 0, 0]);
+    assert_eq!(image.get_pixel(3, 2).0, [0, 0, 0, 0]);
+
+    // Clip rectangles that are not finite or have a negative size are rejected.
+    for clip_rect in [
+        [f32::NAN, 0.0, 2.0, 2.0],
+        [0.0, 0.0, f32::INFINITY, 2.0],
+        [0.0, 0.0, -1.0, 2.0],
+        [0.0, 0.0, 2.0, -1.0],
+    ]
+    .iter()
+    {
+        assert!(matches!(
+            scene.surfaces.set_surface_clip_rect(&0, Some(*clip_rect)),
+            Err(crate::ScreenTaskError::InvalidRect(_))
+        ));
+    }
+    let (_engine, mut screen_task) = mock_screen_task();
+    screen_task
+        .create_surface(
+            0,
+            "surface",
+            solid_source([2, 2], [0; 4]),
+            [0, 0, 0],
+            [2, 2],
+        )
+        .unwrap();
+    assert!(matches!(
+        screen_task.set_surface_clip_rect(0, Some([0.0, 0.0, -2.0, 2.0])),
+        Err(crate::ScreenTaskError::InvalidRect(_))
+    ));
+    // Empty clip rectangles hide the surface.
+    screen_task
+        .set_surface_clip_rect(0, Some([-1.0, -1.0, 0.0, 0.0]))
+        .unwrap();
+    screen_task.set_surface_clip_rect(0, None).unwrap();
+}
+
+#[test]
+fn decoration_test() {
+    let mut scene = SoftwareScene::new();
+    let source = solid_source([2, 2], [255, 255, 255, 255]);
+    scene.add(0, source.clone(), [0, 0, 0], [16, 16]);
+    let decoration = crate::SurfaceDecoration {
+        radius: 4.0,
+        border_width: 2.0,
+        border_color: [1.0, 0.0, 0.0, 1.0],
+    };
+    scene
+        .surfaces
+        .set_surface_decoration(&0, decoration)
+        .unwrap();
+    let push_constants = PushConstants::new([0, 0], [16, 16]);
+    let image = scene.render(&push_constants, [16, 16]);
+    // Outside of the rounded corner.
+    assert_eq!(image.get_pixel(0, 0).0, [0, 0, 0, 0]);
+    assert_eq!(image.get_pixel(15, 15).0, [0, 0, 0, 0]);
+    // Anti-aliased edge of the corner.
+    let edge = image.get_pixel(1, 1).0[3];
+    assert!(edge > 0 && edge < 255);
+    // Border and content.
+    assert_eq!(image.get_pixel(8, 0).0, [255, 0, 0, 255]);
+    assert_eq!(image.get_pixel(0, 8).0, [255, 0, 0, 255]);
+    assert_eq!(image.get_pixel(8, 2).0, [255, 255, 255, 255]);
+    assert_eq!(image.get_pixel(8, 8).0, [255, 255, 255, 255]);
+
+    // Without decoration the corners are drawn.
+    scene
+        .surfaces
+        .set_surface_decoration(&0, Default::default())
+        .unwrap();
+    let image = scene.render(&push_constants, [16, 16]);
+    assert_eq!(image.get_pixel(0, 0).0, [255, 255, 255, 255]);
+
+    let (_engine, mut screen_task) = mock_screen_task();
+    screen_task
+        .create_surface(0, "surface", source, [0, 0, 0], [16, 16])
+        .unwrap();
+    assert!(screen_task
+        .set_surface_decoration(0, 4.0, 1.0, [0.0; 4])
+        .is_ok());
+    assert!(matches!(
+        screen_task.set_surface_decoration(0, -1.0, 1.0, [0.0; 4]),
+        Err(crate::ScreenTaskError::InvalidDecoration { .. })
+    ));
+    assert!(screen_task
+        .set_surface_decoration(0, 1.0, f32::NAN, [0.0; 4])
+        .is_err());
+}
+
+#[test]
+fn shadow_test() {
+    let shadow = crate::SurfaceShadow {
+        offset: [2.0, 2.0],
+        blur_radius: 4.0,
+        color: [0.0, 0.0, 0.0, 1.0],
+    };
+    // The coverage is one half on the edges of the shadowed rectangle and fades out in the margin.
+    let size = [40.0, 40.0];
+    let edge = crate::shadow_coverage([6.0, 20.0], size, 4.0);
+    assert!((edge - 0.5).abs() < 0.01);
+    assert!(crate::shadow_coverage([20.0, 20.0], size, 4.0) > 0.99);
+    assert!(crate::shadow_coverage([0.0, 20.0], size, 4.0) < 0.01);
+    assert!(crate::shadow_coverage([3.0, 20.0], size, 4.0) < edge);
+
+    let mut scene = SoftwareScene::new();
+    for (id, color) in [[255, 255, 255, 255], [255, 0, 0, 255]].iter().enumerate() {
+        scene.add(
+            id,
+            solid_source([2, 2], *color),
+            [id as i32 * 8 + 4, 4, 0],
+            [8, 8],
+        );
+    }
+    assert!(scene.surfaces.set_surface_shadow(&1, Some(shadow)).unwrap());
+    assert!(!scene.surfaces.set_surface_shadow(&1, Some(shadow)).unwrap());
+    assert_eq!(scene.surfaces.instance_count(), 3);
+
+    let surface_data = scene.instances();
+    assert_eq!(surface_data.len(), 3);
+    assert_eq!(surface_data[1].sampling, crate::SAMPLING_SHADOW);
+    assert_eq!(surface_data[1].position[0..2], [8.0, 0.0]);
+    assert_eq!(surface_data[1].size, [20.0, 20.0]);
+    // The shadow is drawn above the surfaces below and beneath its own surface.
+    assert!(surface_data[0].position[2] > surface_data[1].position[2]);
+    assert!(surface_data[1].position[2] > surface_data[2].position[2]);
+
+    let image = scene.render(&PushConstants::new([0, 0], [28, 16]), [28, 16]);
+    assert_eq!(image.get_pixel(14, 8).0, [255, 0, 0, 255]);
+    // The shadow darkens the surface below it.
+    let shadowed = image.get_pixel(11, 11).0;
+    assert!(shadowed[0] < 255 && shadowed[0] > 0);
+    assert_eq!(image.get_pixel(5, 5).0, [255, 255, 255, 255]);
+    // Outside of the surfaces only the shadow is drawn.
+    let outside = image.get_pixel(21, 13).0;
+    assert_eq!(outside[0..3], [0, 0, 0]);
+    assert!(outside[3] > 0);
+
+    let calls = {
+        let (mut engine, mut screen_task) = mock_screen_task();
+        screen_task
+            .create_surface(
+                0,
+                "surface",
+                solid_source([2, 2], [0; 4]),
+                [0, 0, 0],
+                [8, 8],
+            )
+            .unwrap();
+        mock_frame(&mut engine, &mut screen_task);
+        assert!(screen_task
+            .set_surface_shadow(
+                0,
+                Some(crate::SurfaceShadow {
+                    blur_radius: -1.0,
+                    ..shadow
+                })
+            )
+            .is_err());
+        screen_task.set_surface_shadow(0, Some(shadow)).unwrap();
+        mock_frame(&mut engine, &mut screen_task)
+    };
+    assert!(rebuilt_resources(&calls).contains(&crate::ResourceKind::CommandBuffer));
+}
+
+/// Returns the target and the drawn instance ranges of every render pass of a command buffer.
+fn render_passes(
+    engine: &crate::MockEngine,
+    command_buffer: &wgpu_engine::CommandBufferId,
+) -> Vec<(
+    Option<wgpu_engine::TextureViewId>,
+    Vec<std::ops::Range<u32>>,
+)> {
+    use wgpu_engine::{ColorView, Command, RenderCommand};
+    engine
+        .command_buffer(command_buffer)
+        .unwrap()
+        .commands
+        .iter()
+        .filter_map(|command| match command {
+            Command::RenderPass {
+                color_attachments,
+                commands,
+                ..
+            } => {
+                let target = match &color_attachments[0].view {
+                    ColorView::TextureView(view) => Some(*view),
+                    _ => None,
+                };
+                let draws = commands
+                    .iter()
+                    .filter_map(|command| match command {
+                        RenderCommand::Draw { instances, .. } => Some(instances.clone()),
+                        _ => None,
+                    })
+                    .collect();
+                Some((target, draws))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+#[test]
+fn background_blur_test() {
+    let (mut engine, mut screen_task) = mock_screen_task();
+    for id in 0..3 {
+        screen_task
+            .create_surface(
+                id,
+                "surface",
+                solid_source([2, 2], [0; 4]),
+                [0, 0, 0],
+                [8, 8],
+            )
+            .unwrap();
+    }
+    mock_frame(&mut engine, &mut screen_task);
+    let command_buffer = screen_task.engine_command_buffers()[0];
+    assert_eq!(render_passes(&engine, &command_buffer).len(), 1);
+
+    screen_task.set_surface_background_blur(1, true).unwrap();
+    let calls = mock_frame(&mut engine, &mut screen_task);
+    assert!(calls.contains(&crate::EngineCall::Add(crate::ResourceKind::Texture)));
+    let passes = render_passes(&engine, &command_buffer);
+    // Scene beneath the blurred surface, downsample and upsample passes, the rest of the scene and the final copy.
+    let blur_passes = 2 * crate::BLUR_PASSES;
+    assert_eq!(passes.len(), 1 + blur_passes + 1 + 1);
+    assert_eq!(passes[0].1, vec![0..1]);
+    assert_eq!(passes[blur_passes + 1].1, vec![1..2, 1..3]);
+    assert!(passes[0].0.is_some());
+    assert_eq!(passes[0].0, passes[blur_passes + 1].0);
+    assert_eq!(passes.last().unwrap().0, None);
+
+    // Raising the blurred surface moves its passes.
+    screen_task.raise_surface(1).unwrap();
+    mock_frame(&mut engine, &mut screen_task);
+    let passes = render_passes(&engine, &command_buffer);
+    assert_eq!(passes[0].1, vec![0..2]);
+    assert_eq!(passes[blur_passes + 1].1, vec![2..3, 2..3]);
+
+    // Displays that do not show the blurred surface skip the blur passes.
+    screen_task.move_surface(1, [100, 0, 0]).unwrap();
+    mock_frame(&mut engine, &mut screen_task);
+    assert_eq!(render_passes(&engine, &command_buffer).len(), 1);
+    screen_task.move_surface(1, [60, 0, 0]).unwrap();
+    mock_frame(&mut engine, &mut screen_task);
+    assert_eq!(
+        render_passes(&engine, &command_buffer).len(),
+        1 + blur_passes + 1 + 1
+    );
+
+    screen_task.set_surface_background_blur(1, false).unwrap();
+    mock_frame(&mut engine, &mut screen_task);
+    assert_eq!(render_passes(&engine, &command_buffer).len(), 1);
+}
+
+#[test]
+fn solid_color_test() {
+    use crate::{EngineCall, ResourceKind};
+    let mut scene = SoftwareScene::new();
+    scene.engine.finish_frame();
+
+    let solid = SurfaceSource::SolidColor([0.0, 0.0, 1.0, 0.5]);
+    // Solid colors have no planes in the texture array.
+    assert!(crate::SoftwareTexture::from_source(&solid)
+        .unwrap()
+        .is_empty());
+    scene
+        .surfaces
+        .create_surface(
+            &mut scene.engine,
+            String::new(),
+            0,
+            solid,
+            [0, 0, 0],
+            [8, 4],
+        )
+        .unwrap();
+    assert!(!scene
+        .engine
+        .finish_frame()
+        .contains(&EngineCall::Add(ResourceKind::Texture)));
+    assert_eq!(scene.surfaces.view_count(), 0);
+
+    scene.add(1, solid_source([2, 2], [255, 0, 0, 255]), [4, 0, 0], [4, 4]);
+    assert_eq!(scene.surfaces.view_count(), 1);
+
+    let surface_data = scene.instances();
+    assert_eq!(surface_data[0].sampling, crate::SAMPLING_SOLID_COLOR);
+    assert_eq!(surface_data[0].source_rect, [0.0, 0.0, 1.0, 0.5]);
+    assert_eq!(surface_data[1].image_index, 0);
+    let image = scene.render(&PushConstants::new([0, 0], [8, 4]), [8, 4]);
+    // The output stores premultiplied colors encoded as sRGB.
+    assert_eq!(image.get_pixel(1, 1).0, [0, 0, 188, 128]);
+    assert_eq!(image.get_pixel(5, 1).0, [255, 0, 0, 255]);
+
+    // Solid colors have no data to update and become textured surfaces through a new source.
+    assert!(scene
+        .surfaces
+        .update_data(&mut scene.engine, &0, vec![0; 4])
+        .is_err());
+    assert!(scene
+        .surfaces
+        .update_source(&mut scene.engine, &0, solid_source([1, 1], [0; 4]))
+        .unwrap());
+    assert_eq!(scene.surfaces.view_count(), 2);
+    assert!(scene
+        .surfaces
+        .update_source(
+            &mut scene.engine,
+            &0,
+            SurfaceSource::SolidColor([0.0, 0.0, 0.0, 1.0])
+        )
+        .unwrap());
+    assert_eq!(scene.surfaces.view_count(), 1);
+    assert!(SurfaceSource::SolidColor([f32::NAN, 0.0, 0.0, 1.0])
+        .validate()
+        .is_err());
+    // The color is not a buffer that a source rectangle can crop.
+    assert!(matches!(
+        scene
+            .surfaces
+            .set_surface_source_rect(&0, [0.0, 0.0, 1.0, 1.0]),
+        Err(crate::ScreenTaskError::UnsupportedFormat(_))
+    ));
+
+    // Scenes of solid colors only are drawn with the placeholder in the texture array.
+    let mut scene = SoftwareScene::new();
+    let red = SurfaceSource::SolidColor([1.0, 0.0, 0.0, 1.0]);
+    scene.add(0, red.clone(), [0, 0, 0], [2, 2]);
+    assert_eq!(scene.surfaces.view_count(), 0);
+    assert_eq!(scene.surfaces.rectangle_views().len(), 1);
+    let image = scene.render(&PushConstants::new([0, 0], [2, 2]), [2, 2]);
+    assert_eq!(image.get_pixel(1, 1).0, [255, 0, 0, 255]);
+
+    let (mut engine, mut screen_task) = mock_screen_task();
+    screen_task
+        .create_surface(0, "solid", red, [0, 0, 0], [8, 8])
+        .unwrap();
+    assert!(matches!(
+        screen_task.set_surface_source_rect(0, [0.0, 0.0, 1.0, 1.0]),
+        Err(crate::ScreenTaskError::UnsupportedFormat(_))
+    ));
+    mock_frame(&mut engine, &mut screen_task);
+    let command_buffer = screen_task.engine_command_buffers()[0];
+    let passes = render_passes(&engine, &command_buffer);
+    assert_eq!(passes.len(), 1);
+    assert_eq!(passes[0].0, None);
+    assert_eq!(passes[0].1, vec![0..1]);
+}
+
+#[test]
+fn dmabuf_fourcc_test() {
+    use crate::DrmFourcc;
+    let table = [
+        (
+            DrmFourcc::ARGB8888,
+            wgpu::TextureFormat::Bgra8UnormSrgb,
+            false,
+        ),
+        (
+            DrmFourcc::XRGB8888,
+            wgpu::TextureFormat::Bgra8UnormSrgb,
+            true,
+        ),
+        (
+            DrmFourcc::ABGR8888,
+            wgpu::TextureFormat::Rgba8UnormSrgb,
+            false,
+        ),
+        (
+            DrmFourcc::XBGR8888,
+            wgpu::TextureFormat::Rgba8UnormSrgb,
+            true,
+        ),
+    ];
+    for (fourcc, format, ignores_alpha) in table.iter() {
+        assert_eq!(fourcc.texture_format().unwrap(), *format);
+        assert_eq!(fourcc.ignores_alpha(), *ignores_alpha);
+    }
+    assert_eq!(DrmFourcc::ARGB8888.0, 0x3432_5241);
+    assert_eq!(DrmFourcc::XRGB8888.to_string(), "XR24");
+    assert!(DrmFourcc::NV12.texture_format().is_err());
+    assert!(DrmFourcc::from_chars(*b"RG16").texture_format().is_err());
+    assert_eq!(DrmFourcc(0).to_string(), "0x00000000");
+}
+
+#[test]
+fn dmabuf_layout_test() {
+    let plane = |fd, offset, stride| crate::DmabufPlane { fd, offset, stride };
+    let info = |modifier, planes| crate::DmabufInfo {
+        size: [64, 32],
+        fourcc: crate::DrmFourcc::XRGB8888,
+        modifier: DrmModifier(modifier),
+        planes,
+    };
+
+    // Every plane spans stride * height bytes from its own offset.
+    let tiled = info(
+        0x0100_0000_0000_0006,
+        vec![plane(3, 0, 256), plane(3, 8192, 64)],
+    );
+    tiled.validate().unwrap();
+    let layouts = tiled.plane_layouts();
+    assert_eq!(layouts.len(), 2);
+    assert_eq!(layouts[0].slice, 0..8192);
+    assert_eq!(layouts[0].row_pitch, 256);
+    assert_eq!(layouts[1].slice, 8192..10240);
+    assert_eq!(layouts[1].row_pitch, 64);
+    assert_eq!(tiled.import_fd().unwrap(), 3);
+
+    let linear = info(0, vec![plane(3, 128, 256)]);
+    linear.validate().unwrap();
+    assert_eq!(linear.plane_layouts()[0].slice, 128..8320);
+    assert_eq!(
+        crate::SurfaceSourceInfo::from(linear.clone()).sampling_code(),
+        crate::SAMPLING_RGBA | crate::SAMPLING_OPAQUE
+    );
+
+    // Linear buffers have a single plane, and no buffer has more than four.
+    assert!(info(0, vec![plane(3, 0, 256), plane(3, 8192, 64)])
+        .validate()
+        .is_err());
+    assert!(info(0x0100_0000_0000_0006, vec![plane(3, 0, 256); 5])
+        .validate()
+        .is_err());
+    assert!(info(0, Vec::new()).validate().is_err());
+    assert!(info(0, vec![plane(3, 0, 128)]).validate().is_err());
+    let mut yuv = linear.clone();
+    yuv.fourcc = crate::DrmFourcc::NV12;
+    assert!(yuv.validate().is_err());
+
+    let mut engine = crate::MockEngine::new();
+    let device = engine.create_device();
+    let textures = crate::SurfaceManager::<crate::MockEngine>::prepare_texture(
+        device,
+        String::from("dmabuf"),
+        SurfaceSource::Dmabuf { info: tiled },
+        true,
+    )
+    .unwrap();
+    assert_eq!(textures.len(), 1);
+    assert_eq!(textures[0].0.format, wgpu::TextureFormat::Bgra8UnormSrgb);
+    match &textures[0].0.source {
+        TextureSource::DmaBuf {
+            fd, drm_properties, ..
+        } => {
+            assert_eq!(*fd, 3);
+            let drm_properties = drm_properties.as_ref().unwrap();
+            assert_eq!(
+                drm_properties.drm_modifier,
+                DrmModifier(0x0100_0000_0000_0006)
+            );
+            assert_eq!(drm_properties.plane_layouts, layouts);
+        }
+        source => panic!("unexpected texture source {:?}", source),
+    }
+}
+
+#[test]
+fn dmabuf_plane_fd_test() {
+    use std::os::unix::io::AsRawFd;
+    let path = std::env::temp_dir().join(format!("screen_task_dmabuf_{}", std::process::id()));
+    let other_path = path.with_extension("other");
+    let file = std::fs::File::create(&path).unwrap();
+    let duplicate = file.try_clone().unwrap();
+    let other = std::fs::File::create(&other_path).unwrap();
+    let info = |fds: [i32; 2]| crate::DmabufInfo {
+        size: [16, 16],
+        fourcc: crate::DrmFourcc::ARGB8888,
+        modifier: DrmModifier(0x0100_0000_0000_0006),
+        planes: fds
+            .iter()
+            .map(|fd| crate::DmabufPlane {
+                fd: *fd,
+                offset: 0,
+                stride: 64,
+            })
+            .collect(),
+    };
+
+    // Different descriptors of the same buffer are accepted, the first one is imported.
+    let fd = file.as_raw_fd();
+    assert_eq!(info([fd, duplicate.as_raw_fd()]).import_fd().unwrap(), fd);
+    assert!(info([fd, other.as_raw_fd()]).import_fd().is_err());
+    assert!(info([fd, -1]).import_fd().is_err());
+    std::fs::remove_file(&path).unwrap();
+    std::fs::remove_file(&other_path).unwrap();
+}
+
+#[test]
+fn supported_formats_test() {
+    use crate::{DrmFourcc, SupportedFormats, DRM_FORMAT_MOD_LINEAR};
+    let formats = SupportedFormats::new(true);
+    assert!(formats.supports_shm(DrmFourcc::ARGB8888));
+    assert!(formats.supports_shm(DrmFourcc::P010));
+    assert!(!formats.supports_shm(DrmFourcc::XRGB8888));
+    assert!(formats.supports_dmabuf(DrmFourcc::XRGB8888, DRM_FORMAT_MOD_LINEAR));
+    assert!(!formats.supports_dmabuf(DrmFourcc::P010, DRM_FORMAT_MOD_LINEAR));
+    assert!(!formats.supports_dmabuf(DrmFourcc::ARGB8888, DrmModifier(0x0100_0000_0000_0006)));
+    // P010 Dma buffers are converted to half floats when they are copied through host memory.
+    let copied_formats = SupportedFormats::new(false);
+    assert!(copied_formats.supports_dmabuf(DrmFourcc::P010, DRM_FORMAT_MOD_LINEAR));
+    assert_eq!(copied_formats.shm, formats.shm);
+
+    // Every advertised format is accepted by the sources it is imported with.
+    let size = [4, 4];
+    for fourcc in &formats.shm {
+        let source = match fourcc.yuv_format() {
+            Some(format) => SurfaceSource::YuvHostAllocation {
+                info: crate::YuvHostAllocationInfo {
+                    size,
+                    format,
+                    color_space: crate::YuvColorSpace::default(),
+                    strides: (0..format.plane_count())
+                        .map(|plane| size[0] * format.plane_bytes_per_pixel(plane))
+                        .collect(),
+                },
+                planes: vec![vec![0; 32]; format.plane_count()],
+            },
+            None => SurfaceSource::HostAllocation {
+                info: crate::HostAllocationInfo {
+                    size,
+                    format: fourcc.texture_format().unwrap(),
+                    stride: size[0] * 4,
+                },
+                data: vec![0; 64],
+            },
+        };
+        assert!(source.validate().is_ok(), "{} host allocation", fourcc);
+    }
+    for (fourcc, modifier) in &copied_formats.dmabuf {
+        let plane = |stride| crate::DmabufPlane {
+            fd: 3,
+            offset: 0,
+            stride,
+        };
+        let source = match fourcc.yuv_format() {
+            Some(format) => SurfaceSource::YuvDmabuf {
+                info: crate::YuvDmabufInfo {
+                    size,
+                    format,
+                    color_space: crate::YuvColorSpace::default(),
+                    modifier: *modifier,
+                    planes: (0..format.plane_count())
+                        .map(|plane_index| {
+                            plane(size[0] * format.plane_bytes_per_pixel(plane_index))
+                        })
+                        .collect(),
+                },
+            },
+            None => SurfaceSource::Dmabuf {
+                info: crate::DmabufInfo {
+                    size,
+                    fourcc: *fourcc,
+                    modifier: *modifier,
+                    planes: vec![plane(16)],
+                },
+            },
+        };
+        assert!(source.validate().is_ok(), "{} Dma buffer", fourcc);
+    }
+
+    let mut engine = crate::MockEngine::new();
+    let mut screen_task = ScreenTask::new(&mut engine);
+    let device = engine.create_device();
+    assert!(matches!(
+        screen_task.supported_formats(device),
+        Err(crate::ScreenTaskError::UnknownDevice(_))
+    ));
+    engine.create_swapchain(0, device, [64, 64]);
+    screen_task.update_engine_resources(&mut engine);
+    let external_memory = ScreenTask::<crate::MockEngine>::features_and_limits()
+        .0
+        .contains(wgpu::Features::EXTERNAL_MEMORY);
+    assert_eq!(
+        screen_task.supported_formats(device).unwrap(),
+        SupportedFormats::new(external_memory)
+    );
+}
+
+/// Create a memfd holding the provided data, standing for a linear Dma buffer or a shared memory pool.
+fn memfd_buffer(data: &[u8]) -> std::fs::File {
+    use std::io::Write;
+    use std::os::unix::io::FromRawFd;
+    let name = std::ffi::CString::new("screen_task_test").unwrap();
+    let fd = unsafe { libc::memfd_create(name.as_ptr(), libc::MFD_CLOEXEC) };
+    assert!(fd >= 0);
+    let mut file = unsafe { std::fs::File::from_raw_fd(fd) };
+    file.write_all(data).unwrap();
+    file
+}
+
+#[test]
+fn dmabuf_host_copy_test() {
+    use crate::{EngineCall, ResourceKind};
+    use std::os::unix::fs::FileExt;
+    use std::os::unix::io::AsRawFd;
+    // Two rows of 2 pixels with a stride of 12 bytes, starting at an offset that is not page aligned.
+    let mut content = vec![0xee; 100];
+    content.extend((0..24).map(|byte| byte as u8));
+    let file = memfd_buffer(&content);
+    let client = file.try_clone().unwrap();
+    let info = |fd, modifier, offset| crate::DmabufInfo {
+        size: [2, 2],
+        fourcc: crate::DrmFourcc::ARGB8888,
+        modifier: DrmModifier(modifier),
+        planes: vec![crate::DmabufPlane {
+            fd,
+            offset,
+            stride: 12,
+        }],
+    };
+    let source = SurfaceSource::Dmabuf {
+        info: info(client.as_raw_fd(), 0, 100),
+    };
+
+    let mut engine = crate::MockEngine::new();
+    let device = engine.create_device();
+    let textures = crate::SurfaceManager::<crate::MockEngine>::prepare_texture(
+        device,
+        String::from("dmabuf"),
+        source.clone(),
+        false,
+    )
+    .unwrap();
+    assert_eq!(textures[0].0.source, TextureSource::Local);
+    assert_eq!(textures[0].0.format, wgpu::TextureFormat::Bgra8UnormSrgb);
+    // The data is uploaded from the mapping of the buffer.
+    assert!(textures[0].1.is_none());
+
+    let mut surface_manager = crate::SurfaceManager::new(&mut engine, device).unwrap();
+    surface_manager.set_external_memory(false);
+    let mut create = |engine: &mut crate::MockEngine, source| {
+        surface_manager.create_surface(engine, String::new(), 0, source, [0, 0, 0], [2, 2])
+    };
+    // Tiled buffers can not be copied, and the buffer must hold every row.
+    let tiled = info(client.as_raw_fd(), 0x0100_0000_0000_0006, 100);
+    assert!(create(&mut engine, SurfaceSource::Dmabuf { info: tiled }).is_err());
+    let short = info(client.as_raw_fd(), 0, 104);
+    assert!(matches!(
+        create(&mut engine, SurfaceSource::Dmabuf { info: short }),
+        Err(crate::ScreenTaskError::InvalidDataLength { .. })
+    ));
+    let out_of_range = info(client.as_raw_fd(), 0, u64::MAX);
+    assert!(matches!(
+        create(&mut engine, SurfaceSource::Dmabuf { info: out_of_range }),
+        Err(crate::ScreenTaskError::BufferOutOfRange {
+            offset: u64::MAX,
+            length: 24
+        })
+    ));
+    engine.finish_frame();
+
+    create(&mut engine, source).unwrap();
+    let calls = engine.finish_frame();
+    assert!(calls.contains(&EngineCall::Add(ResourceKind::Texture)));
+    assert!(calls.contains(&EngineCall::Write(1)));
+    let texture = engine
+        .texture_of_view(&surface_manager.rectangle_views()[0])
+        .unwrap();
+    let written =
+        |engine: &crate::MockEngine| engine.texture_writes(&texture).last().unwrap().data.clone();
+    assert_eq!(written(&engine), &content[100..]);
+
+    // Every commit copies the buffer again from the mapping, which outlives the descriptor of the client.
+    drop(client);
+    file.write_all_at(&[0xff; 4], 100).unwrap();
+    surface_manager.commit_surface(&mut engine, &0).unwrap();
+    assert_eq!(engine.finish_frame(), vec![EngineCall::Write(1)]);
+    assert_eq!(written(&engine)[..4], [0xff; 4]);
+
+    // Wallpapers are copied through host memory too.
+    let wallpaper = SurfaceSource::Dmabuf {
+        info: info(file.as_raw_fd(), 0, 100),
+    };
+    surface_manager
+        .set_wallpaper(
+            &mut engine,
+            0,
+            wallpaper,
+            crate::WallpaperMode::Tile,
+            [0.0, 0.0, 2.0, 2.0],
+        )
+        .unwrap();
+    let calls = engine.finish_frame();
+    assert!(calls.contains(&EngineCall::Add(ResourceKind::Texture)));
+    assert!(calls.contains(&EngineCall::Write(1)));
+    let wallpaper_texture = engine
+        .texture_of_view(surface_manager.rectangle_views().last().unwrap())
+        .unwrap();
+    assert_ne!(wallpaper_texture, texture);
+    let mut expected = content[100..].to_vec();
+    expected[..4].copy_from_slice(&[0xff; 4]);
+    assert_eq!(
+        engine
+            .texture_writes(&wallpaper_texture)
+            .last()
+            .unwrap()
+            .data,
+        expected
+    );
+
+    // Imported buffers are sampled directly.
+    surface_manager.set_external_memory(true);
+    let imported = SurfaceSource::Dmabuf {
+        info: info(file.as_raw_fd(), 0, 100),
+    };
+    surface_manager
+        .update_source(&mut engine, &0, imported)
+        .unwrap();
+    engine.finish_frame();
+    surface_manager.commit_surface(&mut engine, &0).unwrap();
+    assert!(engine.finish_frame().is_empty());
+    assert!(surface_manager.commit_surface(&mut engine, &1).is_err());
+
+    // P010 planes are converted to half floats while they are copied, and can not be imported.
+    let p010_file = memfd_buffer(&[0xff; 16]);
+    let p010 = SurfaceSource::YuvDmabuf {
+        info: crate::YuvDmabufInfo {
+            size: [2, 2],
+            format: crate::YuvFormat::P010,
+            color_space: crate::YuvColorSpace::default(),
+            modifier: crate::DRM_FORMAT_MOD_LINEAR,
+            planes: vec![
+                crate::DmabufPlane {
+                    fd: p010_file.as_raw_fd(),
+                    offset: 0,
+                    stride: 4,
+                },
+                crate::DmabufPlane {
+                    fd: p010_file.as_raw_fd(),
+                    offset: 8,
+                    stride: 4,
+                },
+            ],
+        },
+    };
+    assert!(matches!(
+        surface_manager.create_surface(
+            &mut engine,
+            String::new(),
+            1,
+            p010.clone(),
+            [0, 0, 0],
+            [2, 2]
+        ),
+        Err(crate::ScreenTaskError::UnsupportedFormat(_))
+    ));
+    let mut surface_manager = crate::SurfaceManager::new(&mut engine, device).unwrap();
+    surface_manager.set_external_memory(false);
+    surface_manager
+        .create_surface(&mut engine, String::new(), 1, p010, [0, 0, 0], [2, 2])
+        .unwrap();
+    let views = surface_manager.rectangle_views();
+    let planes: Vec<_> = views
+        .iter()
+        .map(|view| {
+            let texture = engine.texture_of_view(view).unwrap();
+            engine.texture_writes(&texture).last().unwrap().data.clone()
+        })
+        .collect();
+    // 1.0 as a half float.
+    assert_eq!(planes, vec![[0x00, 0x3c].repeat(4), [0x00, 0x3c].repeat(2)]);
+}
+
+/// Acquire fence signaled by the test.
+#[derive(Debug, Default, Clone)]
+struct FakeFence(std::sync::Arc<std::sync::atomic::AtomicBool>);
+impl crate::AcquireFence for FakeFence {
+    fn is_signaled(&self) -> bool {
+        self.0.load(std::sync::atomic::Ordering::SeqCst)
+    }
+}
+
+#[test]
+fn acquire_fence_test() {
+    use crate::{BufferSync, EngineCall, ResourceKind};
+    let (mut engine, mut screen_task) = mock_screen_task();
+    screen_task
+        .create_surface(
+            0,
+            "surface",
+            solid_source([4, 4], [0; 4]),
+            [0, 0, 0],
+            [4, 4],
+        )
+        .unwrap();
+    mock_frame(&mut engine, &mut screen_task);
+
+    let fence = FakeFence::default();
+    screen_task
+        .update_source_synced(
+            0,
+            solid_source([4, 4], [255; 4]),
+            BufferSync {
+                acquire: Some(Box::new(fence.clone())),
+                release: Some(1),
+            },
+        )
+        .unwrap();
+    screen_task.move_surface(0, [8, 8, 0]).unwrap();
+    assert!(mock_frame(&mut engine, &mut screen_task).is_empty());
+    assert!(mock_frame(&mut engine, &mut screen_task).is_empty());
+
+    // The source and the later events of the surface are applied together once the fence is signaled.
+    fence.0.store(true, std::sync::atomic::Ordering::SeqCst);
+    let calls = mock_frame(&mut engine, &mut screen_task);
+    assert!(calls.contains(&EngineCall::Write(1)));
+    assert!(calls.contains(&EngineCall::Update(ResourceKind::SurfaceBuffer)));
+    assert!(screen_task.drain_released_buffers().is_empty());
+}
+
+#[test]
+fn acquire_fence_order_test() {
+    use crate::{BufferSync, EngineCall, ResourceKind};
+    let (mut engine, mut screen_task) = mock_screen_task();
+    for id in 0..4 {
+        screen_task
+            .create_surface(
+                id,
+                "surface",
+                solid_source([4, 4], [0; 4]),
+                [0, 0, id as i32],
+                [4, 4],
+            )
+            .unwrap();
+    }
+    mock_frame(&mut engine, &mut screen_task);
+
+    let fence = FakeFence::default();
+    screen_task
+        .update_source_synced(
+            1,
+            solid_source([4, 4], [255; 4]),
+            BufferSync {
+                acquire: Some(Box::new(fence.clone())),
+                release: None,
+            },
+        )
+        .unwrap();
+    // The new subsurface waits for its parent, and the later events of the subsurface wait for it.
+    screen_task
+        .create_subsurface(
+            5,
+            1,
+            "subsurface",
+            solid_source([4, 4], [0; 4]),
+            [1, 1],
+            [4, 4],
+        )
+        .unwrap();
+    screen_task.move_surface(5, [2, 2, 0]).unwrap();
+    // The removal waits for the subsurfaces of surface 1, and the new surface 3 waits for the removal.
+    screen_task.remove_surface(3).unwrap();
+    screen_task
+        .create_surface(
+            3,
+            "surface",
+            solid_source([4, 4], [0; 4]),
+            [0, 0, 3],
+            [4, 4],
+        )
+        .unwrap();
+    // Every surface waits for the removal, the outputs do not.
+    screen_task.move_surface(2, [8, 8, 2]).unwrap();
+    screen_task.set_output_scale(0, 2.0).unwrap();
+    let calls = mock_frame(&mut engine, &mut screen_task);
+    assert!(!calls.is_empty());
+    assert!(!calls.contains(&EngineCall::Update(ResourceKind::SurfaceBuffer)));
+    assert!(!calls.contains(&EngineCall::Add(ResourceKind::Texture)));
+
+    fence.0.store(true, std::sync::atomic::Ordering::SeqCst);
+    let calls = mock_frame(&mut engine, &mut screen_task);
+    assert!(calls.contains(&EngineCall::Update(ResourceKind::SurfaceBuffer)));
+    assert!(calls.contains(&EngineCall::Add(ResourceKind::Texture)));
+    screen_task.move_surface(3, [4, 4, 3]).unwrap();
+    screen_task.remove_surface(5).unwrap();
+    mock_frame(&mut engine, &mut screen_task);
+}
+
+#[test]
+fn acquire_timeout_test() {
+    use crate::{BufferSync, EngineCall, ResourceKind, ScreenTaskError};
+    let (mut engine, mut screen_task) = mock_screen_task();
+    screen_task
+        .create_surface(
+            0,
+            "surface",
+            solid_source([4, 4], [0; 4]),
+            [0, 0, 0],
+            [4, 4],
+        )
+        .unwrap();
+    mock_frame(&mut engine, &mut screen_task);
+
+    // The fence is never signaled.
+    let fence = FakeFence::default();
+    screen_task
+        .update_source_synced(
+            0,
+            SurfaceSource::SolidColor([1.0; 4]),
+            BufferSync {
+                acquire: Some(Box::new(fence)),
+                release: Some(1),
+            },
+        )
+        .unwrap();
+    screen_task.move_surface(0, [8, 8, 0]).unwrap();
+    assert!(mock_frame(&mut engine, &mut screen_task).is_empty());
+    assert!(matches!(
+        screen_task.update_data(0, vec![0; 64]),
+        Err(ScreenTaskError::UnsupportedFormat(_))
+    ));
+
+    // Once the timeout elapsed the source is dropped and its buffer released, the later events are applied.
+    screen_task.set_acquire_timeout(std::time::Duration::ZERO);
+    screen_task.update_engine_resources(&mut engine);
+    assert!(matches!(
+        screen_task.drain_errors()[..],
+        [ScreenTaskError::AcquireTimeout(0)]
+    ));
+    let calls = engine.finish_frame();
+    assert!(!calls.contains(&EngineCall::Write(1)));
+    assert!(calls.contains(&EngineCall::Update(ResourceKind::SurfaceBuffer)));
+    assert_eq!(screen_task.drain_released_buffers()[0].token, 1);
+    assert!(mock_frame(&mut engine, &mut screen_task).is_empty());
+
+    // The validator follows the source kept by the surface.
+    screen_task.update_data(0, vec![0; 64]).unwrap();
+    assert!(mock_frame(&mut engine, &mut screen_task).contains(&EngineCall::Write(1)));
+
+    // So it does when the devices reject the new source, here a pool shorter than the buffer.
+    let file = memfd_buffer(&[0; 8]);
+    screen_task
+        .update_source(
+            0,
+            SurfaceSource::ShmPool {
+                fd: std::os::unix::io::AsRawFd::as_raw_fd(&file),
+                offset: 0,
+                size: [2, 2],
+                stride: 8,
+                format: wgpu::TextureFormat::Rgba8UnormSrgb,
+            },
+        )
+        .unwrap();
+    screen_task.update_engine_resources(&mut engine);
+    assert_eq!(screen_task.drain_errors().len(), 1);
+    engine.finish_frame();
+    assert!(matches!(
+        screen_task.update_data(0, vec![0; 16]),
+        Err(ScreenTaskError::InvalidDataLength {
+            length: 16,
+            expected: 64
+        })
+    ));
+}
+
+#[test]
+fn buffer_release_test() {
+    use crate::{BufferRelease, BufferSync};
+    let (mut engine, mut screen_task) = mock_screen_task();
+    let sync = |token| BufferSync {
+        acquire: None,
+        release: Some(token),
+    };
+    screen_task
+        .create_surface(
+            0,
+            "surface",
+            solid_source([4, 4], [0; 4]),
+            [0, 0, 0],
+            [4, 4],
+        )
+        .unwrap();
+    screen_task
+        .update_source_synced(0, solid_source([4, 4], [1; 4]), sync(1))
+        .unwrap();
+    mock_frame(&mut engine, &mut screen_task);
+    let first_frame = screen_task.frame_serial();
+
+    // The buffer sampled by the first frame is released when the frame is completed.
+    screen_task
+        .update_source_synced(0, solid_source([4, 4], [2; 4]), sync(2))
+        .unwrap();
+    mock_frame(&mut engine, &mut screen_task);
+    assert!(screen_task.drain_released_buffers().is_empty());
+    screen_task.frame_completed(first_frame);
+    assert_eq!(
+        screen_task.drain_released_buffers(),
+        vec![BufferRelease {
+            surface: 0,
+            token: 1
+        }]
+    );
+
+    // A buffer replaced in the frame that applied it was never sampled.
+    screen_task
+        .update_source_synced(0, solid_source([4, 4], [3; 4]), sync(3))
+        .unwrap();
+    screen_task
+        .update_source_synced(0, solid_source([4, 4], [4; 4]), sync(4))
+        .unwrap();
+    mock_frame(&mut engine, &mut screen_task);
+    let releases = screen_task.drain_released_buffers();
+    assert_eq!(
+        releases
+            .iter()
+            .map(|release| release.token)
+            .collect::<Vec<_>>(),
+        vec![3]
+    );
+    screen_task.frame_completed(screen_task.frame_serial());
+    assert_eq!(screen_task.drain_released_buffers()[0].token, 2);
+
+    // Removing the surface releases its buffer.
+    screen_task.remove_surface(0).unwrap();
+    mock_frame(&mut engine, &mut screen_task);
+    screen_task.frame_completed(screen_task.frame_serial());
+    assert_eq!(screen_task.drain_released_buffers()[0].token, 4);
+}
+
+#[test]
+fn sync_file_fence_test() {
+    use crate::AcquireFence;
+    use std::io::Write;
+    use std::os::unix::io::FromRawFd;
+    let mut fds = [0; 2];
+    assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+    let fence = unsafe { crate::SyncFileFence::from_raw_fd(fds[0]) };
+    let mut writer = unsafe { std::fs::File::from_raw_fd(fds[1]) };
+    assert!(!fence.is_signaled());
+    writer.write_all(&[1]).unwrap();
+    assert!(fence.is_signaled());
+}
+
+#[test]
+fn shm_pool_test() {
+    use crate::{EngineCall, ResourceKind, ScreenTaskError};
+    use std::os::unix::io::AsRawFd;
+    // Two buffers of 2x2 pixels with a stride of 12 bytes.
+    let content: Vec<u8> = (0..48).collect();
+    let file = memfd_buffer(&content);
+    let source = |offset| SurfaceSource::ShmPool {
+        fd: file.as_raw_fd(),
+        offset,
+        size: [2, 2],
+        stride: 12,
+        format: wgpu::TextureFormat::Rgba8UnormSrgb,
+    };
+    assert!(matches!(
+        crate::SurfaceSource::ShmPool {
+            fd: file.as_raw_fd(),
+            offset: 0,
+            size: [4, 2],
+            stride: 12,
+            format: wgpu::TextureFormat::Rgba8UnormSrgb,
+        }
+        .validate(),
+        Err(ScreenTaskError::InvalidStride { .. })
+    ));
+    // Offsets are provided by the clients, the end of the buffer must not overflow.
+    assert!(matches!(
+        source(u64::MAX).validate(),
+        Err(ScreenTaskError::BufferOutOfRange {
+            offset: u64::MAX,
+            length: 24
+        })
+    ));
+
+    // Clones of the pools share the mappings, so the devices map every pool once.
+    let pools = crate::mapping::ShmPools::default();
+    let mapping = pools.clone().map(file.as_raw_fd(), 24).unwrap();
+    assert!(std::sync::Arc::ptr_eq(
+        &mapping,
+        &pools.map(file.as_raw_fd(), 48).unwrap()
+    ));
+    assert!(matches!(
+        mapping.read_range(usize::MAX, 24),
+        Err(ScreenTaskError::InvalidDataLength { .. })
+    ));
+
+    let textures = crate::SoftwareTexture::from_source(&source(24)).unwrap();
+    assert_eq!(textures.len(), 1);
+
+    let mut engine = crate::MockEngine::new();
+    let device = engine.create_device();
+    let mut surface_manager = crate::SurfaceManager::new(&mut engine, device).unwrap();
+    surface_manager
+        .create_surface(&mut engine, String::new(), 0, source(0), [0, 0, 0], [2, 2])
+        .unwrap();
+    let calls = engine.finish_frame();
+    assert!(calls.contains(&EngineCall::Add(ResourceKind::Texture)));
+    assert!(calls.contains(&EngineCall::Write(1)));
+
+    // Every commit uploads the buffer from the mapping.
+    surface_manager.commit_surface(&mut engine, &0).unwrap();
+    assert_eq!(engine.finish_frame(), vec![EngineCall::Write(1)]);
+    surface_manager
+        .update_source(&mut engine, &0, source(24))
+        .unwrap();
+    assert!(engine.finish_frame().contains(&EngineCall::Write(1)));
+    assert!(matches!(
+        surface_manager.update_source(&mut engine, &0, source(48)),
+        Err(ScreenTaskError::InvalidDataLength { .. })
+    ));
+    assert!(matches!(
+        surface_manager.update_source(&mut engine, &0, source(u64::MAX)),
+        Err(ScreenTaskError::BufferOutOfRange { .. })
+    ));
+
+    // A client shrinking the pool makes the commits fail instead of crashing.
+    file.set_len(0).unwrap();
+    assert!(matches!(
+        surface_manager.commit_surface(&mut engine, &0),
+        Err(ScreenTaskError::TruncatedPool)
+    ));
+    assert!(matches!(
+        surface_manager.commit_surface(&mut engine, &0),
+        Err(ScreenTaskError::TruncatedPool)
+    ));
+}
+
+#[test]
+fn failed_creation_test() {
+    use crate::ScreenTaskError;
+    use std::os::unix::io::AsRawFd;
+    // The pool is shorter than the buffer, which is only found out when the devices map it.
+    let file = memfd_buffer(&[0; 8]);
+    let short_pool = SurfaceSource::ShmPool {
+        fd: file.as_raw_fd(),
+        offset: 0,
+        size: [2, 2],
+        stride: 8,
+        format: wgpu::TextureFormat::Rgba8UnormSrgb,
+    };
+    let (mut engine, mut screen_task) = mock_screen_task();
+    screen_task
+        .create_surface(0, "surface", short_pool.clone(), [0, 0, 0], [2, 2])
+        .unwrap();
+    screen_task
+        .create_subsurface(
+            1,
+            0,
+            "subsurface",
+            solid_source([2, 2], [0; 4]),
+            [0, 0],
+            [2, 2],
+        )
+        .unwrap();
+    // A later creation of the same id is already mirrored by the validator.
+    screen_task
+        .create_surface(2, "surface", short_pool, [0, 0, 1], [2, 2])
+        .unwrap();
+    screen_task.remove_surface(2).unwrap();
+    screen_task
+        .create_surface(
+            2,
+            "surface",
+            solid_source([2, 2], [0; 4]),
+            [0, 0, 1],
+            [2, 2],
+        )
+        .unwrap();
+    screen_task.update_engine_resources(&mut engine);
+    engine.finish_frame();
+    assert_eq!(screen_task.drain_errors().len(), 4);
+
+    // The failed surfaces are forgotten, together with their subsurfaces.
+    assert_eq!(screen_task.stacking_order(), vec![2]);
+    assert!(matches!(
+        screen_task.set_surface_opacity(1, 0.5),
+        Err(ScreenTaskError::UnknownSurface(1))
+    ));
+    screen_task
+        .create_surface(
+            0,
+            "surface",
+            solid_source([2, 2], [0; 4]),
+            [0, 0, 0],
+            [2, 2],
+        )
+        .unwrap();
+    screen_task.set_surface_opacity(2, 0.5).unwrap();
+    mock_frame(&mut engine, &mut screen_task);
+}
+
+#[test]
+fn shm_pool_mapping_test() {
+    use crate::mapping::{MemoryMapping, ShmPools};
+    use std::os::unix::io::AsRawFd;
+    let content: Vec<u8> = (0..64).collect();
+    let file = memfd_buffer(&content);
+    let fd = file.as_raw_fd();
+
+    // The buffers of a pool share its mapping, until the pool grows past it.
+    let pools = ShmPools::default();
+    let mapping = pools.map(fd, 32).unwrap();
+    assert_eq!(mapping.len(), 64);
+    assert!(std::sync::Arc::ptr_eq(
+        &mapping,
+        &pools.map(fd, 64).unwrap()
+    ));
+    assert_eq!(mapping.read_range(8, 4).unwrap(), vec![8, 9, 10, 11]);
+    assert!(pools.map(fd, 128).is_err());
+    file.set_len(8192).unwrap();
+    let grown = pools.map(fd, 128).unwrap();
+    assert!(!std::sync::Arc::ptr_eq(&mapping, &grown));
+    assert_eq!(grown.len(), 8192);
+
+    // Reads past the end of the shrunk file are recovered.
+    let mapping = MemoryMapping::new(fd, 0, 8192).unwrap();
+    file.set_len(0).unwrap();
+    assert!(matches!(
+        mapping.read_range(4096, 16),
+        Err(crate::ScreenTaskError::TruncatedPool)
+    ));
+    assert!(mapping.read_range(0, 16).is_err());
+    assert!(mapping.read_range(8000, 400).is_err());
+}
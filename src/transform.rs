@@ -0,0 +1,107 @@
+/**
+Transform applied to a buffer or an output, following the wl_output transform semantics.
+Rotations are counter-clockwise.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Transform {
+    #[default]
+    Normal,
+    Rotated90,
+    Rotated180,
+    Rotated270,
+    Flipped,
+    Flipped90,
+    Flipped180,
+    Flipped270,
+}
+impl Transform {
+    /// All the available transforms.
+    pub const ALL: [Transform; 8] = [
+        Self::Normal,
+        Self::Rotated90,
+        Self::Rotated180,
+        Self::Rotated270,
+        Self::Flipped,
+        Self::Flipped90,
+        Self::Flipped180,
+        Self::Flipped270,
+    ];
+
+    /// Returns true if the transform swaps width and height.
+    pub fn swaps_dimensions(&self) -> bool {
+        matches!(
+            self,
+            Self::Rotated90 | Self::Rotated270 | Self::Flipped90 | Self::Flipped270
+        )
+    }
+
+    /**
+    Returns the affine matrix, as two rows of `[x, y, translation]`, that maps a normalized surface coordinate
+    to the normalized buffer coordinate to sample.
+    */
+    pub fn uv_matrix(&self) -> [[f32; 3]; 2] {
+        match self {
+            Self::Normal => [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0]],
+            Self::Rotated90 => [[0.0, -1.0, 1.0], [1.0, 0.0, 0.0]],
+            Self::Rotated180 => [[-1.0, 0.0, 1.0], [0.0, -1.0, 1.0]],
+            Self::Rotated270 => [[0.0, 1.0, 0.0], [-1.0, 0.0, 1.0]],
+            Self::Flipped => [[-1.0, 0.0, 1.0], [0.0, 1.0, 0.0]],
+            Self::Flipped90 => [[0.0, -1.0, 1.0], [-1.0, 0.0, 1.0]],
+            Self::Flipped180 => [[1.0, 0.0, 0.0], [0.0, -1.0, 1.0]],
+            Self::Flipped270 => [[0.0, 1.0, 0.0], [1.0, 0.0, 0.0]],
+        }
+    }
+
+    /// Map a normalized surface coordinate to the normalized buffer coordinate to sample.
+    pub fn apply_to_uv(&self, uv: [f32; 2]) -> [f32; 2] {
+        apply_affine(&self.uv_matrix(), uv)
+    }
+}
+
+/// Apply an affine matrix, expressed as two rows of `[x, y, translation]`, to a point.
+pub fn apply_affine(matrix: &[[f32; 3]; 2], point: [f32; 2]) -> [f32; 2] {
+    [
+        matrix[0][0] * point[0] + matrix[0][1] * point[1] + matrix[0][2],
+        matrix[1][0] * point[0] + matrix[1][1] * point[1] + matrix[1][2],
+    ]
+}
+
+/**
+Transform of a surface.
+The buffer transform is applied while sampling the surface's buffer,
+while the matrix is applied to the surface geometry, around the center of the surface.
+*/
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SurfaceTransform {
+    pub buffer_transform: Transform,
+    /// Affine matrix in surface pixels, as two rows of `[x, y, translation]`.
+    pub matrix: [[f32; 3]; 2],
+}
+impl Default for SurfaceTransform {
+    fn default() -> Self {
+        Self {
+            buffer_transform: Transform::Normal,
+            matrix: Self::IDENTITY,
+        }
+    }
+}
+impl From<Transform> for SurfaceTransform {
+    fn from(buffer_transform: Transform) -> Self {
+        Self {
+            buffer_transform,
+            matrix: Self::IDENTITY,
+        }
+    }
+}
+impl SurfaceTransform {
+    pub const IDENTITY: [[f32; 3]; 2] = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0]];
+
+    /// Create a surface transform made only of a rotation of the geometry, in radians, counter-clockwise.
+    pub fn rotation(angle: f32) -> Self {
+        let (sin, cos) = angle.sin_cos();
+        Self {
+            buffer_transform: Transform::Normal,
+            matrix: [[cos, sin, 0.0], [-sin, cos, 0.0]],
+        }
+    }
+}
@@ -0,0 +1,209 @@
+use wgpu_engine::*;
+
+/// Pixel layout of a multi-plane YUV buffer. Chroma planes are subsampled by 2 in both dimensions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum YuvFormat {
+    /// 8 bit luma plane followed by an interleaved 8 bit CbCr plane.
+    Nv12,
+    /// 8 bit luma plane followed by separated 8 bit Cb and Cr planes.
+    Yuv420,
+    /**
+    16 bit little endian luma plane followed by an interleaved 16 bit CbCr plane, with the 10 significant bits
+    stored in the most significant bits. Only host allocations are supported, and their samples are uploaded
+    as half precision floats, which hold the 10 significant bits exactly.
+    */
+    P010,
+}
+impl YuvFormat {
+    /// Returns the number of planes of the format.
+    pub fn plane_count(&self) -> usize {
+        match self {
+            Self::Nv12 | Self::P010 => 2,
+            Self::Yuv420 => 3,
+        }
+    }
+
+    /// Returns the size in pixels of the requested plane.
+    pub fn plane_size(&self, plane: usize, size: [u32; 2]) -> [u32; 2] {
+        if plane == 0 {
+            size
+        } else {
+            [size[0].div_ceil(2), size[1].div_ceil(2)]
+        }
+    }
+
+    /// Returns the texture format used to sample the requested plane.
+    pub fn plane_texture_format(&self, plane: usize) -> wgpu::TextureFormat {
+        match (self, plane) {
+            (Self::Nv12, 1) => wgpu::TextureFormat::Rg8Unorm,
+            (Self::P010, 0) => wgpu::TextureFormat::R16Float,
+            (Self::P010, _) => wgpu::TextureFormat::Rg16Float,
+            _ => wgpu::TextureFormat::R8Unorm,
+        }
+    }
+
+    /// Returns the size in bytes of a pixel of the requested plane, as stored in the client buffer.
+    pub fn plane_bytes_per_pixel(&self, plane: usize) -> u32 {
+        match (self, plane) {
+            (Self::Nv12, 0) | (Self::Yuv420, _) => 1,
+            (Self::Nv12, _) => 2,
+            (Self::P010, 0) => 2,
+            (Self::P010, _) => 4,
+        }
+    }
+
+    /// Returns the code used by the fragment shader to sample the planes.
+    pub fn sampling_code(&self) -> u32 {
+        match self {
+            Self::Nv12 | Self::P010 => SAMPLING_YUV_SEMI_PLANAR,
+            Self::Yuv420 => SAMPLING_YUV_PLANAR,
+        }
+    }
+
+    /**
+    Convert the data of a plane, as stored in the client buffer, into the data expected by the texture
+    of that plane. The returned data is tightly packed if the plane needed a conversion.
+    Returns the data together with its stride.
+    */
+    pub fn convert_plane(
+        &self,
+        plane: usize,
+        size: [u32; 2],
+        stride: u32,
+        data: Vec<u8>,
+    ) -> (Vec<u8>, u32) {
+        match self {
+            Self::P010 => {
+                let plane_size = self.plane_size(plane, size);
+                let row_length = (plane_size[0] * self.plane_bytes_per_pixel(plane)) as usize;
+                let mut converted = Vec::with_capacity(row_length * plane_size[1] as usize);
+                for row in data.chunks(stride as usize).take(plane_size[1] as usize) {
+                    // Little endian 16 bit unsigned normalized samples become half precision floats.
+                    converted.extend(row[..row_length].chunks_exact(2).flat_map(|sample| {
+                        let value = u16::from_le_bytes([sample[0], sample[1]]) as f32 / 65535.0;
+                        half_float_bits(value).to_le_bytes()
+                    }));
+                }
+                (converted, row_length as u32)
+            }
+            _ => (data, stride),
+        }
+    }
+}
+
+/// Encode a value of the [0, 1] range as a half precision float, rounding to the nearest.
+pub(crate) fn half_float_bits(value: f32) -> u16 {
+    if value <= 0.0 {
+        return 0;
+    }
+    let bits = value.min(1.0).to_bits();
+    let exponent = ((bits >> 23) & 0xff) as i32 - 127 + 15;
+    if exponent <= 0 {
+        // Subnormal halves are multiples of 2^-24.
+        return (value * (1 << 24) as f32).round() as u16;
+    }
+    let mantissa = bits & 0x7f_ffff;
+    // A carry of the rounding into the exponent still gives the nearest half.
+    (((exponent as u32) << 10 | mantissa >> 13) + ((mantissa >> 12) & 1)) as u16
+}
+
+/// Sampling code for single plane RGBA surfaces.
+pub const SAMPLING_RGBA: u32 = 0;
+/// Sampling code for surfaces with a luma plane and an interleaved chroma plane.
+pub const SAMPLING_YUV_SEMI_PLANAR: u32 = 1;
+/// Sampling code for surfaces with a luma plane and two separated chroma planes.
+pub const SAMPLING_YUV_PLANAR: u32 = 2;
+
+/// Matrix used to convert YUV values into RGB values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum YuvMatrix {
+    Bt601,
+    Bt709,
+}
+impl YuvMatrix {
+    /// Returns the red and blue luma coefficients (Kr, Kb) of the matrix.
+    pub fn luma_coefficients(&self) -> (f32, f32) {
+        match self {
+            Self::Bt601 => (0.299, 0.114),
+            Self::Bt709 => (0.2126, 0.0722),
+        }
+    }
+}
+
+/// Range of the encoded YUV values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum YuvRange {
+    /// Values cover the whole [0, 255] range.
+    Full,
+    /// Luma covers [16, 235] and chroma covers [16, 240], as usual for video.
+    Limited,
+}
+
+/// Color space of a YUV buffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct YuvColorSpace {
+    pub matrix: YuvMatrix,
+    pub range: YuvRange,
+}
+impl Default for YuvColorSpace {
+    fn default() -> Self {
+        Self {
+            matrix: YuvMatrix::Bt709,
+            range: YuvRange::Limited,
+        }
+    }
+}
+impl YuvColorSpace {
+    /// Returns the code used by the fragment shader to select the conversion.
+    pub fn shader_code(&self) -> u32 {
+        let matrix = match self.matrix {
+            YuvMatrix::Bt601 => 0,
+            YuvMatrix::Bt709 => 1,
+        };
+        let range = match self.range {
+            YuvRange::Full => 0,
+            YuvRange::Limited => 2,
+        };
+        matrix | range
+    }
+
+    /// Returns the color space selected by a shader code.
+    pub fn from_shader_code(code: u32) -> Self {
+        let matrix = if code & 1 != 0 {
+            YuvMatrix::Bt709
+        } else {
+            YuvMatrix::Bt601
+        };
+        let range = if code & 2 != 0 {
+            YuvRange::Limited
+        } else {
+            YuvRange::Full
+        };
+        Self { matrix, range }
+    }
+}
+
+/**
+Convert a normalized YUV triple into a normalized RGB triple.
+This is the same conversion performed by the fragment shader.
+*/
+pub fn yuv_to_rgb(yuv: [f32; 3], color_space: YuvColorSpace) -> [f32; 3] {
+    let (y, u, v) = match color_space.range {
+        YuvRange::Full => (yuv[0], yuv[1] - 128.0 / 255.0, yuv[2] - 128.0 / 255.0),
+        YuvRange::Limited => (
+            (yuv[0] - 16.0 / 255.0) * 255.0 / 219.0,
+            (yuv[1] - 128.0 / 255.0) * 255.0 / 224.0,
+            (yuv[2] - 128.0 / 255.0) * 255.0 / 224.0,
+        ),
+    };
+    let (r_v, g_u, g_v, b_u) = match color_space.matrix {
+        YuvMatrix::Bt601 => (1.402, 0.344136, 0.714136, 1.772),
+        YuvMatrix::Bt709 => (1.5748, 0.187324, 0.468124, 1.8556),
+    };
+    let clamp = |value: f32| value.clamp(0.0, 1.0);
+    [
+        clamp(y + r_v * v),
+        clamp(y - g_u * u - g_v * v),
+        clamp(y + b_u * u),
+    ]
+}